@@ -0,0 +1,69 @@
+extern crate bindgen;
+
+use std::env;
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rerun-if-changed=wrapper.h");
+    println!("cargo:rerun-if-changed=vendor");
+
+    // cups-raster-sys has already found CUPS, and taken care of linking it.
+    // We just need to look for headers in the same places it did.
+    let mut include_paths = env::var_os("DEP_CUPS_INCLUDE")
+        .map(|paths| env::split_paths(&paths).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if env::var_os("CARGO_FEATURE_VENDORED_HEADERS").is_some() {
+        include_paths.insert(
+            0,
+            PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("vendor"),
+        );
+    }
+
+    // The bindgen::Builder is the main entry point
+    // to bindgen, and lets you build up options for
+    // the resulting bindings.
+    let mut builder = bindgen::Builder::default()
+        .rust_target(bindgen::RustTarget::stable(85, 0).map_err(|_| ()).unwrap())
+        .rust_edition(bindgen::RustEdition::Edition2024)
+        .wrap_unsafe_ops(true)
+
+        // The input header we would like to generate
+        // bindings for.
+        .header("wrapper.h")
+        // Only generate bindings for things declared in ppd.h (and whatever
+        // types they drag in).
+        .allowlist_file(".*/cups/ppd\\.h")
+        // ...except for the core types that cups-raster-sys already binds,
+        // which we re-export instead so that they're interchangeable.
+        .blocklist_type("cups_option_[st]")
+        // bindgen layout tests fail on Rust nightly >1.21.0
+        // "thread 'bindgen_test_layout_max_align_t' panicked at
+        // 'assertion failed: `(left == right)`"
+        .layout_tests(false)
+        // The entire PPD API is deprecated, which is not news to us. Defining
+        // this away keeps the availability annotations on Apple's headers (and
+        // newer CUPS releases) from complaining about every declaration.
+        .clang_arg("-D_PPD_DEPRECATED=")
+        .clang_arg("-Wno-deprecated-declarations");
+
+    // As in cups-raster-sys: when cross-compiling, clang needs to find the
+    // target's headers rather than the host's.
+    if let Ok(sysroot) = env::var("PKG_CONFIG_SYSROOT_DIR") {
+        builder = builder.clang_arg(format!("--sysroot={sysroot}"));
+    }
+    for path in include_paths {
+        builder = builder.clang_arg(format!("-I{}", path.display()));
+    }
+
+    let bindings = builder
+        // Finish the builder and generate the bindings.
+        .generate()
+        // Unwrap the Result and panic on failure.
+        .expect("Unable to generate bindings");
+
+    // Write the bindings to the $OUT_DIR/bindings.rs file.
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
+    bindings
+        .write_to_file(out_path.join("bindings.rs"))
+        .expect("Couldn't write bindings!");
+}
@@ -0,0 +1,10 @@
+#![allow(nonstandard_style)] // hey man, I didn't pick these names
+#![allow(unsafe_op_in_unsafe_fn)] // TODO bindgen bug #3147
+
+#![allow(clippy::all)]
+
+// The PPD API uses some core CUPS types, which are bound (once) by
+// cups-raster-sys.
+pub use cups_raster_sys::{cups_option_s, cups_option_t};
+
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
@@ -0,0 +1 @@
+#include <cups/ppd.h>
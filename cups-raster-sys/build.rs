@@ -0,0 +1,145 @@
+extern crate bindgen;
+extern crate pkg_config;
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=wrapper.h");
+    println!("cargo:rerun-if-env-changed=CUPS_INCLUDE");
+    println!("cargo:rerun-if-env-changed=CUPS_LIB");
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR");
+    println!("cargo:rerun-if-changed=vendor");
+
+    // Work out where CUPS lives, and tell cargo to tell rustc to link it.
+    let include_paths = find_cups();
+
+    // The bindgen::Builder is the main entry point
+    // to bindgen, and lets you build up options for
+    // the resulting bindings.
+    let mut builder = bindgen::Builder::default()
+        .rust_target(bindgen::RustTarget::stable(85, 0).map_err(|_| ()).unwrap())
+        .rust_edition(bindgen::RustEdition::Edition2024)
+        .wrap_unsafe_ops(true)
+
+        // The input header we would like to generate
+        // bindings for.
+        .header("wrapper.h")
+        // Only generate bindings for the CUPS API itself (and whatever types
+        // it drags in), rather than everything the system headers declare.
+        // This is a tiny fraction of the output, and avoids tripping over
+        // platform types that bindgen can't lay out. The PPD API lives in
+        // cups-ppd-sys.
+        .allowlist_function("cups[A-Z].*")
+        .allowlist_type("cups_.*")
+        .allowlist_var("CUPS_.*")
+        // bindgen layout tests fail on Rust nightly >1.21.0
+        // "thread 'bindgen_test_layout_max_align_t' panicked at
+        // 'assertion failed: `(left == right)`"
+        .layout_tests(false)
+        // Apple's headers mark a lot of this API deprecated, which is not news
+        // to us.
+        .clang_arg("-Wno-deprecated-declarations");
+
+    // bindgen already passes the cargo target through to clang, but when
+    // cross-compiling, clang also needs to find the target's headers rather
+    // than the host's.
+    if let Ok(sysroot) = env::var("PKG_CONFIG_SYSROOT_DIR") {
+        builder = builder.clang_arg(format!("--sysroot={sysroot}"));
+    }
+    for path in &include_paths {
+        builder = builder.clang_arg(format!("-I{}", path.display()));
+    }
+
+    // Share the include paths with dependents (like cups-ppd-sys) through
+    // `DEP_CUPS_INCLUDE`, so they find the same headers we did.
+    if let Ok(paths) = env::join_paths(&include_paths) {
+        println!("cargo:include={}", paths.to_string_lossy());
+    }
+
+    let bindings = builder
+        // Finish the builder and generate the bindings.
+        .generate()
+        // Unwrap the Result and panic on failure.
+        .expect("Unable to generate bindings");
+
+    // Write the bindings to the $OUT_DIR/bindings.rs file.
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
+    bindings
+        .write_to_file(out_path.join("bindings.rs"))
+        .expect("Couldn't write bindings!");
+}
+
+/// Locates the CUPS headers and library, emitting the linker directives, and
+/// returns any extra include paths needed to find the headers.
+///
+/// With the `vendored-headers` feature, we use our own copies of the headers
+/// and link against the runtime library directly, so only the CUPS runtime
+/// package needs to be installed.
+///
+/// The `CUPS_INCLUDE` and `CUPS_LIB` environment variables, if set, take
+/// precedence. Otherwise, we ask pkg-config, which honors the usual
+/// `PKG_CONFIG_SYSROOT_DIR`/`PKG_CONFIG_PATH` variables for cross builds. If
+/// that fails too, we fall back to hoping CUPS is in the default search paths.
+/// On macOS, we skip pkg-config and use the SDK's copy of CUPS.
+fn find_cups() -> Vec<PathBuf> {
+    let include = env::var_os("CUPS_INCLUDE").map(PathBuf::from);
+    let lib = env::var_os("CUPS_LIB").map(PathBuf::from);
+
+    if env::var_os("CARGO_FEATURE_VENDORED_HEADERS").is_some() {
+        let vendor = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("vendor");
+        if let Some(lib) = lib {
+            println!("cargo:rustc-link-search=native={}", lib.display());
+        }
+        if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("macos") {
+            println!("cargo:rustc-link-lib=cups");
+        } else {
+            // Without the development package there's no unversioned
+            // `libcups.so` symlink, so name the runtime library exactly.
+            println!("cargo:rustc-link-lib=dylib:+verbatim=libcups.so.2");
+        }
+        return vec![vendor];
+    }
+
+    if include.is_some() || lib.is_some() {
+        if let Some(lib) = lib {
+            println!("cargo:rustc-link-search=native={}", lib.display());
+        }
+        println!("cargo:rustc-link-lib=cups");
+        return include.into_iter().collect();
+    }
+
+    // Apple ships CUPS as part of the OS, with headers in the SDK rather than
+    // anywhere pkg-config would know about.
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("macos") {
+        println!("cargo:rustc-link-lib=cups");
+        return macos_sdk_include().into_iter().collect();
+    }
+
+    match pkg_config::Config::new().probe("cups") {
+        Ok(library) => library.include_paths,
+        Err(e) => {
+            // Warnings must fit on one line, and pkg-config errors don't.
+            let e = e.to_string().replace('\n', " ");
+            println!("cargo:warning=pkg-config couldn't find cups ({e}), assuming system paths");
+            println!("cargo:rustc-link-lib=cups");
+            vec![]
+        }
+    }
+}
+
+/// Asks Xcode where the macOS SDK is, and returns its include directory.
+fn macos_sdk_include() -> Option<PathBuf> {
+    let output = Command::new("xcrun")
+        .args(["--sdk", "macosx", "--show-sdk-path"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success());
+    let Some(output) = output else {
+        println!("cargo:warning=couldn't find the macOS SDK with xcrun, assuming system paths");
+        return None;
+    };
+    let sdk = String::from_utf8_lossy(&output.stdout);
+    Some(PathBuf::from(sdk.trim()).join("usr/include"))
+}
@@ -0,0 +1,31 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Feeds arbitrary job option strings through option parsing and the settings
+//! derived from them.
+
+#![no_main]
+
+use std::ffi::CString;
+
+use libfuzzer_sys::fuzz_target;
+use raster_tspl::{api::Options, form::Form, stock, testpage::Pattern};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(arg) = CString::new(data) else {
+        return;
+    };
+    let options = Options::parse(&arg);
+
+    if let Some(value) = options.get(c"label-stock").and_then(|v| v.to_str().ok()) {
+        let _ = stock::lookup(value);
+    }
+    if let Some(value) = options.get(c"tspl-test-page").and_then(|v| v.to_str().ok()) {
+        let _ = value.parse::<Pattern>();
+    }
+    // Don't go reading whatever files the input names.
+    if options.get(c"form-file").is_none() {
+        let _ = Form::from_options(&options);
+    }
+});
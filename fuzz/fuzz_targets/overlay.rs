@@ -0,0 +1,30 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Decodes arbitrary overlay images, and composites them onto a page.
+
+#![no_main]
+
+use cups_raster_sys::cups_cspace_e_CUPS_CSPACE_K;
+use libfuzzer_sys::fuzz_target;
+use raster_tspl::{api, overlay::Overlay};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(overlay) = Overlay::from_bytes(data, (1.0, 1.0)) else {
+        return;
+    };
+
+    let mut header = api::blank_page_header();
+    header.HWResolution = [203, 203];
+    header.cupsWidth = 100;
+    header.cupsHeight = 100;
+    header.cupsBitsPerColor = 8;
+    header.cupsBytesPerLine = 100;
+    header.cupsColorSpace = cups_cspace_e_CUPS_CSPACE_K;
+
+    let mut row = vec![0; 100];
+    for y in 0..header.cupsHeight {
+        overlay.apply(&header, y, &mut row);
+    }
+});
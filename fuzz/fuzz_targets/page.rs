@@ -0,0 +1,81 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Feeds pages with arbitrary raster headers through the page pipeline, as a
+//! misbehaving upstream filter might: a header, a byte choosing the model and
+//! a few settings, and then the page's rows, for as long as they last.
+
+#![no_main]
+
+use std::io;
+
+use cups_raster_sys::cups_page_header2_t;
+use libfuzzer_sys::fuzz_target;
+use raster_tspl::{
+    job::{JobSettings, Oversize},
+    model,
+    page::{self, ResolvedSettings},
+    settings::MemorySettings,
+    stock,
+    tspl::TsplWriter,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((header, rest)) = data.split_at_checked(size_of::<cups_page_header2_t>()) else {
+        return;
+    };
+    let Some((&flags, pixels)) = rest.split_first() else {
+        return;
+    };
+    // Every field is a number or an array of them, so any bytes will do.
+    let header: cups_page_header2_t = unsafe { std::ptr::read_unaligned(header.as_ptr().cast()) };
+    if page::check_format(&header).is_err() {
+        return;
+    }
+    // Don't let a hostile header make us allocate the world; the interesting
+    // part is what's done with each row.
+    let line = header.cupsBytesPerLine as usize;
+    if line > 1 << 16 || header.cupsHeight > 1 << 16 {
+        return;
+    }
+
+    let models = model::models().collect::<Vec<_>>();
+    let model = models[usize::from(flags & 0x3) % models.len()];
+    let mut ppd = MemorySettings::default();
+    if flags & 0x4 != 0 {
+        ppd = ppd
+            .with(c"MediaType", c"Continuous")
+            .with(c"ContinuousLength", c"Content");
+    }
+    if flags & 0x8 != 0 {
+        ppd = ppd.with(c"SkipBlankPages", c"True");
+    }
+    let job = JobSettings {
+        copies: 1,
+        stock: (flags & 0x10 != 0).then(|| stock::lookup("2x1").unwrap()),
+        oversize: Some(if flags & 0x20 != 0 {
+            Oversize::Scale
+        } else {
+            Oversize::Clip
+        }),
+        ..JobSettings::default()
+    };
+
+    let out = &mut TsplWriter::buffered(io::sink());
+    let Ok(settings) = ResolvedSettings::resolve(model, &mut ppd, &job, &header) else {
+        return;
+    };
+    let Ok(mut state) = page::start_page(out, model, &settings, &job, &header) else {
+        return;
+    };
+    for (y, row) in pixels.chunks_exact(line.max(1)).enumerate() {
+        if y as u32 >= header.cupsHeight
+            || page::output_line(out, model, &header, y as u32, row, &mut state).is_err()
+        {
+            return;
+        }
+    }
+    // A page cut short is padded out, as when the rasterizer dies.
+    let _ = page::end_page(out, model, &settings, &job, state);
+});
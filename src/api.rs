@@ -14,23 +14,75 @@
 //! construction invariants hold.
 
 use std::{
+    collections::BTreeMap,
     error::Error,
-    ffi::{CStr, c_int},
+    ffi::{CStr, CString, c_char, c_int, c_uchar, c_void},
     fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
     mem::MaybeUninit,
     ops::{Deref, DerefMut},
     os::fd::{AsRawFd, IntoRawFd},
     path::Path,
-    ptr::{NonNull, null_mut},
+    ptr::{NonNull, null, null_mut},
     str::FromStr,
+    sync::{Mutex, PoisonError},
+    time::Duration,
 };
 
-use cups_filter_sys::{
+use cups_ppd_sys::{
+    cupsMarkOptions, cupsResolveConflicts, ppd_choice_t, ppd_cptype_e_PPD_CUSTOM_CURVE,
+    ppd_cptype_e_PPD_CUSTOM_INT, ppd_cptype_e_PPD_CUSTOM_INVCURVE, ppd_cptype_e_PPD_CUSTOM_POINTS,
+    ppd_cptype_e_PPD_CUSTOM_REAL, ppd_cptype_e_PPD_CUSTOM_STRING, ppd_file_t, ppd_option_t,
+    ppdClose, ppdConflicts, ppdErrorString, ppdFindAttr, ppdFindCustomOption, ppdFindMarkedChoice,
+    ppdFindOption, ppdFirstCustomParam, ppdFirstOption, ppdLastError, ppdMarkDefaults,
+    ppdNextOption, ppdOpenFd, ppdPageSize,
+};
+use cups_raster_sys::{
     cups_mode_e_CUPS_RASTER_READ, cups_option_t, cups_page_header2_t, cups_raster_t,
-    cupsFreeOptions, cupsMarkOptions, cupsParseOptions, cupsRasterClose, cupsRasterOpen,
-    cupsRasterReadHeader2, cupsRasterReadPixels, ppd_choice_t, ppd_file_t, ppdClose,
-    ppdErrorString, ppdFindMarkedChoice, ppdLastError, ppdMarkDefaults, ppdOpenFd,
+    cups_sc_bidi_e_CUPS_SC_BIDI_SUPPORTED, cups_sc_command_e_CUPS_SC_CMD_DRAIN_OUTPUT,
+    cups_sc_command_e_CUPS_SC_CMD_GET_BIDI, cups_sc_command_e_CUPS_SC_CMD_GET_CONNECTED,
+    cups_sc_command_e_CUPS_SC_CMD_GET_DEVICE_ID, cups_sc_command_e_CUPS_SC_CMD_GET_STATE,
+    cups_sc_command_e_CUPS_SC_CMD_SOFT_RESET, cups_sc_command_t,
+    cups_sc_status_e_CUPS_SC_STATUS_BAD_MESSAGE, cups_sc_status_e_CUPS_SC_STATUS_IO_ERROR,
+    cups_sc_status_e_CUPS_SC_STATUS_NO_RESPONSE, cups_sc_status_e_CUPS_SC_STATUS_NOT_IMPLEMENTED,
+    cups_sc_status_e_CUPS_SC_STATUS_OK, cups_sc_status_e_CUPS_SC_STATUS_TIMEOUT,
+    cups_sc_status_e_CUPS_SC_STATUS_TOO_BIG, cupsAddOption, cupsBackChannelRead,
+    cupsBackChannelWrite, cupsFreeDests, cupsFreeOptions, cupsGetNamedDest, cupsGetOption,
+    cupsParseOptions, cupsRasterClose, cupsRasterNew, cupsRasterReadHeader2, cupsRasterReadPixels,
+    cupsSideChannelDoRequest, ssize_t,
 };
+use flate2::bufread::MultiGzDecoder;
+
+/// A PPD keyword, as a `CStr` (which is what CUPS wants) or, for convenience,
+/// a `str`.
+///
+/// A `str` has to be copied to add the terminating NUL. The copies are kept
+/// for reuse, for as long as the program runs, so this is meant for the fixed
+/// set of keywords a program looks up rather than for arbitrary input.
+pub trait Keyword {
+    /// Returns the keyword as a `CStr`, or `None` if it can't be one because
+    /// it contains a NUL (in which case no PPD has it).
+    fn as_cstr(&self) -> Option<&CStr>;
+}
+
+impl Keyword for CStr {
+    fn as_cstr(&self) -> Option<&CStr> {
+        Some(self)
+    }
+}
+
+impl Keyword for str {
+    fn as_cstr(&self) -> Option<&CStr> {
+        static INTERNED: Mutex<BTreeMap<Box<str>, &'static CStr>> = Mutex::new(BTreeMap::new());
+        let mut interned = INTERNED.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&keyword) = interned.get(self) {
+            return Some(keyword);
+        }
+        let keyword = Box::leak(CString::new(self).ok()?.into_boxed_c_str());
+        interned.insert(self.into(), keyword);
+        Some(keyword)
+    }
+}
 
 /// An evaluated PPD file with mutable state for "choices."
 ///
@@ -57,10 +109,9 @@ impl PpdFile {
             let status_str = unsafe { CStr::from_ptr(ppdErrorString(status)) };
             let status_str = status_str.to_string_lossy();
 
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("PPD load failed: line {linenum}: {status_str}"),
-            ))
+            Err(std::io::Error::other(format!(
+                "PPD load failed: line {linenum}: {status_str}"
+            )))
         }
     }
 
@@ -92,11 +143,60 @@ impl PpdFile {
     /// contents, if found.
     ///
     /// If not found, returns `None`.
-    pub fn find_marked_choice<'s>(&'s mut self, keyword: &CStr) -> Option<PpdChoice<'s>> {
+    pub fn find_marked_choice<'s>(
+        &'s mut self,
+        keyword: &(impl Keyword + ?Sized),
+    ) -> Option<PpdChoice<'s>> {
+        let keyword = keyword.as_cstr()?;
         let choice = unsafe { ppdFindMarkedChoice(self.raw_mut(), keyword.as_ptr()) };
         unsafe { choice.as_ref().map(PpdChoice) }
     }
 
+    /// Looks up the option called `keyword`, if the PPD has one.
+    pub fn find_option(&mut self, keyword: &CStr) -> Option<PpdOption<'_>> {
+        let option = unsafe { ppdFindOption(self.raw_mut(), keyword.as_ptr()) };
+        unsafe { option.as_ref().map(PpdOption) }
+    }
+
+    /// Returns the value entered for the custom option `keyword`, as text,
+    /// for when its `Custom` choice is marked.
+    ///
+    /// Only the first parameter counts, which is all that options like
+    /// `CustomDarkness` have. Lengths in points are given in whole
+    /// millimeters, the unit the filter's options are in; passwords and
+    /// passcodes aren't given at all.
+    pub fn custom_value(&mut self, keyword: &CStr) -> Option<String> {
+        let option = unsafe { ppdFindCustomOption(self.raw_mut(), keyword.as_ptr()) };
+        if option.is_null() {
+            return None;
+        }
+        let param = unsafe { ppdFirstCustomParam(option).as_ref() }?;
+        // The type says which member of the union is current.
+        let (kind, value) = (param.type_, &param.current);
+        unsafe {
+            if kind == ppd_cptype_e_PPD_CUSTOM_INT {
+                Some(value.custom_int.to_string())
+            } else if kind == ppd_cptype_e_PPD_CUSTOM_REAL {
+                Some(value.custom_real.to_string())
+            } else if kind == ppd_cptype_e_PPD_CUSTOM_CURVE {
+                Some(value.custom_curve.to_string())
+            } else if kind == ppd_cptype_e_PPD_CUSTOM_INVCURVE {
+                Some(value.custom_invcurve.to_string())
+            } else if kind == ppd_cptype_e_PPD_CUSTOM_POINTS {
+                let mm = value.custom_points * 25.4 / 72.0;
+                Some((mm.round() as i32).to_string())
+            } else if kind == ppd_cptype_e_PPD_CUSTOM_STRING && !value.custom_string.is_null() {
+                Some(
+                    CStr::from_ptr(value.custom_string)
+                        .to_string_lossy()
+                        .into_owned(),
+                )
+            } else {
+                None
+            }
+        }
+    }
+
     /// Finds a marked choice named `keyword` and parses it into a `T`, unless
     /// its value is the exact string `"Default"`.
     ///
@@ -104,7 +204,7 @@ impl PpdFile {
     /// the very common case.
     pub fn parse_default_marked_choice<T>(
         &mut self,
-        keyword: &CStr,
+        keyword: &(impl Keyword + ?Sized),
     ) -> Result<Option<T>, Box<dyn Error>>
     where
         T: FromStr,
@@ -117,7 +217,7 @@ impl PpdFile {
     /// if they do not match `default`.
     pub fn parse_optional_marked_choice<T>(
         &mut self,
-        keyword: &CStr,
+        keyword: &(impl Keyword + ?Sized),
         default: &CStr,
     ) -> Result<Option<T>, Box<dyn Error>>
     where
@@ -130,6 +230,80 @@ impl PpdFile {
             Ok(None)
         }
     }
+
+    /// Lists all of the options defined in the PPD.
+    pub fn options(&mut self) -> Vec<PpdOption<'_>> {
+        let mut options = vec![];
+        let mut option = unsafe { ppdFirstOption(self.raw_mut()) };
+        while let Some(o) = unsafe { option.as_ref() } {
+            options.push(PpdOption(o));
+            option = unsafe { ppdNextOption(self.raw_mut()) };
+        }
+        options
+    }
+
+    /// Changes `options` to settle any conflicts between the choices marked
+    /// by them and the PPD's defaults, as its `cupsResolver` rules and
+    /// constraints allow. The changed options still need marking.
+    ///
+    /// Returns `false`, leaving `options` alone, if the conflicts can't be
+    /// settled.
+    pub fn resolve_conflicts(&mut self, options: &mut Options) -> bool {
+        let mut num_options = c_int::try_from(options.1).unwrap();
+        let mut raw = options.0.map_or(null_mut(), NonNull::as_ptr);
+        let resolved = unsafe {
+            cupsResolveConflicts(self.raw_mut(), null(), null(), &mut num_options, &mut raw)
+        };
+        // If CUPS changed anything, it's replaced the array (and freed the
+        // old one).
+        let p = NonNull::new(raw);
+        options.0 = p;
+        options.1 = if p.is_some() {
+            usize::try_from(num_options).unwrap()
+        } else {
+            0
+        };
+        resolved != 0
+    }
+
+    /// Lists the marked choice for each option that has one, as keyword and
+    /// choice.
+    pub fn marked_choices(&mut self) -> impl Iterator<Item = (&CStr, &CStr)> {
+        self.options().into_iter().filter_map(|option| {
+            let choice = option.marked_choice()?;
+            Some((option.keyword(), choice.choice()))
+        })
+    }
+
+    /// Checks the marked choices against the constraints in the PPD, and
+    /// returns any options involved in a conflict.
+    ///
+    /// An empty result means the selected choices are consistent.
+    pub fn conflicts(&mut self) -> Vec<PpdOption<'_>> {
+        let count = unsafe { ppdConflicts(self.raw_mut()) };
+        if count == 0 {
+            return vec![];
+        }
+        // ppdConflicts flags the offending options as a side effect.
+        self.options()
+            .into_iter()
+            .filter(PpdOption::conflicted)
+            .collect()
+    }
+
+    /// Returns the value of the first attribute named `name`, if there is one
+    /// and it has a value.
+    pub fn find_attr(&mut self, name: &CStr) -> Option<&CStr> {
+        let attr = unsafe { ppdFindAttr(self.raw_mut(), name.as_ptr(), null()) };
+        let value = unsafe { attr.as_ref() }?.value;
+        (!value.is_null()).then(|| unsafe { CStr::from_ptr(value) })
+    }
+
+    /// Returns the width and length of the marked page size, in points.
+    pub fn page_size(&mut self) -> Option<(f32, f32)> {
+        let size = unsafe { ppdPageSize(self.raw_mut(), null()) };
+        unsafe { size.as_ref().map(|size| (size.width, size.length)) }
+    }
 }
 
 impl Drop for PpdFile {
@@ -140,15 +314,52 @@ impl Drop for PpdFile {
     }
 }
 
+/// Borrowed reference to an option within a `PpdFile`.
+pub struct PpdOption<'a>(&'a ppd_option_t);
+
+impl<'a> PpdOption<'a> {
+    /// Returns the option's keyword, e.g. `PageSize`.
+    pub fn keyword(&self) -> &'a CStr {
+        unsafe { CStr::from_ptr(self.0.keyword.as_ptr()) }
+    }
+
+    /// Checks whether the option was found to be in conflict by the last call
+    /// to `PpdFile::conflicts`.
+    pub fn conflicted(&self) -> bool {
+        self.0.conflicted != 0
+    }
+
+    /// Returns all of the option's choices.
+    pub fn choices(&self) -> impl Iterator<Item = PpdChoice<'a>> {
+        let choices = if self.0.choices.is_null() {
+            &[][..]
+        } else {
+            let n = usize::try_from(self.0.num_choices).unwrap_or(0);
+            unsafe { std::slice::from_raw_parts(self.0.choices, n) }
+        };
+        choices.iter().map(PpdChoice)
+    }
+
+    /// Returns the choice that is currently marked, if any.
+    pub fn marked_choice(&self) -> Option<PpdChoice<'a>> {
+        self.choices().find(PpdChoice::marked)
+    }
+}
+
 /// Borrowed reference to a choice within a `PpdFile`.
 pub struct PpdChoice<'a>(&'a ppd_choice_t);
 
-impl PpdChoice<'_> {
+impl<'a> PpdChoice<'a> {
     /// Returns the chosen value.
-    pub fn choice(&self) -> &CStr {
+    pub fn choice(&self) -> &'a CStr {
         unsafe { CStr::from_ptr(self.0.choice.as_ptr()) }
     }
 
+    /// Checks whether this choice is currently marked (selected).
+    pub fn marked(&self) -> bool {
+        self.0.marked != 0
+    }
+
     /// Attempts to parse the chosen value, if it is not equal to `default`.
     ///
     /// This returns `Ok(None)` if the value matches `default`, `Ok(Some(x))` if
@@ -161,11 +372,30 @@ impl PpdChoice<'_> {
         if self.choice() == default {
             Ok(None)
         } else {
-            Ok(Some(default.to_str()?.parse()?))
+            Ok(Some(self.choice().to_str()?.parse()?))
         }
     }
 }
 
+/// Produces an all-zero page header, for describing pages we make up
+/// ourselves rather than read. (Every field is plain data, for which zero is a
+/// valid value.)
+pub fn blank_page_header() -> cups_page_header2_t {
+    unsafe { MaybeUninit::zeroed().assume_init() }
+}
+
+/// Reads one of a page header's string fields, such as `MediaType`: a
+/// fixed-size array holding a NUL-terminated string. One that isn't UTF-8
+/// reads as empty.
+pub fn header_str(field: &[c_char]) -> &str {
+    // c_char is a byte, whether it's signed or not.
+    let bytes = unsafe { std::slice::from_raw_parts(field.as_ptr().cast::<u8>(), field.len()) };
+    CStr::from_bytes_until_nul(bytes)
+        .ok()
+        .and_then(|s| s.to_str().ok())
+        .unwrap_or("")
+}
+
 /// A parsed set of options.
 ///
 /// This is a wrapper around the CUPS `cups_option_t` type, which is used in
@@ -188,6 +418,86 @@ impl Options {
         let p = NonNull::new(options);
         Self(p, if p.is_some() { usize::try_from(num_options).unwrap() } else { 0 })
     }
+
+    /// Looks up the value of the option called `name`, if it was given.
+    ///
+    /// This is for options that aren't described by the PPD, and so can't be
+    /// found through the PPD's marked choices.
+    pub fn get(&self, name: &CStr) -> Option<&CStr> {
+        let value = unsafe {
+            cupsGetOption(
+                name.as_ptr(),
+                self.len() as c_int,
+                // cupsGetOption doesn't modify the options, it's just not
+                // const-correct.
+                self.as_ptr().cast_mut(),
+            )
+        };
+        if value.is_null() {
+            None
+        } else {
+            Some(unsafe { CStr::from_ptr(value) })
+        }
+    }
+
+    /// Lists the options given, as names and values.
+    pub fn pairs(&self) -> impl Iterator<Item = (&CStr, &CStr)> {
+        self.iter()
+            .map(|option| unsafe { (CStr::from_ptr(option.name), CStr::from_ptr(option.value)) })
+    }
+
+    /// Sets the option called `name` to `value`, replacing any value it had.
+    pub fn set(&mut self, name: &CStr, value: &CStr) {
+        let mut raw = self.0.map_or(null_mut(), NonNull::as_ptr);
+        let num_options = unsafe {
+            cupsAddOption(
+                name.as_ptr(),
+                value.as_ptr(),
+                c_int::try_from(self.1).unwrap(),
+                &mut raw,
+            )
+        };
+        // cupsAddOption may move the array to grow it.
+        let p = NonNull::new(raw);
+        self.0 = p;
+        self.1 = if p.is_some() {
+            usize::try_from(num_options).unwrap()
+        } else {
+            0
+        };
+    }
+
+    /// Adds the options saved for the destination (queue) `name`, such as
+    /// those set with `lpoptions`, that aren't already given. Returns the
+    /// names of the options added.
+    ///
+    /// This asks the CUPS server about the destination, and adds nothing if
+    /// it can't be reached or doesn't know it.
+    pub fn add_destination_defaults(&mut self, name: &CStr) -> Vec<CString> {
+        let dest = unsafe { cupsGetNamedDest(null_mut(), name.as_ptr(), null()) };
+        let Some(dest) = NonNull::new(dest) else {
+            return vec![];
+        };
+        let saved = unsafe {
+            let dest = dest.as_ref();
+            if dest.options.is_null() {
+                &[][..]
+            } else {
+                std::slice::from_raw_parts(dest.options, usize::try_from(dest.num_options).unwrap())
+            }
+        };
+        let mut added = vec![];
+        for option in saved {
+            let (name, value) =
+                unsafe { (CStr::from_ptr(option.name), CStr::from_ptr(option.value)) };
+            if self.get(name).is_none() {
+                self.set(name, value);
+                added.push(name.to_owned());
+            }
+        }
+        unsafe { cupsFreeDests(1, dest.as_ptr()) };
+        added
+    }
 }
 
 impl Drop for Options {
@@ -224,76 +534,234 @@ impl DerefMut for Options {
     }
 }
 
-/// A stream of raster data from an earlier filter.
+/// The job's document, as it comes from an earlier filter (or the file CUPS
+/// names), before we've decided what's in it.
 ///
-/// This is a wrapper around the CUPS type `cups_raster_t`, but also manages the
-/// lifecycle of the input source (file or stdin).
-pub struct Raster {
-    _handle: Box<dyn AsRawFd>,
-    raw: NonNull<cups_raster_t>,
+/// Reads wait no longer than the document's timeout for data to show up.
+pub struct Document {
+    input: Input,
+    timeout: Option<Duration>,
+    /// Bytes read to look at, but not yet consumed.
+    peeked: Vec<u8>,
 }
 
-impl Raster {
-    /// Opens a file at a given path in the filesystem, so that it can be read
-    /// as raster data.
+impl Document {
+    /// Opens a file at a given path in the filesystem.
     ///
-    /// This doesn't actually read anything, just opens the file.
+    /// Files compressed with gzip, which some spoolers and test setups use to
+    /// store raster, are decompressed as they're read.
     ///
-    /// A `Raster` created in this way will automatically close the file
-    /// descriptor on drop.
-    pub fn open_file(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
-        Self::new(Box::new(File::open(path)?))
+    /// The file descriptor is closed on drop.
+    pub fn open_file(
+        path: impl AsRef<Path>,
+        timeout: Option<Duration>,
+    ) -> Result<Self, std::io::Error> {
+        let mut file = File::open(path)?;
+        let mut magic = [0; 2];
+        let gzip = matches!(file.read_exact(&mut magic), Ok(()) if magic == [0x1F, 0x8B]);
+        file.seek(SeekFrom::Start(0))?;
+        let input = if gzip {
+            Input::Decoded(Box::new(MultiGzDecoder::new(BufReader::new(file))))
+        } else {
+            Input::Fd(Box::new(file))
+        };
+        Ok(Self::new(input, timeout))
     }
 
-    /// Starts reading stdin as raster data, which is common in filters.
+    /// Starts reading stdin, which is common in filters.
     ///
     /// To ensure a consistent stream of bytes, this locks stdin, so other
     /// attempts to use stdin will wait; if performed from the same thread,
     /// they'll hang. This seemed preferable to the traditional practice of
     /// hoping it never happens.
     ///
-    /// A `Raster` created in this way will unlock stdin on drop, but will _not_
-    /// close it, because that'd be rude.
-    pub fn stdin() -> Result<Self, std::io::Error> {
+    /// Stdin is unlocked on drop, but _not_ closed, because that'd be rude.
+    pub fn stdin(timeout: Option<Duration>) -> Result<Self, std::io::Error> {
         let stdin = std::io::stdin();
-        Self::new(Box::new(stdin.lock()))
+        Ok(Self::new(Input::Fd(Box::new(stdin.lock())), timeout))
     }
 
-    /// Constructs a raster stream from anything that wraps a system file
-    /// descriptor.
+    /// If `timeout` is provided, any single read from a file descriptor that
+    /// waits longer than that for data will fail with `ErrorKind::TimedOut`.
+    fn new(input: Input, timeout: Option<Duration>) -> Self {
+        Self {
+            input,
+            timeout,
+            peeked: vec![],
+        }
+    }
+
+    /// Returns up to the first `len` bytes of what's left of the document,
+    /// fewer only if it ends sooner, without consuming them.
+    pub fn peek(&mut self, len: usize) -> Result<&[u8], std::io::Error> {
+        let mut buffer = vec![0; len];
+        while self.peeked.len() < len {
+            let n = self.read_input(&mut buffer[..len - self.peeked.len()])?;
+            if n == 0 {
+                break;
+            }
+            self.peeked.extend_from_slice(&buffer[..n]);
+        }
+        Ok(&self.peeked[..self.peeked.len().min(len)])
+    }
+
+    /// Starts reading the document as raster data.
+    ///
+    /// This reads and checks the stream's sync word, but not any pages.
+    pub fn into_raster(self) -> Result<Raster, std::io::Error> {
+        Raster::new(self)
+    }
+
+    /// Reads directly from the input, waiting no longer than `self.timeout`
+    /// for data to show up.
+    ///
+    /// Note that this deliberately bypasses any buffering in the handle (such
+    /// as the buffer inside a `StdinLock`) to keep `poll` honest.
+    fn read_input(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        let fd = match &mut self.input {
+            Input::Fd(handle) => handle.as_raw_fd(),
+            Input::Decoded(reader) => return reader.read(buffer),
+        };
+        if let Some(timeout) = self.timeout {
+            let mut pfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ms = c_int::try_from(timeout.as_millis()).unwrap_or(c_int::MAX);
+            // An EINTR here is almost certainly our SIGTERM handler, so we
+            // pass it up rather than retrying, to give the caller a chance to
+            // notice the cancellation.
+            match unsafe { libc::poll(&mut pfd, 1, ms) } {
+                0 => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!(
+                            "no data received from upstream filter in {} seconds",
+                            timeout.as_secs()
+                        ),
+                    ));
+                }
+                r if r < 0 => return Err(std::io::Error::last_os_error()),
+                _ => (),
+            }
+        }
+        loop {
+            let r = unsafe { libc::read(fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+            if r >= 0 {
+                return Ok(r as usize);
+            }
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+}
+
+impl Read for Document {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if self.peeked.is_empty() {
+            return self.read_input(buffer);
+        }
+        let n = buffer.len().min(self.peeked.len());
+        buffer[..n].copy_from_slice(&self.peeked[..n]);
+        self.peeked.drain(..n);
+        Ok(n)
+    }
+}
+
+/// Where a `Document` gets its bytes.
+enum Input {
+    /// Anything that wraps a system file descriptor, read directly.
+    Fd(Box<dyn AsRawFd>),
+    /// Data that needs decoding on the way in. This is always from a file, so
+    /// there's no waiting to time out.
+    Decoded(Box<dyn Read>),
+}
+
+/// A stream of raster data from an earlier filter.
+///
+/// This is a wrapper around the CUPS type `cups_raster_t`, but also manages the
+/// lifecycle of the input source (file or stdin).
+///
+/// Rather than letting CUPS read the file descriptor directly, we hand it a
+/// callback that reads on its behalf. This lets us bound how long we'll wait
+/// for data, and lets us find out what actually went wrong when a read fails.
+pub struct Raster {
+    raw: NonNull<cups_raster_t>,
+    /// Context pointer we've given to CUPS. This is a leaked `Box` that is
+    /// reclaimed on drop, after CUPS is done with it.
+    source: NonNull<RasterSource>,
+}
+
+impl Raster {
+    /// Opens a file at a given path in the filesystem, so that it can be read
+    /// as raster data, as with [`Document::open_file`].
+    pub fn open_file(
+        path: impl AsRef<Path>,
+        timeout: Option<Duration>,
+    ) -> Result<Self, std::io::Error> {
+        Document::open_file(path, timeout)?.into_raster()
+    }
+
+    /// Starts reading stdin as raster data, as with [`Document::stdin`].
+    pub fn stdin(timeout: Option<Duration>) -> Result<Self, std::io::Error> {
+        Document::stdin(timeout)?.into_raster()
+    }
+
+    /// Constructs a raster stream from a document.
     ///
     /// You probably don't want to call this directly.
-    fn new(source: Box<dyn AsRawFd>) -> Result<Self, std::io::Error> {
-        let ras = unsafe { cupsRasterOpen(source.as_raw_fd(), cups_mode_e_CUPS_RASTER_READ) };
-
-        let raw = NonNull::new(ras).ok_or_else(|| {
-            std::io::Error::new(std::io::ErrorKind::Other, "couldn't open raster stream")
-        })?;
-        Ok(Self {
-            _handle: source,
-            raw,
-        })
+    fn new(document: Document) -> Result<Self, std::io::Error> {
+        let source = NonNull::from(Box::leak(Box::new(RasterSource {
+            document,
+            error: None,
+        })));
+        let ras = unsafe {
+            cupsRasterNew(
+                Some(raster_source_read),
+                source.as_ptr().cast(),
+                cups_mode_e_CUPS_RASTER_READ,
+            )
+        };
+
+        if let Some(raw) = NonNull::new(ras) {
+            Ok(Self { raw, source })
+        } else {
+            // Reclaim the source, since nobody else is going to.
+            let source = unsafe { Box::from_raw(source.as_ptr()) };
+            Err(source
+                .error
+                .unwrap_or_else(|| std::io::Error::other("couldn't open raster stream")))
+        }
     }
 
     /// Reads a raster page header from the stream.
     ///
-    /// Traditionally, end of stream is detected by this function failing.
-    pub fn read_header(&mut self) -> Result<cups_page_header2_t, std::io::Error> {
+    /// Returns `Ok(None)` at the end of the stream. CUPS can't distinguish a
+    /// clean end of stream from a garbled header, so both are reported that
+    /// way; errors from the underlying input (including timeouts) are reported
+    /// as `Err`.
+    pub fn read_header(&mut self) -> Result<Option<cups_page_header2_t>, std::io::Error> {
         let mut header: MaybeUninit<cups_page_header2_t> = MaybeUninit::uninit();
         let r = unsafe { cupsRasterReadHeader2(self.raw.as_ptr(), header.as_mut_ptr()) };
         if r == 0 {
-            // TODO: this may not have been an OS error!
-            return Err(std::io::Error::last_os_error());
+            return match self.take_error() {
+                Some(e) => Err(e),
+                None => Ok(None),
+            };
         }
 
-        Ok(unsafe { header.assume_init() })
+        Ok(Some(unsafe { header.assume_init() }))
     }
 
     /// Reads a chunk of pixels from the input stream. Typically this will be
     /// called on single rows.
     ///
-    /// Returns the number of pixels read.
-    pub fn read_pixels(&mut self, buffer: &mut [u8]) -> usize {
+    /// Returns the number of pixels read. A short read without an error
+    /// generally indicates end of stream.
+    pub fn read_pixels(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
         let r = unsafe {
             cupsRasterReadPixels(
                 self.raw.as_ptr(),
@@ -301,12 +769,172 @@ impl Raster {
                 buffer.len().try_into().unwrap(),
             )
         };
-        r as usize
+        let r = r as usize;
+        match self.take_error() {
+            Some(e) if r < buffer.len() => Err(e),
+            _ => Ok(r),
+        }
+    }
+
+    /// Retrieves (and clears) any error recorded by the read callback.
+    fn take_error(&mut self) -> Option<std::io::Error> {
+        // Safety: CUPS only touches the source during calls we make, and we
+        // have exclusive access to `self`, so there are no other references.
+        unsafe { self.source.as_mut() }.error.take()
     }
 }
 
 impl Drop for Raster {
     fn drop(&mut self) {
-        unsafe { cupsRasterClose(self.raw.as_ptr()) }
+        unsafe {
+            cupsRasterClose(self.raw.as_ptr());
+            drop(Box::from_raw(self.source.as_ptr()));
+        }
     }
 }
+
+/// Input side of a `Raster`, passed to CUPS as the context for
+/// `raster_source_read`.
+struct RasterSource {
+    document: Document,
+    /// The most recent read error. CUPS only gets to see `-1`, so we hang on to
+    /// the details here for reporting.
+    error: Option<std::io::Error>,
+}
+
+/// Read callback for `cupsRasterNew`, which forwards to the source's
+/// `Document`.
+unsafe extern "C" fn raster_source_read(
+    ctx: *mut c_void,
+    buffer: *mut c_uchar,
+    length: usize,
+) -> ssize_t {
+    // Safety: `ctx` is the `RasterSource` registered in `Raster::new`, which
+    // outlives the `cups_raster_t`, and CUPS gives us a valid buffer.
+    let source = unsafe { &mut *ctx.cast::<RasterSource>() };
+    let buffer = unsafe { std::slice::from_raw_parts_mut(buffer, length) };
+    match source.document.read(buffer) {
+        Ok(n) => n as ssize_t,
+        Err(e) => {
+            source.error = Some(e);
+            -1
+        }
+    }
+}
+
+/// The file descriptor CUPS gives filters for reading what the printer sends
+/// back, and backends for writing it.
+const BACK_CHANNEL_FD: c_int = 3;
+
+/// The file descriptor CUPS gives filters and backends for the side channel.
+const SIDE_CHANNEL_FD: c_int = 4;
+
+/// Reads what the printer has sent back into `buffer`, waiting up to
+/// `timeout` for something to come. Gives the number of bytes read, or
+/// `None` if nothing came (CUPS doesn't say whether that's for want of an
+/// answer or of a back channel, so neither can we).
+pub fn back_channel_read(buffer: &mut [u8], timeout: Duration) -> Option<usize> {
+    // cupsBackChannelRead retries its wait forever on a closed descriptor.
+    if unsafe { libc::fcntl(BACK_CHANNEL_FD, libc::F_GETFD) } == -1 {
+        return None;
+    }
+    let n = unsafe {
+        cupsBackChannelRead(
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+            timeout.as_secs_f64(),
+        )
+    };
+    (n > 0).then_some(n as usize)
+}
+
+/// Sends `data` to the filters ahead of us, as a backend relays what the
+/// printer says, waiting up to `timeout` for room. Gives the number of bytes
+/// sent.
+pub fn back_channel_write(data: &[u8], timeout: Duration) -> Result<usize, std::io::Error> {
+    let n =
+        unsafe { cupsBackChannelWrite(data.as_ptr().cast(), data.len(), timeout.as_secs_f64()) };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// What a filter can ask the backend over the side channel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SideChannelRequest {
+    /// Resets the connection to the printer, dropping whatever's queued.
+    SoftReset,
+    /// Waits for everything sent so far to reach the printer.
+    DrainOutput,
+    /// Whether the printer can answer over the back channel.
+    GetBidi,
+    /// The printer's IEEE 1284 device ID.
+    GetDeviceId,
+    /// The printer's state, as a byte of `CUPS_SC_STATE_*` flags.
+    GetState,
+    /// Whether the printer is connected at all.
+    GetConnected,
+}
+
+impl SideChannelRequest {
+    fn command(self) -> cups_sc_command_t {
+        match self {
+            Self::SoftReset => cups_sc_command_e_CUPS_SC_CMD_SOFT_RESET,
+            Self::DrainOutput => cups_sc_command_e_CUPS_SC_CMD_DRAIN_OUTPUT,
+            Self::GetBidi => cups_sc_command_e_CUPS_SC_CMD_GET_BIDI,
+            Self::GetDeviceId => cups_sc_command_e_CUPS_SC_CMD_GET_DEVICE_ID,
+            Self::GetState => cups_sc_command_e_CUPS_SC_CMD_GET_STATE,
+            Self::GetConnected => cups_sc_command_e_CUPS_SC_CMD_GET_CONNECTED,
+        }
+    }
+}
+
+/// Asks the backend `request` over the side channel, waiting up to `timeout`
+/// for the answer, and gives the answer's data.
+pub fn side_channel_request(
+    request: SideChannelRequest,
+    timeout: Duration,
+) -> Result<Vec<u8>, String> {
+    // CUPS makes the side channel a socket. Without one there, the
+    // descriptor is something else of ours, or nothing, and not to be
+    // written to.
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    let socket = unsafe { libc::fstat(SIDE_CHANNEL_FD, stat.as_mut_ptr()) } == 0
+        && unsafe { stat.assume_init() }.st_mode & libc::S_IFMT == libc::S_IFSOCK;
+    if !socket {
+        return Err("there's no side channel to the backend".into());
+    }
+    // The most a side-channel message can carry.
+    let mut data = vec![0u8; 65535];
+    let mut len = data.len() as c_int;
+    let status = unsafe {
+        cupsSideChannelDoRequest(
+            request.command(),
+            data.as_mut_ptr().cast(),
+            &mut len,
+            timeout.as_secs_f64(),
+        )
+    };
+    #[allow(non_upper_case_globals)]
+    let problem = match status {
+        cups_sc_status_e_CUPS_SC_STATUS_OK => {
+            data.truncate(len.max(0) as usize);
+            return Ok(data);
+        }
+        cups_sc_status_e_CUPS_SC_STATUS_IO_ERROR => "couldn't talk to the backend",
+        cups_sc_status_e_CUPS_SC_STATUS_TIMEOUT => "the backend didn't answer in time",
+        cups_sc_status_e_CUPS_SC_STATUS_NO_RESPONSE => "the printer didn't answer",
+        cups_sc_status_e_CUPS_SC_STATUS_BAD_MESSAGE => "the backend's answer was garbled",
+        cups_sc_status_e_CUPS_SC_STATUS_TOO_BIG => "the backend's answer was too big",
+        cups_sc_status_e_CUPS_SC_STATUS_NOT_IMPLEMENTED => "the backend can't do that",
+        _ => "the backend gave no answer",
+    };
+    Err(format!("{request:?}: {problem}"))
+}
+
+/// Asks the backend whether the printer can answer over the back channel.
+pub fn side_channel_bidi(timeout: Duration) -> Result<bool, String> {
+    let answer = side_channel_request(SideChannelRequest::GetBidi, timeout)?;
+    Ok(answer.first() == Some(&(cups_sc_bidi_e_CUPS_SC_BIDI_SUPPORTED as u8)))
+}
@@ -0,0 +1,172 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! What the printer says back.
+//!
+//! CUPS gives filters the back channel, whatever the backend reads from the
+//! printer, on file descriptor 3. Only some backends fill it in (USB and
+//! `socket` do), so a filter can't count on an answer to anything it asks.
+//!
+//! The backend also answers questions of its own over the side channel, such
+//! as what state it sees the printer in. That's coarser than what the printer
+//! says for itself, but some backends know it (like a printer being offline)
+//! when the printer can't answer at all.
+
+use std::{os::fd::RawFd, time::Duration};
+
+use crate::api::{self, SideChannelRequest};
+
+/// Where CUPS puts the back channel.
+const FD: RawFd = 3;
+
+/// How long to wait for the backend to say whether the printer can answer.
+const BIDI_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// TSPL's `<ESC>!?`, which has the printer answer at once with a [`Status`],
+/// even while it's busy printing.
+pub const STATUS_QUERY: &[u8] = b"\x1b!?";
+
+/// The read end of the back channel.
+pub struct BackChannel(());
+
+impl BackChannel {
+    /// Takes the back channel, if there's one to read, and the backend
+    /// doesn't say the printer can't answer on it.
+    pub fn open() -> Option<Self> {
+        let flags = unsafe { libc::fcntl(FD, libc::F_GETFL) };
+        if flags == -1 || flags & libc::O_ACCMODE == libc::O_WRONLY {
+            return None;
+        }
+        // Backends without a side channel, or that don't know, get the
+        // benefit of the doubt.
+        if api::side_channel_bidi(BIDI_TIMEOUT) == Ok(false) {
+            return None;
+        }
+        Some(Self(()))
+    }
+
+    /// Reads the printer's answer to [`STATUS_QUERY`], waiting up to
+    /// `timeout` for it. Gives `None` if it doesn't answer in time, or the
+    /// channel has closed.
+    pub fn status(&mut self, timeout: Duration) -> Option<Status> {
+        // A byte at a time, so that a late answer to one query can't be
+        // taken for part of the next.
+        let mut byte = [0u8];
+        api::back_channel_read(&mut byte, timeout).map(|_| Status(byte[0]))
+    }
+}
+
+/// A printer's answer to [`STATUS_QUERY`]: a byte of flags, all clear when
+/// it's idle.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Status(pub u8);
+
+impl Status {
+    /// Set while the printer's printing, which isn't a problem.
+    const PRINTING: u8 = 0x20;
+
+    /// Flags that keep the printer from printing, and what they mean.
+    const PROBLEMS: &[(u8, &str)] = &[
+        (0x01, "head open"),
+        (0x02, "paper jam"),
+        (0x04, "out of paper"),
+        (0x08, "out of ribbon"),
+        (0x10, "paused"),
+        (0x80, "printer error"),
+    ];
+
+    /// Whether the printer can take another label, even if it's still
+    /// printing the last.
+    pub fn ready(self) -> bool {
+        self.0 & !Self::PRINTING == 0
+    }
+
+    /// Says what's keeping the printer from printing.
+    pub fn problems(self) -> String {
+        Self::PROBLEMS
+            .iter()
+            .filter(|(flag, _)| self.0 & flag != 0)
+            .map(|(_, problem)| *problem)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The printer's state as the backend sees it, in answer to the side
+/// channel's `CUPS_SC_CMD_GET_STATE`: a byte of `CUPS_SC_STATE_*` flags.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BackendState(pub u8);
+
+impl BackendState {
+    /// Set while the printer is there to print on.
+    const ONLINE: u8 = 0x01;
+
+    /// Flags that keep the printer from printing, and what they mean. Running
+    /// low on media or ribbon isn't one.
+    const PROBLEMS: &[(u8, &str)] = &[
+        (0x04, "printer error"),
+        (0x20, "out of paper"),
+        (0x80, "out of ribbon"),
+    ];
+
+    /// Asks the backend, waiting up to `timeout` for the answer. Gives `None`
+    /// if there's no side channel, or the backend can't say.
+    pub fn query(timeout: Duration) -> Option<Self> {
+        let answer = api::side_channel_request(SideChannelRequest::GetState, timeout).ok()?;
+        answer.first().map(|&state| Self(state))
+    }
+
+    /// Whether the printer can print.
+    pub fn ready(self) -> bool {
+        self.0 & Self::ONLINE != 0 && Self::PROBLEMS.iter().all(|(flag, _)| self.0 & flag == 0)
+    }
+
+    /// Says what's keeping the printer from printing.
+    pub fn problems(self) -> String {
+        let offline = (self.0 & Self::ONLINE == 0).then_some("offline");
+        offline
+            .into_iter()
+            .chain(
+                Self::PROBLEMS
+                    .iter()
+                    .filter(|(flag, _)| self.0 & flag != 0)
+                    .map(|(_, problem)| *problem),
+            )
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Waits up to `timeout` for the backend to have sent the printer everything
+/// it's been given.
+pub fn drain_output(timeout: Duration) -> Result<(), String> {
+    api::side_channel_request(SideChannelRequest::DrainOutput, timeout).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses() {
+        assert!(Status(0x00).ready());
+        assert!(Status(0x20).ready());
+        assert!(!Status(0x10).ready());
+        assert_eq!(Status(0x05).problems(), "head open, out of paper");
+        assert_eq!(Status(0x30).problems(), "paused");
+    }
+
+    #[test]
+    fn backend_states() {
+        assert!(BackendState(0x01).ready());
+        assert!(BackendState(0x13).ready());
+        assert!(!BackendState(0x00).ready());
+        assert!(!BackendState(0x21).ready());
+        assert_eq!(BackendState(0x20).problems(), "offline, out of paper");
+        assert_eq!(
+            BackendState(0x85).problems(),
+            "printer error, out of ribbon"
+        );
+    }
+}
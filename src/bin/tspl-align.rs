@@ -0,0 +1,195 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Walks through lining up a queue's printing with its labels.
+//!
+//! ```text
+//! tspl-align QUEUE
+//! ```
+//!
+//! For each of the across, down, and tear-off adjustments in turn, this prints
+//! a run of alignment test labels, each shifted a millimeter further than the
+//! last, and asks which came out best. The answers are saved as the queue's
+//! default `AdjustHorizontal`, `AdjustVertical`, and `FeedOffset` options.
+//! This runs `lp` and `lpadmin`, so it needs the privileges they do.
+
+use std::{
+    error::Error,
+    io::{BufRead, Write},
+    process::{Command, ExitCode, Stdio},
+};
+
+const USAGE: &str = "usage: tspl-align QUEUE";
+
+/// Offsets to try, in millimeters, in the order they're printed.
+const OFFSETS: &[i32] = &[-2, -1, 0, 1, 2];
+
+/// An adjustment to settle, and how to tell which label got it right.
+struct Adjustment {
+    keyword: &'static str,
+    question: &'static str,
+}
+
+const ADJUSTMENTS: &[Adjustment] = &[
+    Adjustment {
+        keyword: "AdjustHorizontal",
+        question: "On which label is the cross closest to the middle, side to side?",
+    },
+    Adjustment {
+        keyword: "AdjustVertical",
+        question: "On which label is the cross closest to the middle, top to bottom?",
+    },
+    Adjustment {
+        keyword: "FeedOffset",
+        question: "Which label stopped with its gap closest to the tear bar?",
+    },
+];
+
+fn main() -> ExitCode {
+    match align() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("tspl-align: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn align() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let [queue] = &args[..] else {
+        return Err(USAGE.into());
+    };
+    if queue.starts_with('-') {
+        return Err(USAGE.into());
+    }
+
+    let stdin = std::io::stdin();
+    let mut answers = stdin.lock().lines();
+    let mut settings = vec![];
+    for adjustment in ADJUSTMENTS {
+        println!(
+            "Printing {} labels with {} from {} to {} mm...",
+            OFFSETS.len(),
+            adjustment.keyword,
+            OFFSETS[0],
+            OFFSETS[OFFSETS.len() - 1]
+        );
+        for (i, &offset) in OFFSETS.iter().enumerate() {
+            // Earlier answers apply, so each step starts from the last.
+            let mut trial = settings.clone();
+            trial.push((adjustment.keyword, offset));
+            run(
+                &print_args(queue, i + 1, OFFSETS.len(), &trial),
+                // The filter ignores this in favor of the test page, but CUPS
+                // still wants a document to convert.
+                Some("tspl-align test label\n"),
+            )?;
+        }
+        let choice = loop {
+            print!(
+                "{} (1-{}, as they came out) ",
+                adjustment.question,
+                OFFSETS.len()
+            );
+            std::io::stdout().flush()?;
+            let answer = answers.next().ok_or("no answer given")??;
+            match parse_choice(&answer, OFFSETS.len()) {
+                Some(choice) => break choice,
+                None => println!("Please give a number from 1 to {}.", OFFSETS.len()),
+            }
+        };
+        settings.push((adjustment.keyword, OFFSETS[choice - 1]));
+    }
+
+    run(&save_args(queue, &settings), None)?;
+    println!("Saved the adjustments as the defaults for {queue}.");
+    Ok(())
+}
+
+/// Works out the `lp` command that prints test label `n` of `count`, with the
+/// adjustments in `settings`.
+fn print_args(queue: &str, n: usize, count: usize, settings: &[(&str, i32)]) -> Vec<String> {
+    let mut args = vec![
+        "lp".into(),
+        "-d".into(),
+        queue.into(),
+        "-t".into(),
+        format!("tspl-align {n} of {count}"),
+        "-o".into(),
+        "tspl-test-page=alignment".into(),
+    ];
+    for (keyword, value) in settings {
+        args.extend(["-o".into(), format!("{keyword}={value}")]);
+    }
+    args
+}
+
+/// Works out the `lpadmin` command that saves `settings` as the queue's
+/// defaults.
+fn save_args(queue: &str, settings: &[(&str, i32)]) -> Vec<String> {
+    let mut args = vec!["lpadmin".into(), "-p".into(), queue.into()];
+    for (keyword, value) in settings {
+        args.extend(["-o".into(), format!("{keyword}-default={value}")]);
+    }
+    args
+}
+
+/// Reads a choice of label, counting from 1.
+fn parse_choice(answer: &str, count: usize) -> Option<usize> {
+    answer
+        .trim()
+        .parse()
+        .ok()
+        .filter(|choice| (1..=count).contains(choice))
+}
+
+/// Runs a command, feeding it `input`, and fails if it does.
+fn run(args: &[String], input: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let mut child = Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("can't run {}: {e}", args[0]))?;
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(format!("{} failed ({status})", args[0]).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        let settings = [("AdjustHorizontal", -1), ("AdjustVertical", 2)];
+        assert_eq!(
+            print_args("labels", 3, 5, &settings).join(" "),
+            "lp -d labels -t tspl-align 3 of 5 -o tspl-test-page=alignment \
+             -o AdjustHorizontal=-1 -o AdjustVertical=2"
+        );
+        assert_eq!(
+            save_args("labels", &settings).join(" "),
+            "lpadmin -p labels -o AdjustHorizontal-default=-1 -o AdjustVertical-default=2"
+        );
+    }
+
+    #[test]
+    fn choices() {
+        assert_eq!(parse_choice(" 2\n", 5), Some(2));
+        assert_eq!(parse_choice("0", 5), None);
+        assert_eq!(parse_choice("6", 5), None);
+        assert_eq!(parse_choice("middle", 5), None);
+    }
+}
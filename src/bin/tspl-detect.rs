@@ -0,0 +1,410 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Finds TSPL printers attached to this machine, and optionally on the
+//! network, and says how to set up a queue for each.
+//!
+//! ```text
+//! tspl-detect [--network ADDRESS/PREFIX]
+//! ```
+//!
+//! USB printers are found in sysfs, so this needs Linux, but no privileges.
+//! They're recognized by the USB IDs in the model registry and in printer
+//! profiles, or else by the IEEE 1284 device ID the printer gives, when it
+//! says the printer speaks TSPL and names a model whose PPD has that
+//! `*ModelName`.
+//!
+//! With `--network`, every host in the given IPv4 network is tried on the raw
+//! printing port, and asked its model with TSPL's `~!T`. Only hosts that
+//! answer are listed. Printers that don't speak TSPL take the query for text
+//! to print, so this may feed a page on them.
+
+use std::{
+    error::Error,
+    fs,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    thread,
+    time::Duration,
+};
+
+use raster_tspl::model::{self, Model};
+use raster_tspl::profile;
+
+/// Where the kernel lists USB devices.
+const USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+/// The raw printing ("JetDirect") port, which TSPL printers listen on.
+const RAW_PORT: u16 = 9100;
+
+/// How long to wait for a host to answer on the network.
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(300);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most hosts to try at once.
+const PARALLEL_PROBES: usize = 64;
+
+fn main() -> ExitCode {
+    match detect() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("tspl-detect: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn detect() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let network = match &args[..] {
+        [] => None,
+        [flag, network] if flag == "--network" => Some(parse_network(network)?),
+        _ => return Err("usage: tspl-detect [--network ADDRESS/PREFIX]".into()),
+    };
+
+    model::load_profiles(&profile::dir())?;
+    let devices = usb_printers(Path::new(USB_DEVICES))?;
+    let mut found = 0;
+    for device in &devices {
+        if let Some(model) = device.model() {
+            println!("{}", device.uri());
+            println!(
+                "    {} {} ({})",
+                device.manufacturer, device.product, model.name
+            );
+            println!("    PPD: ppd/{}", model.ppd);
+            println!(
+                "    set up with: tspl-setup --model {} QUEUE {}",
+                model.name,
+                device.uri()
+            );
+            found += 1;
+        }
+    }
+    let others = devices
+        .iter()
+        .filter(|device| device.model().is_none())
+        .collect::<Vec<_>>();
+    if !others.is_empty() {
+        println!("Other USB printers:");
+        for device in others {
+            println!(
+                "    {:04x}:{:04x} {} {}{}",
+                device.vendor,
+                device.product_id,
+                device.manufacturer,
+                device.product,
+                if device.speaks_tspl() {
+                    " (speaks TSPL, but isn't a model we know)"
+                } else {
+                    ""
+                }
+            );
+        }
+        println!("If one of these is a TSPL printer, please report its ID.");
+    }
+
+    if let Some((base, prefix)) = network {
+        for (addr, name) in probe_network(base, prefix) {
+            println!("socket://{addr}:{RAW_PORT}");
+            println!("    {name}");
+            found += 1;
+        }
+    }
+
+    if found == 0 {
+        return Err("no TSPL printers found".into());
+    }
+    Ok(())
+}
+
+/// A USB printer, as the kernel describes it.
+struct UsbDevice {
+    vendor: u16,
+    product_id: u16,
+    manufacturer: String,
+    product: String,
+    serial: Option<String>,
+    /// The IEEE 1284 device ID, as the kernel's printer driver reads it, e.g.
+    /// `MFG:iDPRT;CMD:TSPL;MDL:SP420;`.
+    device_id: Option<String>,
+}
+
+impl UsbDevice {
+    /// Finds the registry entry for the device, if it's one we know: by its
+    /// USB IDs, or else by the model its device ID names, if it speaks TSPL.
+    fn model(&self) -> Option<&'static Model> {
+        if let Some(model) =
+            model::models().find(|model| model.usb_ids.contains(&(self.vendor, self.product_id)))
+        {
+            return Some(model);
+        }
+        if !self.speaks_tspl() {
+            return None;
+        }
+        let name = self
+            .device_id_field(&["MODEL", "MDL"])?
+            .to_ascii_lowercase();
+        model::models().find(|model| {
+            ppd_model_name(model).is_some_and(|ppd| name.contains(&ppd.to_ascii_lowercase()))
+        })
+    }
+
+    /// Checks whether the device ID lists TSPL among the printer's command
+    /// sets.
+    fn speaks_tspl(&self) -> bool {
+        self.device_id_field(&["COMMAND SET", "CMD"])
+            .is_some_and(|commands| {
+                commands
+                    .split(',')
+                    .any(|command| command.trim().eq_ignore_ascii_case("TSPL"))
+            })
+    }
+
+    /// Finds a field of the device ID, which may go by any of `keys`.
+    fn device_id_field(&self, keys: &[&str]) -> Option<&str> {
+        self.device_id.as_deref()?.split(';').find_map(|field| {
+            let (key, value) = field.split_once(':')?;
+            keys.iter()
+                .any(|k| key.trim().eq_ignore_ascii_case(k))
+                .then_some(value)
+        })
+    }
+
+    /// Produces the device URI as CUPS's `usb` backend names it. (`lpinfo -v`
+    /// has the final word.)
+    fn uri(&self) -> String {
+        let mut uri = format!(
+            "usb://{}/{}",
+            uri_escape(&self.manufacturer),
+            uri_escape(&self.product)
+        );
+        if let Some(serial) = &self.serial {
+            uri.push_str("?serial=");
+            uri.push_str(&uri_escape(serial));
+        }
+        uri
+    }
+}
+
+/// Lists the USB devices under `root` (normally [`USB_DEVICES`]) with a
+/// printer interface.
+fn usb_printers(root: &Path) -> Result<Vec<UsbDevice>, Box<dyn Error>> {
+    let mut devices = vec![];
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        // No USB at all, as in some containers.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(devices),
+        Err(e) => return Err(format!("can't list {}: {e}", root.display()).into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        // Interfaces are listed alongside devices, as DEVICE:CONFIG.INTERFACE.
+        if name.contains(':') {
+            continue;
+        }
+        let Some(interface) = printer_interface(root, &name)? else {
+            continue;
+        };
+        let read = |attribute| {
+            fs::read_to_string(path.join(attribute))
+                .ok()
+                .map(|value| value.trim().to_string())
+        };
+        let id = |attribute| read(attribute).and_then(|id| u16::from_str_radix(&id, 16).ok());
+        let (Some(vendor), Some(product_id)) = (id("idVendor"), id("idProduct")) else {
+            continue;
+        };
+        devices.push(UsbDevice {
+            vendor,
+            product_id,
+            manufacturer: read("manufacturer").unwrap_or_else(|| "Unknown".to_string()),
+            product: read("product").unwrap_or_else(|| "Printer".to_string()),
+            serial: read("serial"),
+            device_id: fs::read_to_string(interface.join("ieee1284_id"))
+                .ok()
+                .map(|id| id.trim().to_string()),
+        });
+    }
+    devices.sort_by_key(|device| (device.vendor, device.product_id));
+    Ok(devices)
+}
+
+/// Finds the USB device `device`'s interface of the printer class, if it has
+/// one.
+fn printer_interface(root: &Path, device: &str) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let prefix = format!("{device}:");
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with(&prefix) {
+            let class = fs::read_to_string(path.join("bInterfaceClass")).unwrap_or_default();
+            if class.trim() == "07" {
+                return Ok(Some(path));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the `*ModelName` from a model's PPD.
+fn ppd_model_name(model: &Model) -> Option<String> {
+    let ppd = String::from_utf8_lossy(model.ppd_contents()?).into_owned();
+    ppd.lines().find_map(|line| {
+        let name = line.strip_prefix("*ModelName:")?.trim().trim_matches('"');
+        Some(name.to_string())
+    })
+}
+
+/// Escapes a string for a URI path or query, as CUPS does.
+fn uri_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    escaped
+}
+
+/// Parses an IPv4 network in `ADDRESS/PREFIX` form, such as `192.168.1.0/24`.
+fn parse_network(s: &str) -> Result<(Ipv4Addr, u32), Box<dyn Error>> {
+    let bad = || format!("bad network {s:?} (expected e.g. 192.168.1.0/24)");
+    let (addr, prefix) = s.split_once('/').ok_or_else(bad)?;
+    let addr = addr.parse::<Ipv4Addr>().map_err(|_| bad())?;
+    let prefix = prefix
+        .parse::<u32>()
+        .ok()
+        .filter(|&prefix| prefix <= 32)
+        .ok_or_else(bad)?;
+    // Anything bigger would take all day.
+    if prefix < 16 {
+        return Err(format!("network {s:?} is too big to scan (the limit is /16)").into());
+    }
+    Ok((addr, prefix))
+}
+
+/// Lists the hosts in a network, leaving out its network and broadcast
+/// addresses, unless it's too small to have them.
+fn hosts(base: Ipv4Addr, prefix: u32) -> Vec<Ipv4Addr> {
+    let size = 1u32 << (32 - prefix);
+    let first = u32::from(base) & !(size - 1);
+    let range = if size > 2 { 1..size - 1 } else { 0..size };
+    range.map(|i| Ipv4Addr::from(first + i)).collect()
+}
+
+/// Tries every host in a network on the raw printing port, and returns those
+/// that answer, with the model name each gave.
+fn probe_network(base: Ipv4Addr, prefix: u32) -> Vec<(Ipv4Addr, String)> {
+    let mut found = vec![];
+    for batch in hosts(base, prefix).chunks(PARALLEL_PROBES) {
+        let probes = batch
+            .iter()
+            .map(|&addr| thread::spawn(move || probe(addr).map(|reply| (addr, reply))))
+            .collect::<Vec<_>>();
+        found.extend(
+            probes
+                .into_iter()
+                .filter_map(|probe| probe.join().ok().flatten()),
+        );
+    }
+    found
+}
+
+/// Asks a host for its model, returning `None` if it isn't listening or
+/// doesn't say.
+///
+/// A host that's listening but says nothing is most likely a printer that
+/// doesn't speak TSPL, which can't be told apart from one that's just slow.
+fn probe(addr: Ipv4Addr) -> Option<String> {
+    let mut stream =
+        TcpStream::connect_timeout(&SocketAddr::from((addr, RAW_PORT)), CONNECT_TIMEOUT).ok()?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT)).ok()?;
+    stream.write_all(b"~!T").ok()?;
+    let mut buffer = [0; 128];
+    let n = stream.read(&mut buffer).ok()?;
+    let name = String::from_utf8_lossy(&buffer[..n]).trim().to_string();
+    (!name.is_empty()).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usb_uris() {
+        let device = UsbDevice {
+            vendor: 0x1234,
+            product_id: 0x5678,
+            manufacturer: "iDPRT".to_string(),
+            product: "SP420 Label/Printer".to_string(),
+            serial: Some("A1 2".to_string()),
+            device_id: None,
+        };
+        assert_eq!(
+            device.uri(),
+            "usb://iDPRT/SP420%20Label%2FPrinter?serial=A1%202"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "beeprt")]
+    fn device_ids() {
+        // A printer as sysfs shows it, with IDs no model lists.
+        let root = std::env::temp_dir().join(format!("tspl-detect-{}", std::process::id()));
+        let device = root.join("1-1");
+        let interface = root.join("1-1:1.0");
+        fs::create_dir_all(&device).unwrap();
+        fs::create_dir_all(&interface).unwrap();
+        fs::write(device.join("idVendor"), "1234\n").unwrap();
+        fs::write(device.join("idProduct"), "5678\n").unwrap();
+        fs::write(interface.join("bInterfaceClass"), "07\n").unwrap();
+        fs::write(
+            interface.join("ieee1284_id"),
+            "MFG:iDPRT;CMD:TSPL,ZPL;MDL:SP420;CLS:PRINTER;\n",
+        )
+        .unwrap();
+        let devices = usb_printers(&root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let [device] = &devices[..] else {
+            panic!("expected one printer");
+        };
+        assert!(device.speaks_tspl());
+        assert_eq!(device.model().map(|model| model.name), Some("Beeprt"));
+
+        // Others that speak TSPL aren't taken for one we know.
+        let other = UsbDevice {
+            vendor: 0x1234,
+            product_id: 0x5678,
+            manufacturer: "Acme".to_string(),
+            product: "LP-100".to_string(),
+            serial: None,
+            device_id: Some("MANUFACTURER:Acme;COMMAND SET:TSPL;MODEL:LP-100;".to_string()),
+        };
+        assert!(other.speaks_tspl());
+        assert!(other.model().is_none());
+    }
+
+    #[test]
+    fn network_hosts() {
+        let (base, prefix) = parse_network("192.168.1.77/30").unwrap();
+        assert_eq!(
+            hosts(base, prefix),
+            [
+                Ipv4Addr::new(192, 168, 1, 77),
+                Ipv4Addr::new(192, 168, 1, 78)
+            ]
+        );
+        assert_eq!(hosts(base, 32), [base]);
+        assert_eq!(hosts(base, 24).len(), 254);
+        assert!(parse_network("10.0.0.0/8").is_err());
+        assert!(parse_network("10.0.0.0").is_err());
+    }
+}
@@ -0,0 +1,268 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Sets up a CUPS queue for a TSPL printer, such as one `tspl-detect` found,
+//! and prints a test label on it.
+//!
+//! ```text
+//! tspl-setup [--model NAME] [--dry-run] QUEUE DEVICE-URI
+//! ```
+//!
+//! The PPD comes from the model registry, printer profiles included; `--model`
+//! picks the entry, which can be left out while there's only one. This runs
+//! `lpadmin` and `lp`, so it needs the privileges they do (usually root, or
+//! the `lpadmin` group).
+//! `--dry-run` shows the commands without running them.
+
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    process::{Command, ExitCode, Stdio},
+};
+
+use raster_tspl::model::{self, Model};
+use raster_tspl::profile;
+
+/// Where CUPS looks for filters, on the systems we know of.
+const FILTER_PATHS: &[&str] = &[
+    "/usr/lib/cups/filter/raster-tspl",
+    "/usr/libexec/cups/filter/raster-tspl",
+    "/Library/Printers/raster-tspl/Filters/raster-tspl",
+];
+
+const USAGE: &str = "usage: tspl-setup [--model NAME] [--dry-run] QUEUE DEVICE-URI";
+
+fn main() -> ExitCode {
+    match setup() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("tspl-setup: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn setup() -> Result<(), Box<dyn Error>> {
+    let mut model_name = None;
+    let mut dry_run = false;
+    let mut positional = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => model_name = Some(args.next().ok_or(USAGE)?),
+            "--dry-run" => dry_run = true,
+            _ if arg.starts_with('-') => return Err(USAGE.into()),
+            _ => positional.push(arg),
+        }
+    }
+    let [queue, uri] = &positional[..] else {
+        return Err(USAGE.into());
+    };
+    check_queue_name(queue)?;
+    model::load_profiles(&profile::dir())?;
+    let model = find_model(model_name.as_deref())?;
+
+    if !FILTER_PATHS.iter().any(|path| Path::new(path).exists()) {
+        eprintln!(
+            "tspl-setup: warning: the raster-tspl filter doesn't seem to be installed, \
+             so the queue won't print until it is (see the README)"
+        );
+    }
+
+    let ppd = std::env::temp_dir().join(format!("tspl-setup-{}.ppd", std::process::id()));
+    let contents = model
+        .ppd_contents()
+        .ok_or_else(|| format!("no PPD for {} is available", model.name))?;
+    if !dry_run {
+        write_ppd(&ppd, contents).map_err(|e| format!("can't write {}: {e}", ppd.display()))?;
+    }
+    let result = run_all(&commands(queue, uri, &ppd), dry_run);
+    if !dry_run {
+        let _ = fs::remove_file(&ppd);
+    }
+    result?;
+    if !dry_run {
+        println!("Queue {queue} is set up, and a test label is on its way.");
+    }
+    Ok(())
+}
+
+/// Writes the PPD for `lpadmin` to a new file at `path`, readable only by us.
+///
+/// This usually runs as root, in a directory anyone can write to, so it won't
+/// follow a link or reuse a file someone else has put there.
+fn write_ppd(path: &Path, contents: &[u8]) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+/// Checks that `queue` is a name CUPS will take.
+fn check_queue_name(queue: &str) -> Result<(), Box<dyn Error>> {
+    let ok = !queue.is_empty()
+        && queue.len() <= 127
+        && queue
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"/#\\'\"".contains(c));
+    if !ok {
+        return Err(format!(
+            "{queue:?} can't be a queue name (use letters, digits, and punctuation \
+             other than / # \\ ' \")"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Finds the registry entry named `name`, or the only entry if it's `None`.
+fn find_model(name: Option<&str>) -> Result<&'static Model, Box<dyn Error>> {
+    let models = model::models().collect::<Vec<_>>();
+    let names = || {
+        models
+            .iter()
+            .map(|model| model.name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match (name, &models[..]) {
+        (Some(name), _) => models
+            .iter()
+            .copied()
+            .find(|model| model.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unknown model {name:?} (known: {})", names()).into()),
+        (None, [model]) => Ok(*model),
+        (None, _) => Err(format!("which model? use --model (known: {})", names()).into()),
+    }
+}
+
+/// A command to run, and what to feed it.
+struct Step {
+    args: Vec<String>,
+    stdin: Option<&'static str>,
+}
+
+/// Works out the commands that set up `queue` for the printer at `uri` with
+/// the PPD at `ppd`, and print a test label.
+fn commands(queue: &str, uri: &str, ppd: &Path) -> Vec<Step> {
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect();
+    vec![
+        Step {
+            args: args(&[
+                "lpadmin",
+                "-p",
+                queue,
+                "-E",
+                "-v",
+                uri,
+                "-P",
+                &ppd.to_string_lossy(),
+            ]),
+            stdin: None,
+        },
+        Step {
+            // The filter ignores what's printed in favor of the test page,
+            // but CUPS still wants a document to convert.
+            args: args(&[
+                "lp",
+                "-d",
+                queue,
+                "-t",
+                "tspl-setup test label",
+                "-o",
+                "tspl-test-page=alignment",
+            ]),
+            stdin: Some("tspl-setup test label\n"),
+        },
+    ]
+}
+
+/// Runs each of `steps` in turn, stopping at the first to fail, or just shows
+/// them if `dry_run`.
+fn run_all(steps: &[Step], dry_run: bool) -> Result<(), Box<dyn Error>> {
+    for step in steps {
+        println!("+ {}", shell_words(&step.args));
+        if dry_run {
+            continue;
+        }
+        let mut child = Command::new(&step.args[0])
+            .args(&step.args[1..])
+            .stdin(if step.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .spawn()
+            .map_err(|e| format!("can't run {}: {e}", step.args[0]))?;
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), step.stdin) {
+            stdin.write_all(input.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(format!("{} failed ({status})", step.args[0]).into());
+        }
+    }
+    Ok(())
+}
+
+/// Quotes arguments for showing as a shell command.
+fn shell_words(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            let plain = arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+            if plain && !arg.is_empty() {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_model_has_a_ppd() {
+        for model in model::models() {
+            assert!(model.ppd_contents().is_some(), "{}", model.name);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "beeprt")]
+    fn models_by_name() {
+        assert_eq!(find_model(Some("beeprt")).unwrap().name, "Beeprt");
+        assert!(find_model(Some("nonesuch")).is_err());
+    }
+
+    #[test]
+    fn queue_setup_commands() {
+        let steps = commands("labels", "usb://iDPRT/SP420", Path::new("/tmp/x.ppd"));
+        let shown = steps
+            .iter()
+            .map(|step| shell_words(&step.args))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            shown,
+            [
+                "lpadmin -p labels -E -v usb://iDPRT/SP420 -P /tmp/x.ppd",
+                "lp -d labels -t 'tspl-setup test label' -o tspl-test-page=alignment",
+            ]
+        );
+
+        assert!(check_queue_name("Shipping_2").is_ok());
+        assert!(check_queue_name("has space").is_err());
+        assert!(check_queue_name("a/b").is_err());
+    }
+}
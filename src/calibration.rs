@@ -0,0 +1,129 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Remembering when a queue's printer last calibrated its media sensor.
+//!
+//! Calibrating (`GAPDETECT` or `BLINEDETECT`) feeds a label or two to measure
+//! the stock, which is wasted. It's only needed when the stock changes, or
+//! now and then to keep up with the sensor drifting, so with
+//! `tspl-calibrate=auto` the filter keeps a small state file per queue saying
+//! what stock the last calibration was for, and when it was.
+
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// When to calibrate, as the `tspl-calibrate` option says.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Every job.
+    Always,
+    /// When the stock's changed since last time, or the interval has passed.
+    Auto,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!("unknown calibration policy {s:?}")),
+        }
+    }
+}
+
+/// What's remembered about the last calibration.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct State {
+    /// The commands describing the stock it was for, such as `SIZE 102
+    /// mm,152 mm` and `GAP 3 mm,0 mm`, on one line.
+    pub media: String,
+    /// When it was, in seconds since the Unix epoch.
+    pub calibrated: u64,
+}
+
+impl State {
+    /// Where the state for the queue named `queue` lives: CUPS's cache
+    /// directory, which it passes to filters in `CUPS_CACHEDIR`.
+    pub fn path(queue: &str) -> Option<PathBuf> {
+        let dir = std::env::var_os("CUPS_CACHEDIR")?;
+        Some(Path::new(&dir).join(format!("tspl-{queue}.calibration")))
+    }
+
+    /// Reads the state saved at `path`. A missing or unreadable file is no
+    /// state, which just means calibrating.
+    pub fn load(path: &Path) -> Option<Self> {
+        toml::from_str(&fs::read_to_string(path).ok()?).ok()
+    }
+
+    /// Saves the state at `path`.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        // Written aside and renamed, so that a job that's killed partway
+        // through doesn't leave half a file.
+        let temp = path.with_extension("calibration.new");
+        fs::write(&temp, toml::to_string(self)?)?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Whether a job printing on `media` at `now` should calibrate, if it
+    /// was last done as this says. With an `interval`, it's done again once
+    /// that's passed, stock or no.
+    pub fn due(last: Option<&Self>, media: &str, now: u64, interval: Option<Duration>) -> bool {
+        let Some(last) = last else {
+            return true;
+        };
+        let expired = interval
+            .is_some_and(|interval| now.saturating_sub(last.calibrated) >= interval.as_secs());
+        last.media != media || expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_due() {
+        let media = "SIZE 102 mm,152 mm GAP 3 mm,0 mm";
+        let last = State {
+            media: media.into(),
+            calibrated: 1000,
+        };
+        let day = Some(Duration::from_secs(86400));
+        assert!(State::due(None, media, 1000, None));
+        assert!(!State::due(Some(&last), media, 1_000_000, None));
+        assert!(State::due(
+            Some(&last),
+            "SIZE 50 mm,30 mm GAP 2 mm,0 mm",
+            1001,
+            day
+        ));
+        assert!(!State::due(Some(&last), media, 1000 + 86399, day));
+        assert!(State::due(Some(&last), media, 1000 + 86400, day));
+    }
+
+    #[test]
+    fn round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("tspl-test-{}.calibration", std::process::id()));
+        let state = State {
+            media: "SIZE 102 mm,152 mm BLINE 3 mm,0 mm".into(),
+            calibrated: 1_700_000_000,
+        };
+        state.save(&path).unwrap();
+        assert_eq!(State::load(&path), Some(state));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(State::load(&path), None);
+    }
+}
@@ -0,0 +1,162 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Site-wide configuration, for tuning queues without touching their PPDs.
+//!
+//! The file is TOML, and entirely optional. Settings in `[defaults]` apply to
+//! every queue, and tables named `[queue.NAME]` (by CUPS queue name) add to or
+//! replace them for one queue:
+//!
+//! ```toml
+//! [defaults]
+//! max-darkness = 12
+//!
+//! [queue.shipping]
+//! threshold = 100
+//! max-print-count = 500
+//!
+//! [queue.shipping.options]
+//! MediaType = "Continuous"
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    ffi::CString,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::settings::MemorySettings;
+
+/// Where the configuration lives, unless `TSPL_FILTER_CONF` says otherwise.
+pub const PATH: &str = "/etc/cups/tspl-filter.conf";
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    defaults: QueueConfig,
+    #[serde(default)]
+    queue: BTreeMap<String, QueueConfig>,
+}
+
+/// Settings for a queue.
+#[derive(Deserialize, Default, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Lightness, from 0 (black) to 255 (white), below which a pixel counts
+    /// as ink. It means the same for K raster, which counts ink instead.
+    pub threshold: Option<u8>,
+    /// Cap on `DENSITY`, for stock or heads that don't take kindly to being
+    /// run dark, whatever the job asks for.
+    pub max_darkness: Option<i32>,
+    /// Most labels to ask for with one `PRINT`, for firmware that caps it
+    /// lower than the model registry expects.
+    pub max_print_count: Option<u32>,
+    /// Directory to save a small picture of each label in, as a record of
+    /// what was printed. The filter runs as CUPS's user, which must be able
+    /// to write there.
+    pub preview_dir: Option<PathBuf>,
+    /// PPD choices to use, by keyword, whatever the PPD and the job say.
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Reads the configuration from `path`, which needn't exist.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("can't read {}: {e}", path.display()).into()),
+        };
+        toml::from_str(&text)
+            .map_err(|e| format!("bad configuration in {}: {e}", path.display()).into())
+    }
+
+    /// Works out the settings for the queue named `queue`, if we know it.
+    pub fn for_queue(&self, queue: Option<&str>) -> QueueConfig {
+        let mut config = self.defaults.clone();
+        if let Some(overrides) = queue.and_then(|queue| self.queue.get(queue)) {
+            config.threshold = overrides.threshold.or(config.threshold);
+            config.max_darkness = overrides.max_darkness.or(config.max_darkness);
+            config.max_print_count = overrides.max_print_count.or(config.max_print_count);
+            config.preview_dir = overrides.preview_dir.clone().or(config.preview_dir);
+            config.options.extend(overrides.options.clone());
+        }
+        config
+    }
+}
+
+impl QueueConfig {
+    /// Collects the PPD choices to override.
+    pub fn option_overrides(&self) -> Result<MemorySettings, Box<dyn Error>> {
+        let mut settings = MemorySettings::default();
+        for (keyword, choice) in &self.options {
+            settings = settings.with(
+                &CString::new(keyword.as_str())?,
+                &CString::new(choice.as_str())?,
+            );
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    #[test]
+    fn queues_override_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            [defaults]
+            threshold = 128
+            max-darkness = 12
+            options = { MediaType = "DieCut", Encoder = "On" }
+
+            [queue.shipping]
+            threshold = 100
+            preview-dir = "/var/spool/cups/previews"
+
+            [queue.shipping.options]
+            MediaType = "Continuous"
+            "#,
+        )
+        .unwrap();
+
+        let shipping = config.for_queue(Some("shipping"));
+        assert_eq!(shipping.threshold, Some(100));
+        assert_eq!(shipping.max_darkness, Some(12));
+        let mut options = shipping.option_overrides().unwrap();
+        assert_eq!(options.marked_choice(c"MediaType"), Some(c"Continuous"));
+        assert_eq!(options.marked_choice(c"Encoder"), Some(c"On"));
+
+        assert_eq!(
+            shipping.preview_dir.as_deref(),
+            Some(Path::new("/var/spool/cups/previews"))
+        );
+
+        let other = config.for_queue(Some("other"));
+        assert_eq!(other.threshold, Some(128));
+        assert_eq!(other.preview_dir, None);
+        assert_eq!(config.for_queue(None).max_print_count, None);
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(toml::from_str::<Config>("[defaults]\nthreshhold = 100").is_err());
+        assert!(toml::from_str::<Config>("threshold = 100").is_err());
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        let config = Config::load(Path::new("/nonexistent/tspl-filter.conf")).unwrap();
+        assert_eq!(config.for_queue(Some("any")).threshold, None);
+    }
+}
@@ -0,0 +1,146 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Stored forms: a static background image kept in the printer's flash, so
+//! that each label only needs to carry the part of the page that changes.
+//!
+//! The form is a BMP file, named as the printer knows it. It can be downloaded
+//! as part of a job (once is enough; it stays in flash), and is then recalled
+//! with `PUTBMP` at the start of every label, with our bitmap drawn over just
+//! the variable region.
+
+use std::{
+    error::Error,
+    ffi::OsStr,
+    fs,
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use cups_raster_sys::cups_page_header2_t;
+
+use crate::{api::Options, tspl::TsplWriter, units};
+
+/// A stored form, and the part of the label that varies over it.
+pub struct Form {
+    /// File name of the form in printer flash, e.g. `BADGE.BMP`.
+    pub name: String,
+    /// Area of the label that changes from job to job, as X, Y, width, and
+    /// height in millimeters from the top left. Only this part of the raster
+    /// is sent.
+    region_mm: [f64; 4],
+    /// Contents of the form, if it should be downloaded to the printer.
+    download: Option<Vec<u8>>,
+}
+
+/// The part of a page sent as a bitmap.
+#[derive(Copy, Clone)]
+pub struct Region {
+    /// Left edge, in bytes (so, multiples of 8 dots).
+    pub x_bytes: usize,
+    /// Top edge, in rows.
+    pub y: u32,
+    pub width_bytes: usize,
+    pub height: u32,
+}
+
+impl Region {
+    /// Covers `height` rows of the whole width of a page.
+    pub fn page(header: &cups_page_header2_t, height: u32) -> Self {
+        Self {
+            x_bytes: 0,
+            y: 0,
+            width_bytes: units::bytes_per_row(header.cupsWidth) as usize,
+            height,
+        }
+    }
+
+    pub fn contains_row(&self, y: u32) -> bool {
+        (self.y..self.y + self.height).contains(&y)
+    }
+
+    /// Cuts this region's columns out of a packed row.
+    pub fn crop<'a>(&self, row: &'a [u8]) -> &'a [u8] {
+        &row[self.x_bytes..self.x_bytes + self.width_bytes]
+    }
+}
+
+impl Form {
+    /// Reads the form settings from the job options: `form` names the form,
+    /// `form-region` gives the variable region as `X,Y,W,H` in millimeters, and
+    /// `form-file`, if given, is a BMP file to download as the form first.
+    pub fn from_options(options: &Options) -> Result<Option<Self>, Box<dyn Error>> {
+        let Some(name) = options.get(c"form") else {
+            return Ok(None);
+        };
+        let name = name.to_str()?.to_string();
+        if name.is_empty() || name.contains('"') {
+            return Err(format!("bad form name {name:?}").into());
+        }
+
+        let region = options
+            .get(c"form-region")
+            .ok_or("the form option needs a form-region too")?
+            .to_str()?;
+        let bad = || format!("bad form-region {region:?}, expected X,Y,W,H in mm");
+        let values = region
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| bad())?;
+        let region_mm: [f64; 4] = values.try_into().map_err(|_| bad())?;
+        if region_mm[2] <= 0.0 || region_mm[3] <= 0.0 {
+            return Err(bad().into());
+        }
+
+        let download = match options.get(c"form-file") {
+            Some(path) => {
+                let path = Path::new(OsStr::from_bytes(path.to_bytes()));
+                Some(
+                    fs::read(path)
+                        .map_err(|e| format!("can't read form {}: {e}", path.display()))?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Some(Self {
+            name,
+            region_mm,
+            download,
+        }))
+    }
+
+    /// Sends the form to the printer's flash, if we were given its contents.
+    pub fn download(&self, out: &mut TsplWriter<impl Write>) -> io::Result<()> {
+        if let Some(data) = &self.download {
+            out.begin_data(format_args!("DOWNLOAD F,\"{}\",{},", self.name, data.len()))?;
+            out.data(data)?;
+            out.end_data()?;
+        }
+        Ok(())
+    }
+
+    /// Works out the variable region in dots on a page, widened to whole bytes
+    /// and clipped to the page.
+    pub fn region(&self, header: &cups_page_header2_t) -> Region {
+        let [x_dpi, y_dpi] = header.HWResolution;
+        let to_dots = |mm: f64, dpi: u32| (mm.max(0.0) * f64::from(dpi) / 25.4).round() as u32;
+        let [x, y, w, h] = self.region_mm;
+
+        let page_bytes = units::bytes_per_row(header.cupsWidth) as usize;
+        let left = (to_dots(x, x_dpi) / 8) as usize;
+        let right = (to_dots(x + w, x_dpi).div_ceil(8) as usize).min(page_bytes);
+        let top = to_dots(y, y_dpi).min(header.cupsHeight);
+        let bottom = to_dots(y + h, y_dpi).min(header.cupsHeight);
+
+        Region {
+            x_bytes: left.min(right),
+            y: top,
+            width_bytes: right.saturating_sub(left),
+            height: bottom.saturating_sub(top),
+        }
+    }
+}
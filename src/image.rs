@@ -0,0 +1,403 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Image files (PNG and JPEG) printed directly.
+//!
+//! CUPS would otherwise take images through PDF and back to raster, which is
+//! slow on small machines, and blurs and thresholds what it scales. Here they
+//! are scaled to fit the label and dithered to black and white in one step,
+//! which keeps photos and gradients looking like themselves.
+
+use std::{error::Error, str::FromStr};
+
+/// MIME types of the images we print, as CUPS names them.
+pub const MIME_TYPES: &[&str] = &["image/png", "image/jpeg"];
+
+/// How much of a document [`is_image`] needs to see.
+pub const SNIFF_BYTES: usize = 8;
+
+/// The most pixels we'll decode, so that a small file claiming to be a huge
+/// image can't exhaust memory.
+const MAX_PIXELS: u64 = 64 << 20;
+
+/// Difference in ink between neighboring pixels that makes an edge, for
+/// [`Image::barcode_mask`].
+const EDGE_CONTRAST: u8 = 128;
+/// Rows above and below an edge to look for it in, more than half of which
+/// must have it for it to count as the side of a bar.
+const BAR_RUN: usize = 8;
+/// Pixels either side of a pixel to count the sides of bars in, and how many
+/// of them make it part of a barcode.
+const BAR_SPAN: usize = 16;
+const MIN_BAR_SIDES: u32 = 6;
+
+/// Checks whether the start of a document (at least [`SNIFF_BYTES`] of it,
+/// unless it's shorter) is a PNG or JPEG file.
+pub fn is_image(start: &[u8]) -> bool {
+    start.starts_with(b"\x89PNG\r\n\x1A\n") || start.starts_with(&[0xFF, 0xD8, 0xFF])
+}
+
+/// The order [`Image::dither`] visits pixels in.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ScanOrder {
+    /// Every row left to right, which can leave diagonal "worms" of dots
+    /// across large even grays.
+    #[default]
+    Raster,
+    /// Rows alternately left to right and right to left, which breaks up the
+    /// worms, at the cost of a little speed.
+    Serpentine,
+}
+
+/// Parses the `tspl-dither-order` option.
+impl FromStr for ScanOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raster" => Ok(Self::Raster),
+            "serpentine" => Ok(Self::Serpentine),
+            _ => Err(format!("unknown dither order {s:?}")),
+        }
+    }
+}
+
+/// A grayscale image.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// Ink for each pixel, row by row, from 0 (none) to 255 (full).
+    pub ink: Vec<u8>,
+}
+
+impl Image {
+    /// Decodes a PNG or JPEG file. Color is reduced to gray, and transparent
+    /// parts are left blank.
+    pub fn decode(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if data.starts_with(b"\x89PNG") {
+            decode_png(data).map_err(|e| format!("bad PNG image: {e}").into())
+        } else {
+            decode_jpeg(data).map_err(|e| format!("bad JPEG image: {e}").into())
+        }
+    }
+
+    /// Scales the image to fit `width` by `height` pixels as closely as it
+    /// can while keeping its proportions, centered, with blank space around
+    /// it.
+    ///
+    /// Each pixel of the result averages the pixels it covers, so that fine
+    /// detail turns to gray rather than disappearing when shrunk.
+    pub fn fit(&self, width: u32, height: u32) -> Self {
+        let mut ink = vec![0; width as usize * height as usize];
+        if self.width == 0 || self.height == 0 {
+            return Self { width, height, ink };
+        }
+        // Scale by whichever dimension is tighter.
+        let (num, den) = if u64::from(width) * u64::from(self.height)
+            < u64::from(height) * u64::from(self.width)
+        {
+            (u64::from(width), u64::from(self.width))
+        } else {
+            (u64::from(height), u64::from(self.height))
+        };
+        let scaled_width = (u64::from(self.width) * num / den).clamp(1, width.into()) as u32;
+        let scaled_height = (u64::from(self.height) * num / den).clamp(1, height.into()) as u32;
+        let left = (width - scaled_width) / 2;
+        let top = (height - scaled_height) / 2;
+
+        // The range of source pixels covering destination pixel `i` of `n`.
+        let span = |i: u32, n: u32, source: u32| {
+            let start = u64::from(i) * u64::from(source) / u64::from(n);
+            let end = (u64::from(i + 1) * u64::from(source)).div_ceil(u64::from(n));
+            start as usize..(end as usize).max(start as usize + 1)
+        };
+        for y in 0..scaled_height {
+            let rows = span(y, scaled_height, self.height);
+            for x in 0..scaled_width {
+                let columns = span(x, scaled_width, self.width);
+                let mut total = 0;
+                for row in rows.clone() {
+                    let row = &self.ink[row * self.width as usize..][..self.width as usize];
+                    total += row[columns.clone()]
+                        .iter()
+                        .map(|&ink| u64::from(ink))
+                        .sum::<u64>();
+                }
+                let count = (rows.len() * columns.len()) as u64;
+                ink[((top + y) * width + left + x) as usize] = (total / count) as u8;
+            }
+        }
+        Self { width, height, ink }
+    }
+
+    /// Reduces the image to pixels of no ink or full ink, spreading the
+    /// difference over the neighboring pixels (Floyd-Steinberg error
+    /// diffusion), so that grays come out as a pattern of dots. Rows are
+    /// taken top to bottom, and their pixels in the given `order`.
+    pub fn dither(&mut self, order: ScanOrder) {
+        self.dither_except(&[], order);
+    }
+
+    /// Dithers the image as [`dither`](Self::dither) does, except for the
+    /// pixels marked in `mask`, which are cut off at half ink instead, taking
+    /// none of their neighbors' error and passing on none of their own.
+    pub fn dither_except(&mut self, mask: &[bool], order: ScanOrder) {
+        let width = self.width as usize;
+        // Errors carried to this row and the next, with a spare pixel at
+        // each end, so that pixel `x` is at `x + 1`.
+        let mut this_row = vec![0i32; width + 2];
+        let mut next_row = vec![0i32; width + 2];
+        for (y, row) in self.ink.chunks_exact_mut(width).enumerate() {
+            let reverse = order == ScanOrder::Serpentine && !y.is_multiple_of(2);
+            for i in 0..width {
+                let x = if reverse { width - 1 - i } else { i };
+                let pixel = &mut row[x];
+                if mask.get(y * width + x) == Some(&true) {
+                    *pixel = if *pixel >= 128 { 255 } else { 0 };
+                    continue;
+                }
+                let wanted = i32::from(*pixel) + this_row[x + 1] / 16;
+                let printed = if wanted >= 128 { 255 } else { 0 };
+                *pixel = printed as u8;
+                let error = wanted - printed;
+                // The error goes mostly to the pixels still to come, which
+                // are to the left on a reversed row.
+                let (ahead, behind) = if reverse { (x, x + 2) } else { (x + 2, x) };
+                this_row[ahead] += error * 7;
+                next_row[behind] += error * 3;
+                next_row[x + 1] += error * 5;
+                next_row[ahead] += error;
+            }
+            std::mem::swap(&mut this_row, &mut next_row);
+            next_row.fill(0);
+        }
+    }
+
+    /// Finds the pixels that look like part of a barcode, which dithering
+    /// would leave ragged enough to trouble a scanner: those with several
+    /// sharp edges across near them, each carried on down for a while, as
+    /// the sides of a barcode's bars are.
+    pub fn barcode_mask(&self) -> Vec<bool> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let edge = |x: usize, y: usize| {
+            let row = &self.ink[y * width..][..width];
+            x > 0 && row[x].abs_diff(row[x - 1]) >= EDGE_CONTRAST
+        };
+        // Edges in each column above each row, so that runs of them can be
+        // counted without going over every row of the run.
+        let mut above = vec![0u32; width * (height + 1)];
+        for y in 0..height {
+            for x in 0..width {
+                above[(y + 1) * width + x] = above[y * width + x] + u32::from(edge(x, y));
+            }
+        }
+
+        let mut mask = vec![false; width * height];
+        // Sides of bars in the row to the left of each pixel, likewise.
+        let mut sides = vec![0u32; width + 1];
+        for y in 0..height {
+            let (top, bottom) = (y.saturating_sub(BAR_RUN), (y + BAR_RUN + 1).min(height));
+            for x in 0..width {
+                let run = above[bottom * width + x] - above[top * width + x];
+                let side = edge(x, y) && run as usize > BAR_RUN;
+                sides[x + 1] = sides[x] + u32::from(side);
+            }
+            for x in 0..width {
+                let (left, right) = (x.saturating_sub(BAR_SPAN), (x + BAR_SPAN + 1).min(width));
+                mask[y * width + x] = sides[right] - sides[left] >= MIN_BAR_SIDES;
+            }
+        }
+        mask
+    }
+}
+
+/// Checks an image's size before decoding it.
+fn check_size(width: u32, height: u32) -> Result<(), String> {
+    if u64::from(width) * u64::from(height) > MAX_PIXELS {
+        return Err(format!("{width}x{height} is too big to print"));
+    }
+    Ok(())
+}
+
+/// Converts a light level to ink.
+fn ink(light: u8) -> u8 {
+    255 - light
+}
+
+/// Works out the light level of a color, as in `main`'s reduction of RGB
+/// raster.
+fn luminance(rgb: &[u8]) -> u8 {
+    let [r, g, b] = [rgb[0], rgb[1], rgb[2]].map(u32::from);
+    ((r * 299 + g * 587 + b * 114) / 1000) as u8
+}
+
+/// Lays a light level with the given opacity over white paper.
+fn over_white(light: u8, alpha: u8) -> u8 {
+    let (light, alpha) = (u32::from(light), u32::from(alpha));
+    ((light * alpha + 255 * (255 - alpha)) / 255) as u8
+}
+
+fn decode_png(data: &[u8]) -> Result<Image, Box<dyn Error>> {
+    let mut decoder = png::Decoder::new(data);
+    // Palettes and small bit depths become 8-bit gray or color, and 16-bit
+    // samples are more precision than a label can show.
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let header = decoder.read_header_info()?;
+    check_size(header.width, header.height)?;
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer)?;
+    let (color, _) = reader.output_color_type();
+    let channels = color.samples();
+    let ink = buffer[..frame.buffer_size()]
+        .chunks_exact(frame.line_size)
+        .flat_map(|line| {
+            line.chunks_exact(channels)
+                .take(frame.width as usize)
+                .map(|px| {
+                    ink(match color {
+                        png::ColorType::Grayscale => px[0],
+                        png::ColorType::GrayscaleAlpha => over_white(px[0], px[1]),
+                        png::ColorType::Rgba => over_white(luminance(px), px[3]),
+                        _ => luminance(px),
+                    })
+                })
+        })
+        .collect();
+    Ok(Image {
+        width: frame.width,
+        height: frame.height,
+        ink,
+    })
+}
+
+fn decode_jpeg(data: &[u8]) -> Result<Image, Box<dyn Error>> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder.read_info()?;
+    let info = decoder.info().ok_or("no image information")?;
+    let (width, height) = (u32::from(info.width), u32::from(info.height));
+    check_size(width, height)?;
+    let pixels = decoder.decode()?;
+    let ink = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => pixels.iter().map(|&light| ink(light)).collect(),
+        // Big-endian, so the first byte is the significant one.
+        jpeg_decoder::PixelFormat::L16 => pixels.chunks_exact(2).map(|px| ink(px[0])).collect(),
+        jpeg_decoder::PixelFormat::RGB24 => pixels
+            .chunks_exact(3)
+            .map(|px| ink(luminance(px)))
+            .collect(),
+        jpeg_decoder::PixelFormat::CMYK32 => {
+            return Err("CMYK images aren't supported; convert to RGB or gray".into());
+        }
+    };
+    Ok(Image { width, height, ink })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fitting() {
+        // A 2x1 image, half ink, fit to a square: it keeps its proportions,
+        // centered.
+        let image = Image {
+            width: 2,
+            height: 1,
+            ink: vec![255, 0],
+        };
+        let fitted = image.fit(4, 4);
+        #[rustfmt::skip]
+        assert_eq!(fitted.ink, [
+            0, 0, 0, 0,
+            255, 255, 0, 0,
+            255, 255, 0, 0,
+            0, 0, 0, 0,
+        ]);
+
+        // Shrinking averages.
+        let image = Image {
+            width: 4,
+            height: 2,
+            ink: vec![255, 0, 255, 255, 0, 0, 255, 255],
+        };
+        assert_eq!(image.fit(2, 1).ink, [63, 255]);
+    }
+
+    #[test]
+    fn dithering_keeps_the_average() {
+        let gray = || Image {
+            width: 64,
+            height: 64,
+            ink: vec![64; 64 * 64],
+        };
+        let mut raster = gray();
+        raster.dither(ScanOrder::Raster);
+        let mut serpentine = gray();
+        serpentine.dither(ScanOrder::Serpentine);
+        for image in [&raster, &serpentine] {
+            assert!(image.ink.iter().all(|&ink| ink == 0 || ink == 255));
+            let inked = image.ink.iter().filter(|&&ink| ink == 255).count();
+            // A quarter, give or take the edges.
+            assert!((900..1150).contains(&inked), "{inked}");
+        }
+        // The first row goes the same way, but the second doesn't.
+        assert_eq!(raster.ink[..64], serpentine.ink[..64]);
+        assert_ne!(raster.ink[64..128], serpentine.ink[64..128]);
+    }
+
+    /// Bars two pixels wide of light and dark gray down the left half, and a
+    /// lighter gray on the right.
+    fn barcode_beside_gray() -> Image {
+        let (width, height) = (128, 48);
+        let ink = (0..width * height)
+            .map(|i| match i % width {
+                x if x < 64 && x / 2 % 2 == 0 => 200,
+                x if x < 64 => 40,
+                _ => 64,
+            })
+            .collect();
+        Image { width, height, ink }
+    }
+
+    #[test]
+    fn finding_barcodes() {
+        let image = barcode_beside_gray();
+        let mask = image.barcode_mask();
+        let at = |x: usize, y: usize| mask[y * 128 + x];
+        assert!(at(8, 24) && at(32, 2) && at(62, 45));
+        assert!(!at(90, 24) && !at(127, 0));
+    }
+
+    #[test]
+    fn barcodes_are_thresholded() {
+        let mut image = barcode_beside_gray();
+        let mask = image.barcode_mask();
+        image.dither_except(&mask, ScanOrder::Raster);
+        for row in image.ink.chunks_exact(128) {
+            let mut bars = row.iter().enumerate().take(60).skip(4);
+            assert!(bars.all(|(x, &ink)| ink == if x / 2 % 2 == 0 { 255 } else { 0 }));
+        }
+        // The gray is still dithered.
+        let gray = image.ink.chunks_exact(128).flat_map(|row| &row[96..]);
+        assert!(gray.clone().any(|&ink| ink == 255) && gray.clone().any(|&ink| ink == 0));
+
+        // Dithering all of it breaks up the bars.
+        let mut image = barcode_beside_gray();
+        image.dither(ScanOrder::Raster);
+        let broken = image.ink.chunks_exact(128).any(|row| {
+            let mut bars = row.iter().enumerate().take(60).skip(4);
+            bars.any(|(x, &ink)| ink != if x / 2 % 2 == 0 { 255 } else { 0 })
+        });
+        assert!(broken);
+    }
+
+    #[test]
+    fn sniffing() {
+        assert!(is_image(b"\x89PNG\r\n\x1A\n"));
+        assert!(is_image(&[0xFF, 0xD8, 0xFF, 0xE0]));
+        assert!(!is_image(b"RaS3"));
+        assert!(!is_image(b"SIZE 50 mm"));
+    }
+}
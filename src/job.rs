@@ -0,0 +1,416 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Settings given as job options, and the choices they take.
+
+use std::{
+    cell::Cell,
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use crate::calibration::{self, State};
+use crate::config::QueueConfig;
+use crate::form::Form;
+use crate::image::ScanOrder;
+use crate::overlay::Overlay;
+use crate::preview::Preview;
+use crate::stock::Stock;
+use crate::testpage;
+use crate::tspl::{BitOrder, LineEnding, Polarity, TsplWriter};
+use crate::{out, tr};
+
+/// Choices for `tspl-display`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DisplayMode {
+    /// The job's title, when it starts.
+    Title,
+    /// The job ID and the labels printed so far, after each page.
+    Progress,
+}
+
+impl FromStr for DisplayMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "title" => Ok(Self::Title),
+            "progress" => Ok(Self::Progress),
+            _ => Err(format!("unknown display mode {s:?}")),
+        }
+    }
+}
+
+/// Choices for `tspl-self-test`: reports the printer prints about itself.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SelfTest {
+    /// `SELFTEST`: the configuration page, with the firmware version and
+    /// settings.
+    Configuration,
+    /// `DIAGNOSTIC`: the firmware's diagnostic dump, which says more about
+    /// the sensors and the head.
+    Diagnostic,
+}
+
+impl SelfTest {
+    pub fn command(self) -> &'static str {
+        match self {
+            Self::Configuration => "SELFTEST",
+            Self::Diagnostic => "DIAGNOSTIC",
+        }
+    }
+}
+
+impl FromStr for SelfTest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "selftest" => Ok(Self::Configuration),
+            "diagnostic" => Ok(Self::Diagnostic),
+            _ => Err(format!("unknown self test {s:?}")),
+        }
+    }
+}
+
+/// Settings given as job options, including the IPP attributes that driverless
+/// and mobile clients send instead of PPD options. Where given, these take
+/// precedence over the PPD.
+#[derive(Default)]
+pub struct JobSettings {
+    /// Labels to print of each page. This comes from the command line rather
+    /// than an option, and it's ours to do because the PPD says
+    /// `cupsManualCopies: False`.
+    pub copies: u32,
+    /// `print-darkness`: -100 to 100, relative to the printer's default.
+    pub darkness: Option<i32>,
+    /// `print-speed`: hundredths of a millimeter per second.
+    pub speed: Option<u32>,
+    /// `darkness` and `speed`: plain numbers, on the scales of the PPD's
+    /// `Darkness` (0 to 15) and `zePrintRate` (inches per second), for
+    /// driverless clients and scripts that can't offer the PPD's choices.
+    /// These take precedence over `print-darkness` and `print-speed`.
+    pub darkness_setting: Option<i32>,
+    pub speed_setting: Option<u32>,
+    /// `media-tracking`: `continuous`, `gap`, or `mark`.
+    pub media_tracking: Option<MediaTracking>,
+    /// `label-stock`: the name of a stock preset.
+    pub stock: Option<&'static Stock>,
+    /// `tspl-test-page`: print a generated test page instead of the input.
+    pub test_page: Option<testpage::Pattern>,
+    /// `overlay-image`: an image to put on every label, at the position given
+    /// by `overlay-position`.
+    pub overlay: Option<Overlay>,
+    /// `form` and friends: a stored form to print each label over.
+    pub form: Option<Form>,
+    /// `oversize`: what to do with pages bigger than the label stock.
+    pub oversize: Option<Oversize>,
+    /// `tspl-comments`: mark the output with `REM` comments saying which job
+    /// and page it came from, for matching up captured output with jobs.
+    pub comments: bool,
+    /// `tspl-deterministic`: leave out anything that would differ between
+    /// runs over the same input, such as the time and the job ID, so that
+    /// output can be compared byte for byte.
+    pub deterministic: bool,
+    /// `page-ranges`: the pages of each raster document to print, when it's
+    /// ours to pick them out.
+    pub page_ranges: Option<PageRanges>,
+    /// `outputorder=reverse` (or the PPD's `OutputOrder=Reverse`): send the
+    /// pages last to first, when it's ours to.
+    pub reverse: bool,
+    /// `tspl-validate`: check everything sent against TSPL's grammar, and
+    /// fail the job rather than send a malformed command.
+    pub validate: bool,
+    /// `tspl-prolog` and `tspl-epilog`: raw TSPL to send before each label's
+    /// `CLS` and after its `PRINT`, in place of the PPD attributes of the same
+    /// names.
+    pub prolog: Option<String>,
+    pub epilog: Option<String>,
+    /// `tspl-dither-order`: `raster` or `serpentine`, the order to dither
+    /// images' pixels in.
+    pub dither_order: Option<ScanOrder>,
+    /// `tspl-barcodes`: cut off the parts of images that look like barcodes
+    /// at half ink rather than dithering them, so that they still scan.
+    pub barcodes: bool,
+    /// `tspl-border`: outline the label with a box one dot wide, for checking
+    /// the label size and reference point against the stock.
+    pub border: bool,
+    /// `tspl-self-test`: a report to have the printer print about itself after
+    /// the job, so that support staff can ask for one through the usual print
+    /// path; with `tspl-self-test-only`, in place of the job's input.
+    pub self_test: Option<SelfTest>,
+    pub self_test_only: bool,
+    /// `tspl-calibrate` and `tspl-calibrate-interval`: when to have the
+    /// printer measure the stock with its sensor.
+    pub calibration: Option<Calibration>,
+    /// `tspl-wait-ready`: ask the printer over the back channel before each
+    /// page whether it can print, and wait while it says it can't.
+    pub wait_ready: bool,
+    /// `tspl-rate`: the most bytes per second to send, for printers that lose
+    /// data sent faster. 0, like leaving it out, means no limit.
+    pub rate: Option<u32>,
+    /// `tspl-display`: what to show on the printer's display, if it has one.
+    pub display: Option<DisplayMode>,
+    /// `tspl-line-ending`: `crlf` or `lf`, for firmware that wants something
+    /// other than what the model registry says.
+    pub line_ending: Option<LineEnding>,
+    /// `tspl-bit-order` (`msb` or `lsb`) and `tspl-polarity` (`normal` or
+    /// `inverted`): how the firmware reads bitmaps, if not as the model
+    /// registry says.
+    pub bit_order: Option<BitOrder>,
+    pub polarity: Option<Polarity>,
+    /// `tspl-strict`: leave out the `SETC` commands that only some firmwares
+    /// take (`true`), or send them (`false`), whatever the model registry
+    /// says.
+    pub strict: Option<bool>,
+    /// Settings for this queue from the site configuration, which aren't job
+    /// options but travel with them.
+    pub site: QueueConfig,
+    /// Where to save pictures of the labels, if the site configuration has a
+    /// `preview-dir`.
+    pub preview: Option<PreviewFiles>,
+}
+
+/// Calibrating the printer's media sensor at the start of a job.
+pub struct Calibration {
+    pub policy: calibration::Policy,
+    /// Time after which `auto` calibrates again, even on the same stock.
+    pub interval: Option<Duration>,
+    /// Where the queue's calibration state lives, if we know, and whether to
+    /// update it.
+    pub state: Option<PathBuf>,
+    pub save: bool,
+    /// Whether the job's first label has been set up, and with it any
+    /// calibration.
+    pub done: Cell<bool>,
+}
+
+impl Calibration {
+    /// Has the printer calibrate its sensor, if this is the job's first label
+    /// and the policy says it's due for the stock `media` describes.
+    /// Continuous stock has nothing to calibrate on.
+    pub fn first_label(
+        &self,
+        out: &mut TsplWriter<impl Write>,
+        tracking: MediaTracking,
+        media: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.done.replace(true) {
+            return Ok(());
+        }
+        let command = match tracking {
+            MediaTracking::Gap => "GAPDETECT",
+            MediaTracking::BLine => "BLINEDETECT",
+            MediaTracking::Continuous => return Ok(()),
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        if self.policy == calibration::Policy::Auto {
+            let last = self.state.as_deref().and_then(State::load);
+            if !State::due(last.as_ref(), media, now, self.interval) {
+                eprintln!("DEBUG: already calibrated for this stock, not calibrating");
+                return Ok(());
+            }
+        }
+        out!(out, "{command}");
+        if let (Some(path), true) = (&self.state, self.save) {
+            let state = State {
+                media: media.into(),
+                calibrated: now,
+            };
+            if let Err(e) = state.save(path) {
+                eprintln!(
+                    "WARNING: {}",
+                    tr!(
+                        "can't save the calibration state as {path}: {error}",
+                        path = path.display(),
+                        error = e
+                    )
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pictures of a job's labels, saved as `QUEUE-JOB-N.png` in the preview
+/// directory, counting labels from 1.
+pub struct PreviewFiles {
+    /// The path of each, up to the `-N`.
+    pub prefix: PathBuf,
+    pub saved: Cell<u32>,
+}
+
+impl PreviewFiles {
+    /// Saves the picture of the next label, the first `height` rows of
+    /// `preview`. Not managing to is worth a warning, but not worth failing
+    /// the job over.
+    pub fn save(&self, preview: Preview, height: u32) {
+        self.saved.set(self.saved.get() + 1);
+        let mut path = self.prefix.clone().into_os_string();
+        path.push(format!("-{}.png", self.saved.get()));
+        let path = PathBuf::from(path);
+        let saved = File::create(&path)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|file| Ok(preview.write(BufWriter::new(file), height)?));
+        if let Err(e) = saved {
+            eprintln!(
+                "WARNING: {}",
+                tr!(
+                    "can't save a preview of the label as {path}: {error}",
+                    path = path.display(),
+                    error = e
+                )
+            );
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MediaTracking {
+    Gap,
+    BLine,
+    Continuous,
+}
+
+impl MediaTracking {
+    /// Guesses the tracking from a media type, such as IPP's `labels` or
+    /// `labels-continuous`, or a PPD's `Tag-with-mark`.
+    pub fn for_media_type(media_type: &str) -> Option<Self> {
+        let media_type = media_type.to_ascii_lowercase();
+        if media_type.contains("continuous") || media_type.contains("roll") {
+            Some(Self::Continuous)
+        } else if media_type.contains("mark") {
+            Some(Self::BLine)
+        } else if ["label", "tag", "gap", "die"]
+            .iter()
+            .any(|kind| media_type.contains(kind))
+        {
+            Some(Self::Gap)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses the IPP `media-tracking` keywords.
+impl FromStr for MediaTracking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gap" => Ok(Self::Gap),
+            "mark" => Ok(Self::BLine),
+            "continuous" => Ok(Self::Continuous),
+            _ => Err(format!("unknown media tracking {s:?}")),
+        }
+    }
+}
+
+/// The pages to print, as the `page-ranges` option gives them: numbers and
+/// ranges of them, such as `1-5,8,12-`, counting from 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageRanges(Vec<(u32, u32)>);
+
+impl PageRanges {
+    pub fn contains(&self, page: u32) -> bool {
+        self.0
+            .iter()
+            .any(|&(first, last)| (first..=last).contains(&page))
+    }
+}
+
+impl FromStr for PageRanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("bad page range in {s:?}");
+        let page = |n: &str| n.trim().parse::<u32>().ok().filter(|&n| n > 0);
+        s.split(',')
+            .map(|range| {
+                let (first, last) = match range.split_once('-') {
+                    // An open end goes on to the last page.
+                    Some((first, last)) if last.trim().is_empty() => (page(first), Some(u32::MAX)),
+                    Some((first, last)) => (page(first), page(last)),
+                    None => (page(range), page(range)),
+                };
+                match (first, last) {
+                    (Some(first), Some(last)) if first <= last => Ok((first, last)),
+                    _ => Err(bad()),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Ways to deal with a page that's bigger than the selected label stock.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Oversize {
+    /// Fail the job.
+    Error,
+    /// Print the part of the page that fits, from the top left corner.
+    Clip,
+    /// Shrink the page to fit, keeping its proportions.
+    Scale,
+    /// Turn a page that's too wide a quarter turn, if it fits across that
+    /// way, and otherwise clip it. This also applies without label stock,
+    /// to pages too wide for the head.
+    Rotate,
+}
+
+impl FromStr for Oversize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "clip" => Ok(Self::Clip),
+            "scale" => Ok(Self::Scale),
+            "rotate" => Ok(Self::Rotate),
+            _ => Err(format!("unknown oversize policy {s:?}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_types() {
+        for (media_type, tracking) in [
+            ("Labels", Some(MediaTracking::Gap)),
+            ("labels-continuous", Some(MediaTracking::Continuous)),
+            ("Continuous", Some(MediaTracking::Continuous)),
+            ("Tag-with-mark", Some(MediaTracking::BLine)),
+            ("BlackMark", Some(MediaTracking::BLine)),
+            ("stationery", None),
+            ("", None),
+        ] {
+            assert_eq!(
+                MediaTracking::for_media_type(media_type),
+                tracking,
+                "{media_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn page_ranges() {
+        let ranges: PageRanges = "1-3, 8,12-".parse().unwrap();
+        assert!(ranges.contains(1) && ranges.contains(3));
+        assert!(!ranges.contains(4));
+        assert!(ranges.contains(8));
+        assert!(ranges.contains(12) && ranges.contains(1000));
+        for bad in ["", "0", "5-2", "a-b", "1,,2"] {
+            assert!(bad.parse::<PageRanges>().is_err(), "{bad:?}");
+        }
+    }
+}
@@ -0,0 +1,29 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The pieces of the `raster-tspl` filter that don't depend on being run by
+//! CUPS, split out of the binary so they can be tested and fuzzed.
+
+pub mod api;
+pub mod backchannel;
+pub mod calibration;
+pub mod config;
+pub mod form;
+pub mod image;
+pub mod job;
+pub mod messages;
+pub mod model;
+pub mod overlay;
+pub mod page;
+pub mod passthrough;
+pub mod preview;
+pub mod profile;
+pub mod settings;
+pub mod spool;
+pub mod stock;
+pub mod template;
+pub mod testpage;
+pub mod tspl;
+pub mod units;
+pub mod validate;
@@ -2,35 +2,71 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-mod api;
-
-use std::io::Write;
+use std::io::{Read, Write};
 use std::{
+    cell::Cell,
+    collections::BTreeMap,
     error::Error,
-    ffi::{CString, c_int},
+    ffi::{CStr, CString, OsStr},
+    fmt::Display,
+    fs::File,
+    os::fd::AsRawFd,
     os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
     process::exit,
+    ptr::null_mut,
+    str::FromStr,
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
+    time::{Duration, Instant},
 };
 
-use api::{Options, PpdFile, Raster};
-use cups_filter_sys::cups_page_header2_t;
+use cups_raster_sys::{cups_cspace_e_CUPS_CSPACE_K, cups_page_header2_t};
+use raster_tspl::api::{self, Document, Options, PpdFile, Raster};
+use raster_tspl::backchannel::{self, BackChannel, BackendState};
+use raster_tspl::calibration::State;
+use raster_tspl::config::{self, Config, QueueConfig};
+use raster_tspl::form::Form;
+use raster_tspl::image::{self, Image};
+use raster_tspl::job::{Calibration, DisplayMode, JobSettings, PreviewFiles};
+use raster_tspl::model::{self, Family, Model};
+use raster_tspl::overlay::Overlay;
+use raster_tspl::page::{
+    PageSink, ResolvedSettings, check_format, colorspace_name, density, end_page, label_setup,
+    label_stock, needs_quarter_turn, output_line, printer_setting, quarter_turn, raster_blank,
+    start_page,
+};
+use raster_tspl::passthrough::{self, Scan};
+use raster_tspl::profile;
+use raster_tspl::settings::{self, Layered, MemorySettings, Settings};
+use raster_tspl::spool::{SPILL_BYTES, Spool};
+use raster_tspl::stock;
+use raster_tspl::tspl::{LineEnding, TsplWriter};
+use raster_tspl::validate::Validator;
+use raster_tspl::{out, testpage, tr, units};
 
-const WHITE_THRESHOLD: u8 = 128;
+/// How long we'll wait on the upstream filter for raster data before giving up,
+/// unless overridden by the `read-timeout` option.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(600);
 
-/// We need to write strings to stdout to send them to the printer. The printer
-/// _usually_ expects `\r\n` terminators, which are hard to achieve with
-/// `println!`. So, custom macro it is:
-macro_rules! out {
-    ($fmt:literal $($args:tt)*) => {
-        print!($fmt $($args)*);
-        print!("\r\n");
-        std::io::stdout().flush()?;
-    }
-}
+/// How long we'll wait for a full output pipe to drain before giving up.
+const WRITE_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long each wait for room in a full output pipe lasts, at most, before we
+/// look again for cancellation. A `SIGTERM` cuts the wait short anyway; this
+/// is in case it arrives just before the wait starts.
+const WRITE_POLL_MS: libc::c_int = 50;
+
+/// How long we'll wait for the printer to answer a status query, and how
+/// often we'll ask while it's not ready, for `tspl-wait-ready`.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long we'll wait at the end of a job, with `tspl-wait-ready`, for the
+/// backend to have sent everything on to the printer.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
 
 fn main() {
     std::panic::set_hook(Box::new(|m| {
@@ -50,238 +86,1694 @@ fn main() {
     }));
     match error_main() {
         Ok(()) => (),
+        Err(e) if Cancelled::caused(&*e) => {
+            eprintln!("DEBUG: cancelled while the printer wasn't taking data");
+        }
         Err(e) => {
-            eprintln!("ERROR: {e}");
-            exit(1);
+            eprintln!("ERROR: {}", tr!("the job failed: {error}", error = e));
+            exit(ExitStatus::for_error(&*e) as i32);
+        }
+    }
+}
+
+/// Exit statuses we use to tell the scheduler how to treat a failed job.
+///
+/// These follow the `CUPS_BACKEND_*` conventions from `cups/backend.h`, which
+/// is the closest thing CUPS has to a vocabulary for this.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ExitStatus {
+    /// The job itself is bad (unreadable raster, no pages, etc.) and sending it
+    /// again won't help.
+    Failed = 1,
+    /// Something went wrong in transit, and the job may well succeed if it's
+    /// retried later.
+    Retry = 6,
+}
+
+impl ExitStatus {
+    /// Chooses an exit status for an error that escaped `error_main`.
+    ///
+    /// I/O errors of the sort caused by flaky connections and stalled peers are
+    /// treated as transient; everything else is blamed on the job.
+    fn for_error(e: &(dyn Error + 'static)) -> Self {
+        use std::io::ErrorKind;
+
+        match e.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+            Some(
+                ErrorKind::BrokenPipe
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionReset
+                | ErrorKind::Interrupted
+                | ErrorKind::NotConnected
+                | ErrorKind::TimedOut
+                | ErrorKind::WouldBlock
+                | ErrorKind::WriteZero,
+            ) => Self::Retry,
+            _ => Self::Failed,
         }
     }
 }
 
+/// PPD options the filter looks for on every job, as opposed to the ones
+/// only some PPDs have (like `Cutter` or `LabelStock`).
+const EXPECTED_KEYWORDS: &[&CStr] = &[
+    c"Darkness",
+    c"zePrintRate",
+    c"zeMediaTracking",
+    c"GapOrMarkHeight",
+    c"GapOrMarkOffset",
+    c"FeedOffset",
+    c"Rotate",
+    c"AdjustVertical",
+];
+
 fn error_main() -> Result<(), Box<dyn Error>> {
     // setbuf(stderr, NULL) is not necessary -- Rust never buffers stderr
     // without you asking for it
 
-    let args = std::env::args_os().collect::<Vec<_>>();
+    let job_started = Instant::now();
+    let mut args = std::env::args_os().collect::<Vec<_>>();
+    // Run by hand, `--check` first checks a job without printing it.
+    let check = args.get(1).is_some_and(|arg| arg == "--check");
+    if check {
+        args.remove(1);
+    }
 
-    if !matches!(args.len(), 6 | 7) {
-        return Err("tspl-filter-rs job-id user title copies options [file]".into());
+    if args.len() < 6 {
+        return Err(
+            "tspl-filter-rs [--check] job-id user title copies options [file|@list...]".into(),
+        );
     }
 
-    // Open the page stream
+    check_final_content_type()?;
 
-    let mut ras = if let Some(filename) = args.get(6) {
-        Raster::open_file(filename)?
-    } else {
-        Raster::stdin()?
+    // Parse the job options, which we need before opening the page stream.
+    let mut options = {
+        let args_c = CString::new(args[5].as_bytes())?;
+        Options::parse(&args_c)
     };
+    // CUPS names the queue in PRINTER.
+    let queue = std::env::var("PRINTER").ok();
+    // Applications don't always pass on the options a user has saved for the
+    // queue with lpoptions, so fill in any they left out.
+    if let Some(queue) = &queue {
+        let added = options.add_destination_defaults(&CString::new(queue.as_str())?);
+        if !added.is_empty() {
+            let added = added
+                .iter()
+                .map(|name| name.to_string_lossy())
+                .collect::<Vec<_>>();
+            eprintln!(
+                "DEBUG: using saved options for {queue}: {}",
+                added.join(" ")
+            );
+        }
+    }
+    let timeout = read_timeout(&options)?;
+    let copies = args[4]
+        .to_str()
+        .and_then(|c| c.parse().ok())
+        .filter(|&c| c > 0)
+        .ok_or_else(|| format!("bad number of copies {:?}", args[4]))?;
+    let config_path =
+        std::env::var_os("TSPL_FILTER_CONF").map_or(config::PATH.into(), PathBuf::from);
+    let site = Config::load(&config_path)?.for_queue(queue.as_deref());
+    let mut job = job_settings(&options, copies, site)?;
+    warn_unsupported_options(&options, copies)?;
+    if let Some(dir) = &job.site.preview_dir {
+        let queue = queue.as_deref().unwrap_or("tspl");
+        job.preview = Some(PreviewFiles {
+            prefix: dir.join(format!("{queue}-{}", args[1].to_string_lossy())),
+            saved: Cell::new(0),
+        });
+    }
 
     // Register a signal handler to let us know if we get cancelled.
     let cancelled: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGTERM, cancelled.clone())?;
 
-    // Open the PPD file and apply options
-    let mut options = {
-        let args_c = CString::new(args[5].as_bytes())?;
-        Options::parse(&args_c)
+    model::load_profiles(&profile::dir())?;
+    // Outside a full CUPS install, there may be no PPD, and then the settings
+    // come from the environment and the job options.
+    let (model, ppd) = match std::env::var_os("PPD").filter(|path| !path.is_empty()) {
+        Some(path) => {
+            let (model, ppd, others) = open_ppd(Path::new(&path), &mut options, &job)?;
+            (model, Layered(Some(ppd), others))
+        }
+        None => {
+            eprintln!("DEBUG: no PPD, taking settings from the environment and job options");
+            (
+                model_without_ppd()?,
+                Layered(None, settings_without_ppd(&options)?),
+            )
+        }
     };
-    let mut ppd = PpdFile::open_file(std::env::var("PPD")?)?;
 
-    PpdFile::mark_defaults(&mut ppd);
-    PpdFile::mark_options(&mut ppd, &mut options);
+    // Choices from the site configuration win over the PPD and the job.
+    let mut ppd = Layered(job.site.option_overrides()?, ppd);
 
-    setup(&ppd)?;
+    if let Some(calibration) = &mut job.calibration {
+        calibration.state = queue.as_deref().and_then(State::path);
+        // Checking a job doesn't calibrate anything.
+        calibration.save = !check;
+    }
 
-    let mut page = 0;
-    loop {
-        let Ok(header) = ras.read_header() else {
-            break;
-        };
+    if check {
+        return check_job(model, &mut ppd, &job, &args[6..], timeout);
+    }
 
-        if cancelled.load(Ordering::Relaxed) {
-            break;
+    let line_ending = job.line_ending.unwrap_or(model.line_ending);
+    let out = &mut TsplWriter::new(Counting {
+        inner: Reversing {
+            inner: Validating {
+                inner: Paced {
+                    inner: Link::stdout(cancelled.clone())?,
+                    rate: job.rate,
+                    started: Instant::now(),
+                    sent: 0,
+                },
+                validator: job.validate.then(|| Validator::new(line_ending)),
+            },
+            pages: job.reverse.then(|| (Spool::new(SPILL_BYTES), vec![])),
+        },
+        bytes: 0,
+    })
+    .with_line_ending(line_ending);
+    setup(out, model, &mut ppd, &job)?;
+
+    if job.comments && job.deterministic {
+        out!(out, "REM raster-tspl {}", env!("CARGO_PKG_VERSION"));
+    } else if job.comments {
+        out!(
+            out,
+            "REM raster-tspl {} job {} user {}",
+            env!("CARGO_PKG_VERSION"),
+            comment_text(&args[1]),
+            comment_text(&args[2])
+        );
+    }
+
+    let display = match (job.display, model.display_command) {
+        (Some(mode), Some(command)) => Some((mode, command)),
+        (Some(_), None) => {
+            eprintln!(
+                "WARNING: {}",
+                tr!("this printer has no display, so tspl-display is ignored")
+            );
+            None
         }
+        (None, _) => None,
+    };
+    if let Some((DisplayMode::Title, command)) = display {
+        out!(out, "{}", display_text(command, &args[3].to_string_lossy()));
+    }
+
+    if let Some(form) = &job.form {
+        form.download(out)?;
+    }
 
-        page += 1;
+    if job.self_test_only {
+        // Likewise, but the printer prints what it likes, so we can't say how
+        // many labels it took.
+        send_self_test(out, &job)?;
+        report_summary(None, out.get_ref().bytes, job_started.elapsed());
+        return Ok(());
+    }
 
-        start_page(&mut ppd, &header)?;
+    let mut readiness = Readiness {
+        back_channel: None,
+        backend: job.wait_ready,
+    };
+    if job.wait_ready {
+        readiness.back_channel = BackChannel::open();
+        if readiness.back_channel.is_none() {
+            eprintln!(
+                "DEBUG: no back channel the printer can answer on, so only asking the backend"
+            );
+        }
+    }
+
+    if let Some(pattern) = job.test_page {
+        // This replaces the job's input, which we don't even open.
+        wait_until_ready(out, &mut readiness, &cancelled)?;
+        let usage = print_test_page(out, model, &mut ppd, &job, pattern)?;
+        send_self_test(out, &job)?;
+        report_summary(Some(usage), out.get_ref().bytes, job_started.elapsed());
+        return Ok(());
+    }
+
+    // Open the page streams: the files named after the options in turn,
+    // sharing the setup above, or else stdin.
+    let inputs = input_paths(&args[6..])?;
+    let inputs = if inputs.is_empty() {
+        vec![None]
+    } else {
+        inputs.into_iter().map(Some).collect()
+    };
+
+    let mut page = 0;
+    let mut usage = (0, 0);
+    let mut usage_known = true;
+    let mut resolved: Option<ResolvedSettings> = None;
+    for input in inputs {
+        if cancelled.load(Ordering::Relaxed) {
+            break;
+        }
+        let mut document = match &input {
+            Some(path) => Document::open_file(path, timeout)
+                .map_err(|e| format!("can't open {}: {e}", path.display()))?,
+            None => Document::stdin(timeout)?,
+        };
+        // Jobs that are already TSPL are labelled as such by CUPS, if it's
+        // been told about the type, or can be told by their first command.
+        let labelled = std::env::var("CONTENT_TYPE").is_ok_and(|t| t == passthrough::MIME_TYPE);
+        let looks_like_tspl =
+            passthrough::looks_like_tspl(document.peek(passthrough::SNIFF_BYTES)?);
+        if labelled && !looks_like_tspl {
+            return Err("the document is labelled as TSPL, but doesn't start like TSPL".into());
+        }
+        if looks_like_tspl {
+            start_reversed_page(out);
+            pass_through(out, model, &mut ppd, &job, document)?;
+            // We can't tell how many labels a TSPL program prints.
+            usage_known = false;
+            continue;
+        }
+        // Likewise PNG and JPEG files, when the queue takes them directly.
+        let labelled =
+            std::env::var("CONTENT_TYPE").is_ok_and(|t| image::MIME_TYPES.contains(&t.as_str()));
+        let is_image = image::is_image(document.peek(image::SNIFF_BYTES)?);
+        if labelled && !is_image {
+            return Err("the document is labelled as an image, but isn't PNG or JPEG".into());
+        }
+        if is_image {
+            start_reversed_page(out);
+            if !job.reverse {
+                wait_until_ready(out, &mut readiness, &cancelled)?;
+            }
+            let (labels, stock_mm) = print_image(out, model, &mut ppd, &job, document)?;
+            usage = (usage.0 + labels, usage.1 + stock_mm);
+            continue;
+        }
+        let mut ras = document.into_raster()?;
 
-        let mut buffer = vec![0; header.cupsBytesPerLine as usize];
+        let first_page = page;
+        loop {
+            let mut header = match ras.read_header() {
+                Ok(Some(header)) => header,
+                Ok(None) => break,
+                // A read interrupted by cancellation is not worth reporting.
+                Err(_) if cancelled.load(Ordering::Relaxed) => break,
+                Err(e) => return Err(e.into()),
+            };
 
-        // Loop for each line on the page...
-        for y in 0..header.cupsHeight {
             if cancelled.load(Ordering::Relaxed) {
                 break;
             }
-            if (y & 15) == 0 {
-                let pct = 100 * y / header.cupsHeight;
-                eprintln!("INFO: printing page {page}, {pct}% complete.");
-                eprintln!("ATTR: job-media-progress={pct}");
+
+            page += 1;
+
+            if let Some(ranges) = &job.page_ranges
+                && !ranges.contains(page - first_page)
+            {
+                eprintln!(
+                    "DEBUG: page {} isn't in page-ranges, skipping it",
+                    page - first_page
+                );
+                skip_page(&mut ras, &header)?;
+                continue;
+            }
+
+            if header.cupsWidth == 0 || header.cupsHeight == 0 {
+                // There's nothing to read, and nothing sensible to print.
+                eprintln!(
+                    "WARNING: {}",
+                    tr!(
+                        "page {page} is empty ({width}x{height} pixels), skipping",
+                        page = page,
+                        width = header.cupsWidth,
+                        height = header.cupsHeight
+                    )
+                );
+                continue;
+            }
+
+            check_format(&header)?;
+
+            start_reversed_page(out);
+            if job.comments {
+                out!(out, "REM page {page}");
             }
 
-            // Read a line of graphics
-            let r = ras.read_pixels(&mut buffer);
-            if r < buffer.len() {
-                // Abort on end of stream or any short read (which is most
-                // likely end-of-stream in disguise).
+            // Held-back pages don't go to the printer yet, so there's no
+            // waiting for it until they do.
+            if !job.reverse {
+                wait_until_ready(out, &mut readiness, &cancelled)?;
+            }
+            let settings = match &mut resolved {
+                Some(settings)
+                    if settings.resolution == header.HWResolution
+                        && settings.media_type == api::header_str(&header.MediaType) =>
+                {
+                    settings
+                }
+                resolved => {
+                    resolved.insert(ResolvedSettings::resolve(model, &mut ppd, &job, &header)?)
+                }
+            };
+
+            let started = Instant::now();
+            // A turned page has to be read in full before its first row is
+            // known.
+            let mut turned = None;
+            if needs_quarter_turn(model, settings, &job, &header)? {
+                eprintln!(
+                    "INFO: {}",
+                    tr!(
+                        "page {page} is too wide for the label, so it's turned a quarter turn to fit",
+                        page = page
+                    )
+                );
+                let pixels;
+                (header, pixels) = quarter_turn(&header, &read_page(&mut ras, &header, page)?);
+                turned = Some(pixels);
+            }
+            let mut state = start_page(out, model, settings, &job, &header)?;
+            let mut rows = 0;
+
+            let mut buffer = vec![0; header.cupsBytesPerLine as usize];
+
+            // Loop for each line on the page...
+            for y in 0..header.cupsHeight {
+                if cancelled.load(Ordering::Relaxed) {
+                    break;
+                }
+                if (y & 15) == 0 {
+                    let pct = 100 * y / header.cupsHeight;
+                    eprintln!(
+                        "INFO: {}",
+                        tr!(
+                            "printing page {page}, {percent}% complete.",
+                            page = page,
+                            percent = pct
+                        )
+                    );
+                    eprintln!("ATTR: job-media-progress={pct}");
+                }
+
+                // Read a line of graphics
+                let reading = Instant::now();
+                let r = match &turned {
+                    Some(pixels) => {
+                        let line = buffer.len();
+                        buffer.copy_from_slice(&pixels[y as usize * line..][..line]);
+                        line
+                    }
+                    None => match ras.read_pixels(&mut buffer) {
+                        Ok(r) => r,
+                        Err(_) if cancelled.load(Ordering::Relaxed) => break,
+                        Err(e) => return Err(e.into()),
+                    },
+                };
+                state.stats.reading += reading.elapsed();
+                if r < buffer.len() {
+                    // Abort on end of stream or any short read (which is most
+                    // likely end-of-stream in disguise). The rest of the page
+                    // is left blank.
+                    if !cancelled.load(Ordering::Relaxed) {
+                        warn_short_page(page, y, header.cupsHeight);
+                    }
+                    break;
+                }
+
+                if let Some(overlay) = &job.overlay {
+                    overlay.apply(&header, y, &mut buffer);
+                }
+
+                output_line(out, model, &header, y, &buffer, &mut state)?;
+                rows += 1;
+            }
+
+            eprintln!("INFO: {}", tr!("finished page {page}", page = page));
+
+            let stats = end_page(out, model, settings, &job, state)?;
+            usage = (usage.0 + stats.labels, usage.1 + stats.stock_mm);
+            if let Some((DisplayMode::Progress, command)) = display {
+                let text = format!("Job {}: {} labels", args[1].to_string_lossy(), usage.0);
+                out!(out, "{}", display_text(command, &text));
+            }
+            let elapsed = started.elapsed();
+            eprintln!(
+                "DEBUG: page {page}: {rows} lines in {:.3}s ({:.0} lines/s), {} bytes sent; \
+                 {:.3}s waiting for input, {:.3}s sending, {:.3}s converting",
+                elapsed.as_secs_f64(),
+                f64::from(rows) / elapsed.as_secs_f64(),
+                stats.bytes,
+                stats.reading.as_secs_f64(),
+                stats.writing.as_secs_f64(),
+                elapsed
+                    .saturating_sub(stats.reading + stats.writing)
+                    .as_secs_f64(),
+            );
+            if let Some(summary) = stats.lightness.summary(settings.threshold) {
+                eprintln!("DEBUG2: page {page}: {summary}");
+            }
+
+            if cancelled.load(Ordering::Relaxed) {
                 break;
             }
+        }
+
+        if page == first_page {
+            return Err(match &input {
+                Some(path) => format!("no pages were found in {}.", path.display()).into(),
+                None => "no pages were found.".into(),
+            });
+        }
+    }
 
-            output_line(&ppd, &header, y, &buffer)?;
+    if job.reverse && !cancelled.load(Ordering::Relaxed) {
+        wait_until_ready(out, &mut readiness, &cancelled)?;
+        send_reversed_pages(out)?;
+    }
+    if !cancelled.load(Ordering::Relaxed) {
+        send_self_test(out, &job)?;
+    }
+    if readiness.backend && !cancelled.load(Ordering::Relaxed) {
+        // So that the job isn't done, and the next one started, while its
+        // labels are still on their way, and a printer that stops on them
+        // holds up this job rather than that one.
+        out.flush()?;
+        if let Err(e) = backchannel::drain_output(DRAIN_TIMEOUT) {
+            eprintln!("DEBUG: couldn't wait for the backend to send everything: {e}");
         }
+    }
+    report_summary(
+        usage_known.then_some(usage),
+        out.get_ref().bytes,
+        job_started.elapsed(),
+    );
+    Ok(())
+}
 
-        eprintln!("INFO: finished page {page}");
+/// Checks that the queue wants what this filter writes.
+///
+/// CUPS says what the end of the filter chain should be in
+/// `FINAL_CONTENT_TYPE`: for a PPD's `*cupsFilter2` lines that's their
+/// destination type, which for us is TSPL, and for the older `*cupsFilter`
+/// lines it's `printer/` and the queue name. Anything else means the queue has
+/// us in the wrong place in its chain, where what we write would go somewhere
+/// expecting something else.
+fn check_final_content_type() -> Result<(), Box<dyn Error>> {
+    let Ok(wanted) = std::env::var("FINAL_CONTENT_TYPE") else {
+        return Ok(());
+    };
+    if wanted.is_empty() || wanted == passthrough::MIME_TYPE || wanted.starts_with("printer/") {
+        return Ok(());
+    }
+    Err(format!(
+        "the queue wants {wanted} from this filter, but it writes {}",
+        passthrough::MIME_TYPE
+    )
+    .into())
+}
 
-        end_page(&ppd, &header)?;
+/// Opens the queue's PPD, marks the job's choices in it, and finds the model
+/// it's for, warning about anything that looks amiss.
+///
+/// Also returns the job's options that the PPD doesn't have, taken as they
+/// are, so that settings like `AdjustHorizontal` can be given per job or as
+/// queue defaults (`lpadmin -o AdjustHorizontal-default=2`) even so.
+fn open_ppd(
+    path: &Path,
+    options: &mut Options,
+    job: &JobSettings,
+) -> Result<(&'static Model, PpdFile, MemorySettings), Box<dyn Error>> {
+    let mut ppd = PpdFile::open_file(path)?;
 
-        if cancelled.load(Ordering::Relaxed) {
-            break;
+    PpdFile::mark_defaults(&mut ppd);
+    PpdFile::mark_options(&mut ppd, options);
+
+    if !ppd.conflicts().is_empty() {
+        // Settle the conflicts the way the PPD says to, as other drivers do,
+        // and say what that changed.
+        let before = selected_options(&mut ppd);
+        if ppd.resolve_conflicts(options) {
+            PpdFile::mark_defaults(&mut ppd);
+            PpdFile::mark_options(&mut ppd, options);
+            let changed = selected_options(&mut ppd)
+                .into_iter()
+                .filter(|(keyword, choice)| before.get(keyword) != Some(choice))
+                .map(|(keyword, choice)| format!("{keyword}={choice}"))
+                .collect::<Vec<_>>();
+            if !changed.is_empty() {
+                eprintln!(
+                    "WARNING: {}",
+                    tr!(
+                        "selected options conflicted, changed them to {options}",
+                        options = changed.join(", ")
+                    )
+                );
+            }
         }
     }
 
-    if page == 0 {
-        return Err("no pages were found.".into());
+    let selected = selected_options(&mut ppd)
+        .into_iter()
+        .map(|(keyword, choice)| format!("{keyword}={choice}"))
+        .collect::<Vec<_>>();
+    eprintln!("DEBUG: selected options: {}", selected.join(" "));
+
+    let conflicts = ppd
+        .conflicts()
+        .iter()
+        .map(|option| {
+            let choice = option.marked_choice();
+            format!(
+                "{}={}",
+                option.keyword().to_string_lossy(),
+                choice
+                    .as_ref()
+                    .map_or("?".into(), |c| c.choice().to_string_lossy()),
+            )
+        })
+        .collect::<Vec<_>>();
+    if !conflicts.is_empty() {
+        eprintln!(
+            "WARNING: {}",
+            tr!(
+                "selected options conflict, and may not print as expected: {options}",
+                options = conflicts.join(", ")
+            )
+        );
     }
 
-    Ok(())
+    let model = model::lookup(ppd.raw().model_number)?;
+
+    // A PPD that spells a keyword differently would otherwise quietly get
+    // the defaults, which is a puzzle to track down from the output alone.
+    let missing = EXPECTED_KEYWORDS
+        .iter()
+        .filter(|keyword| {
+            settings::spellings(keyword)
+                .into_iter()
+                .all(|k| ppd.find_option(k).is_none())
+        })
+        .filter(|keyword| options.get(keyword).is_none())
+        .map(|keyword| keyword.to_string_lossy())
+        .filter(|keyword| !job.site.options.contains_key(keyword.as_ref()))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        eprintln!(
+            "WARNING: {}",
+            tr!(
+                "the PPD has no options {keywords}, using their defaults",
+                keywords = missing.join(", ")
+            )
+        );
+    }
+
+    let mut others = MemorySettings::default();
+    for (keyword, choice) in options.pairs() {
+        if ppd.find_option(keyword).is_none() {
+            others = others.with(keyword, choice);
+        }
+    }
+
+    Ok((model, ppd, others))
+}
+
+/// Works out the model when there's no PPD to say, from `TSPL_FILTER_MODEL`
+/// (a `cupsModelNumber`), or else the only model there is.
+fn model_without_ppd() -> Result<&'static Model, Box<dyn Error>> {
+    if let Ok(number) = std::env::var("TSPL_FILTER_MODEL") {
+        let number = number
+            .parse()
+            .map_err(|_| format!("bad TSPL_FILTER_MODEL {number:?}"))?;
+        return model::lookup(number);
+    }
+    let mut models = model::models();
+    match (models.next(), models.next()) {
+        (Some(model), None) => Ok(model),
+        _ => {
+            Err("there's no PPD, so TSPL_FILTER_MODEL must give the printer's model number".into())
+        }
+    }
 }
 
-const BEEPRT: c_int = 37155;
+/// Collects the choices for a job without a PPD: those in
+/// `TSPL_FILTER_OPTIONS` (given as on the command line, e.g.
+/// `"media=na_index-4x6_4x6in Darkness=10"`), overridden by the job's own.
+///
+/// The page size comes from `media` or `PageSize`, as a PWG self-describing
+/// name such as `om_w100h150_100x150mm`.
+fn settings_without_ppd(options: &Options) -> Result<MemorySettings, Box<dyn Error>> {
+    let defaults = std::env::var("TSPL_FILTER_OPTIONS").unwrap_or_default();
+    let defaults = Options::parse(&CString::new(defaults)?);
+    let mut settings = MemorySettings::default();
+    let mut media = None;
+    for (keyword, choice) in defaults.pairs().chain(options.pairs()) {
+        let keyword_bytes = keyword.to_bytes();
+        if keyword_bytes.eq_ignore_ascii_case(b"media")
+            || keyword_bytes.eq_ignore_ascii_case(b"PageSize")
+        {
+            let name = choice.to_string_lossy();
+            media = Some(
+                units::media_size_pt(&name)
+                    .ok_or_else(|| format!("unknown media size {name:?}"))?,
+            );
+        }
+        settings = settings.with(keyword, choice);
+    }
+    if let Some((width, length)) = media {
+        settings = settings.with_page_size(width, length);
+    }
+    Ok(settings)
+}
 
-fn setup(ppd: &PpdFile) -> Result<(), Box<dyn Error>> {
-    match ppd.raw().model_number {
-        BEEPRT => {
-            // nothing to do here
-            Ok(())
+/// Works out the input files from the arguments after the options: each is a
+/// file to print, or `@LIST` for a file listing files to print, one per line.
+fn input_paths(args: &[std::ffi::OsString]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut paths = vec![];
+    for arg in args {
+        match arg.as_bytes().strip_prefix(b"@") {
+            Some(list) => {
+                let list = Path::new(OsStr::from_bytes(list));
+                let text = std::fs::read_to_string(list)
+                    .map_err(|e| format!("can't read {}: {e}", list.display()))?;
+                paths.extend(
+                    text.lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty())
+                        .map(PathBuf::from),
+                );
+            }
+            None => paths.push(PathBuf::from(arg)),
         }
-        x => unimplemented!("model number {x}"),
     }
+    Ok(paths)
 }
 
-enum MediaTracking {
-    Gap,
-    BLine,
-    Continuous,
+/// Tells the scheduler what the job did, in a single record at its end, for
+/// monitoring and accounting: how many labels it printed and how much stock
+/// they used (if we know, as `(labels, stock_mm)`), how many bytes went to the
+/// printer, and how long it all took.
+fn report_summary(usage: Option<(u32, u32)>, bytes: u64, elapsed: Duration) {
+    let mut attributes = vec![];
+    if let Some((labels, _)) = usage {
+        attributes.push(format!(
+            "job-media-sheets={labels} job-media-sheets-completed={labels} \
+             job-impressions-completed={labels}"
+        ));
+    }
+    attributes.push(format!("tspl-bytes-sent={bytes}"));
+    attributes.push(format!("tspl-elapsed-seconds={:.1}", elapsed.as_secs_f64()));
+    eprintln!("ATTR: {}", attributes.join(" "));
+
+    if let Some((labels, stock_mm)) = usage {
+        eprintln!(
+            "INFO: {}",
+            tr!(
+                "printed {labels} labels, using about {length} m of stock",
+                labels = labels,
+                length = format!("{:.2}", f64::from(stock_mm) / 1000.0)
+            )
+        );
+    }
 }
 
-fn start_page(ppd: &mut PpdFile, header: &cups_page_header2_t) -> Result<(), Box<dyn Error>> {
-    match ppd.raw().model_number {
-        BEEPRT => {
-            let dots_per_mm_x = (10 * header.HWResolution[0]).div_ceil(254);
-            let dots_per_mm_y = (10 * header.HWResolution[1]).div_ceil(254);
+/// Counts the bytes written through it, for the job summary.
+struct Counting<W> {
+    inner: W,
+    bytes: u64,
+}
 
-            let width_mm = header.cupsWidth.div_ceil(dots_per_mm_x);
-            let height_mm = header.cupsHeight.div_ceil(dots_per_mm_y);
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-            out!("SIZE {width_mm} mm,{height_mm} mm");
+/// Holds back the pages, with `outputorder=reverse`, to send them last to
+/// first once they've all been converted. Anything before the first page,
+/// such as the job's setup, goes out as usual.
+struct Reversing<W> {
+    inner: W,
+    /// Where the pages are saved, and where each starts, while reversing.
+    pages: Option<(Spool, Vec<usize>)>,
+}
 
-            // n.b. the typo in this is claimed in one of the open-source
-            // drivers to appear in a vendor PPD, but it does not appear in the
-            // PPDs I've inspected. Nevertheless...
-            let reference_x = ppd
-                .parse_default_marked_choice(c"AdjustHoriaontal")?
-                .unwrap_or(0);
-            let reference_y = ppd
-                .parse_default_marked_choice(c"AdjustVertical")?
-                .unwrap_or(0);
-            let rotate = ppd.parse_default_marked_choice(c"Rotate")?.unwrap_or(0);
+impl<W: Write> Reversing<W> {
+    /// Marks where a page starts, if reversing.
+    fn start_page(&mut self) {
+        if let Some((spool, starts)) = &mut self.pages {
+            starts.push(spool.len());
+        }
+    }
 
-            let mut media_tracking = MediaTracking::Gap;
-            if let Some(choice) = ppd.find_marked_choice(c"zeMediaTracking") {
-                if choice.choice() == c"BLine" {
-                    media_tracking = MediaTracking::BLine;
-                } else if choice.choice() == c"Continuous" {
-                    media_tracking = MediaTracking::Continuous;
-                }
+    /// Sends the pages held back, last first, and stops holding them back.
+    fn finish(&mut self) -> std::io::Result<()> {
+        let Some((mut spool, starts)) = self.pages.take() else {
+            return Ok(());
+        };
+        let mut end = spool.len();
+        for &start in starts.iter().rev() {
+            spool.send_range(start, end - start, |data| self.inner.write_all(data))?;
+            end = start;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for Reversing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.pages {
+            Some((spool, starts)) if !starts.is_empty() => {
+                spool.append(buf)?;
+                Ok(buf.len())
             }
+            _ => self.inner.write(buf),
+        }
+    }
 
-            let gap_mark_height = ppd
-                .parse_default_marked_choice(c"GapOrMarkHeight")?
-                .unwrap_or(3);
-            let gap_mark_offset = ppd
-                .parse_default_marked_choice(c"GapOrMarkOffset")?
-                .unwrap_or(0);
-            let feed_offset = ppd.parse_default_marked_choice(c"FeedOffset")?.unwrap_or(0);
-            let darkness = ppd.parse_default_marked_choice(c"Darkness")?.unwrap_or(8);
-            let speed = ppd
-                .parse_default_marked_choice(c"zePrintRate")?
-                .unwrap_or(4);
-            let autodotted = ppd.parse_default_marked_choice(c"Autodotted")?.unwrap_or(0);
-
-            out!(
-                "REFERENCE {},{}",
-                dots_per_mm_x as i32 * reference_x,
-                dots_per_mm_y as i32 * reference_y
-            );
-            out!("DIRECTION {rotate},0");
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-            match media_tracking {
-                MediaTracking::Gap => {
-                    out!("GAP {gap_mark_height} mm,{gap_mark_offset} mm");
-                }
-                MediaTracking::BLine => {
-                    out!("BLINE {gap_mark_height} mm,{gap_mark_offset} mm");
+/// The way to the printer: our stdout, which the backend reads.
+///
+/// If stdout is a pipe or socket, as it is under CUPS, writes to it don't
+/// block. When it's full, we wait for room with `poll`, a little at a time,
+/// and give up if the job's been cancelled; a write blocked on a printer
+/// that's stopped taking data would otherwise hold off the cancellation until
+/// the pipe drained, which might be never. Says plainly when the printer has
+/// gone. The errors keep their kinds, so that the job is still retried.
+struct Link {
+    inner: File,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Link {
+    fn stdout(cancelled: Arc<AtomicBool>) -> std::io::Result<Self> {
+        use std::os::{fd::AsFd, unix::fs::FileTypeExt};
+
+        let inner = File::from(std::io::stdout().as_fd().try_clone_to_owned()?);
+        // Not a terminal or a file, whose flags the shell shares with us.
+        let kind = inner.metadata()?.file_type();
+        if kind.is_fifo() || kind.is_socket() {
+            let fd = inner.as_raw_fd();
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags == -1
+                || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(Self { inner, cancelled })
+    }
+
+    fn retry<T>(
+        &mut self,
+        mut op: impl FnMut(&mut File) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        use std::io::ErrorKind;
+
+        let stalled = Instant::now();
+        loop {
+            let e = match op(&mut self.inner) {
+                Err(e) => e,
+                result => return result,
+            };
+            if self.cancelled.load(Ordering::Relaxed) {
+                return Err(std::io::Error::other(Cancelled));
+            }
+            match e.kind() {
+                ErrorKind::Interrupted => {}
+                ErrorKind::WouldBlock if stalled.elapsed() < WRITE_STALL_TIMEOUT => {
+                    let mut pollfd = libc::pollfd {
+                        fd: self.inner.as_raw_fd(),
+                        events: libc::POLLOUT,
+                        revents: 0,
+                    };
+                    // Whatever poll says, the next write tells us more.
+                    unsafe { libc::poll(&mut pollfd, 1, WRITE_POLL_MS) };
+                    if self.cancelled.load(Ordering::Relaxed) {
+                        return Err(std::io::Error::other(Cancelled));
+                    }
                 }
-                MediaTracking::Continuous => {
-                    out!("GAP 0 mm,0 mm");
+                ErrorKind::BrokenPipe
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionReset
+                | ErrorKind::NotConnected => {
+                    return Err(std::io::Error::new(
+                        e.kind(),
+                        format!("printer connection lost ({e})"),
+                    ));
                 }
+                _ => return Err(e),
             }
+        }
+    }
+}
+
+impl Write for Link {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.retry(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.retry(|inner| inner.flush())
+    }
+}
+
+/// The job was cancelled while waiting to send the printer more, which ends
+/// it without anything more to say.
+#[derive(Debug)]
+struct Cancelled;
+
+impl Cancelled {
+    /// Whether `e` is, or is an I/O error caused by, cancellation.
+    fn caused(e: &(dyn Error + 'static)) -> bool {
+        e.is::<Self>()
+            || e.downcast_ref::<std::io::Error>()
+                .and_then(|e| e.get_ref())
+                .is_some_and(|e| e.is::<Self>())
+    }
+}
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the job was cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// Holds writes to a steady rate, for printers on links (such as cheap
+/// USB-serial adapters) that drop data when it comes faster than the printer
+/// drains its buffer.
+struct Paced<W> {
+    inner: W,
+    /// Bytes per second, or `None` to send as fast as the link will take.
+    rate: Option<u32>,
+    started: Instant,
+    sent: u64,
+}
+
+impl<W: Write> Write for Paced<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(rate) = self.rate else {
+            return self.inner.write(buf);
+        };
+        // Wait until what's been sent is due, then send no more than a tenth
+        // of a second's worth, so that the rate holds over short spans too.
+        let due = Duration::from_secs_f64(self.sent as f64 / f64::from(rate));
+        if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+            std::thread::sleep(wait);
+        }
+        let chunk = (rate as usize / 10).max(1);
+        let n = self.inner.write(&buf[..buf.len().min(chunk)])?;
+        self.sent += n as u64;
+        Ok(n)
+    }
 
-            out!("OFFSET {feed_offset} mm");
-            out!("DENSITY {darkness}");
-            out!("SPEED {speed}");
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Checks what's sent against TSPL's grammar, with `tspl-validate`, and fails
+/// the job at the first malformed command. The command isn't finished, so
+/// the printer never acts on it.
+struct Validating<W> {
+    inner: W,
+    validator: Option<Validator>,
+}
+
+impl<W: Write> Write for Validating<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(validator) = &mut self.validator else {
+            return self.inner.write(buf);
+        };
+        // What's been checked has to go out in full, or the validator would
+        // get ahead of the stream.
+        validator.feed(buf).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("generated TSPL is invalid: {e}"),
+            )
+        })?;
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Asks the printer for the report the job wants, if any, once everything
+/// else has been sent.
+fn send_self_test(
+    out: &mut TsplWriter<impl Write>,
+    job: &JobSettings,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(self_test) = job.self_test {
+        eprintln!("DEBUG: asking for the printer's {self_test:?} report");
+        out!(out, "{}", self_test.command());
+    }
+    Ok(())
+}
+
+/// Fills in a model's display command with `text`, which is reduced to
+/// printable ASCII (displays rarely do more) without double quotes (which
+/// would end the string).
+fn display_text(command: &str, text: &str) -> String {
+    let text = text
+        .chars()
+        .map(|c| match c {
+            '"' => '\'',
+            ' '..='~' => c,
+            _ => '?',
+        })
+        .collect::<String>();
+    command.replace("{text}", &text)
+}
+
+/// Collects the marked choices in the PPD, by keyword.
+fn selected_options(ppd: &mut PpdFile) -> BTreeMap<String, String> {
+    ppd.marked_choices()
+        .map(|(keyword, choice)| {
+            (
+                keyword.to_string_lossy().into(),
+                choice.to_string_lossy().into(),
+            )
+        })
+        .collect()
+}
+
+/// Makes a command line argument safe to put in a `REM` comment, which ends
+/// at the end of the line.
+fn comment_text(arg: &OsStr) -> String {
+    arg.to_string_lossy()
+        .chars()
+        .map(|c| if c.is_control() { '?' } else { c })
+        .collect()
+}
 
-            out!(
-                "SETC AUTODOTTED {}",
-                if autodotted != 0 { "ON" } else { "OFF" }
+/// Determines the raster read timeout from the `read-timeout` job option, given
+/// in seconds. A value of 0 disables the timeout.
+fn read_timeout(options: &Options) -> Result<Option<Duration>, Box<dyn Error>> {
+    let Some(secs) = parse_option(options, c"read-timeout")? else {
+        return Ok(Some(DEFAULT_READ_TIMEOUT));
+    };
+    Ok(if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    })
+}
+
+/// Parses a job option's value, if it was given.
+fn parse_option<T>(options: &Options, name: &CStr) -> Result<Option<T>, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match options.get(name) {
+        Some(value) => value
+            .to_str()?
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("bad {}: {e}", name.to_string_lossy()).into()),
+        None => Ok(None),
+    }
+}
+
+fn job_settings(
+    options: &Options,
+    copies: u32,
+    site: QueueConfig,
+) -> Result<JobSettings, Box<dyn Error>> {
+    // CUPS doesn't tell this from the PPD's `Darkness`, so choices of that
+    // which aren't numbers, such as `Custom.12`, are left to the PPD. The
+    // range depends on the head, so it's checked with the page.
+    let darkness_setting = match options.get(c"darkness") {
+        Some(value) => match value.to_str()? {
+            "Default" => None,
+            value if value.starts_with("Custom.") => None,
+            value => Some(value.parse().map_err(|e| format!("bad darkness: {e}"))?),
+        },
+        None => None,
+    };
+    let self_test = parse_option(options, c"tspl-self-test")?;
+    let self_test_only = parse_option(options, c"tspl-self-test-only")?.unwrap_or(false);
+    if self_test_only && self_test.is_none() {
+        return Err("tspl-self-test-only needs tspl-self-test".into());
+    }
+    Ok(JobSettings {
+        copies,
+        site,
+        darkness: parse_option(options, c"print-darkness")?,
+        speed: parse_option(options, c"print-speed")?,
+        darkness_setting,
+        speed_setting: parse_option(options, c"speed")?,
+        media_tracking: parse_option(options, c"media-tracking")?,
+        stock: match options.get(c"label-stock") {
+            Some(name) => Some(stock::lookup(name.to_str()?)?),
+            None => None,
+        },
+        test_page: parse_option(options, c"tspl-test-page")?,
+        prolog: parse_option(options, c"tspl-prolog")?,
+        epilog: parse_option(options, c"tspl-epilog")?,
+        line_ending: parse_option(options, c"tspl-line-ending")?,
+        bit_order: parse_option(options, c"tspl-bit-order")?,
+        polarity: parse_option(options, c"tspl-polarity")?,
+        strict: parse_option(options, c"tspl-strict")?,
+        border: parse_option(options, c"tspl-border")?.unwrap_or(false),
+        barcodes: parse_option(options, c"tspl-barcodes")?.unwrap_or(false),
+        dither_order: parse_option(options, c"tspl-dither-order")?,
+        display: parse_option(options, c"tspl-display")?,
+        rate: parse_option(options, c"tspl-rate")?.filter(|&rate| rate != 0),
+        wait_ready: parse_option(options, c"tspl-wait-ready")?.unwrap_or(false),
+        validate: parse_option(options, c"tspl-validate")?.unwrap_or(false),
+        page_ranges: if pages_left_to_us() {
+            parse_option(options, c"page-ranges")?
+        } else {
+            None
+        },
+        reverse: pages_left_to_us() && reverse_order(options)?,
+        calibration: match parse_option(options, c"tspl-calibrate")? {
+            Some(policy) => Some(Calibration {
+                policy,
+                interval: parse_option::<u64>(options, c"tspl-calibrate-interval")?
+                    .map(|hours| Duration::from_secs(hours * 3600)),
+                state: None,
+                save: false,
+                done: Cell::new(false),
+            }),
+            None => None,
+        },
+        self_test,
+        self_test_only,
+        preview: None,
+        form: Form::from_options(options)?,
+        oversize: parse_option(options, c"oversize")?,
+        comments: parse_option(options, c"tspl-comments")?.unwrap_or(false),
+        deterministic: parse_option(options, c"tspl-deterministic")?.unwrap_or(false),
+        overlay: match options.get(c"overlay-image") {
+            Some(path) => Some(Overlay::load(
+                Path::new(OsStr::from_bytes(path.to_bytes())),
+                overlay_position(options)?,
+            )?),
+            None => None,
+        },
+    })
+}
+
+/// Warns about job options that ask for what no label printer can do, and
+/// which would otherwise be ignored without a word.
+fn warn_unsupported_options(options: &Options, copies: u32) -> Result<(), Box<dyn Error>> {
+    let value = |name: &CStr| -> Result<Option<String>, Box<dyn Error>> {
+        Ok(match options.get(name) {
+            Some(value) => Some(value.to_str()?.to_owned()),
+            None => None,
+        })
+    };
+    let one_sided = ["one-sided", "None", "False", "Off"];
+    for name in [c"sides", c"Duplex"] {
+        if let Some(value) = value(name)?
+            && !one_sided.contains(&value.as_str())
+        {
+            eprintln!(
+                "WARNING: {}",
+                tr!(
+                    "labels print on one side only, ignoring {option}={value}",
+                    option = name.to_string_lossy(),
+                    value = value
+                )
+            );
+        }
+    }
+    // Each label's copies go to the printer in one PRINT command, so they
+    // come out together, however the job asks.
+    let collated = ["True", "true", "separate-documents-collated-copies"];
+    for name in [c"Collate", c"collate", c"multiple-document-handling"] {
+        if copies > 1
+            && let Some(value) = value(name)?
+            && collated.contains(&value.as_str())
+        {
+            eprintln!(
+                "WARNING: {}",
+                tr!(
+                    "each label's copies print together, ignoring {option}={value}",
+                    option = name.to_string_lossy(),
+                    value = value
+                )
             );
+        }
+    }
+    Ok(())
+}
+
+/// Parses the `overlay-position` option, given as `X,Y` in millimeters from the
+/// top left of the label. This defaults to the corner.
+fn overlay_position(options: &Options) -> Result<(f64, f64), Box<dyn Error>> {
+    let Some(value) = options.get(c"overlay-position") else {
+        return Ok((0.0, 0.0));
+    };
+    let value = value.to_str()?;
+    let bad = || format!("bad overlay-position {value:?}, expected X,Y in mm");
+    let (x, y) = value.split_once(',').ok_or_else(bad)?;
+    let x = x.trim().parse().map_err(|_| bad())?;
+    let y = y.trim().parse().map_err(|_| bad())?;
+    Ok((x, y))
+}
+
+/// What can tell us whether the printer's ready, for `tspl-wait-ready`.
+struct Readiness {
+    /// For asking the printer itself.
+    back_channel: Option<BackChannel>,
+    /// Whether to ask the backend, over the side channel.
+    backend: bool,
+}
 
-            out!("SETC PAUSEKEY ON");
-            out!("SETC WATERMARK OFF");
-            out!("CLS");
+/// What the printer, or the backend, says about whether it can print.
+enum Answer {
+    Ready,
+    NotReady(String),
+    /// Nothing to ask, or no answer.
+    Unknown,
+}
 
-            print!(
-                "BITMAP 0,0,{},{},1,",
-                (header.cupsWidth + 7) >> 3,
-                header.cupsHeight
+/// Holds off sending the next page while the printer says it can't print it,
+/// for `tspl-wait-ready`, rather than filling up a printer that's stalled.
+///
+/// The backend's asked first, since it knows about a printer that's offline,
+/// and then the printer. Either that doesn't answer, or stops answering,
+/// isn't asked again for the rest of the job.
+fn wait_until_ready(
+    out: &mut TsplWriter<impl Write>,
+    readiness: &mut Readiness,
+    cancelled: &AtomicBool,
+) -> Result<(), Box<dyn Error>> {
+    let mut last = None;
+    loop {
+        let problems = match printer_readiness(out, readiness)? {
+            Answer::Ready => {
+                if last.is_some() {
+                    eprintln!("INFO: {}", tr!("the printer is ready again"));
+                }
+                break;
+            }
+            Answer::NotReady(problems) => problems,
+            Answer::Unknown => break,
+        };
+        if last.as_ref() != Some(&problems) {
+            eprintln!(
+                "INFO: {}",
+                tr!("waiting for the printer: {problems}", problems = &problems)
             );
+            last = Some(problems);
         }
-        x => unimplemented!("model number {x}"),
+        if cancelled.load(Ordering::Relaxed) {
+            break;
+        }
+        std::thread::sleep(STATUS_INTERVAL);
     }
     Ok(())
 }
 
-fn output_line(
-    ppd: &PpdFile,
-    _header: &cups_page_header2_t,
-    _y: u32,
-    buffer: &[u8],
+/// Asks whatever can say whether the printer's ready.
+fn printer_readiness(
+    out: &mut TsplWriter<impl Write>,
+    readiness: &mut Readiness,
+) -> Result<Answer, Box<dyn Error>> {
+    let mut answer = Answer::Unknown;
+    if readiness.backend {
+        match BackendState::query(STATUS_TIMEOUT) {
+            Some(state) if !state.ready() => return Ok(Answer::NotReady(state.problems())),
+            Some(_) => answer = Answer::Ready,
+            None => {
+                eprintln!("DEBUG: no state from the backend, so not asking it again");
+                readiness.backend = false;
+            }
+        }
+    }
+    let Some(channel) = &mut readiness.back_channel else {
+        return Ok(answer);
+    };
+    out.immediate(backchannel::STATUS_QUERY)?;
+    out.flush()?;
+    Ok(match channel.status(STATUS_TIMEOUT) {
+        Some(status) if status.ready() => Answer::Ready,
+        Some(status) => Answer::NotReady(status.problems()),
+        None => {
+            eprintln!("DEBUG: no status from the printer, so not waiting for it to be ready");
+            readiness.back_channel = None;
+            answer
+        }
+    })
+}
+
+/// Prints a generated test page, sized for the selected label stock or page
+/// size.
+fn print_test_page(
+    out: &mut TsplWriter<impl Write>,
+    model: &Model,
+    ppd: &mut impl Settings,
+    job: &JobSettings,
+    pattern: testpage::Pattern,
+) -> Result<(u32, u32), Box<dyn Error>> {
+    let header = generated_page_header(model, ppd, job)?;
+    let pixels = testpage::render(
+        pattern,
+        header.cupsWidth,
+        header.cupsHeight,
+        header.HWResolution[0],
+    );
+
+    let settings = ResolvedSettings::resolve(model, ppd, job, &header)?;
+    let mut state = start_page(out, model, &settings, job, &header)?;
+    for (y, row) in pixels.chunks(header.cupsWidth as usize).enumerate() {
+        output_line(out, model, &header, y as u32, row, &mut state)?;
+    }
+    let stats = end_page(out, model, &settings, job, state)?;
+
+    eprintln!(
+        "INFO: {}",
+        tr!(
+            "printed {pattern} test page",
+            pattern = format!("{pattern:?}")
+        )
+    );
+    Ok((stats.labels, stats.stock_mm))
+}
+
+/// Prints a PNG or JPEG file, scaled to fit the selected label stock or page
+/// size, and dithered.
+fn print_image(
+    out: &mut TsplWriter<impl Write>,
+    model: &Model,
+    ppd: &mut impl Settings,
+    job: &JobSettings,
+    mut document: Document,
+) -> Result<(u32, u32), Box<dyn Error>> {
+    let mut data = vec![];
+    document.read_to_end(&mut data)?;
+    let image = Image::decode(&data)?;
+
+    let header = generated_page_header(model, ppd, job)?;
+    let mut pixels = image.fit(header.cupsWidth, header.cupsHeight);
+    let order = job.dither_order.unwrap_or_default();
+    if job.barcodes {
+        let mask = pixels.barcode_mask();
+        pixels.dither_except(&mask, order);
+    } else {
+        pixels.dither(order);
+    }
+
+    let settings = ResolvedSettings::resolve(model, ppd, job, &header)?;
+    let mut state = start_page(out, model, &settings, job, &header)?;
+    for (y, row) in pixels.ink.chunks_mut(header.cupsWidth as usize).enumerate() {
+        if let Some(overlay) = &job.overlay {
+            overlay.apply(&header, y as u32, row);
+        }
+        output_line(out, model, &header, y as u32, row, &mut state)?;
+    }
+    let stats = end_page(out, model, &settings, job, state)?;
+
+    eprintln!(
+        "INFO: {}",
+        tr!(
+            "printed {width}x{height} image",
+            width = image.width,
+            height = image.height
+        )
+    );
+    Ok((stats.labels, stats.stock_mm))
+}
+
+/// Goes through the motions of a job for `--check`: sets up as for printing,
+/// reads the first page's header, and reports what would be sent for its
+/// label, without sending anything to the printer.
+fn check_job(
+    model: &Model,
+    ppd: &mut impl Settings,
+    job: &JobSettings,
+    args: &[std::ffi::OsString],
+    timeout: Option<Duration>,
+) -> Result<(), Box<dyn Error>> {
+    let out = &mut TsplWriter::buffered(vec![]).with_line_ending(LineEnding::Lf);
+    setup(out, model, ppd, job)?;
+
+    let mut document = match input_paths(args)?.first() {
+        Some(path) => Document::open_file(path, timeout)
+            .map_err(|e| format!("can't open {}: {e}", path.display()))?,
+        None => Document::stdin(timeout)?,
+    };
+    let (kind, header) = if passthrough::looks_like_tspl(document.peek(passthrough::SNIFF_BYTES)?) {
+        println!("model: {}", model.name);
+        println!("document: TSPL, to be sent as it is");
+        return Ok(());
+    } else if job.test_page.is_some() || image::is_image(document.peek(image::SNIFF_BYTES)?) {
+        ("generated", generated_page_header(model, ppd, job)?)
+    } else {
+        let header = document
+            .into_raster()?
+            .read_header()?
+            .ok_or("no pages were found.")?;
+        check_format(&header)?;
+        ("raster", header)
+    };
+
+    let settings = ResolvedSettings::resolve(model, ppd, job, &header)?;
+    let state = start_page(out, model, &settings, job, &header)?;
+    let trimmed = matches!(state.sink, PageSink::Buffer { trim: true, .. });
+    if matches!(state.sink, PageSink::Buffer { .. }) {
+        // The label is described once the page has been seen; report it at
+        // full length.
+        label_setup(
+            out,
+            model,
+            &settings,
+            job,
+            &state.header,
+            state.header.cupsHeight,
+        )?;
+    }
+
+    println!("model: {}", model.name);
+    println!(
+        "first page: {kind}, {}x{} at {}dpi, {}",
+        header.cupsWidth,
+        header.cupsHeight,
+        header.HWResolution[1],
+        colorspace_name(header.cupsColorSpace)
+    );
+    println!(
+        "label: {}x{} dots, {} mm of stock each{}",
+        state.region.width_bytes * 8,
+        state.region.height,
+        state.label_mm,
+        if trimmed {
+            ", trimmed to the content"
+        } else {
+            ""
+        }
+    );
+    println!("setup:");
+    let commands = String::from_utf8_lossy(out.get_ref());
+    for command in commands.lines().take_while(|c| !c.starts_with("BITMAP ")) {
+        println!("  {command}");
+    }
+    Ok(())
+}
+
+/// Makes up the header of an 8-bit grayscale page the size of the selected
+/// label stock or page size, for pages we generate rather than read.
+fn generated_page_header(
+    model: &Model,
+    ppd: &mut impl Settings,
+    job: &JobSettings,
+) -> Result<cups_page_header2_t, Box<dyn Error>> {
+    let stock = match job.stock {
+        Some(stock) => Some(stock),
+        None => label_stock(ppd)?,
+    };
+    let (width_pt, length_pt) = match stock {
+        Some(stock) => {
+            let mm_to_points = |mm: u32| mm as f32 * 72.0 / 25.4;
+            (mm_to_points(stock.width_mm), mm_to_points(stock.height_mm))
+        }
+        None => ppd
+            .page_size()
+            .ok_or("no label stock or page size is selected")?,
+    };
+    let dpi = selected_dpi(model, ppd);
+
+    let mut header = api::blank_page_header();
+    header.HWResolution = [dpi, dpi];
+    header.PageSize = [width_pt.round() as u32, length_pt.round() as u32];
+    header.cupsWidth = (width_pt * dpi as f32 / 72.0) as u32;
+    header.cupsHeight = (length_pt * dpi as f32 / 72.0) as u32;
+    header.cupsBitsPerColor = 8;
+    header.cupsBitsPerPixel = 8;
+    header.cupsBytesPerLine = header.cupsWidth;
+    header.cupsColorSpace = cups_cspace_e_CUPS_CSPACE_K;
+    Ok(header)
+}
+
+/// Gets the resolution selected in the PPD, for when there's no raster header
+/// to say.
+fn selected_dpi(model: &Model, ppd: &mut impl Settings) -> u32 {
+    // Resolution choices are named like `203dpi`.
+    ppd.marked_choice(c"Resolution")
+        .and_then(|choice| choice.to_str().ok()?.strip_suffix("dpi")?.parse().ok())
+        .unwrap_or(model.heads[0].dpi)
+}
+
+/// Sends a job that's already TSPL to the printer, after checking that it'll
+/// print something, and giving it the queue's label size and darkness if it
+/// doesn't set its own.
+fn pass_through(
+    out: &mut TsplWriter<impl Write>,
+    model: &Model,
+    ppd: &mut impl Settings,
+    job: &JobSettings,
+    mut document: Document,
 ) -> Result<(), Box<dyn Error>> {
-    match ppd.raw().model_number {
-        BEEPRT => {
-            // Convert 8-bit grayscale to 1-bit black and white
-            for chunk in buffer.chunks(8) {
-                let mut out = 0;
-                for (i, &byte) in chunk.iter().enumerate() {
-                    if byte >= WHITE_THRESHOLD {
-                        out |= 1 << (7 - i);
+    // We need to see all of it before we know what to add.
+    let mut program = Spool::new(SPILL_BYTES);
+    let mut scan = Scan::default();
+    let mut buffer = vec![0; 64 << 10];
+    loop {
+        let n = document.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        scan.feed(&buffer[..n]);
+        program.append(&buffer[..n])?;
+    }
+    let scan = scan.finish();
+    if !scan.prints {
+        return Err("the TSPL job has no PRINT command, so it wouldn't print anything".into());
+    }
+
+    match model.family {
+        #[cfg(feature = "beeprt")]
+        Family::Beeprt => {
+            if !scan.size {
+                let stock = match job.stock {
+                    Some(stock) => Some(stock),
+                    None => label_stock(ppd)?,
+                };
+                let (width_mm, height_mm) = match stock {
+                    Some(stock) => (stock.width_mm, stock.height_mm),
+                    None => {
+                        let (width_pt, length_pt) = ppd
+                            .page_size()
+                            .ok_or("the TSPL job has no SIZE, and no page size is selected")?;
+                        (
+                            units::points_to_mm(width_pt.round() as u32),
+                            units::points_to_mm(length_pt.round() as u32),
+                        )
                     }
+                };
+                out!(out, "SIZE {width_mm} mm,{height_mm} mm");
+            }
+            if !scan.density {
+                let dpi = selected_dpi(model, ppd);
+                let head = model
+                    .heads
+                    .iter()
+                    .find(|head| head.dpi == dpi)
+                    .unwrap_or(&model.heads[0]);
+                out!(out, "DENSITY {}", density(ppd, job, head)?);
+            }
+        }
+    }
+
+    let len = program.len();
+    for _ in 0..job.copies {
+        program.send(len, |data| out.program(data))?;
+    }
+    out.flush()?;
+    eprintln!("INFO: {}", tr!("sent the TSPL job as it is"));
+    Ok(())
+}
+
+fn setup(
+    out: &mut TsplWriter<impl Write>,
+    model: &Model,
+    ppd: &mut impl Settings,
+    job: &JobSettings,
+) -> Result<(), Box<dyn Error>> {
+    match model.family {
+        #[cfg(feature = "beeprt")]
+        Family::Beeprt => {
+            let clear = ppd
+                .marked_choice(c"ClearBufferAtStart")
+                .is_some_and(|choice| choice == c"True");
+            if clear {
+                // Get rid of anything left over from an aborted job, so it
+                // doesn't end up mixed into this one.
+                if let Some(cancel) = model.cancel_command {
+                    out!(out, "{cancel}");
                 }
-                out = !out;
-                std::io::stdout().write_all(std::slice::from_ref(&out))?;
+                out!(out, "CLS");
             }
-            std::io::stdout().flush()?;
+
+            if let Some(on) = printer_setting(ppd, c"HeadOpenSensor") {
+                out!(out, "SET HEAD {}", if on { "ON" } else { "OFF" });
+            }
+            if let Some(on) = printer_setting(ppd, c"Encoder") {
+                out!(out, "SET ENCODER {}", if on { "ON" } else { "OFF" });
+            }
+
+            let set_clock = ppd
+                .marked_choice(c"SetClock")
+                .is_some_and(|choice| choice == c"True");
+            if set_clock && !job.deterministic {
+                // Bring the printer's clock into line with ours, so whatever
+                // it stamps with the time doesn't drift.
+                let now = local_time()?;
+                out!(out, "YEAR = {}", now.tm_year + 1900);
+                out!(out, "MONTH = {}", now.tm_mon + 1);
+                out!(out, "DATE = {}", now.tm_mday);
+                out!(out, "HOUR = {}", now.tm_hour);
+                out!(out, "MINUTE = {}", now.tm_min);
+                out!(out, "SECOND = {}", now.tm_sec);
+            }
+            Ok(())
         }
-        x => unimplemented!("model number {x}"),
     }
+}
+
+/// Gets the current local time.
+fn local_time() -> std::io::Result<libc::tm> {
+    let now = unsafe { libc::time(null_mut()) };
+    let mut tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(tm)
+}
+
+/// Whether `page-ranges` and `outputorder` are ours to apply. CUPS's filters
+/// that turn documents into raster (such as `pdftopdf`) apply them
+/// themselves, and the pages we'd get are numbered afresh and already in
+/// order, so we only apply them to jobs that were raster to begin with, or
+/// when run without CUPS saying.
+fn pages_left_to_us() -> bool {
+    const RASTER_TYPES: &[&str] = &[
+        "application/vnd.cups-raster",
+        "image/pwg-raster",
+        "image/urf",
+    ];
+    std::env::var("CONTENT_TYPE").map_or(true, |t| RASTER_TYPES.contains(&t.as_str()))
+}
+
+/// Whether the job asks for its pages last to first, with `outputorder`, or
+/// the PPD option `OutputOrder`.
+fn reverse_order(options: &Options) -> Result<bool, Box<dyn Error>> {
+    let Some(value) = options
+        .get(c"outputorder")
+        .or_else(|| options.get(c"OutputOrder"))
+    else {
+        return Ok(false);
+    };
+    match value.to_str()?.to_ascii_lowercase().as_str() {
+        "normal" => Ok(false),
+        "reverse" => Ok(true),
+        other => Err(format!("bad outputorder {other:?}, expected normal or reverse").into()),
+    }
+}
+
+/// Starts a page, or a document that isn't pages, which with
+/// `outputorder=reverse` is held back, to be sent after the one that follows
+/// it. So it can't count on the settings that one leaves the printer with.
+fn start_reversed_page<W: Write>(out: &mut TsplWriter<Counting<Reversing<W>>>) {
+    let reversing = &mut out.get_mut().inner;
+    if reversing.pages.is_some() {
+        reversing.start_page();
+        out.forget_settings();
+    }
+}
+
+/// Sends the pages held back with `outputorder=reverse`, last first.
+fn send_reversed_pages<W: Write>(
+    out: &mut TsplWriter<Counting<Reversing<W>>>,
+) -> std::io::Result<()> {
+    out.get_mut().inner.finish()?;
+    // What's next follows the first page, not the last.
+    out.forget_settings();
     Ok(())
 }
 
-fn end_page(ppd: &PpdFile, _header: &cups_page_header2_t) -> Result<(), Box<dyn Error>> {
-    match ppd.raw().model_number {
-        BEEPRT => {
-            out!("\r\nPRINT 1,1");
+/// Reads past the rest of a page that isn't being printed.
+fn skip_page(ras: &mut Raster, header: &cups_page_header2_t) -> Result<(), Box<dyn Error>> {
+    let mut buffer = vec![0; header.cupsBytesPerLine as usize];
+    for _ in 0..header.cupsHeight {
+        if ras.read_pixels(&mut buffer)? < buffer.len() {
+            break;
         }
-        x => unimplemented!("model number {x}"),
     }
     Ok(())
 }
+
+/// Reads all of a page's rows, for [`quarter_turn`].
+/// Reads a whole page, for turning. A page cut short is padded out with blank
+/// rows, as it would be if its rows were printed as they came.
+fn read_page(
+    ras: &mut Raster,
+    header: &cups_page_header2_t,
+    page: u32,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let line = header.cupsBytesPerLine as usize;
+    let blank = raster_blank(header);
+    let mut pixels = vec![blank; line * header.cupsHeight as usize];
+    for y in 0..header.cupsHeight {
+        let start = y as usize * line;
+        if ras.read_pixels(&mut pixels[start..][..line])? < line {
+            warn_short_page(page, y, header.cupsHeight);
+            // The short read may have filled part of the row.
+            pixels[start..].fill(blank);
+            break;
+        }
+    }
+    Ok(pixels)
+}
+
+/// Warns that a page ended after `rows` of its `height` rows.
+fn warn_short_page(page: u32, rows: u32, height: u32) {
+    eprintln!(
+        "WARNING: {}",
+        tr!(
+            "page {page} ended after {rows} of its {height} rows, so the rest is left blank",
+            page = page,
+            rows = rows,
+            height = height
+        )
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_text_is_sanitized() {
+        assert_eq!(
+            display_text("DISPLAY \"{text}\"", "Job 3: \"Café\"\n"),
+            "DISPLAY \"Job 3: 'Caf?'?\""
+        );
+    }
+}
@@ -0,0 +1,520 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Translations of the messages users see.
+//!
+//! CUPS shows the text of our `INFO:`, `WARNING:`, and `ERROR:` lines in its
+//! web interface and in print dialogs, and runs filters with `LANG` set to the
+//! job's language. This works like gettext, with the English message as the
+//! key, but with the catalogs compiled in: there are only a handful of
+//! messages, and a filter has nowhere good to install `.mo` files.
+//!
+//! Only the message text is translated. The `INFO:` etc. prefixes are part of
+//! the protocol with CUPS, and error details from lower down (file names, PPD
+//! values) are passed through as they are.
+
+use std::{fmt::Display, sync::OnceLock};
+
+type Catalog = &'static [(&'static str, &'static str)];
+
+/// Catalogs by language, as `language` or `language_TERRITORY`.
+static CATALOGS: &[(&str, Catalog)] = &[
+    (
+        "de",
+        &[
+            (
+                "printing page {page}, {percent}% complete.",
+                "Drucke Seite {page}, {percent}% fertig.",
+            ),
+            ("finished page {page}", "Seite {page} fertig"),
+            (
+                "page {page} is empty ({width}x{height} pixels), skipping",
+                "Seite {page} ist leer ({width}x{height} Pixel) und wird übersprungen",
+            ),
+            (
+                "page is blank, not printing a label for it",
+                "Seite ist leer, dafür wird kein Etikett gedruckt",
+            ),
+            (
+                "labels print on one side only, ignoring {option}={value}",
+                "Etiketten werden nur einseitig gedruckt, {option}={value} wird ignoriert",
+            ),
+            (
+                "each label's copies print together, ignoring {option}={value}",
+                "Die Kopien jedes Etiketts werden zusammen gedruckt, {option}={value} wird ignoriert",
+            ),
+            (
+                "page {page} is too wide for the label, so it's turned a quarter turn to fit",
+                "Seite {page} ist zu breit für das Etikett und wird daher um eine Vierteldrehung gedreht",
+            ),
+            (
+                "{model} printers have no peeler, ignoring Peel=On",
+                "Drucker der Modellreihe {model} haben keinen Spender, Peel=On wird ignoriert",
+            ),
+            (
+                "can't save the calibration state as {path}: {error}",
+                "Kalibrierungsstatus kann nicht als {path} gespeichert werden: {error}",
+            ),
+            (
+                "the printer is ready again",
+                "Der Drucker ist wieder bereit",
+            ),
+            (
+                "waiting for the printer: {problems}",
+                "Warte auf den Drucker: {problems}",
+            ),
+            (
+                "can't save a preview of the label as {path}: {error}",
+                "Vorschau des Etiketts kann nicht als {path} gespeichert werden: {error}",
+            ),
+            (
+                "selected options conflict, and may not print as expected: {options}",
+                "Die gewählten Optionen widersprechen sich, der Druck kann abweichen: {options}",
+            ),
+            (
+                "this printer has no display, so tspl-display is ignored",
+                "Dieser Drucker hat keine Anzeige, daher wird tspl-display ignoriert",
+            ),
+            (
+                "sent the TSPL job as it is",
+                "Der TSPL-Auftrag wurde unverändert gesendet",
+            ),
+            (
+                "printed {width}x{height} image",
+                "Bild mit {width}x{height} Pixeln gedruckt",
+            ),
+            (
+                "the PPD has no options {keywords}, using their defaults",
+                "Die PPD hat die Optionen {keywords} nicht, es werden ihre Standardwerte verwendet",
+            ),
+            (
+                "selected options conflicted, changed them to {options}",
+                "Die gewählten Optionen widersprachen sich und wurden geändert: {options}",
+            ),
+            (
+                "printed {pattern} test page",
+                "Testseite {pattern} gedruckt",
+            ),
+            (
+                "the job failed: {error}",
+                "Der Auftrag ist fehlgeschlagen: {error}",
+            ),
+            (
+                "{model} printers can't cut that way, ignoring Cutter={mode}",
+                "Drucker vom Typ {model} können so nicht schneiden, Cutter={mode} wird ignoriert",
+            ),
+            (
+                "{model} printers have no {x}x{y}dpi head, printing as {dpi}dpi",
+                "Drucker vom Typ {model} haben keinen Druckkopf mit {x}x{y} dpi, es wird mit {dpi} dpi gedruckt",
+            ),
+            (
+                "page {page} ended after {rows} of its {height} rows, so the rest is left blank",
+                "Seite {page} endete nach {rows} von {height} Zeilen, der Rest bleibt leer",
+            ),
+            (
+                "printed {labels} labels, using about {length} m of stock",
+                "{labels} Etiketten gedruckt, etwa {length} m Material verbraucht",
+            ),
+        ],
+    ),
+    (
+        "es",
+        &[
+            (
+                "printing page {page}, {percent}% complete.",
+                "Imprimiendo página {page}, {percent}% completado.",
+            ),
+            ("finished page {page}", "Página {page} terminada"),
+            (
+                "page {page} is empty ({width}x{height} pixels), skipping",
+                "La página {page} está vacía ({width}x{height} píxeles), se omite",
+            ),
+            (
+                "page is blank, not printing a label for it",
+                "La página está en blanco, no se imprime ninguna etiqueta",
+            ),
+            (
+                "labels print on one side only, ignoring {option}={value}",
+                "Las etiquetas se imprimen solo por una cara, se ignora {option}={value}",
+            ),
+            (
+                "each label's copies print together, ignoring {option}={value}",
+                "Las copias de cada etiqueta se imprimen juntas, se ignora {option}={value}",
+            ),
+            (
+                "page {page} is too wide for the label, so it's turned a quarter turn to fit",
+                "La página {page} es demasiado ancha para la etiqueta, así que se gira un cuarto de vuelta para que quepa",
+            ),
+            (
+                "{model} printers have no peeler, ignoring Peel=On",
+                "las impresoras {model} no tienen despegador, se ignora Peel=On",
+            ),
+            (
+                "can't save the calibration state as {path}: {error}",
+                "No se puede guardar el estado de calibración como {path}: {error}",
+            ),
+            (
+                "the printer is ready again",
+                "La impresora vuelve a estar lista",
+            ),
+            (
+                "waiting for the printer: {problems}",
+                "Esperando a la impresora: {problems}",
+            ),
+            (
+                "can't save a preview of the label as {path}: {error}",
+                "No se puede guardar una vista previa de la etiqueta como {path}: {error}",
+            ),
+            (
+                "selected options conflict, and may not print as expected: {options}",
+                "Las opciones seleccionadas están en conflicto y el resultado puede no ser el esperado: {options}",
+            ),
+            (
+                "this printer has no display, so tspl-display is ignored",
+                "Esta impresora no tiene pantalla, así que se ignora tspl-display",
+            ),
+            (
+                "sent the TSPL job as it is",
+                "Se envió el trabajo TSPL tal cual",
+            ),
+            (
+                "printed {width}x{height} image",
+                "Se imprimió la imagen de {width}x{height}",
+            ),
+            (
+                "the PPD has no options {keywords}, using their defaults",
+                "El PPD no tiene las opciones {keywords}; se usarán sus valores predeterminados",
+            ),
+            (
+                "selected options conflicted, changed them to {options}",
+                "Las opciones seleccionadas estaban en conflicto y se cambiaron a: {options}",
+            ),
+            (
+                "printed {pattern} test page",
+                "Página de prueba {pattern} impresa",
+            ),
+            ("the job failed: {error}", "El trabajo ha fallado: {error}"),
+            (
+                "{model} printers can't cut that way, ignoring Cutter={mode}",
+                "Las impresoras {model} no pueden cortar así, se ignora Cutter={mode}",
+            ),
+            (
+                "{model} printers have no {x}x{y}dpi head, printing as {dpi}dpi",
+                "Las impresoras {model} no tienen cabezal de {x}x{y} ppp, se imprime a {dpi} ppp",
+            ),
+            (
+                "page {page} ended after {rows} of its {height} rows, so the rest is left blank",
+                "La página {page} terminó tras {rows} de sus {height} filas, el resto queda en blanco",
+            ),
+            (
+                "printed {labels} labels, using about {length} m of stock",
+                "Se imprimieron {labels} etiquetas, con unos {length} m de material",
+            ),
+        ],
+    ),
+    (
+        "fr",
+        &[
+            (
+                "printing page {page}, {percent}% complete.",
+                "Impression de la page {page}, {percent} % terminé.",
+            ),
+            ("finished page {page}", "Page {page} terminée"),
+            (
+                "page {page} is empty ({width}x{height} pixels), skipping",
+                "La page {page} est vide ({width}x{height} pixels) et sera ignorée",
+            ),
+            (
+                "page is blank, not printing a label for it",
+                "La page est blanche, aucune étiquette n'est imprimée",
+            ),
+            (
+                "labels print on one side only, ignoring {option}={value}",
+                "Les étiquettes ne s'impriment que d'un côté, {option}={value} est ignoré",
+            ),
+            (
+                "each label's copies print together, ignoring {option}={value}",
+                "Les copies de chaque étiquette s'impriment ensemble, {option}={value} est ignoré",
+            ),
+            (
+                "page {page} is too wide for the label, so it's turned a quarter turn to fit",
+                "La page {page} est trop large pour l'étiquette, elle est donc tournée d'un quart de tour",
+            ),
+            (
+                "{model} printers have no peeler, ignoring Peel=On",
+                "les imprimantes {model} n'ont pas de décolleur, Peel=On est ignoré",
+            ),
+            (
+                "can't save the calibration state as {path}: {error}",
+                "Impossible d'enregistrer l'état de calibrage sous {path} : {error}",
+            ),
+            (
+                "the printer is ready again",
+                "L'imprimante est de nouveau prête",
+            ),
+            (
+                "waiting for the printer: {problems}",
+                "En attente de l'imprimante : {problems}",
+            ),
+            (
+                "can't save a preview of the label as {path}: {error}",
+                "Impossible d'enregistrer un aperçu de l'étiquette sous {path} : {error}",
+            ),
+            (
+                "selected options conflict, and may not print as expected: {options}",
+                "Les options choisies sont incompatibles, l'impression peut être différente : {options}",
+            ),
+            (
+                "this printer has no display, so tspl-display is ignored",
+                "Cette imprimante n'a pas d'écran, tspl-display est donc ignoré",
+            ),
+            (
+                "sent the TSPL job as it is",
+                "Tâche TSPL envoyée telle quelle",
+            ),
+            (
+                "printed {width}x{height} image",
+                "Image de {width}x{height} imprimée",
+            ),
+            (
+                "the PPD has no options {keywords}, using their defaults",
+                "Le PPD n'a pas les options {keywords} ; leurs valeurs par défaut seront utilisées",
+            ),
+            (
+                "selected options conflicted, changed them to {options}",
+                "Les options choisies étaient incompatibles et ont été changées : {options}",
+            ),
+            (
+                "printed {pattern} test page",
+                "Page de test {pattern} imprimée",
+            ),
+            ("the job failed: {error}", "Échec de la tâche : {error}"),
+            (
+                "{model} printers can't cut that way, ignoring Cutter={mode}",
+                "Les imprimantes {model} ne peuvent pas couper ainsi, Cutter={mode} est ignoré",
+            ),
+            (
+                "{model} printers have no {x}x{y}dpi head, printing as {dpi}dpi",
+                "Les imprimantes {model} n'ont pas de tête à {x}x{y} ppp, impression à {dpi} ppp",
+            ),
+            (
+                "page {page} ended after {rows} of its {height} rows, so the rest is left blank",
+                "La page {page} s'est arrêtée après {rows} de ses {height} lignes, le reste est laissé blanc",
+            ),
+            (
+                "printed {labels} labels, using about {length} m of stock",
+                "{labels} étiquettes imprimées, environ {length} m de support utilisés",
+            ),
+        ],
+    ),
+    (
+        "zh_CN",
+        &[
+            (
+                "printing page {page}, {percent}% complete.",
+                "正在打印第 {page} 页，已完成 {percent}%。",
+            ),
+            ("finished page {page}", "第 {page} 页已完成"),
+            (
+                "page {page} is empty ({width}x{height} pixels), skipping",
+                "第 {page} 页为空（{width}x{height} 像素），已跳过",
+            ),
+            (
+                "page is blank, not printing a label for it",
+                "页面空白，不打印标签",
+            ),
+            (
+                "labels print on one side only, ignoring {option}={value}",
+                "标签只能单面打印，忽略 {option}={value}",
+            ),
+            (
+                "each label's copies print together, ignoring {option}={value}",
+                "每个标签的副本会一起打印，忽略 {option}={value}",
+            ),
+            (
+                "page {page} is too wide for the label, so it's turned a quarter turn to fit",
+                "第 {page} 页对标签来说太宽，因此旋转四分之一圈以适应",
+            ),
+            (
+                "{model} printers have no peeler, ignoring Peel=On",
+                "{model} 打印机没有剥离器，忽略 Peel=On",
+            ),
+            (
+                "can't save the calibration state as {path}: {error}",
+                "无法将校准状态保存为 {path}：{error}",
+            ),
+            ("the printer is ready again", "打印机已恢复就绪"),
+            (
+                "waiting for the printer: {problems}",
+                "正在等待打印机：{problems}",
+            ),
+            (
+                "can't save a preview of the label as {path}: {error}",
+                "无法将标签预览保存为 {path}：{error}",
+            ),
+            (
+                "selected options conflict, and may not print as expected: {options}",
+                "所选选项相互冲突，打印结果可能与预期不同：{options}",
+            ),
+            (
+                "this printer has no display, so tspl-display is ignored",
+                "此打印机没有显示屏，因此忽略 tspl-display",
+            ),
+            ("sent the TSPL job as it is", "已按原样发送 TSPL 作业"),
+            (
+                "printed {width}x{height} image",
+                "已打印 {width}x{height} 图像",
+            ),
+            (
+                "the PPD has no options {keywords}, using their defaults",
+                "PPD 中没有选项 {keywords}，将使用其默认值",
+            ),
+            (
+                "selected options conflicted, changed them to {options}",
+                "所选选项相互冲突，已更改为：{options}",
+            ),
+            ("printed {pattern} test page", "已打印 {pattern} 测试页"),
+            ("the job failed: {error}", "作业失败：{error}"),
+            (
+                "{model} printers can't cut that way, ignoring Cutter={mode}",
+                "{model} 打印机不支持此切纸方式，已忽略 Cutter={mode}",
+            ),
+            (
+                "{model} printers have no {x}x{y}dpi head, printing as {dpi}dpi",
+                "{model} 打印机没有 {x}x{y}dpi 的打印头，按 {dpi}dpi 打印",
+            ),
+            (
+                "page {page} ended after {rows} of its {height} rows, so the rest is left blank",
+                "第 {page} 页在 {height} 行中的第 {rows} 行后结束，其余部分留白",
+            ),
+            (
+                "printed {labels} labels, using about {length} m of stock",
+                "已打印 {labels} 张标签，约用去 {length} 米耗材",
+            ),
+        ],
+    ),
+];
+
+/// Finds the catalog for a POSIX locale name such as `de_DE.UTF-8`, trying the
+/// language and territory first, then just the language.
+fn catalog_for(locale: &str) -> Option<Catalog> {
+    let name = locale.split(['.', '@']).next().unwrap_or_default();
+    let language = name.split('_').next().unwrap_or_default();
+    [name, language].into_iter().find_map(|wanted| {
+        CATALOGS
+            .iter()
+            .find(|(lang, _)| *lang == wanted)
+            .map(|(_, catalog)| *catalog)
+    })
+}
+
+/// Works out our catalog from the environment, the way gettext would.
+fn catalog() -> Option<Catalog> {
+    static CATALOG: OnceLock<Option<Catalog>> = OnceLock::new();
+    *CATALOG.get_or_init(|| {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .into_iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty())
+            .and_then(|locale| catalog_for(&locale))
+    })
+}
+
+/// Returns the translation of `msgid`, or `msgid` itself if there isn't one.
+pub fn gettext(msgid: &'static str) -> &'static str {
+    catalog()
+        .and_then(|catalog| catalog.iter().find(|(id, _)| *id == msgid))
+        .map_or(msgid, |(_, translation)| translation)
+}
+
+/// Fills in the `{name}` placeholders in `template`. Placeholders without a
+/// value are left alone.
+pub fn format(template: &str, args: &[(&str, &dyn Display)]) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let (_, value) = args.iter().find(|(name, _)| *name == &rest[1..end])?;
+            Some((end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                out.push_str(&value.to_string());
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Translates a message and fills in its placeholders, which are named
+/// `{like_this}` and given as `like_this = value` arguments:
+///
+/// ```
+/// # use raster_tspl::tr;
+/// let page = 3;
+/// eprintln!("INFO: {}", tr!("finished page {page}", page = page));
+/// ```
+#[macro_export]
+macro_rules! tr {
+    ($msgid:literal $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::messages::format(
+            $crate::messages::gettext($msgid),
+            &[$((stringify!($name), &$value as &dyn ::std::fmt::Display)),*],
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_catalogs_by_language() {
+        assert!(catalog_for("de_DE.UTF-8").is_some());
+        assert!(catalog_for("zh_CN.UTF-8").is_some());
+        assert!(catalog_for("fr_CA@euro").is_some());
+        assert!(catalog_for("zh_TW.UTF-8").is_none());
+        assert!(catalog_for("C").is_none());
+        assert!(catalog_for("").is_none());
+    }
+
+    #[test]
+    fn catalogs_keep_placeholders() {
+        for (lang, catalog) in CATALOGS {
+            for (msgid, translation) in *catalog {
+                let names = |s: &str| {
+                    let mut names = s
+                        .split('{')
+                        .skip(1)
+                        .filter_map(|s| s.split_once('}'))
+                        .map(|(name, _)| name.to_string())
+                        .collect::<Vec<_>>();
+                    names.sort();
+                    names
+                };
+                assert_eq!(names(msgid), names(translation), "{lang}: {msgid:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        assert_eq!(
+            format(
+                "page {page} is {width}x{height}",
+                &[("width", &10), ("height", &20), ("page", &"one"),]
+            ),
+            "page one is 10x20"
+        );
+        assert_eq!(format("{missing} {", &[]), "{missing} {");
+    }
+}
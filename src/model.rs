@@ -0,0 +1,333 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Registry of the printers we know how to drive.
+//!
+//! The PPD identifies the printer to us by its `cupsModelNumber`. Vendors tend
+//! to number a product line in blocks, and most of a line speaks the same
+//! dialect, so each entry here covers a _range_ of model numbers and names the
+//! family whose code path handles them.
+//!
+//! Each family's entries live in a module of their own, built in only with the
+//! Cargo feature of the same name, so that a filter can be built with just the
+//! families it needs. Adding a family takes a module that provides a
+//! [`Registration`], a line in [`FAMILIES`], a [`Family`] variant, and its
+//! arms where the filter dispatches on the family.
+
+use std::{
+    error::Error, ffi::c_int, ops::RangeInclusive, path::Path, str::FromStr, sync::OnceLock,
+};
+
+use cups_raster_sys::cups_page_header2_t;
+
+use crate::profile;
+use crate::tspl::{BitmapFormat, LineEnding};
+
+#[cfg(feature = "beeprt")]
+mod beeprt;
+
+#[cfg(not(any(feature = "beeprt")))]
+compile_error!("no printer families are enabled; enable at least one feature, such as `beeprt`");
+
+/// Groups of printers that share a command dialect, and thus a code path.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Family {
+    /// Printers derived from the Beeprt design, such as the iDPRT SP420.
+    #[cfg(feature = "beeprt")]
+    Beeprt,
+}
+
+impl FromStr for Family {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            #[cfg(feature = "beeprt")]
+            "beeprt" => Ok(Self::Beeprt),
+            _ => Err(format!("unknown printer family {s:?}")),
+        }
+    }
+}
+
+/// A registry entry.
+pub struct Model {
+    /// Human-readable name, for messages.
+    pub name: &'static str,
+    /// The `cupsModelNumber` values this entry covers.
+    pub numbers: RangeInclusive<c_int>,
+    pub family: Family,
+    /// Print heads this model can be fitted with, distinguished by resolution.
+    pub heads: &'static [HeadProfile],
+    /// For two-color (black/red) printers, the `BITMAP` mode that draws into
+    /// the red plane. `None` for printers that only do black.
+    pub red_plane_mode: Option<u32>,
+    /// How the firmware wants `SPEED` expressed.
+    pub speed_units: SpeedUnits,
+    /// Command that makes the printer abandon whatever it was in the middle
+    /// of receiving, for printers that have one.
+    pub cancel_command: Option<&'static str>,
+    /// Most labels the firmware will print for one `PRINT`, if it caps the
+    /// count. Bigger quantities are split across several commands.
+    pub max_print_count: Option<u32>,
+    /// Widest the head prints, in millimeters, if known.
+    pub print_width_mm: Option<u32>,
+    /// The cutter the model can be fitted with, if any.
+    pub cutter: Cutter,
+    /// Whether the model can be fitted with a peeler, which strips each label
+    /// from its liner and holds it until a sensor sees it taken.
+    pub peeler: bool,
+    /// What the firmware expects at the end of each command.
+    pub line_ending: LineEnding,
+    /// How the firmware reads `BITMAP` data.
+    pub bitmap_format: BitmapFormat,
+    /// Command that shows a line of text on the printer's display, for models
+    /// that have one, with `{text}` standing for the text.
+    pub display_command: Option<&'static str>,
+    /// Whether the firmware takes the `SETC` commands that Beeprt firmwares
+    /// add to TSPL. Others may abort the job on keywords they don't know, so
+    /// without this, any `SETC` in the templates is left out.
+    pub setc_commands: bool,
+    /// Template (see [`crate::template`]) for the commands that set up each
+    /// label, ahead of its bitmap, using the names in [`SETUP_PLACEHOLDERS`].
+    /// Blank lines are left out, so placeholders for commands that aren't
+    /// always needed can have lines of their own.
+    pub setup_template: &'static str,
+    /// Template for the commands that follow each label's bitmap, using the
+    /// names in [`TEARDOWN_PLACEHOLDERS`].
+    pub teardown_template: &'static str,
+    /// USB vendor and product IDs of the model's printers, for finding them
+    /// with `tspl-detect`.
+    pub usb_ids: &'static [(u16, u16)],
+    /// The PPD, in `ppd/`, that sets up a queue for the model.
+    pub ppd: &'static str,
+}
+
+/// Label setup in the order TSPL's examples use, which suits most firmwares.
+pub const DEFAULT_SETUP_TEMPLATE: &str = "\
+SIZE {width_mm} mm,{height_mm} mm
+REFERENCE {reference_x},{reference_y}
+DIRECTION {rotation},0
+{media}
+OFFSET {feed_offset_mm} mm
+{tear}
+{cutter}
+{peel}
+DENSITY {density}
+SPEED {speed}
+SETC AUTODOTTED {autodotted}
+SETC PAUSEKEY ON
+SETC WATERMARK OFF
+";
+
+/// What follows a label: just printing it.
+pub const DEFAULT_TEARDOWN_TEMPLATE: &str = "{print}";
+
+/// Values for [`Model::setup_template`]:
+///
+/// - `width_mm`, `height_mm`: the label size.
+/// - `reference_x`, `reference_y`: the origin, in dots.
+/// - `rotation`: `0` or `1`, for `DIRECTION`.
+/// - `media`: the `GAP` or `BLINE` command for the stock, and `LIMITFEED` for
+///   continuous stock.
+/// - `gap_mm`, `gap_offset_mm`: the gap or mark, and its offset.
+/// - `feed_offset_mm`: how far past the label to feed.
+/// - `tear`: the `SET TEAR` command, if the media type calls for one.
+/// - `cutter`: the `SET CUTTER` (and `SET PARTIAL_CUTTER`) commands, if any.
+/// - `peel`: the `SET PEEL` command, if any.
+/// - `density`: the `DENSITY` value, 0-15.
+/// - `speed`: the `SPEED` value, in the model's units.
+/// - `autodotted`: `ON` or `OFF`.
+pub const SETUP_PLACEHOLDERS: &[&str] = &[
+    "width_mm",
+    "height_mm",
+    "reference_x",
+    "reference_y",
+    "rotation",
+    "media",
+    "gap_mm",
+    "gap_offset_mm",
+    "feed_offset_mm",
+    "tear",
+    "cutter",
+    "peel",
+    "density",
+    "speed",
+    "autodotted",
+];
+
+/// Values for [`Model::teardown_template`]:
+///
+/// - `print`: the `PRINT` commands for the copies, more than one if the
+///   firmware caps the count, or one for each copy when peeling.
+/// - `copies`: the number of copies.
+pub const TEARDOWN_PLACEHOLDERS: &[&str] = &["print", "copies"];
+
+/// What a model's cutter can do. Printers that take a cutter at all generally
+/// report its absence as an error, so this is about capability, not whether
+/// one is installed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cutter {
+    None,
+    /// Cuts right through the stock (`SET CUTTER`).
+    Full,
+    /// Can also leave a tab holding the labels together (`SET
+    /// PARTIAL_CUTTER`).
+    FullAndPartial,
+}
+
+/// Ways firmwares take the argument to `SPEED`.
+pub enum SpeedUnits {
+    /// The speed itself, in inches per second.
+    InchesPerSecond,
+    /// Fixed codes, each standing for a speed. The table pairs speeds in
+    /// inches per second with their codes.
+    Codes(&'static [(u32, u32)]),
+}
+
+/// Characteristics of a print head. A family shares a command set across
+/// resolutions, but the heads want different treatment.
+pub struct HeadProfile {
+    pub dpi: u32,
+    /// Dots per millimeter, as TSPL reckons it. This is rounded, but it's what
+    /// the printer uses to convert our millimeter measurements back to dots.
+    pub dots_per_mm: u32,
+    pub default_speed: u32,
+    /// Speeds the head can print at, in inches per second.
+    pub speeds: RangeInclusive<u32>,
+    pub default_darkness: i32,
+    /// `DENSITY` to use for each step of the user's 0-15 darkness setting, so
+    /// that a given setting produces similar results across heads. (Smaller
+    /// dots need more heat to come out as dark.)
+    pub darkness_curve: &'static [i32; 16],
+}
+
+/// Darkness curve that passes the setting through unchanged.
+pub(crate) const LINEAR_DARKNESS: &[i32; 16] =
+    &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// What a family module adds to the registry.
+pub struct Registration {
+    /// The family's entries. Ranges must not overlap, within the family or
+    /// with other families'.
+    pub models: &'static [Model],
+    /// The family's PPDs, by file name in `ppd/`, so that `tspl-setup` can
+    /// install them without the source tree.
+    pub ppds: &'static [(&'static str, &'static [u8])],
+}
+
+/// The families built in, each behind a Cargo feature of its own name.
+static FAMILIES: &[&Registration] = &[
+    #[cfg(feature = "beeprt")]
+    &beeprt::REGISTRATION,
+];
+
+/// Models from profile files, once [`load_profiles`] has read them.
+static PROFILES: OnceLock<Registration> = OnceLock::new();
+
+/// Lists the registrations: the families', then the profiles'.
+fn registrations() -> impl Iterator<Item = &'static Registration> {
+    FAMILIES.iter().copied().chain(PROFILES.get())
+}
+
+/// Lists all supported models.
+pub fn models() -> impl Iterator<Item = &'static Model> {
+    registrations().flat_map(|registration| registration.models)
+}
+
+/// Adds the models described by the profile files in `dir` (see
+/// [`profile`]) to the registry. Only the first call has any effect.
+pub fn load_profiles(dir: &Path) -> Result<(), Box<dyn Error>> {
+    if PROFILES.get().is_some() {
+        return Ok(());
+    }
+    let profiles = profile::load(dir)?;
+    for (i, model) in profiles.models.iter().enumerate() {
+        let overlapping = models().chain(&profiles.models[..i]).find(|other| {
+            other.numbers.start() <= model.numbers.end()
+                && model.numbers.start() <= other.numbers.end()
+        });
+        if let Some(other) = overlapping {
+            return Err(format!(
+                "the printer profile for {} covers model numbers that {} already covers",
+                model.name, other.name
+            )
+            .into());
+        }
+    }
+    let _ = PROFILES.set(profiles);
+    Ok(())
+}
+
+/// Finds the registry entry covering `model_number`.
+pub fn lookup(model_number: c_int) -> Result<&'static Model, Box<dyn Error>> {
+    models()
+        .find(|model| model.numbers.contains(&model_number))
+        .ok_or_else(|| {
+            format!("printer model number {model_number} is not supported by this filter").into()
+        })
+}
+
+impl HeadProfile {
+    /// The darkness settings the user can choose from, one for each step of
+    /// the curve.
+    pub fn darkness_range(&self) -> RangeInclusive<i32> {
+        0..=self.darkness_curve.len() as i32 - 1
+    }
+
+    /// Translates the user's darkness setting into a `DENSITY` value.
+    pub fn density(&self, darkness: i32) -> i32 {
+        let range = self.darkness_range();
+        self.darkness_curve[darkness.clamp(*range.start(), *range.end()) as usize]
+    }
+}
+
+impl Model {
+    /// Translates a speed in inches per second (as the PPD's `zePrintRate`
+    /// gives it) into the argument this model's `SPEED` takes.
+    ///
+    /// Speeds the firmware has no code for get the closest one.
+    pub fn speed_value(&self, ips: u32) -> u32 {
+        match self.speed_units {
+            SpeedUnits::InchesPerSecond => ips,
+            SpeedUnits::Codes(codes) => codes
+                .iter()
+                .min_by_key(|(speed, _)| speed.abs_diff(ips))
+                .map_or(ips, |&(_, code)| code),
+        }
+    }
+
+    /// Splits `copies` labels into counts for successive `PRINT` commands,
+    /// none of them over the firmware's cap, or `cap` if that's lower.
+    pub fn print_counts(&self, copies: u32, cap: Option<u32>) -> impl Iterator<Item = u32> {
+        let max = [self.max_print_count, cap]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(u32::MAX)
+            .max(1);
+        (0..copies.div_ceil(max)).map(move |i| (copies - i * max).min(max))
+    }
+
+    /// Returns the contents of the model's PPD.
+    pub fn ppd_contents(&self) -> Option<&'static [u8]> {
+        registrations()
+            .flat_map(|registration| registration.ppds)
+            .find(|(name, _)| *name == self.ppd)
+            .map(|(_, contents)| *contents)
+    }
+
+    /// Picks the head profile matching the page's resolution, or, for a
+    /// resolution no head has, the nearest one. Check [`HeadProfile::dpi`]
+    /// against the page to tell which.
+    pub fn head(
+        &self,
+        header: &cups_page_header2_t,
+    ) -> Result<&'static HeadProfile, Box<dyn Error>> {
+        let [x, y] = header.HWResolution;
+        self.heads
+            .iter()
+            .min_by_key(|head| u64::from(head.dpi.abs_diff(x)) + u64::from(head.dpi.abs_diff(y)))
+            .ok_or_else(|| format!("no print heads are known for {}", self.name).into())
+    }
+}
@@ -0,0 +1,76 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Printers derived from the Beeprt design.
+
+use super::{
+    Cutter, DEFAULT_SETUP_TEMPLATE, DEFAULT_TEARDOWN_TEMPLATE, Family, HeadProfile,
+    LINEAR_DARKNESS, Model, Registration, SpeedUnits,
+};
+use crate::tspl::{BitOrder, BitmapFormat, LineEnding, Polarity};
+
+const BEEPRT_HEADS: &[HeadProfile] = &[
+    HeadProfile {
+        dpi: 203,
+        dots_per_mm: 8,
+        default_speed: 4,
+        speeds: 2..=6,
+        default_darkness: 8,
+        darkness_curve: LINEAR_DARKNESS,
+    },
+    HeadProfile {
+        dpi: 300,
+        dots_per_mm: 12,
+        default_speed: 3,
+        speeds: 2..=6,
+        default_darkness: 10,
+        darkness_curve: &[2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 15, 15],
+    },
+];
+
+/// The family's registry entries.
+static MODELS: &[Model] = &[Model {
+    name: "Beeprt",
+    // Only 37155 (SP420) has been seen in the wild; widen this as siblings
+    // turn up.
+    numbers: 37155..=37155,
+    family: Family::Beeprt,
+    heads: BEEPRT_HEADS,
+    red_plane_mode: None,
+    speed_units: SpeedUnits::InchesPerSecond,
+    cancel_command: None,
+    // 999 is a common cap among TSPL firmwares, and splitting costs nothing
+    // if this one allows more.
+    max_print_count: Some(999),
+    // 4.25 inches.
+    print_width_mm: Some(108),
+    cutter: Cutter::None,
+    peeler: false,
+    line_ending: LineEnding::CrLf,
+    bitmap_format: BitmapFormat {
+        bit_order: BitOrder::MsbFirst,
+        polarity: Polarity::Normal,
+    },
+    // The SP420 has no display.
+    display_command: None,
+    setc_commands: true,
+    setup_template: DEFAULT_SETUP_TEMPLATE,
+    teardown_template: DEFAULT_TEARDOWN_TEMPLATE,
+    // Not yet known, so tspl-detect goes by the device ID. Please send in
+    // what `lsusb` says for one.
+    usb_ids: &[],
+    ppd: "sp420.tspl.ppd",
+}];
+
+pub(super) static REGISTRATION: Registration = Registration {
+    models: MODELS,
+    ppds: &[
+        ("sp420.tspl.ppd", include_bytes!("../../ppd/sp420.tspl.ppd")),
+        // For profiles; no built-in model has a cutter or a peeler.
+        (
+            "beeprt-cutter-peeler.tspl.ppd",
+            include_bytes!("../../ppd/beeprt-cutter-peeler.tspl.ppd"),
+        ),
+    ],
+};
@@ -0,0 +1,208 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Images composited onto every label, such as a logo or a "SAMPLE"
+//! watermark.
+//!
+//! Images can be PNG or JPEG, decoded as for printing them directly, or in the
+//! netpbm formats (PBM or PGM, plain or raw), which are decoded here.
+//!
+//! The overlay is applied to each row of raster as it's read, before it's
+//! reduced to black and white, so it works the same whatever the input format.
+
+use std::{error::Error, fs, path::Path};
+
+use cups_raster_sys::{
+    cups_cspace_e_CUPS_CSPACE_K, cups_cspace_e_CUPS_CSPACE_RGB, cups_cspace_e_CUPS_CSPACE_SRGB,
+    cups_page_header2_t,
+};
+
+use crate::image::{self, Image};
+
+/// A bilevel image, and where to put it on the label.
+pub struct Overlay {
+    width: u32,
+    height: u32,
+    /// One entry per pixel, row by row; `true` for ink.
+    ink: Vec<bool>,
+    /// Position of the top left corner, in millimeters from the top left of
+    /// the label.
+    position_mm: (f64, f64),
+}
+
+impl Overlay {
+    /// Loads an image from a PNG, JPEG, PBM, or PGM file. Gray pixels count
+    /// as ink if they're darker than middle gray.
+    pub fn load(path: &Path, position_mm: (f64, f64)) -> Result<Self, Box<dyn Error>> {
+        let data = fs::read(path)
+            .map_err(|e| format!("can't read overlay image {}: {e}", path.display()))?;
+        Self::from_bytes(&data, position_mm)
+            .map_err(|e| format!("bad overlay image {}: {e}", path.display()).into())
+    }
+
+    /// Decodes an image from the contents of a PNG, JPEG, PBM, or PGM file.
+    pub fn from_bytes(data: &[u8], position_mm: (f64, f64)) -> Result<Self, String> {
+        let (width, height, ink) = if image::is_image(data) {
+            let image = Image::decode(data).map_err(|e| e.to_string())?;
+            let ink = image.ink.iter().map(|&ink| ink > 127).collect();
+            (image.width, image.height, ink)
+        } else {
+            decode_netpbm(data)?
+        };
+        Ok(Self {
+            width,
+            height,
+            ink,
+            position_mm,
+        })
+    }
+
+    /// Darkens row `y` of a page wherever the overlay has ink.
+    pub fn apply(&self, header: &cups_page_header2_t, y: u32, row: &mut [u8]) {
+        let [x_dpi, y_dpi] = header.HWResolution;
+        let to_dots = |mm: f64, dpi: u32| (mm * f64::from(dpi) / 25.4).round() as u32;
+        let left = to_dots(self.position_mm.0, x_dpi);
+        let top = to_dots(self.position_mm.1, y_dpi);
+
+        let Some(oy) = y.checked_sub(top).filter(|&oy| oy < self.height) else {
+            return;
+        };
+        let overlay_row = &self.ink[(oy * self.width) as usize..][..self.width as usize];
+        let cs = header.cupsColorSpace;
+        for (ox, _) in overlay_row.iter().enumerate().filter(|(_, ink)| **ink) {
+            let x = left as usize + ox;
+            if x >= header.cupsWidth as usize {
+                break;
+            }
+            if header.cupsBitsPerColor == 1 {
+                row[x / 8] |= 0x80 >> (x % 8);
+            } else if cs == cups_cspace_e_CUPS_CSPACE_RGB || cs == cups_cspace_e_CUPS_CSPACE_SRGB {
+                row[3 * x..3 * x + 3].fill(0);
+            } else if cs == cups_cspace_e_CUPS_CSPACE_K {
+                row[x] = 255;
+            } else {
+                row[x] = 0;
+            }
+        }
+    }
+}
+
+/// Decodes a PBM (P1, P4) or PGM (P2, P5) image into its size and ink mask.
+fn decode_netpbm(data: &[u8]) -> Result<(u32, u32, Vec<bool>), String> {
+    let mut tokens = Tokens { data, pos: 0 };
+    let magic = tokens.next().ok_or("empty file")?;
+    let bitmap = match magic {
+        b"P1" | b"P4" => true,
+        b"P2" | b"P5" => false,
+        _ => return Err("not a PNG, JPEG, PBM, or PGM file".into()),
+    };
+    let width = tokens.number()?;
+    let height = tokens.number()?;
+    let maxval = if bitmap { 1 } else { tokens.number()? };
+    if maxval == 0 || maxval > 65535 {
+        return Err(format!("bad maximum gray value {maxval}"));
+    }
+    let count = width as usize * height as usize;
+
+    let ink = match magic {
+        // Plain PBM pixels needn't be separated, so they go a digit at a
+        // time.
+        b"P1" => (0..count)
+            .map(|_| tokens.bit())
+            .collect::<Result<Vec<_>, String>>()?,
+        // PGM counts light rather than ink.
+        b"P2" => (0..count)
+            .map(|_| Ok(tokens.number()? * 2 < maxval))
+            .collect::<Result<Vec<_>, String>>()?,
+        b"P4" => {
+            // Exactly one whitespace byte separates the header from the data.
+            let raster = data.get(tokens.pos + 1..).unwrap_or_default();
+            let stride = width.div_ceil(8) as usize;
+            if raster.len() < stride * height as usize {
+                return Err("image data is truncated".into());
+            }
+            (0..count)
+                .map(|i| {
+                    let (y, x) = (i / width as usize, i % width as usize);
+                    raster[y * stride + x / 8] & (0x80 >> (x % 8)) != 0
+                })
+                .collect()
+        }
+        _ => {
+            let raster = data.get(tokens.pos + 1..).unwrap_or_default();
+            let bytes = if maxval > 255 { 2 } else { 1 };
+            if raster.len() < count * bytes {
+                return Err("image data is truncated".into());
+            }
+            raster
+                .chunks_exact(bytes)
+                .take(count)
+                .map(|px| {
+                    let value = px.iter().fold(0, |acc, &b| acc << 8 | u32::from(b));
+                    value * 2 < maxval
+                })
+                .collect()
+        }
+    };
+    Ok((width, height, ink))
+}
+
+/// Splits netpbm headers and plain-format data into whitespace-separated
+/// tokens, skipping comments.
+struct Tokens<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn skip_space(&mut self) {
+        while let Some(&b) = self.data.get(self.pos) {
+            if b == b'#' {
+                while self.data.get(self.pos).is_some_and(|&b| b != b'\n') {
+                    self.pos += 1;
+                }
+            } else if b.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        self.skip_space();
+        if self.pos == self.data.len() {
+            return None;
+        }
+        let start = self.pos;
+        while self
+            .data
+            .get(self.pos)
+            .is_some_and(|b| !b.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+        Some(&self.data[start..self.pos])
+    }
+
+    fn bit(&mut self) -> Result<bool, String> {
+        self.skip_space();
+        let bit = match self.data.get(self.pos) {
+            Some(b'0') => false,
+            Some(b'1') => true,
+            Some(&b) => return Err(format!("expected 0 or 1, found {:?}", b as char)),
+            None => return Err("file is truncated".into()),
+        };
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn number(&mut self) -> Result<u32, String> {
+        let token = self.next().ok_or("file is truncated")?;
+        std::str::from_utf8(token)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| format!("expected a number, found {:?}", token.escape_ascii()))
+    }
+}
@@ -0,0 +1,1565 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The page pipeline: fitting each page to the label, reducing its rows to
+//! the printer's bitmap as they arrive, and framing the bitmap with the
+//! commands that describe and print the label.
+
+use std::{
+    error::Error,
+    ffi::CStr,
+    fmt::Display,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use cups_raster_sys::{
+    cups_cspace_e_CUPS_CSPACE_K, cups_cspace_e_CUPS_CSPACE_RGB, cups_cspace_e_CUPS_CSPACE_SRGB,
+    cups_cspace_e_CUPS_CSPACE_SW, cups_cspace_e_CUPS_CSPACE_W, cups_cspace_t,
+    cups_order_e_CUPS_ORDER_CHUNKED, cups_page_header2_t,
+};
+use flate2::Crc;
+
+use crate::api;
+use crate::form::Region;
+use crate::job::{JobSettings, MediaTracking, Oversize};
+use crate::model::{Cutter, Family, HeadProfile, Model};
+use crate::preview::Preview;
+use crate::settings::Settings;
+use crate::spool::{SPILL_BYTES, Spool};
+use crate::stock::{self, Stock};
+use crate::tspl::{BitmapFormat, TsplWriter};
+use crate::{out, template, testpage, tr, units};
+
+/// Lightness below which pixels print, unless the site configuration sets a
+/// `threshold`.
+const WHITE_THRESHOLD: u8 = 128;
+
+/// Maps an IPP `print-darkness` onto the 0-15 `DENSITY` scale, with 0 landing
+/// on the head's default and the extremes on the ends of the scale.
+fn ipp_darkness(default: i32, darkness: i32) -> i32 {
+    let darkness = darkness.clamp(-100, 100);
+    let range = if darkness < 0 { default } else { 15 - default };
+    default + darkness * range / 100
+}
+
+/// Maps an IPP `print-speed` onto `SPEED`, which is in inches per second.
+fn ipp_speed(speed: u32) -> u32 {
+    (f64::from(speed) / 2540.0).round().max(1.0) as u32
+}
+
+/// Colorspaces we know how to reduce to black and white. All are expected at 8
+/// bits per color, and color ones in chunked order. (Apple raster arrives as SW
+/// or sRGB.) K is also accepted at 1 bit per color.
+const SUPPORTED_COLORSPACES: &[cups_cspace_t] = &[
+    cups_cspace_e_CUPS_CSPACE_K,
+    cups_cspace_e_CUPS_CSPACE_W,
+    cups_cspace_e_CUPS_CSPACE_SW,
+    cups_cspace_e_CUPS_CSPACE_RGB,
+    cups_cspace_e_CUPS_CSPACE_SRGB,
+];
+
+/// Checks that a page's pixel format is one we can handle, so that we can fail
+/// the job with a useful message rather than printing noise.
+pub fn check_format(header: &cups_page_header2_t) -> Result<(), Box<dyn Error>> {
+    let cs = header.cupsColorSpace;
+    let bits = header.cupsBitsPerColor;
+    let one_bit_k = cs == cups_cspace_e_CUPS_CSPACE_K && bits == 1;
+    if !one_bit_k && (!SUPPORTED_COLORSPACES.contains(&cs) || bits != 8) {
+        let supported = SUPPORTED_COLORSPACES
+            .iter()
+            .map(|&cs| colorspace_name(cs))
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(format!(
+            "unsupported raster colorspace {} at {} bits per color \
+             (supported: {supported}, at 8 bits per color; or K at 1 bit)",
+            colorspace_name(cs),
+            bits,
+        )
+        .into());
+    }
+    if is_rgb(cs) && header.cupsColorOrder != cups_order_e_CUPS_ORDER_CHUNKED {
+        return Err("unsupported raster color order (only chunked RGB is supported)".into());
+    }
+    // Rows are cut up by these, so they had better agree. libcups checks
+    // when it reads the header, but not every page comes through it.
+    let pixel_bits = if is_rgb(cs) { 3 * bits } else { bits };
+    let line_bits = u64::from(header.cupsWidth) * u64::from(pixel_bits);
+    if header.cupsWidth == 0
+        || header.cupsHeight == 0
+        || header.cupsBitsPerPixel != pixel_bits
+        || u64::from(header.cupsBytesPerLine) != line_bits.div_ceil(8)
+    {
+        return Err(format!(
+            "bad raster page: {}x{} pixels of {} bits in rows of {} bytes",
+            header.cupsWidth, header.cupsHeight, header.cupsBitsPerPixel, header.cupsBytesPerLine
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Checks whether pixels in a colorspace are red, green, blue triples.
+fn is_rgb(cs: cups_cspace_t) -> bool {
+    cs == cups_cspace_e_CUPS_CSPACE_RGB || cs == cups_cspace_e_CUPS_CSPACE_SRGB
+}
+
+/// Reduces an RGB pixel to a gray level, using the Rec. 601 weights.
+fn luminance(rgb: &[u8]) -> u8 {
+    let [r, g, b] = [rgb[0], rgb[1], rgb[2]].map(u32::from);
+    ((299 * r + 587 * g + 114 * b) / 1000) as u8
+}
+
+/// Produces the name CUPS uses for a colorspace, as in the `cupsColorSpace`
+/// PPD keyword.
+pub fn colorspace_name(cs: cups_cspace_t) -> String {
+    let name = match cs {
+        0 => "W",
+        1 => "RGB",
+        2 => "RGBA",
+        3 => "K",
+        4 => "CMY",
+        5 => "YMC",
+        6 => "CMYK",
+        7 => "YMCK",
+        8 => "KCMY",
+        9 => "KCMYcm",
+        10 => "GMCK",
+        11 => "GMCS",
+        12 => "WHITE",
+        13 => "GOLD",
+        14 => "SILVER",
+        15 => "CIEXYZ",
+        16 => "CIELab",
+        17 => "RGBW",
+        18 => "SW",
+        19 => "SRGB",
+        20 => "AdobeRGB",
+        32..=46 => return format!("ICC{:X}", cs - 31),
+        48..=62 => return format!("Device{:X}", cs - 47),
+        _ => return format!("unknown ({cs})"),
+    };
+    name.to_string()
+}
+
+/// Works out the `DENSITY` to print at, from the job, the PPD, and the site
+/// configuration.
+pub fn density(
+    ppd: &mut impl Settings,
+    job: &JobSettings,
+    head: &HeadProfile,
+) -> Result<i32, Box<dyn Error>> {
+    if let Some(d) = job
+        .darkness_setting
+        .filter(|d| !head.darkness_range().contains(d))
+    {
+        return Err(format!(
+            "darkness {d} is out of range, expected {} to {} at {}dpi",
+            head.darkness_range().start(),
+            head.darkness_range().end(),
+            head.dpi
+        )
+        .into());
+    }
+    // Escape hatch for when the curve doesn't suit the stock.
+    let raw_darkness = ppd
+        .marked_choice(c"RawDarkness")
+        .is_some_and(|choice| choice == c"True");
+    let darkness = match (job.darkness_setting, job.darkness) {
+        (Some(d), _) if raw_darkness => d,
+        (Some(d), _) => head.density(d),
+        (None, Some(d)) => ipp_darkness(head.default_darkness, d),
+        (None, None) => {
+            ppd.parse_default_marked_choice::<i32>("Darkness")?
+                .map_or(head.default_darkness, |d| {
+                    if raw_darkness {
+                        d.clamp(*head.darkness_range().start(), *head.darkness_range().end())
+                    } else {
+                        head.density(d)
+                    }
+                })
+        }
+    };
+    Ok(job
+        .site
+        .max_darkness
+        .map_or(darkness, |max| darkness.min(max)))
+}
+
+/// Reads an option that turns a printer setting on or off, or leaves it alone
+/// (the `PrinterDefault` choice, or no choice at all).
+pub fn printer_setting(ppd: &mut impl Settings, keyword: &CStr) -> Option<bool> {
+    match ppd.marked_choice(keyword) {
+        Some(choice) if choice == c"On" => Some(true),
+        Some(choice) if choice == c"Off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Choices for the `Cutter` option, where the PPD offers one.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CutMode {
+    Off,
+    Full,
+    Partial,
+}
+
+/// Reads the `Cutter` option, leaving the printer's setting alone for
+/// `PrinterDefault`, if the model has no cutter, or if it can't cut the way
+/// that was asked.
+fn cut_mode(model: &Model, ppd: &mut impl Settings) -> Option<CutMode> {
+    let mode = match ppd.marked_choice(c"Cutter")?.to_bytes() {
+        b"Off" => CutMode::Off,
+        b"Full" => CutMode::Full,
+        b"Partial" => CutMode::Partial,
+        _ => return None,
+    };
+    let supported = match (model.cutter, mode) {
+        // There's no cutter to turn off.
+        (Cutter::None, CutMode::Off) => return None,
+        (Cutter::None, _) => false,
+        (Cutter::Full, mode) => mode != CutMode::Partial,
+        (Cutter::FullAndPartial, _) => true,
+    };
+    if !supported {
+        eprintln!(
+            "WARNING: {}",
+            tr!(
+                "{model} printers can't cut that way, ignoring Cutter={mode}",
+                model = model.name,
+                mode = format!("{mode:?}")
+            )
+        );
+        return None;
+    }
+    Some(mode)
+}
+
+/// Reads the `Peel` option, leaving the printer's setting alone for
+/// `PrinterDefault` or if the model has no peeler.
+fn peel_mode(model: &Model, ppd: &mut impl Settings) -> Option<bool> {
+    let peel = printer_setting(ppd, c"Peel")?;
+    if !model.peeler {
+        if peel {
+            eprintln!(
+                "WARNING: {}",
+                tr!(
+                    "{model} printers have no peeler, ignoring Peel=On",
+                    model = model.name
+                )
+            );
+        }
+        return None;
+    }
+    Some(peel)
+}
+
+/// Presets for the `MediaType` option, which stand in for the several
+/// interdependent options describing the stock.
+#[derive(Copy, Clone)]
+enum MediaPreset {
+    DieCut,
+    Continuous,
+    BlackMark,
+    Fanfold,
+}
+
+impl MediaPreset {
+    fn tracking(self) -> MediaTracking {
+        match self {
+            Self::DieCut | Self::Fanfold => MediaTracking::Gap,
+            Self::Continuous => MediaTracking::Continuous,
+            Self::BlackMark => MediaTracking::BLine,
+        }
+    }
+
+    /// Whether to feed the label out to the tear bar after printing. Fanfold
+    /// stock stays put, so that the stack folds back up at the perforation.
+    fn tear(self) -> bool {
+        !matches!(self, Self::Fanfold)
+    }
+}
+
+/// Reads the `MediaType` preset, if one was chosen.
+fn media_preset(ppd: &mut impl Settings) -> Option<MediaPreset> {
+    let choice = ppd.marked_choice(c"MediaType")?;
+    match choice.to_bytes() {
+        b"DieCut" => Some(MediaPreset::DieCut),
+        b"Continuous" => Some(MediaPreset::Continuous),
+        b"BlackMark" => Some(MediaPreset::BlackMark),
+        b"Fanfold" => Some(MediaPreset::Fanfold),
+        _ => None,
+    }
+}
+
+/// Works out the media tracking mode, from the IPP attribute if given, then
+/// the `MediaType` preset, then `zeMediaTracking`, then the page's media type
+/// (the raster header's `MediaType`), defaulting to `Gap`.
+fn media_tracking(ppd: &mut impl Settings, job: &JobSettings, media_type: &str) -> MediaTracking {
+    if let Some(tracking) = job.media_tracking {
+        return tracking;
+    }
+    if let Some(preset) = media_preset(ppd) {
+        return preset.tracking();
+    }
+    match ppd.marked_choice(c"zeMediaTracking") {
+        Some(choice) if choice == c"BLine" => MediaTracking::BLine,
+        Some(choice) if choice == c"Continuous" => MediaTracking::Continuous,
+        Some(_) => MediaTracking::Gap,
+        None => MediaTracking::for_media_type(media_type).unwrap_or(MediaTracking::Gap),
+    }
+}
+
+/// Nearest-neighbor downscaling by `num / den`, applied to packed rows as
+/// they go by.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Scale {
+    pub num: u32,
+    pub den: u32,
+    /// Size of the scaled page, in dots.
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Scale {
+    /// Works out the scale that fits a `page` into a `label`, both in dots.
+    fn to_fit(page: [u32; 2], label: [u32; 2]) -> Self {
+        let [width, height] = page.map(u64::from);
+        let [label_width, label_height] = label.map(u64::from);
+        let (num, den) = if label_width * height <= label_height * width {
+            (label_width, width)
+        } else {
+            (label_height, height)
+        };
+        Self {
+            num: num as u32,
+            den: den as u32,
+            width: (width * num / den) as u32,
+            height: (height * num / den) as u32,
+        }
+    }
+
+    /// Gives the scaled row that page row `y` becomes, if any. Shrinking
+    /// drops rows, so most pages have rows that don't make it.
+    fn row_for(&self, y: u32) -> Option<u32> {
+        let (num, den) = (u64::from(self.num), u64::from(self.den));
+        let scaled = (u64::from(y) * num).div_ceil(den);
+        (scaled < u64::from(self.height) && scaled * den / num == u64::from(y))
+            .then_some(scaled as u32)
+    }
+
+    /// Shrinks a packed row.
+    fn shrink(&self, row: &[u8]) -> Vec<u8> {
+        let (num, den) = (u64::from(self.num), u64::from(self.den));
+        pack_row((0..u64::from(self.width)).map(|x| {
+            let x = (x * den / num) as usize;
+            row[x / 8] & (0x80 >> (x % 8)) == 0
+        }))
+    }
+}
+
+/// What the job options, the PPD, and the site configuration come to for
+/// printing pages. Working these out means looking up dozens of choices, so
+/// it's done once for the job rather than for every page, and again only for
+/// a page at another resolution, which prints with another head.
+pub struct ResolvedSettings {
+    /// Resolution and media type of the page they were worked out for.
+    pub resolution: [u32; 2],
+    pub media_type: String,
+    pub stock: Option<&'static Stock>,
+    pub media_tracking: MediaTracking,
+    /// Whether to end each label on continuous stock after its last inked
+    /// row, for `ContinuousLength=Content`.
+    pub trim: bool,
+    /// Whether to leave out pages with no ink, for `SkipBlankPages=True`.
+    pub skip_blank: bool,
+    pub threshold: u8,
+    pub format: BitmapFormat,
+    /// `AdjustHorizontal` and `AdjustVertical`, or else the stock's
+    /// reference point.
+    pub reference_mm: (i32, i32),
+    pub rotate: i32,
+    /// Whether the page size's margins describe the gap, as the PPD's
+    /// `tspl-gap-from-margins` attribute says.
+    pub gap_from_margins: bool,
+    /// `GapOrMarkHeight`, or else the stock's gap. Without either, it comes
+    /// from the page.
+    pub gap_mm: Option<u32>,
+    pub gap_offset_mm: i32,
+    pub feed_offset_mm: i32,
+    pub density: i32,
+    /// In inches per second.
+    pub speed: u32,
+    /// Whether to feed labels out to the tear bar, if the `MediaType` preset
+    /// says.
+    pub tear: Option<bool>,
+    pub cut: Option<CutMode>,
+    /// Whether to hold each label until it's taken, printing copies one at
+    /// a time.
+    pub peel: Option<bool>,
+    pub autodotted: bool,
+    /// Raw TSPL to send before each label's `CLS` and after its `PRINT`.
+    pub prolog: Option<String>,
+    pub epilog: Option<String>,
+}
+
+impl ResolvedSettings {
+    /// Works out the settings for printing pages like `header`, and logs
+    /// them.
+    pub fn resolve(
+        model: &Model,
+        ppd: &mut impl Settings,
+        job: &JobSettings,
+        header: &cups_page_header2_t,
+    ) -> Result<Self, Box<dyn Error>> {
+        let head = model.head(header)?;
+        let [x_dpi, y_dpi] = header.HWResolution;
+        if head.dpi != x_dpi || head.dpi != y_dpi {
+            eprintln!(
+                "WARNING: {}",
+                tr!(
+                    "{model} printers have no {x}x{y}dpi head, printing as {dpi}dpi",
+                    model = model.name,
+                    x = x_dpi,
+                    y = y_dpi,
+                    dpi = head.dpi,
+                )
+            );
+        }
+        let stock = match job.stock {
+            Some(stock) => Some(stock),
+            None => label_stock(ppd)?,
+        };
+        let media_tracking = media_tracking(ppd, job, api::header_str(&header.MediaType));
+        let (default_reference_x, default_reference_y) =
+            stock.map_or((0, 0), |stock| stock.reference_mm);
+        let speed = match (job.speed_setting, job.speed) {
+            (Some(s), _) if !head.speeds.contains(&s) => {
+                return Err(format!(
+                    "speed {s} is out of range, the {} prints at {} to {} in/s at {}dpi",
+                    model.name,
+                    head.speeds.start(),
+                    head.speeds.end(),
+                    head.dpi
+                )
+                .into());
+            }
+            (Some(s), _) => s,
+            (None, Some(s)) => ipp_speed(s),
+            (None, None) => ppd
+                .parse_default_marked_choice("zePrintRate")?
+                .unwrap_or(head.default_speed),
+        };
+        let settings = Self {
+            resolution: header.HWResolution,
+            media_type: api::header_str(&header.MediaType).to_string(),
+            stock,
+            media_tracking,
+            trim: matches!(media_tracking, MediaTracking::Continuous)
+                && ppd
+                    .marked_choice(c"ContinuousLength")
+                    .is_some_and(|choice| choice == c"Content"),
+            skip_blank: ppd
+                .marked_choice(c"SkipBlankPages")
+                .is_some_and(|choice| choice == c"True"),
+            threshold: job.site.threshold.unwrap_or(WHITE_THRESHOLD),
+            format: BitmapFormat {
+                bit_order: job.bit_order.unwrap_or(model.bitmap_format.bit_order),
+                polarity: job.polarity.unwrap_or(model.bitmap_format.polarity),
+            },
+            reference_mm: (
+                ppd.parse_default_marked_choice("AdjustHorizontal")?
+                    .unwrap_or(default_reference_x),
+                ppd.parse_default_marked_choice("AdjustVertical")?
+                    .unwrap_or(default_reference_y),
+            ),
+            rotate: ppd.parse_default_marked_choice("Rotate")?.unwrap_or(0),
+            // Some PPDs describe the gap with the page size's margins rather
+            // than an option of its own, and say so with an attribute.
+            gap_from_margins: ppd
+                .attribute(c"tspl-gap-from-margins")
+                .is_some_and(|value| value == c"True"),
+            gap_mm: match ppd.parse_default_marked_choice("GapOrMarkHeight")? {
+                Some(height) => Some(height),
+                None => stock.map(|stock| stock.gap_mm),
+            },
+            // This is signed: a negative offset is how TSPL expresses a mark
+            // that starts before the leading edge of the label.
+            gap_offset_mm: ppd
+                .parse_default_marked_choice("GapOrMarkOffset")?
+                .unwrap_or(0),
+            feed_offset_mm: ppd.parse_default_marked_choice("FeedOffset")?.unwrap_or(0),
+            density: density(ppd, job, head)?,
+            speed,
+            tear: media_preset(ppd).map(MediaPreset::tear),
+            cut: cut_mode(model, ppd),
+            peel: peel_mode(model, ppd),
+            autodotted: ppd
+                .parse_default_marked_choice::<i32>("Autodotted")?
+                .is_some_and(|autodotted| autodotted != 0),
+            prolog: raw_tspl(ppd, job.prolog.as_deref(), c"tspl-prolog")?,
+            epilog: raw_tspl(ppd, job.epilog.as_deref(), c"tspl-epilog")?,
+        };
+        eprintln!("DEBUG: settings at {}dpi: {settings}", head.dpi);
+        Ok(settings)
+    }
+}
+
+impl Display for ResolvedSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stock {}, {:?} tracking, density {}, speed {} in/s, reference {},{} mm, \
+             rotation {}, feed offset {} mm",
+            self.stock.map_or("none", |stock| stock.name),
+            self.media_tracking,
+            self.density,
+            self.speed,
+            self.reference_mm.0,
+            self.reference_mm.1,
+            self.rotate,
+            self.feed_offset_mm
+        )?;
+        if let Some(gap) = self.gap_mm {
+            write!(f, ", gap {gap} mm at {} mm", self.gap_offset_mm)?;
+        }
+        if let Some(cut) = self.cut {
+            write!(f, ", cut {cut:?}")?;
+        }
+        if self.peel == Some(true) {
+            write!(f, ", peeling")?;
+        }
+        if self.trim {
+            write!(f, ", trimmed to content")?;
+        }
+        if self.skip_blank {
+            write!(f, ", skipping blank pages")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks a page against the selected label stock, and works out what to
+/// print for it: the geometry of the page as it'll be printed, and how to
+/// shrink it, if it must be.
+///
+/// Without a stock, the label is the page's size, so there's nothing to
+/// check. Nor with a stored form, which decides what part of the page goes
+/// out.
+fn fit_page(
+    model: &Model,
+    settings: &ResolvedSettings,
+    job: &JobSettings,
+    header: &cups_page_header2_t,
+) -> Result<(cups_page_header2_t, Option<Scale>), Box<dyn Error>> {
+    let Some(stock) = settings.stock.filter(|_| job.form.is_none()) else {
+        return Ok((*header, None));
+    };
+    let dots_per_mm = model.head(header)?.dots_per_mm;
+    // Bitmap rows come in whole bytes, so the width has to leave room for
+    // the last one.
+    let label = [
+        stock.width_mm * dots_per_mm / 8 * 8,
+        stock.height_mm * dots_per_mm,
+    ];
+    let page = [header.cupsWidth, header.cupsHeight];
+    if page[0] <= label[0] && page[1] <= label[1] {
+        return Ok((*header, None));
+    }
+
+    let mut printed = *header;
+    match job.oversize.unwrap_or(Oversize::Clip) {
+        Oversize::Error => Err(format!(
+            "the page ({}x{} dots) is bigger than the {} label ({}x{} dots)",
+            page[0], page[1], stock.name, label[0], label[1]
+        )
+        .into()),
+        // Turning the page, if it would help, has been done already.
+        Oversize::Clip | Oversize::Rotate => {
+            printed.cupsWidth = page[0].min(label[0]);
+            printed.cupsHeight = page[1].min(label[1]);
+            Ok((printed, None))
+        }
+        Oversize::Scale => {
+            let scale = Scale::to_fit(page, label);
+            printed.cupsWidth = scale.width;
+            printed.cupsHeight = scale.height;
+            Ok((printed, Some(scale)))
+        }
+    }
+}
+
+/// Whether a page should be turned a quarter turn to fit, with
+/// `oversize=rotate`: it's wider than the label stock (or, without stock,
+/// the head), but its height would fit across.
+pub fn needs_quarter_turn(
+    model: &Model,
+    settings: &ResolvedSettings,
+    job: &JobSettings,
+    header: &cups_page_header2_t,
+) -> Result<bool, Box<dyn Error>> {
+    if job.oversize != Some(Oversize::Rotate) || job.form.is_some() {
+        return Ok(false);
+    }
+    let width_mm = match (settings.stock, model.print_width_mm) {
+        (Some(stock), _) => stock.width_mm,
+        (None, Some(width_mm)) => width_mm,
+        (None, None) => return Ok(false),
+    };
+    let width = width_mm * model.head(header)?.dots_per_mm / 8 * 8;
+    Ok(header.cupsWidth > width && header.cupsHeight <= width)
+}
+
+/// Returns a byte of raster data with no ink, in the page's colorspace.
+pub fn raster_blank(header: &cups_page_header2_t) -> u8 {
+    if header.cupsColorSpace == cups_cspace_e_CUPS_CSPACE_K {
+        0x00
+    } else {
+        0xFF
+    }
+}
+
+/// Turns a page a quarter turn anticlockwise, as CUPS turns landscape pages
+/// for printers (like ours) whose PPD says `LandscapeOrientation: Plus90`:
+/// the right edge becomes the top. Returns the header and rows of the
+/// turned page, which has no margins.
+pub fn quarter_turn(header: &cups_page_header2_t, pixels: &[u8]) -> (cups_page_header2_t, Vec<u8>) {
+    let (width, height) = (header.cupsWidth as usize, header.cupsHeight as usize);
+    let line = header.cupsBytesPerLine as usize;
+    let mut turned = *header;
+    turned.cupsWidth = header.cupsHeight;
+    turned.cupsHeight = header.cupsWidth;
+    turned.PageSize = [header.PageSize[1], header.PageSize[0]];
+    turned.cupsPageSize = [header.cupsPageSize[1], header.cupsPageSize[0]];
+    turned.HWResolution = [header.HWResolution[1], header.HWResolution[0]];
+    turned.ImagingBoundingBox = [0, 0, turned.PageSize[0], turned.PageSize[1]];
+    turned.cupsImagingBBox = [0.0, 0.0, turned.cupsPageSize[0], turned.cupsPageSize[1]];
+    turned.Margins = [0, 0];
+
+    let bits = header.cupsBitsPerPixel as usize;
+    turned.cupsBytesPerLine = (height * bits).div_ceil(8) as u32;
+    let turned_line = turned.cupsBytesPerLine as usize;
+    let mut out = vec![0; turned_line * width];
+    for (y, row) in out.chunks_mut(turned_line).enumerate() {
+        // Row y of the turned page is column width - 1 - y of the page, read
+        // top to bottom.
+        let x = width - 1 - y;
+        for (turned_x, source) in pixels.chunks(line).enumerate() {
+            if bits == 1 {
+                let bit = source[x / 8] >> (7 - x % 8) & 1;
+                row[turned_x / 8] |= bit << (7 - turned_x % 8);
+            } else {
+                let bytes = bits / 8;
+                row[turned_x * bytes..][..bytes].copy_from_slice(&source[x * bytes..][..bytes]);
+            }
+        }
+    }
+    (turned, out)
+}
+
+/// Where packed bitmap rows go as we produce them.
+pub enum PageSink {
+    /// Rows are sent to the printer as they're produced, the label having
+    /// already been described.
+    Stream {
+        /// Number of rows sent so far.
+        sent: u32,
+    },
+    /// Rows are held until the end of the page, because whether there's a
+    /// label at all, or how long it is, depends on what's in them. This is
+    /// used when the `SkipBlankPages` option is `True`, and on continuous
+    /// media when the `ContinuousLength` option is `Content`.
+    Buffer {
+        rows: Spool,
+        /// Number of rows up to and including the last one with any ink.
+        length: u32,
+        /// Whether the label ends there, rather than at the end of the page.
+        trim: bool,
+    },
+}
+
+/// Output state for the page being printed.
+pub struct PageState {
+    /// Where the black plane goes.
+    pub sink: PageSink,
+    /// Packed rows of the red plane, on two-color pages. These are always held
+    /// until the end of the page, because the printer wants the whole black
+    /// bitmap first.
+    pub red: Option<Spool>,
+    /// The part of the page we're sending.
+    pub region: Region,
+    /// Lightness, from 0 (black) to 255 (white), below which a pixel counts
+    /// as ink, whatever the colorspace.
+    pub threshold: u8,
+    /// How the printer reads the bitmap.
+    pub format: BitmapFormat,
+    /// The page as it's printed, which differs from the raster if it didn't
+    /// fit on the label.
+    pub header: cups_page_header2_t,
+    /// How to shrink the page to fit the label, if it must be.
+    pub scale: Option<Scale>,
+    /// Length of stock each label takes, in millimeters, once the label's
+    /// been described.
+    pub label_mm: u32,
+    /// A picture of the label, if the job is saving them.
+    pub preview: Option<Preview>,
+    pub stats: PageStats,
+}
+
+/// How many of a page's pixels came at each lightness, from 0 (black) to 255
+/// (white), before they were reduced to black and white.
+pub struct Histogram([u64; 256]);
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self([0; 256])
+    }
+}
+
+impl Histogram {
+    /// Counts a row of 8-bit pixels in colorspace `cs`. 1-bit rows have
+    /// nothing to say, and aren't counted.
+    fn add_row(&mut self, cs: cups_cspace_t, bits_per_color: u32, row: &[u8]) {
+        if bits_per_color != 8 {
+            return;
+        }
+        if is_rgb(cs) {
+            for rgb in row.chunks_exact(3) {
+                self.0[luminance(rgb) as usize] += 1;
+            }
+        } else if cs == cups_cspace_e_CUPS_CSPACE_K {
+            for &ink in row {
+                self.0[255 - ink as usize] += 1;
+            }
+        } else {
+            for &lightness in row {
+                self.0[lightness as usize] += 1;
+            }
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    /// The lightness that `percent` of the pixels are at or darker than.
+    fn percentile(&self, percent: u64) -> u8 {
+        let wanted = (self.total() * percent).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (lightness, &count) in self.0.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                return lightness as u8;
+            }
+        }
+        255
+    }
+
+    /// Sums up the page for the log, with how much of it prints at
+    /// `threshold`, or `None` if nothing was counted.
+    pub fn summary(&self, threshold: u8) -> Option<String> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        // Pixels print below the threshold, which is a lightness in every
+        // colorspace.
+        let inked: u64 = self.0[..usize::from(threshold)].iter().sum();
+        let [min, p5, p25, p50, p75, p95, max] =
+            [0, 5, 25, 50, 75, 95, 100].map(|percent| self.percentile(percent));
+        Some(format!(
+            "lightness (0 black, 255 white) min {min}, 5% {p5}, 25% {p25}, median {p50}, \
+             75% {p75}, 95% {p95}, max {max}; {:.1}% prints at threshold {threshold}",
+            100.0 * inked as f64 / total as f64
+        ))
+    }
+}
+
+/// What a page cost, for telling whether slow printing is down to us, the
+/// upstream filter, or the link to the printer.
+#[derive(Default)]
+pub struct PageStats {
+    /// Bitmap bytes sent to the printer, and their CRC-32, for matching up
+    /// what was printed with what we sent.
+    pub bytes: usize,
+    pub crc: Crc,
+    /// Time spent waiting for raster from upstream.
+    pub reading: Duration,
+    /// Time spent blocked sending bitmap data to the printer.
+    pub writing: Duration,
+    /// Labels printed, and the length of stock they took.
+    pub labels: u32,
+    pub stock_mm: u32,
+    /// How light the page's pixels were, for choosing a `threshold`.
+    pub lightness: Histogram,
+}
+
+impl PageStats {
+    /// Sends bitmap data to the printer, keeping count.
+    fn send(&mut self, out: &mut TsplWriter<impl Write>, data: &[u8]) -> std::io::Result<()> {
+        let start = Instant::now();
+        out.data(data)?;
+        self.writing += start.elapsed();
+        self.bytes += data.len();
+        self.crc.update(data);
+        Ok(())
+    }
+}
+
+/// Starts printing a page like `header`. The label is described straight
+/// away, unless what's on the page decides whether there is one, or how long
+/// it is.
+pub fn start_page(
+    out: &mut TsplWriter<impl Write>,
+    model: &Model,
+    settings: &ResolvedSettings,
+    job: &JobSettings,
+    header: &cups_page_header2_t,
+) -> Result<PageState, Box<dyn Error>> {
+    match model.family {
+        #[cfg(feature = "beeprt")]
+        Family::Beeprt => {
+            // From here on, we're concerned with the page as it'll be
+            // printed.
+            let (header, scale) = fit_page(model, settings, job, header)?;
+
+            let red = (is_rgb(header.cupsColorSpace) && model.red_plane_mode.is_some())
+                .then(|| Spool::new(SPILL_BYTES));
+
+            // With a stored form, the label is the form's size, not the
+            // content's, and only part of the page goes out.
+            let region = match &job.form {
+                Some(form) => form.region(&header),
+                None => Region::page(&header, header.cupsHeight),
+            };
+
+            let preview = job
+                .preview
+                .as_ref()
+                .map(|_| Preview::new(region.width_bytes as u32 * 8));
+
+            // A form prints even on a blank page, so these don't apply.
+            if job.form.is_none() && (settings.trim || settings.skip_blank) {
+                return Ok(PageState {
+                    sink: PageSink::Buffer {
+                        rows: Spool::new(SPILL_BYTES),
+                        length: 0,
+                        trim: settings.trim,
+                    },
+                    red,
+                    region,
+                    threshold: settings.threshold,
+                    format: settings.format,
+                    header,
+                    scale,
+                    label_mm: 0,
+                    preview,
+                    stats: PageStats::default(),
+                });
+            }
+
+            let label_mm = label_setup(out, model, settings, job, &header, header.cupsHeight)?;
+            Ok(PageState {
+                sink: PageSink::Stream { sent: 0 },
+                red,
+                region,
+                threshold: settings.threshold,
+                format: settings.format,
+                header,
+                scale,
+                label_mm,
+                preview,
+                stats: PageStats::default(),
+            })
+        }
+    }
+}
+
+/// Describes the label and starts its bitmap, which must be followed by
+/// exactly `height` packed rows.
+///
+/// This is normally `header.cupsHeight`, but may be shorter if we've trimmed
+/// the page.
+///
+/// Returns the length of stock each label takes, in millimeters, including
+/// the gap or mark that follows it.
+pub fn label_setup(
+    out: &mut TsplWriter<impl Write>,
+    model: &Model,
+    settings: &ResolvedSettings,
+    job: &JobSettings,
+    header: &cups_page_header2_t,
+    height: u32,
+) -> Result<u32, Box<dyn Error>> {
+    match model.family {
+        #[cfg(feature = "beeprt")]
+        Family::Beeprt => {
+            let head = model.head(header)?;
+            let dots_per_mm = head.dots_per_mm;
+
+            let stock = settings.stock;
+            let (width_mm, height_mm) = match stock {
+                // A trimmed page is the one case where the page, not the
+                // stock, decides the length.
+                Some(stock) if height == header.cupsHeight => (stock.width_mm, stock.height_mm),
+                Some(stock) => (stock.width_mm, height.div_ceil(dots_per_mm)),
+                None => units::label_size_mm(
+                    header.PageSize,
+                    [header.cupsWidth, header.cupsHeight],
+                    height,
+                    dots_per_mm,
+                ),
+            };
+            let (reference_x, reference_y) = settings.reference_mm;
+            let media_tracking = settings.media_tracking;
+
+            let gap_from_margins = settings.gap_from_margins;
+            let margins_gap = gap_from_margins
+                .then(|| units::margins_gap_mm(header.PageSize[1], header.ImagingBoundingBox))
+                .flatten();
+            // Some rasterizers send only the imageable area, which belongs
+            // inside the page's margins -- unless the margins are the gap.
+            let (offset_x, offset_y) = (!gap_from_margins)
+                .then(|| {
+                    units::imaging_offset_dots(
+                        header.PageSize,
+                        header.ImagingBoundingBox,
+                        [header.cupsWidth, header.cupsHeight],
+                        header.HWResolution,
+                    )
+                })
+                .flatten()
+                .unwrap_or((0, 0));
+            let gap_mark_height = settings.gap_mm.or(margins_gap).unwrap_or(3);
+            let gap_mark_offset = settings.gap_offset_mm;
+
+            let media = match media_tracking {
+                MediaTracking::Gap => format!("GAP {gap_mark_height} mm,{gap_mark_offset} mm"),
+                MediaTracking::BLine => {
+                    format!("BLINE {gap_mark_height} mm,{gap_mark_offset} mm")
+                }
+                // With nothing to find on the media, bound how far the
+                // printer will feed looking for it to a single label.
+                MediaTracking::Continuous => format!("GAP 0 mm,0 mm\nLIMITFEED {height_mm} mm"),
+            };
+            // What calibration depends on, for telling whether the stock's
+            // changed since the last.
+            let stock_setup = format!("SIZE {width_mm} mm,{height_mm} mm {media}");
+            let on_off = |on: bool| if on { "ON" } else { "OFF" };
+            let tear = settings
+                .tear
+                .map_or(String::new(), |tear| format!("SET TEAR {}", on_off(tear)));
+            let partial_cutter = model.cutter == Cutter::FullAndPartial;
+            let cutter = match settings.cut {
+                Some(CutMode::Off) if partial_cutter => "SET CUTTER OFF\nSET PARTIAL_CUTTER OFF",
+                Some(CutMode::Off) => "SET CUTTER OFF",
+                Some(CutMode::Full) if partial_cutter => "SET PARTIAL_CUTTER OFF\nSET CUTTER 1",
+                Some(CutMode::Full) => "SET CUTTER 1",
+                Some(CutMode::Partial) => "SET CUTTER OFF\nSET PARTIAL_CUTTER 1",
+                None => "",
+            };
+            let peel = settings
+                .peel
+                .map_or(String::new(), |peel| format!("SET PEEL {}", on_off(peel)));
+
+            send_template(
+                out,
+                model,
+                job,
+                model.setup_template,
+                &[
+                    ("width_mm", width_mm.to_string()),
+                    ("height_mm", height_mm.to_string()),
+                    (
+                        "reference_x",
+                        (units::mm_to_dots(reference_x, dots_per_mm) + offset_x as i32).to_string(),
+                    ),
+                    (
+                        "reference_y",
+                        (units::mm_to_dots(reference_y, dots_per_mm) + offset_y as i32).to_string(),
+                    ),
+                    ("rotation", settings.rotate.to_string()),
+                    ("media", media),
+                    ("gap_mm", gap_mark_height.to_string()),
+                    ("gap_offset_mm", gap_mark_offset.to_string()),
+                    ("feed_offset_mm", settings.feed_offset_mm.to_string()),
+                    ("tear", tear),
+                    ("cutter", cutter.to_string()),
+                    ("peel", peel),
+                    ("density", settings.density.to_string()),
+                    ("speed", model.speed_value(settings.speed).to_string()),
+                    ("autodotted", on_off(settings.autodotted).to_string()),
+                ],
+            )?;
+            if let Some(calibration) = &job.calibration {
+                calibration.first_label(out, media_tracking, &stock_setup)?;
+            }
+            send_raw(out, settings.prolog.as_deref())?;
+            out!(out, "CLS");
+
+            let region = match &job.form {
+                Some(form) => {
+                    out!(out, "PUTBMP 0,0,\"{}\"", form.name);
+                    form.region(header)
+                }
+                None => Region::page(header, height),
+            };
+            if job.border {
+                // Drawn first, so that the bitmap (ORed in) doesn't erase it.
+                out!(
+                    out,
+                    "BOX 0,0,{},{},1",
+                    width_mm * dots_per_mm - 1,
+                    height_mm * dots_per_mm - 1
+                );
+            }
+            if let Some(barcode) = job.test_page.and_then(|pattern| {
+                testpage::barcode(
+                    pattern,
+                    header.cupsWidth,
+                    header.cupsHeight,
+                    header.HWResolution[0],
+                )
+            }) {
+                // Likewise. The printer draws it, so the test page shows
+                // whether its barcodes scan.
+                out!(out, "{barcode}");
+            }
+            out.begin_data(format_args!(
+                "BITMAP {},{},{},{},1,",
+                region.x_bytes * 8,
+                region.y,
+                region.width_bytes,
+                region.height
+            ))?;
+
+            Ok(match media_tracking {
+                MediaTracking::Continuous => height_mm,
+                MediaTracking::Gap | MediaTracking::BLine => height_mm + gap_mark_height,
+            })
+        }
+    }
+}
+
+/// Sends the commands a model's template makes with `values`, leaving out
+/// blank lines, and `SETC` commands if the firmware doesn't take them.
+fn send_template(
+    out: &mut TsplWriter<impl Write>,
+    model: &Model,
+    job: &JobSettings,
+    template: &str,
+    values: &[(&str, String)],
+) -> Result<(), Box<dyn Error>> {
+    let strict = job.strict.unwrap_or(!model.setc_commands);
+    let commands = template::render(template, |name| {
+        values
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, value)| value.clone())
+    })
+    .map_err(|e| format!("bad command template: {e}"))?;
+    for command in commands.lines().map(str::trim).filter(|c| !c.is_empty()) {
+        let setc = command.split(' ').next() == Some("SETC");
+        if !(strict && setc) {
+            // The printer keeps settings from label to label, so unchanged
+            // ones are left out, which saves time on slow links.
+            out.setting(format_args!("{command}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Finds the raw TSPL given by a job option or, failing that, the PPD
+/// attribute `name`. This is an escape hatch for printer features we don't
+/// otherwise support, so it goes out as it is.
+fn raw_tspl(
+    ppd: &mut impl Settings,
+    job: Option<&str>,
+    name: &CStr,
+) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(match job {
+        Some(text) => Some(text.into()),
+        None => match ppd.attribute(name) {
+            Some(value) => Some(value.to_str()?.into()),
+            None => None,
+        },
+    })
+}
+
+/// Sends raw TSPL from [`raw_tspl`], a command per line.
+fn send_raw(out: &mut TsplWriter<impl Write>, text: Option<&str>) -> Result<(), Box<dyn Error>> {
+    for line in text.into_iter().flat_map(str::lines) {
+        out!(out, "{line}");
+    }
+    Ok(())
+}
+
+/// Packs a row of pixels into bits, most significant first, in the printer's
+/// polarity: a 0 bit prints, a 1 bit doesn't. Any bits past the end of the row
+/// in the last byte don't print.
+fn pack_row(inked: impl Iterator<Item = bool>) -> Vec<u8> {
+    let mut packed = vec![];
+    let mut byte = 0;
+    let mut bits = 0;
+    for ink in inked {
+        if ink {
+            byte |= 0x80 >> bits;
+        }
+        bits += 1;
+        if bits == 8 {
+            packed.push(!byte);
+            byte = 0;
+            bits = 0;
+        }
+    }
+    if bits != 0 {
+        packed.push(!byte);
+    }
+    packed
+}
+
+/// Converts a row of 1-bit K, packed with 1 for ink, to TSPL's packing, with 1
+/// for no ink, keeping any padding past `width` pixels white.
+fn invert_row(row: &[u8], width: usize) -> Vec<u8> {
+    let mut packed = row[..width.div_ceil(8)]
+        .iter()
+        .map(|&byte| !byte)
+        .collect::<Vec<_>>();
+    // Set the bits of the last byte past the end of the row, if it's partial.
+    if let (Some(last), used @ 1..) = (packed.last_mut(), width % 8) {
+        *last |= 0xFF >> used;
+    }
+    packed
+}
+
+/// Reads the `LabelStock` preset from the PPD, if one was chosen.
+pub fn label_stock(ppd: &mut impl Settings) -> Result<Option<&'static Stock>, Box<dyn Error>> {
+    match ppd.marked_choice(c"LabelStock") {
+        Some(choice) if choice != c"None" => Ok(Some(stock::lookup(choice.to_str()?)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Reduces row `y` of the page, as it came from the raster, to the printer's
+/// bitmap, and sends it on or holds it.
+pub fn output_line(
+    out: &mut TsplWriter<impl Write>,
+    model: &Model,
+    header: &cups_page_header2_t,
+    y: u32,
+    buffer: &[u8],
+    state: &mut PageState,
+) -> Result<(), Box<dyn Error>> {
+    state
+        .stats
+        .lightness
+        .add_row(header.cupsColorSpace, header.cupsBitsPerColor, buffer);
+    // Rows are numbered as they're printed from here on.
+    let y = match &state.scale {
+        Some(scale) => match scale.row_for(y) {
+            Some(y) => y,
+            None => return Ok(()),
+        },
+        None => y,
+    };
+    if !state.region.contains_row(y) {
+        return Ok(());
+    }
+    match model.family {
+        #[cfg(feature = "beeprt")]
+        Family::Beeprt => {
+            let cs = header.cupsColorSpace;
+            let threshold = state.threshold;
+            let (packed, red) = if is_rgb(cs) && state.red.is_some() {
+                // Dark pixels print black, and strongly red ones print red.
+                // Everything else (including other colors) is left blank.
+                let pixels = buffer.chunks_exact(3);
+                let black = pixels.clone().map(|rgb| rgb.iter().all(|&c| c < threshold));
+                let red = pixels
+                    .map(|rgb| rgb[0] >= threshold && rgb[1] < threshold && rgb[2] < threshold);
+                (pack_row(black), Some(pack_row(red)))
+            } else if is_rgb(cs) {
+                let black = buffer.chunks_exact(3).map(|rgb| luminance(rgb) < threshold);
+                (pack_row(black), None)
+            } else if header.cupsBitsPerColor == 1 {
+                // 1-bit K (as the PPD asks for, or PWG raster's `black_1`) is
+                // already packed, just with 1 for ink.
+                (invert_row(buffer, header.cupsWidth as usize), None)
+            } else if cs == cups_cspace_e_CUPS_CSPACE_K {
+                // Convert 8-bit grayscale to 1-bit black and white. K counts
+                // ink, so it's turned into lightness to compare.
+                let black = buffer.iter().map(|&byte| 255 - byte < threshold);
+                (pack_row(black), None)
+            } else {
+                // W and SW count lightness rather than ink.
+                let black = buffer.iter().map(|&byte| byte < threshold);
+                (pack_row(black), None)
+            };
+            let (packed, red) = match &state.scale {
+                Some(scale) => (scale.shrink(&packed), red.map(|red| scale.shrink(&red))),
+                None => (packed, red),
+            };
+            let mut packed = state.region.crop(&packed).to_vec();
+            let mut red = red.as_deref().map(|red| state.region.crop(red).to_vec());
+            let inked = packed
+                .iter()
+                .chain(red.iter().flatten())
+                .any(|&b| b != 0xFF);
+            if let Some(preview) = &mut state.preview {
+                preview.push_row(&packed, red.as_deref());
+            }
+            state.format.encode(&mut packed);
+            if let Some(red) = &mut red {
+                state.format.encode(red);
+            }
+
+            if let (Some(rows), Some(red)) = (&mut state.red, red) {
+                rows.append(&red)?;
+            }
+            match &mut state.sink {
+                PageSink::Stream { sent } => {
+                    state.stats.send(out, &packed)?;
+                    *sent += 1;
+                }
+                PageSink::Buffer { rows, length, .. } => {
+                    rows.append(&packed)?;
+                    if inked {
+                        *length = y + 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finishes the page's bitmap, padding it out if the page was cut short, and
+/// prints the label, returning what it took.
+pub fn end_page(
+    out: &mut TsplWriter<impl Write>,
+    model: &Model,
+    settings: &ResolvedSettings,
+    job: &JobSettings,
+    mut state: PageState,
+) -> Result<PageStats, Box<dyn Error>> {
+    match model.family {
+        #[cfg(feature = "beeprt")]
+        Family::Beeprt => {
+            let region = state.region;
+            let bytes_per_row = region.width_bytes;
+            let height = match state.sink {
+                PageSink::Stream { sent } => {
+                    // The BITMAP command promised the whole region, and
+                    // whatever follows would be taken for the rest of it.
+                    let missing = bytes_per_row * region.height.saturating_sub(sent) as usize;
+                    if missing != 0 {
+                        state
+                            .stats
+                            .send(out, &vec![state.format.blank(); missing])?;
+                    }
+                    region.height
+                }
+                PageSink::Buffer {
+                    mut rows,
+                    length,
+                    trim,
+                } => {
+                    if length == 0 {
+                        eprintln!(
+                            "INFO: {}",
+                            tr!("page is blank, not printing a label for it")
+                        );
+                        return Ok(state.stats);
+                    }
+                    // Trim the label to the last inked row, which is also the
+                    // last row the printer will feed, or else send it all, as
+                    // it would have been sent as it came.
+                    let length = if trim {
+                        state.label_mm =
+                            label_setup(out, model, settings, job, &state.header, length)?;
+                        length
+                    } else {
+                        let full = bytes_per_row * region.height as usize;
+                        rows.fill(state.format.blank(), full.saturating_sub(rows.len()))?;
+                        state.label_mm = label_setup(
+                            out,
+                            model,
+                            settings,
+                            job,
+                            &state.header,
+                            state.header.cupsHeight,
+                        )?;
+                        region.height
+                    };
+                    let stats = &mut state.stats;
+                    rows.send(bytes_per_row * length as usize, |data| {
+                        stats.send(out, data)
+                    })?;
+                    length
+                }
+            };
+            if let (Some(mut red), Some(mode)) = (state.red, model.red_plane_mode) {
+                // The red bitmap must cover the same area as the black one,
+                // even if the page was cut short.
+                let red_len = bytes_per_row * height as usize;
+                red.fill(state.format.blank(), red_len.saturating_sub(red.len()))?;
+                out.end_data()?;
+                out.begin_data(format_args!(
+                    "BITMAP {},{},{bytes_per_row},{height},{mode},",
+                    region.x_bytes * 8,
+                    region.y
+                ))?;
+                let stats = &mut state.stats;
+                red.send(red_len, |data| stats.send(out, data))?;
+            }
+            out.end_data()?;
+            if let (Some(preview), Some(files)) = (state.preview, &job.preview) {
+                files.save(preview, height);
+            }
+            eprintln!(
+                "DEBUG: bitmap CRC-32 {:08x} over {} bytes",
+                state.stats.crc.sum(),
+                state.stats.bytes
+            );
+            let print = if settings.peel == Some(true) {
+                // A PRINT of several labels would have the printer feed the
+                // rest while the first still waits to be taken, so the
+                // copies go out one command at a time.
+                vec!["PRINT 1,1"; job.copies as usize].join("\n")
+            } else {
+                model
+                    .print_counts(job.copies, job.site.max_print_count)
+                    .map(|count| format!("PRINT {count},1"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
+            send_template(
+                out,
+                model,
+                job,
+                model.teardown_template,
+                &[("print", print), ("copies", job.copies.to_string())],
+            )?;
+            send_raw(out, settings.epilog.as_deref())?;
+            state.stats.labels = job.copies;
+            state.stats.stock_mm = state.label_mm * job.copies;
+        }
+    }
+    Ok(state.stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model;
+    use crate::settings::MemorySettings;
+    use crate::tspl::LineEnding;
+
+    #[test]
+    fn partial_bytes_are_padded_white() {
+        assert_eq!(pack_row([true; 3].into_iter()), [0b0001_1111]);
+        assert_eq!(pack_row([true; 9].into_iter()), [0x00, 0b0111_1111]);
+    }
+
+    #[test]
+    fn one_bit_rows_are_inverted() {
+        // 1 for ink, with junk in the padding of the last byte.
+        let row = [0b1010_0000, 0b1111_1111];
+        for width in 1..=16 {
+            let black = (0..width).map(|x| row[x / 8] & (0x80 >> (x % 8)) != 0);
+            assert_eq!(invert_row(&row, width), pack_row(black), "width {width}");
+        }
+    }
+
+    #[test]
+    fn media_tracking_precedence() {
+        let mut ppd = MemorySettings::default();
+        let job = JobSettings::default();
+        assert!(matches!(
+            media_tracking(&mut ppd, &job, ""),
+            MediaTracking::Gap
+        ));
+        assert!(matches!(
+            media_tracking(&mut ppd, &job, "Tag-with-mark"),
+            MediaTracking::BLine
+        ));
+
+        let mut ppd = ppd.with(c"zeMediaTracking", c"Gap");
+        assert!(matches!(
+            media_tracking(&mut ppd, &job, "Tag-with-mark"),
+            MediaTracking::Gap
+        ));
+
+        let mut ppd = ppd.with(c"zeMediaTracking", c"BLine");
+        assert!(matches!(
+            media_tracking(&mut ppd, &job, ""),
+            MediaTracking::BLine
+        ));
+
+        let mut ppd = ppd.with(c"MediaType", c"Continuous");
+        assert!(matches!(
+            media_tracking(&mut ppd, &job, ""),
+            MediaTracking::Continuous
+        ));
+
+        let job = JobSettings {
+            media_tracking: Some(MediaTracking::Gap),
+            ..JobSettings::default()
+        };
+        assert!(matches!(
+            media_tracking(&mut ppd, &job, ""),
+            MediaTracking::Gap
+        ));
+    }
+
+    #[test]
+    fn printer_settings() {
+        let mut ppd = MemorySettings::default()
+            .with(c"HeadOpenSensor", c"Off")
+            .with(c"Encoder", c"PrinterDefault");
+        assert_eq!(printer_setting(&mut ppd, c"HeadOpenSensor"), Some(false));
+        assert_eq!(printer_setting(&mut ppd, c"Encoder"), None);
+        assert_eq!(printer_setting(&mut ppd, c"Missing"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "beeprt")]
+    fn cutting_and_peeling_are_gated_by_model() {
+        let model = |cutter| Model {
+            name: "Test",
+            numbers: 0..=0,
+            family: Family::Beeprt,
+            heads: &[],
+            red_plane_mode: None,
+            speed_units: model::SpeedUnits::InchesPerSecond,
+            cancel_command: None,
+            max_print_count: None,
+            print_width_mm: None,
+            cutter,
+            peeler: false,
+            line_ending: LineEnding::CrLf,
+            bitmap_format: BitmapFormat::default(),
+            display_command: None,
+            setc_commands: true,
+            setup_template: model::DEFAULT_SETUP_TEMPLATE,
+            teardown_template: model::DEFAULT_TEARDOWN_TEMPLATE,
+            usb_ids: &[],
+            ppd: "test.ppd",
+        };
+        let mut ppd = MemorySettings::default().with(c"Cutter", c"Partial");
+        assert_eq!(cut_mode(&model(Cutter::None), &mut ppd), None);
+        assert_eq!(cut_mode(&model(Cutter::Full), &mut ppd), None);
+        assert_eq!(
+            cut_mode(&model(Cutter::FullAndPartial), &mut ppd),
+            Some(CutMode::Partial)
+        );
+
+        let mut ppd = ppd.with(c"Cutter", c"Full");
+        assert_eq!(cut_mode(&model(Cutter::None), &mut ppd), None);
+        assert_eq!(
+            cut_mode(&model(Cutter::Full), &mut ppd),
+            Some(CutMode::Full)
+        );
+
+        let mut ppd = ppd.with(c"Cutter", c"Off");
+        assert_eq!(cut_mode(&model(Cutter::None), &mut ppd), None);
+        assert_eq!(cut_mode(&model(Cutter::Full), &mut ppd), Some(CutMode::Off));
+
+        let mut ppd = ppd.with(c"Cutter", c"PrinterDefault");
+        assert_eq!(cut_mode(&model(Cutter::FullAndPartial), &mut ppd), None);
+
+        let mut ppd = ppd.with(c"Peel", c"On");
+        assert_eq!(peel_mode(&model(Cutter::None), &mut ppd), None);
+        let peeler = Model {
+            peeler: true,
+            ..model(Cutter::None)
+        };
+        assert_eq!(peel_mode(&peeler, &mut ppd), Some(true));
+        let mut ppd = ppd.with(c"Peel", c"Off");
+        assert_eq!(peel_mode(&model(Cutter::None), &mut ppd), None);
+        assert_eq!(peel_mode(&peeler, &mut ppd), Some(false));
+    }
+
+    #[test]
+    fn quarter_turns() {
+        // Two rows of three gray pixels: a b c / d e f.
+        let mut header = cups_page_header2_t {
+            cupsWidth: 3,
+            cupsHeight: 2,
+            cupsBitsPerPixel: 8,
+            cupsBytesPerLine: 3,
+            PageSize: [30, 20],
+            ..unsafe { std::mem::zeroed() }
+        };
+        let (turned, pixels) = quarter_turn(&header, b"abcdef");
+        assert_eq!((turned.cupsWidth, turned.cupsHeight), (2, 3));
+        assert_eq!(turned.PageSize, [20, 30]);
+        assert_eq!(pixels, b"cfbead");
+
+        // Likewise in bits: 101 / 011.
+        header.cupsBitsPerPixel = 1;
+        header.cupsBytesPerLine = 1;
+        let (turned, pixels) = quarter_turn(&header, &[0b1010_0000, 0b0110_0000]);
+        assert_eq!(turned.cupsBytesPerLine, 1);
+        assert_eq!(pixels, [0b1100_0000, 0b0100_0000, 0b1000_0000]);
+    }
+
+    #[test]
+    fn lightness_histograms() {
+        let mut histogram = Histogram::default();
+        let row = (0..=99).map(|n| n * 2).collect::<Vec<u8>>();
+        histogram.add_row(cups_cspace_e_CUPS_CSPACE_W, 8, &row);
+        assert_eq!(histogram.percentile(0), 0);
+        assert_eq!(histogram.percentile(50), 98);
+        assert_eq!(histogram.percentile(100), 198);
+        let summary = histogram.summary(100).unwrap();
+        assert!(summary.contains("median 98"), "{summary}");
+        assert!(
+            summary.contains("50.0% prints at threshold 100"),
+            "{summary}"
+        );
+
+        // K counts ink.
+        let mut histogram = Histogram::default();
+        histogram.add_row(cups_cspace_e_CUPS_CSPACE_K, 8, &[255, 0, 0, 0]);
+        assert_eq!(histogram.percentile(25), 0);
+        let summary = histogram.summary(128).unwrap();
+        assert!(summary.contains("25.0% prints"), "{summary}");
+
+        histogram = Histogram::default();
+        histogram.add_row(cups_cspace_e_CUPS_CSPACE_K, 1, &[0xFF]);
+        assert_eq!(histogram.summary(128), None);
+    }
+
+    #[test]
+    fn scaling_to_fit() {
+        // Limited by height, to two thirds.
+        let scale = Scale::to_fit([30, 60], [40, 40]);
+        assert_eq!([scale.width, scale.height], [20, 40]);
+        // Every row maps to at most one, and the scaled ones are all covered.
+        let rows = (0..60).filter_map(|y| scale.row_for(y)).collect::<Vec<_>>();
+        assert_eq!(rows, (0..40).collect::<Vec<_>>());
+
+        // Limited by width, to half.
+        let scale = Scale::to_fit([32, 16], [16, 16]);
+        assert_eq!([scale.width, scale.height], [16, 8]);
+        assert_eq!(scale.row_for(4), Some(2));
+        assert_eq!(scale.row_for(5), None);
+        // Ink in the first and last four columns.
+        assert_eq!(scale.shrink(&[0x0F, 0xFF, 0xFF, 0xF0]), [0x3F, 0xFC]);
+    }
+
+    #[test]
+    fn label_stock_presets() {
+        let mut ppd = MemorySettings::default().with(c"LabelStock", c"None");
+        assert!(label_stock(&mut ppd).unwrap().is_none());
+
+        let mut ppd = ppd.with(c"LabelStock", c"4x6");
+        assert_eq!(label_stock(&mut ppd).unwrap().unwrap().name, "4x6");
+
+        let mut ppd = ppd.with(c"LabelStock", c"Bogus");
+        assert!(label_stock(&mut ppd).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Jobs that are already TSPL, from label design software that speaks it
+//! natively.
+//!
+//! These go to the printer as they are, after a look to make sure they're
+//! TSPL at all, and will print something. Binary data (in `BITMAP` and
+//! `DOWNLOAD`) makes TSPL awkward to parse properly, so the look is a quick
+//! one: commands are picked out wherever a line starts with them, which could
+//! be fooled by data that happens to look like a command.
+
+/// MIME type of TSPL jobs, as `tspl.types` declares it to CUPS.
+pub const MIME_TYPE: &str = "application/vnd.tspl";
+
+/// How much of a document [`looks_like_tspl`] needs to see.
+pub const SNIFF_BYTES: usize = 16;
+
+/// Commands we expect TSPL jobs to start with: setup, and the start of a
+/// label.
+const FIRST_COMMANDS: &[&[u8]] = &[
+    b"SIZE",
+    b"GAP",
+    b"BLINE",
+    b"OFFSET",
+    b"SPEED",
+    b"DENSITY",
+    b"DIRECTION",
+    b"REFERENCE",
+    b"SHIFT",
+    b"CODEPAGE",
+    b"SET",
+    b"CLS",
+    b"DOWNLOAD",
+    b"REM",
+];
+
+/// Checks whether the start of a document (at least [`SNIFF_BYTES`] of it,
+/// unless it's shorter) looks like TSPL, rather than raster.
+pub fn looks_like_tspl(start: &[u8]) -> bool {
+    let start = start.trim_ascii_start();
+    let name = start
+        .split(|&b| b == b' ' || b == b'\r' || b == b'\n')
+        .next()
+        .unwrap_or_default();
+    FIRST_COMMANDS.contains(&name)
+}
+
+/// What a TSPL job does for itself, so that we know what to add.
+#[derive(Default, Debug)]
+pub struct Scan {
+    /// Whether the job sets the label size (`SIZE`).
+    pub size: bool,
+    /// Whether the job sets the darkness (`DENSITY`).
+    pub density: bool,
+    /// Whether the job prints anything (`PRINT`).
+    pub prints: bool,
+    /// The start of the line being scanned, as far as it matters.
+    line: Vec<u8>,
+}
+
+impl Scan {
+    /// Looks through the next part of a job.
+    pub fn feed(&mut self, data: &[u8]) {
+        for &b in data {
+            if b == b'\n' {
+                self.end_line();
+            } else if self.line.len() < 9 {
+                // Long enough for the name of any command we look for.
+                self.line.push(b);
+            }
+        }
+    }
+
+    /// Finishes looking through a job.
+    pub fn finish(mut self) -> Self {
+        self.end_line();
+        self
+    }
+
+    /// Notes the command at the start of a line, and starts the next.
+    fn end_line(&mut self) {
+        let line = self.line.trim_ascii();
+        let is = |name: &[u8]| {
+            line.strip_prefix(name)
+                .is_some_and(|rest| rest.is_empty() || rest[0] == b' ')
+        };
+        self.size |= is(b"SIZE");
+        self.density |= is(b"DENSITY");
+        self.prints |= is(b"PRINT");
+        self.line.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffing() {
+        assert!(looks_like_tspl(b"SIZE 50 mm,30 mm\r\n"));
+        assert!(looks_like_tspl(b"\r\nCLS\r\n"));
+        assert!(!looks_like_tspl(b"RaS3\0\0\0\0"));
+        assert!(!looks_like_tspl(b"SIZES"));
+        assert!(!looks_like_tspl(b""));
+    }
+
+    #[test]
+    fn scanning() {
+        let mut scan = Scan::default();
+        scan.feed(b"SIZE 50 mm,30 mm\r\nCLS\r\nBITMAP 0,0,1,1,1,\n");
+        scan.feed(b"\r\nPRI");
+        scan.feed(b"NT 1,1");
+        let scan = scan.finish();
+        assert!(scan.size && scan.prints && !scan.density);
+
+        let mut scan = Scan::default();
+        scan.feed(b"DENSITY 8\r\nREM PRINT 1\r\nPRINTER\r\n");
+        let scan = scan.finish();
+        assert!(!scan.size && !scan.prints && scan.density);
+    }
+}
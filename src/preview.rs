@@ -0,0 +1,117 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Small PNG pictures of labels as they were sent to the printer, for keeping
+//! a record of what was printed.
+//!
+//! Rows come in as they're sent, packed eight dots to a byte with the most
+//! significant bit first and 0 for ink, as TSPL's `BITMAP` takes them. Each
+//! block of `SCALE` by `SCALE` dots becomes one pixel, shaded by how much of
+//! it is inked, so a 4x6 inch label at 203 dpi comes out about 200 by 300.
+
+use std::io::Write;
+
+/// Dots on a side of the block each pixel stands for.
+pub const SCALE: u32 = 4;
+
+pub struct Preview {
+    /// Width of the label, in dots.
+    width: u32,
+    /// Inked dots of each color in each pixel of the row of pixels being
+    /// gathered.
+    black: Vec<u32>,
+    red: Vec<u32>,
+    /// Rows of dots gathered so far.
+    rows: u32,
+    /// Finished RGB pixels.
+    pixels: Vec<u8>,
+}
+
+impl Preview {
+    pub fn new(width: u32) -> Self {
+        let columns = width.div_ceil(SCALE) as usize;
+        Self {
+            width,
+            black: vec![0; columns],
+            red: vec![0; columns],
+            rows: 0,
+            pixels: vec![],
+        }
+    }
+
+    /// Adds a row of the black plane, and of the red one on two-color labels.
+    pub fn push_row(&mut self, black: &[u8], red: Option<&[u8]>) {
+        for x in 0..self.width {
+            let inked = |row: &[u8]| {
+                row.get(x as usize / 8)
+                    .is_some_and(|byte| byte & (0x80 >> (x % 8)) == 0)
+            };
+            let column = (x / SCALE) as usize;
+            if inked(black) {
+                self.black[column] += 1;
+            } else if red.is_some_and(inked) {
+                self.red[column] += 1;
+            }
+        }
+        self.rows += 1;
+        if self.rows.is_multiple_of(SCALE) {
+            self.finish_row();
+        }
+    }
+
+    /// Turns the dots gathered into a row of pixels.
+    fn finish_row(&mut self) {
+        let dots = SCALE * SCALE;
+        for (black, red) in self.black.iter_mut().zip(&mut self.red) {
+            let shade = |ink: u32| (255 * (dots - ink.min(dots)) / dots) as u8;
+            let (r, gb) = (shade(*black), shade(*black + *red));
+            self.pixels.extend([r, gb, gb]);
+            (*black, *red) = (0, 0);
+        }
+    }
+
+    /// Writes the first `height` rows of dots as a PNG, with any not added
+    /// left blank.
+    pub fn write(mut self, out: impl Write, height: u32) -> Result<(), png::EncodingError> {
+        while self.rows < height.next_multiple_of(SCALE) {
+            self.push_row(&[], None);
+        }
+        let columns = self.width.div_ceil(SCALE);
+        let rows = height.div_ceil(SCALE);
+        let mut encoder = png::Encoder::new(out, columns, rows);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels[..(columns * rows * 3) as usize])?;
+        writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shading() {
+        // Eight dots across: the left half black, the right half red on the
+        // first two rows.
+        let mut preview = Preview::new(8);
+        for y in 0..SCALE {
+            let red = (y < 2).then_some(&[0b1111_0000][..]);
+            preview.push_row(&[0b0000_1111], red);
+        }
+        preview.push_row(&[0xFF], None);
+        assert_eq!(
+            preview.pixels,
+            [0, 0, 0, 255, 127, 127],
+            "one row, finished"
+        );
+
+        let mut png = vec![];
+        preview.write(&mut png, 6).unwrap();
+        let decoder = png::Decoder::new(&png[..]);
+        let reader = decoder.read_info().unwrap();
+        assert_eq!((reader.info().width, reader.info().height), (2, 2));
+    }
+}
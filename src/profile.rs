@@ -0,0 +1,347 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Printer profiles, for adding printers to the registry without a new
+//! release.
+//!
+//! Each `.toml` file in the profile directory describes one registry entry, in
+//! the same terms as the built-in ones in [`crate::model`]. The printer is
+//! driven by the code path of a family that's built in, so a profile suits a
+//! printer (often a clone) that speaks a dialect we know, with its own model
+//! numbers and quirks:
+//!
+//! ```toml
+//! name = "Acme LP-100"
+//! model-numbers = [40000, 40009]
+//! family = "beeprt"
+//! # In the profile directory, or one of the built-in PPDs.
+//! ppd = "acme-lp100.ppd"
+//! line-ending = "lf"
+//! bit-order = "lsb"
+//! cutter = "full"
+//! speed-codes = [[2, 0], [3, 1], [4, 2]]
+//! display-command = "DISPLAY \"{text}\""
+//! usb-ids = [[0x1234, 0x5678]]
+//!
+//! [[heads]]
+//! dpi = 203
+//! default-speed = 4
+//! default-darkness = 8
+//! ```
+//!
+//! Anything left out takes the value TSPL calls for, or "none". Printers that
+//! want their setup commands in another order, or different ones, can have
+//! `setup-template` and `teardown-template` in place of the registry's
+//! defaults; see [`crate::model::DEFAULT_SETUP_TEMPLATE`] and
+//! [`crate::model::SETUP_PLACEHOLDERS`].
+
+use std::{
+    error::Error,
+    ffi::c_int,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::model::{
+    Cutter, DEFAULT_SETUP_TEMPLATE, DEFAULT_TEARDOWN_TEMPLATE, HeadProfile, LINEAR_DARKNESS, Model,
+    Registration, SETUP_PLACEHOLDERS, SpeedUnits, TEARDOWN_PLACEHOLDERS,
+};
+use crate::template;
+use crate::tspl::BitmapFormat;
+
+/// Where profiles live, unless `TSPL_FILTER_MODELS` says otherwise.
+pub const DIR: &str = "/usr/share/tspl-filter/models";
+
+/// Works out the profile directory, from `TSPL_FILTER_MODELS` or [`DIR`].
+pub fn dir() -> PathBuf {
+    std::env::var_os("TSPL_FILTER_MODELS").map_or(DIR.into(), PathBuf::from)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Profile {
+    name: String,
+    /// The first and last `cupsModelNumber` covered.
+    model_numbers: [c_int; 2],
+    family: String,
+    ppd: String,
+    heads: Vec<Head>,
+    red_plane_mode: Option<u32>,
+    /// Pairs of speeds in inches per second and their codes, for firmwares
+    /// that take codes.
+    speed_codes: Option<Vec<(u32, u32)>>,
+    cancel_command: Option<String>,
+    max_print_count: Option<u32>,
+    print_width_mm: Option<u32>,
+    cutter: Option<String>,
+    peeler: Option<bool>,
+    line_ending: Option<String>,
+    bit_order: Option<String>,
+    polarity: Option<String>,
+    display_command: Option<String>,
+    setc_commands: Option<bool>,
+    setup_template: Option<String>,
+    teardown_template: Option<String>,
+    #[serde(default)]
+    usb_ids: Vec<(u16, u16)>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Head {
+    dpi: u32,
+    /// Worked out from `dpi` if left out.
+    dots_per_mm: Option<u32>,
+    default_speed: u32,
+    /// The slowest and fastest speeds, in inches per second.
+    speeds: Option<[u32; 2]>,
+    default_darkness: i32,
+    darkness_curve: Option<[i32; 16]>,
+}
+
+/// Reads every profile in `dir`, which needn't exist, in order of file name.
+///
+/// The entries last as long as the process, as the registry's do.
+pub fn load(dir: &Path) -> Result<Registration, Box<dyn Error>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Registration {
+                models: &[],
+                ppds: &[],
+            });
+        }
+        Err(e) => return Err(format!("can't list {}: {e}", dir.display()).into()),
+    };
+    let mut paths = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "toml"));
+    paths.sort();
+
+    let mut models = vec![];
+    let mut ppds = vec![];
+    for path in paths {
+        let text =
+            fs::read_to_string(&path).map_err(|e| format!("can't read {}: {e}", path.display()))?;
+        let model =
+            parse(&text).map_err(|e| format!("bad printer profile {}: {e}", path.display()))?;
+        let ppd_path = dir.join(model.ppd);
+        match fs::read(&ppd_path) {
+            Ok(contents) => ppds.push((model.ppd, &*Vec::leak(contents))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if model.ppd_contents().is_none() {
+                    return Err(format!(
+                        "bad printer profile {}: PPD {} is neither there nor built in",
+                        path.display(),
+                        model.ppd
+                    )
+                    .into());
+                }
+            }
+            Err(e) => return Err(format!("can't read {}: {e}", ppd_path.display()).into()),
+        }
+        models.push(model);
+    }
+    Ok(Registration {
+        models: models.leak(),
+        ppds: ppds.leak(),
+    })
+}
+
+/// Parses a profile into a registry entry.
+fn parse(text: &str) -> Result<Model, Box<dyn Error>> {
+    let profile: Profile = toml::from_str(text)?;
+    let [first, last] = profile.model_numbers;
+    if first > last {
+        return Err(format!("model numbers {first} to {last} are backwards").into());
+    }
+    if profile.heads.is_empty() {
+        return Err("no heads are listed".into());
+    }
+    // Without a range, the speed codes are as good as one.
+    let coded_speeds = profile.speed_codes.as_ref().and_then(|codes| {
+        let speeds = || codes.iter().map(|&(speed, _)| speed);
+        Some([speeds().min()?, speeds().max()?])
+    });
+    let mut heads = vec![];
+    for head in profile.heads {
+        let curve = head.darkness_curve.unwrap_or(*LINEAR_DARKNESS);
+        if head.dpi == 0 || curve.iter().any(|step| !(0..=15).contains(step)) {
+            return Err(format!("bad head profile for {}dpi", head.dpi).into());
+        }
+        heads.push(HeadProfile {
+            dpi: head.dpi,
+            dots_per_mm: head
+                .dots_per_mm
+                .unwrap_or((f64::from(head.dpi) / 25.4).round() as u32),
+            default_speed: head.default_speed,
+            speeds: match head.speeds.or(coded_speeds) {
+                Some([slowest, fastest]) => slowest..=fastest,
+                None => 1..=u32::MAX,
+            },
+            default_darkness: head.default_darkness.clamp(0, 15),
+            darkness_curve: Box::leak(Box::new(curve)),
+        });
+    }
+    let display_command = profile.display_command.as_deref();
+    if display_command.is_some_and(|command| !command.contains("{text}")) {
+        return Err("the display command has no {text}".into());
+    }
+    let setup_template = checked_template(
+        profile.setup_template,
+        DEFAULT_SETUP_TEMPLATE,
+        SETUP_PLACEHOLDERS,
+    )?;
+    let teardown_template = checked_template(
+        profile.teardown_template,
+        DEFAULT_TEARDOWN_TEMPLATE,
+        TEARDOWN_PLACEHOLDERS,
+    )?;
+    let leak = |s: String| &*s.leak();
+    Ok(Model {
+        name: leak(profile.name),
+        numbers: first..=last,
+        family: profile.family.parse()?,
+        heads: heads.leak(),
+        red_plane_mode: profile.red_plane_mode,
+        speed_units: match profile.speed_codes {
+            Some(codes) => SpeedUnits::Codes(codes.leak()),
+            None => SpeedUnits::InchesPerSecond,
+        },
+        cancel_command: profile.cancel_command.map(leak),
+        max_print_count: profile.max_print_count,
+        print_width_mm: profile.print_width_mm,
+        cutter: match profile.cutter.as_deref() {
+            None | Some("none") => Cutter::None,
+            Some("full") => Cutter::Full,
+            Some("partial") => Cutter::FullAndPartial,
+            Some(other) => return Err(format!("unknown cutter {other:?}").into()),
+        },
+        peeler: profile.peeler.unwrap_or(false),
+        line_ending: profile.line_ending.as_deref().unwrap_or("crlf").parse()?,
+        bitmap_format: BitmapFormat {
+            bit_order: profile.bit_order.as_deref().unwrap_or("msb").parse()?,
+            polarity: profile.polarity.as_deref().unwrap_or("normal").parse()?,
+        },
+        display_command: profile.display_command.map(leak),
+        // Profiles are for printers that speak the dialect of a family we
+        // know, which for now means Beeprt's.
+        setc_commands: profile.setc_commands.unwrap_or(true),
+        setup_template,
+        teardown_template,
+        usb_ids: profile.usb_ids.leak(),
+        ppd: leak(profile.ppd),
+    })
+}
+
+/// Checks a command template from a profile, or takes the default if there
+/// isn't one.
+fn checked_template(
+    template: Option<String>,
+    default: &'static str,
+    names: &[&str],
+) -> Result<&'static str, String> {
+    let Some(template) = template else {
+        return Ok(default);
+    };
+    template::check(&template, names)?;
+    Ok(template.leak())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Family;
+    use crate::tspl::{BitOrder, LineEnding, Polarity};
+
+    const EXAMPLE: &str = r#"
+        name = "Acme LP-100"
+        model-numbers = [40000, 40009]
+        family = "beeprt"
+        ppd = "acme-lp100.ppd"
+        line-ending = "lf"
+        bit-order = "lsb"
+        print-width-mm = 104
+        cutter = "full"
+        peeler = true
+        speed-codes = [[2, 0], [3, 1], [4, 2]]
+        display-command = "DISPLAY \"{text}\""
+        usb-ids = [[0x1234, 0x5678]]
+
+        [[heads]]
+        dpi = 300
+        default-speed = 4
+        default-darkness = 8
+    "#;
+
+    #[test]
+    #[cfg(feature = "beeprt")]
+    fn profiles_become_registry_entries() {
+        let model = parse(EXAMPLE).unwrap();
+        assert_eq!(model.name, "Acme LP-100");
+        assert_eq!(model.numbers, 40000..=40009);
+        assert_eq!(model.family, Family::Beeprt);
+        assert_eq!(model.heads[0].dots_per_mm, 12);
+        assert_eq!(model.speed_value(3), 1);
+        assert_eq!(model.heads[0].speeds, 2..=4);
+        assert_eq!(model.print_width_mm, Some(104));
+        assert_eq!(model.cutter, Cutter::Full);
+        assert!(model.peeler);
+        assert_eq!(model.line_ending, LineEnding::Lf);
+        assert_eq!(model.bitmap_format.bit_order, BitOrder::LsbFirst);
+        assert_eq!(model.bitmap_format.polarity, Polarity::Normal);
+        assert_eq!(model.usb_ids, [(0x1234, 0x5678)]);
+        assert_eq!(model.cancel_command, None);
+        assert!(model.setc_commands);
+        let strict = parse(&EXAMPLE.replacen("ppd =", "setc-commands = false\nppd =", 1));
+        assert!(!strict.unwrap().setc_commands);
+    }
+
+    #[test]
+    #[cfg(feature = "beeprt")]
+    fn bad_profiles_are_rejected() {
+        for (from, to) in [
+            ("[40000, 40009]", "[40009, 40000]"),
+            ("\"beeprt\"", "\"zebra\""),
+            ("\"full\"", "\"sometimes\""),
+            ("{text}", "text"),
+            ("dpi = 300", "dpi = 300\ncolor = true"),
+        ] {
+            let text = EXAMPLE.replace(from, to);
+            assert!(parse(&text).is_err(), "{to}");
+        }
+        let headless = &EXAMPLE[..EXAMPLE.find("[[heads]]").unwrap()];
+        assert!(parse(headless).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "beeprt")]
+    fn templates_are_checked() {
+        let model = parse(EXAMPLE).unwrap();
+        assert_eq!(model.setup_template, DEFAULT_SETUP_TEMPLATE);
+
+        let with_template = |key: &str, template: &str| {
+            let line = format!("{key} = \"{template}\"\n");
+            parse(&EXAMPLE.replacen("ppd =", &(line + "ppd ="), 1))
+        };
+        let model =
+            with_template("setup-template", "DENSITY {density}\\nSIZE {width_mm} mm").unwrap();
+        assert_eq!(
+            model.setup_template,
+            "DENSITY {density}\nSIZE {width_mm} mm"
+        );
+        assert!(with_template("setup-template", "SIZE {depth_mm} mm").is_err());
+        assert!(with_template("teardown-template", "{print}\\nEOP").is_ok());
+        assert!(with_template("teardown-template", "PRINT {density}").is_err());
+    }
+
+    #[test]
+    fn missing_directory_is_empty() {
+        let registration = load(Path::new("/nonexistent/tspl-filter/models")).unwrap();
+        assert!(registration.models.is_empty());
+    }
+}
@@ -0,0 +1,323 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Access to the printer settings chosen for a job.
+//!
+//! These normally come from the marked choices in a PPD, but going through a
+//! trait lets the logic that interprets them run against settings held in
+//! memory, without libcups or a PPD file.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    ffi::{CStr, CString},
+    str::FromStr,
+};
+
+use crate::api::{Keyword, PpdFile};
+
+pub trait Settings {
+    /// Returns the choice marked for `keyword`, if there is one.
+    fn marked_choice(&mut self, keyword: &CStr) -> Option<&CStr>;
+
+    /// Returns the width and length of the selected page size, in points.
+    fn page_size(&mut self) -> Option<(f32, f32)>;
+
+    /// Returns the value of the PPD attribute `name`, for settings that are
+    /// fixed for the queue rather than chosen.
+    fn attribute(&mut self, name: &CStr) -> Option<&CStr>;
+
+    /// Returns the value entered for `keyword`, as text, when the marked
+    /// choice is `Custom`. Settings that don't allow custom values have none.
+    fn custom_value(&mut self, _keyword: &CStr) -> Option<String> {
+        None
+    }
+
+    /// Finds the choice marked for `keyword` and parses it into a `T`, unless
+    /// it is the exact string `"Default"`. For a `Custom` choice, it's the
+    /// value entered that's parsed.
+    ///
+    /// The keyword can be given as a `str`, e.g. `"Darkness"`, as well as a
+    /// `CStr`.
+    fn parse_default_marked_choice<T>(
+        &mut self,
+        keyword: &(impl Keyword + ?Sized),
+    ) -> Result<Option<T>, Box<dyn Error>>
+    where
+        T: FromStr,
+        T::Err: Error + 'static,
+    {
+        let Some(keyword) = keyword.as_cstr() else {
+            return Ok(None);
+        };
+        match self.marked_choice(keyword) {
+            Some(choice) if choice == c"Custom" => match self.custom_value(keyword) {
+                Some(value) => Ok(Some(value.parse()?)),
+                None => Ok(None),
+            },
+            Some(choice) if choice != c"Default" => Ok(Some(choice.to_str()?.parse()?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Spellings of the same keyword found in different vendors' PPDs. The first
+/// of each is the one the filter asks for.
+const SYNONYMS: &[&[&CStr]] = &[
+    // The misspelling is claimed in one of the open-source drivers to appear
+    // in a vendor PPD, though not in any we've seen.
+    &[c"AdjustHorizontal", c"AdjustHoriaontal"],
+    &[c"AdjustVertical", c"ShiftMove"],
+    &[c"Darkness", c"PrintDarkness"],
+    // Misspelled in the iDPRT PPD, which `ppd/` has.
+    &[c"FeedOffset", c"FowardOffset"],
+    &[c"zeMediaTracking", c"MediaTracking", c"PaperType"],
+    &[c"zePrintRate", c"PrintRate", c"PrintSpeed"],
+];
+
+/// Choices that a vendor spelling of a keyword names differently, as the
+/// vendor's keyword and choice and the filter's choice. `None` is for
+/// choices that leave the setting to the printer, which the filter takes as
+/// no choice at all.
+const CHOICE_SYNONYMS: &[(&CStr, &CStr, Option<&CStr>)] = &[
+    (c"PrintDarkness", c"None", None),
+    (c"FowardOffset", c"None", None),
+    (c"PaperType", c"None", None),
+    (c"PaperType", c"1", Some(c"Gap")),
+    (c"PaperType", c"2", Some(c"BLine")),
+    (c"PaperType", c"3", Some(c"Continuous")),
+    (c"PrintSpeed", c"None", None),
+    (c"ShiftMove", c"None", None),
+];
+
+/// Lists the ways `keyword` might be spelled in a PPD, starting with the
+/// usual one.
+pub fn spellings(keyword: &CStr) -> Vec<&CStr> {
+    match SYNONYMS.iter().find(|group| group.contains(&keyword)) {
+        Some(group) => group.to_vec(),
+        None => vec![keyword],
+    }
+}
+
+/// Translates `choice`, as marked for the vendor spelling `keyword`, into the
+/// one the filter expects.
+fn translate_choice<'c>(keyword: &CStr, choice: &'c CStr) -> Option<&'c CStr> {
+    match CHOICE_SYNONYMS
+        .iter()
+        .find(|&&(k, c, _)| k == keyword && c == choice)
+    {
+        Some(&(_, _, ours)) => ours,
+        None => Some(choice),
+    }
+}
+
+impl PpdFile {
+    /// Works out how the PPD spells `keyword`, if it has it at all.
+    fn spelling<'k>(&mut self, keyword: &'k CStr) -> &'k CStr {
+        spellings(keyword)
+            .into_iter()
+            .find(|&k| self.find_marked_choice(k).is_some())
+            .unwrap_or(keyword)
+    }
+}
+
+impl Settings for PpdFile {
+    fn marked_choice(&mut self, keyword: &CStr) -> Option<&CStr> {
+        let keyword = self.spelling(keyword);
+        let choice = self.find_marked_choice(keyword)?.choice();
+        translate_choice(keyword, choice)
+    }
+
+    fn page_size(&mut self) -> Option<(f32, f32)> {
+        PpdFile::page_size(self)
+    }
+
+    fn attribute(&mut self, name: &CStr) -> Option<&CStr> {
+        self.find_attr(name)
+    }
+
+    fn custom_value(&mut self, keyword: &CStr) -> Option<String> {
+        let keyword = self.spelling(keyword);
+        PpdFile::custom_value(self, keyword)
+    }
+}
+
+/// Settings held in memory, for overriding others and for testing.
+#[derive(Default)]
+pub struct MemorySettings {
+    choices: HashMap<CString, CString>,
+    page_size: Option<(f32, f32)>,
+    attributes: HashMap<CString, CString>,
+}
+
+impl MemorySettings {
+    /// Marks `choice` for `keyword`.
+    pub fn with(mut self, keyword: &CStr, choice: &CStr) -> Self {
+        self.choices.insert(keyword.into(), choice.into());
+        self
+    }
+
+    /// Selects a page size, in points.
+    pub fn with_page_size(mut self, width: f32, length: f32) -> Self {
+        self.page_size = Some((width, length));
+        self
+    }
+
+    /// Sets the attribute `name` to `value`.
+    pub fn with_attribute(mut self, name: &CStr, value: &CStr) -> Self {
+        self.attributes.insert(name.into(), value.into());
+        self
+    }
+}
+
+impl Settings for MemorySettings {
+    fn marked_choice(&mut self, keyword: &CStr) -> Option<&CStr> {
+        self.choices.get(keyword).map(CString::as_c_str)
+    }
+
+    fn page_size(&mut self) -> Option<(f32, f32)> {
+        self.page_size
+    }
+
+    fn attribute(&mut self, name: &CStr) -> Option<&CStr> {
+        self.attributes.get(name).map(CString::as_c_str)
+    }
+}
+
+/// Settings that may not be there at all, such as a PPD when running without
+/// one.
+impl<S: Settings> Settings for Option<S> {
+    fn marked_choice(&mut self, keyword: &CStr) -> Option<&CStr> {
+        self.as_mut()?.marked_choice(keyword)
+    }
+
+    fn page_size(&mut self) -> Option<(f32, f32)> {
+        self.as_mut()?.page_size()
+    }
+
+    fn attribute(&mut self, name: &CStr) -> Option<&CStr> {
+        self.as_mut()?.attribute(name)
+    }
+
+    fn custom_value(&mut self, keyword: &CStr) -> Option<String> {
+        self.as_mut()?.custom_value(keyword)
+    }
+}
+
+/// Two sets of settings, with choices from the first taking precedence.
+pub struct Layered<A, B>(pub A, pub B);
+
+impl<A: Settings, B: Settings> Settings for Layered<A, B> {
+    fn marked_choice(&mut self, keyword: &CStr) -> Option<&CStr> {
+        self.0
+            .marked_choice(keyword)
+            .or_else(|| self.1.marked_choice(keyword))
+    }
+
+    fn page_size(&mut self) -> Option<(f32, f32)> {
+        self.0.page_size().or_else(|| self.1.page_size())
+    }
+
+    fn attribute(&mut self, name: &CStr) -> Option<&CStr> {
+        self.0.attribute(name).or_else(|| self.1.attribute(name))
+    }
+
+    fn custom_value(&mut self, keyword: &CStr) -> Option<String> {
+        // The value goes with whichever choice won.
+        if self.0.marked_choice(keyword).is_some() {
+            self.0.custom_value(keyword)
+        } else {
+            self.1.custom_value(keyword)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_no_value() {
+        let mut settings = MemorySettings::default().with(c"Darkness", c"Default");
+        let darkness: Option<i32> = settings.parse_default_marked_choice(c"Darkness").unwrap();
+        assert_eq!(darkness, None);
+    }
+
+    #[test]
+    fn parses_choices() {
+        let mut settings = MemorySettings::default()
+            .with(c"Darkness", c"12")
+            .with(c"GapOrMarkOffset", c"-2");
+        assert_eq!(
+            settings
+                .parse_default_marked_choice::<i32>(c"Darkness")
+                .unwrap(),
+            Some(12)
+        );
+        assert_eq!(
+            settings
+                .parse_default_marked_choice::<i32>(c"GapOrMarkOffset")
+                .unwrap(),
+            Some(-2)
+        );
+        assert_eq!(
+            settings
+                .parse_default_marked_choice::<i32>(c"Rotate")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn keywords_as_str() {
+        let mut settings = MemorySettings::default().with(c"Darkness", c"12");
+        assert_eq!(
+            settings
+                .parse_default_marked_choice::<i32>("Darkness")
+                .unwrap(),
+            Some(12)
+        );
+        // Again, from the cache.
+        assert_eq!(
+            settings
+                .parse_default_marked_choice::<i32>("Darkness")
+                .unwrap(),
+            Some(12)
+        );
+        assert_eq!(
+            settings
+                .parse_default_marked_choice::<i32>("Dark\0ness")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn synonyms() {
+        assert_eq!(
+            spellings(c"AdjustHoriaontal"),
+            [c"AdjustHorizontal", c"AdjustHoriaontal"]
+        );
+        assert_eq!(spellings(c"Rotate"), [c"Rotate"]);
+    }
+
+    #[test]
+    fn vendor_choices() {
+        assert_eq!(translate_choice(c"PaperType", c"2"), Some(c"BLine"));
+        assert_eq!(translate_choice(c"PrintDarkness", c"None"), None);
+        assert_eq!(translate_choice(c"PrintDarkness", c"7"), Some(c"7"));
+        // Only the vendor's spelling has vendor choices.
+        assert_eq!(translate_choice(c"zeMediaTracking", c"2"), Some(c"2"));
+    }
+
+    #[test]
+    fn bad_choices_are_errors() {
+        let mut settings = MemorySettings::default().with(c"Darkness", c"dark");
+        assert!(
+            settings
+                .parse_default_marked_choice::<i32>(c"Darkness")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,232 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Holding bitmap data that can't be sent yet.
+//!
+//! Most of a page goes to the printer as it's converted, but some can't: the
+//! rows of a label whose length depends on what's in them, and the red plane
+//! of a two-color label, which has to follow the black one. On continuous
+//! stock a page can be a meter or more long, so a [`Spool`] keeps only so much
+//! in memory, and spills the rest to a temporary file.
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    os::unix::fs::OpenOptionsExt,
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// How much a [`Spool`] holds in memory before spilling to a file, by
+/// default. This is a few labels' worth of 4 inch stock at 300 dpi.
+pub const SPILL_BYTES: usize = 1 << 20;
+
+/// Bytes saved up to be sent later.
+pub struct Spool {
+    /// Most to hold in `memory`.
+    limit: usize,
+    /// What's been saved, until it outgrows `limit`.
+    memory: Vec<u8>,
+    /// Where everything's saved once it outgrew `limit`.
+    file: Option<BufWriter<File>>,
+    len: usize,
+}
+
+impl Spool {
+    /// Makes an empty spool that holds up to `limit` bytes in memory.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            memory: vec![],
+            file: None,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of bytes saved.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks whether nothing's been saved.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Checks whether the spool has outgrown memory.
+    pub fn is_spilled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Saves `data` after what's already saved.
+    pub fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.file.is_none() && self.memory.len() + data.len() > self.limit {
+            let mut file = BufWriter::new(temp_file()?);
+            file.write_all(&self.memory)?;
+            self.memory = vec![];
+            self.file = Some(file);
+        }
+        match &mut self.file {
+            Some(file) => file.write_all(data)?,
+            None => self.memory.extend_from_slice(data),
+        }
+        self.len += data.len();
+        Ok(())
+    }
+
+    /// Saves `len` copies of `byte`.
+    pub fn fill(&mut self, byte: u8, len: usize) -> io::Result<()> {
+        let chunk = [byte; 4096];
+        let mut left = len;
+        while left != 0 {
+            let n = left.min(chunk.len());
+            self.append(&chunk[..n])?;
+            left -= n;
+        }
+        Ok(())
+    }
+
+    /// Hands the first `len` bytes saved to `sink`, in order, in as many
+    /// pieces as it takes. They can be sent again.
+    ///
+    /// Panics if fewer than `len` bytes have been saved.
+    pub fn send(
+        &mut self,
+        len: usize,
+        sink: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.send_range(0, len, sink)
+    }
+
+    /// Hands `len` bytes saved, starting `start` bytes in, to `sink`, like
+    /// [`send`](Self::send).
+    ///
+    /// Panics if fewer than `start + len` bytes have been saved.
+    pub fn send_range(
+        &mut self,
+        start: usize,
+        len: usize,
+        mut sink: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        assert!(start + len <= self.len, "sending more than was saved");
+        let Some(file) = &mut self.file else {
+            return sink(&self.memory[start..start + len]);
+        };
+        file.flush()?;
+        let file = file.get_mut();
+        let mut copy = || {
+            file.seek(SeekFrom::Start(start as u64))?;
+            let mut part = (&mut *file).take(len as u64);
+            let mut chunk = vec![0; 64 << 10];
+            loop {
+                let n = part.read(&mut chunk)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                sink(&chunk[..n])?;
+            }
+        };
+        let sent = copy();
+        // Whatever happened, the next append has to go on the end rather than
+        // over what's saved.
+        let end = file.seek(SeekFrom::End(0));
+        sent.and(end.map(drop))
+    }
+}
+
+/// Opens a new file in the temporary directory (which CUPS sets for filters),
+/// readable only by us.
+///
+/// The file is unlinked straight away, so that it goes away when we close it,
+/// however we exit.
+fn temp_file() -> io::Result<File> {
+    static COUNT: AtomicU32 = AtomicU32::new(0);
+    let path = env::temp_dir().join(format!(
+        "raster-tspl-{}-{}",
+        process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(spool: &mut Spool, len: usize) -> Vec<u8> {
+        let mut sent = vec![];
+        spool
+            .send(len, |data| {
+                sent.extend_from_slice(data);
+                Ok(())
+            })
+            .unwrap();
+        sent
+    }
+
+    #[test]
+    fn small_stays_in_memory() {
+        let mut spool = Spool::new(8);
+        spool.append(b"abc").unwrap();
+        spool.append(b"defgh").unwrap();
+        assert!(!spool.is_spilled());
+        assert_eq!(contents(&mut spool, 6), b"abcdef");
+    }
+
+    #[test]
+    fn large_spills_to_a_file() {
+        let mut spool = Spool::new(8);
+        spool.append(b"abcdef").unwrap();
+        spool.fill(b'x', 100_000).unwrap();
+        spool.append(b"ghi").unwrap();
+        assert!(spool.is_spilled());
+        assert_eq!(spool.len(), 100_009);
+
+        let sent = contents(&mut spool, 100_008);
+        assert_eq!(&sent[..6], b"abcdef");
+        assert!(sent[6..100_006].iter().all(|&b| b == b'x'));
+        assert_eq!(&sent[100_006..], b"gh");
+
+        // It can be sent again, and added to after.
+        spool.append(b"jkl").unwrap();
+        assert_eq!(contents(&mut spool, 100_012)[100_006..], *b"ghijkl");
+
+        // Or just part of it.
+        let mut sent = vec![];
+        spool
+            .send_range(100_004, 5, |data| {
+                sent.extend_from_slice(data);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(sent, b"xxghi");
+    }
+
+    #[test]
+    fn failed_sends_leave_the_spool_whole() {
+        let mut spool = Spool::new(8);
+        spool.append(b"abcdef").unwrap();
+        spool.fill(b'x', 100_000).unwrap();
+        assert!(spool.is_spilled());
+
+        let error = spool
+            .send_range(3, 100_000, |_| Err(io::Error::other("printer went away")))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "printer went away");
+
+        spool.append(b"ghi").unwrap();
+        let sent = contents(&mut spool, 100_009);
+        assert_eq!(&sent[..6], b"abcdef");
+        assert!(sent[6..100_006].iter().all(|&b| b == b'x'));
+        assert_eq!(&sent[100_006..], b"ghi");
+    }
+}
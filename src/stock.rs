@@ -0,0 +1,90 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Presets for common label stocks.
+//!
+//! Choosing a stock by name sets the label size, gap, and reference point in
+//! one go. Any of these can still be overridden by the usual options.
+
+use std::error::Error;
+
+/// A label stock preset. Dimensions are rounded to whole millimeters, as
+/// that's what we send the printer.
+pub struct Stock {
+    /// Name used to select the stock, in both the `label-stock` job option and
+    /// the PPD's `LabelStock` choices.
+    pub name: &'static str,
+    pub width_mm: u32,
+    pub height_mm: u32,
+    /// Gap (or mark) between labels.
+    pub gap_mm: u32,
+    /// Where the image starts on the label, as for `REFERENCE`.
+    pub reference_mm: (i32, i32),
+}
+
+pub static STOCKS: &[Stock] = &[
+    // 4x6 inch shipping label.
+    Stock {
+        name: "4x6",
+        width_mm: 102,
+        height_mm: 152,
+        gap_mm: 3,
+        reference_mm: (0, 0),
+    },
+    // 4x4 inch shipping/warehouse label.
+    Stock {
+        name: "4x4",
+        width_mm: 102,
+        height_mm: 102,
+        gap_mm: 3,
+        reference_mm: (0, 0),
+    },
+    // 2.25x1.25 inch barcode label.
+    Stock {
+        name: "2.25x1.25",
+        width_mm: 57,
+        height_mm: 32,
+        gap_mm: 3,
+        reference_mm: (1, 1),
+    },
+    // 57x32 mm price tag.
+    Stock {
+        name: "57x32",
+        width_mm: 57,
+        height_mm: 32,
+        gap_mm: 2,
+        reference_mm: (1, 1),
+    },
+    // 2x1 inch barcode label.
+    Stock {
+        name: "2x1",
+        width_mm: 51,
+        height_mm: 25,
+        gap_mm: 3,
+        reference_mm: (1, 1),
+    },
+    // 100x150 mm shipping label, as used outside North America.
+    Stock {
+        name: "100x150",
+        width_mm: 100,
+        height_mm: 150,
+        gap_mm: 3,
+        reference_mm: (0, 0),
+    },
+];
+
+/// Finds a stock preset by name.
+pub fn lookup(name: &str) -> Result<&'static Stock, Box<dyn Error>> {
+    STOCKS
+        .iter()
+        .find(|stock| stock.name == name)
+        .ok_or_else(|| {
+            let known = STOCKS
+                .iter()
+                .map(|stock| stock.name)
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("unknown label stock {name:?} (known: {known})").into()
+        })
+}
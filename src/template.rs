@@ -0,0 +1,81 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Command templates, so that models can differ in the order and form of
+//! their setup commands without code of their own.
+//!
+//! A template is text with placeholders in braces, such as `SIZE {width_mm}
+//! mm,{height_mm} mm`, filled in with values the filter works out. `{{` and
+//! `}}` stand for braces themselves.
+
+/// Fills in the placeholders in `template` with what `value` gives for their
+/// names.
+///
+/// Fails on placeholders `value` doesn't know, and on stray braces.
+pub fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..i]);
+        let brace = &rest[i..i + 1];
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix(brace) {
+            // Doubled, so it stands for itself.
+            rendered.push_str(brace);
+            rest = after;
+        } else if brace == "}" {
+            return Err(format!("stray }} in template {template:?}"));
+        } else {
+            let (name, after) = rest
+                .split_once('}')
+                .ok_or_else(|| format!("unclosed {{ in template {template:?}"))?;
+            rendered
+                .push_str(&value(name).ok_or_else(|| format!("unknown placeholder {{{name}}}"))?);
+            rest = after;
+        }
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Checks that `template` is well formed, and uses only placeholders in
+/// `names`.
+pub fn check(template: &str, names: &[&str]) -> Result<(), String> {
+    render(template, |name| names.contains(&name).then(String::new)).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(name: &str) -> Option<String> {
+        match name {
+            "width_mm" => Some("50".into()),
+            "height_mm" => Some("30".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn rendering() {
+        assert_eq!(
+            render("SIZE {width_mm} mm,{height_mm} mm", values).unwrap(),
+            "SIZE 50 mm,30 mm"
+        );
+        assert_eq!(
+            render("{{width_mm}} is {width_mm}}}", values).unwrap(),
+            "{width_mm} is 50}"
+        );
+        assert_eq!(render("CLS", values).unwrap(), "CLS");
+    }
+
+    #[test]
+    fn bad_templates() {
+        assert!(render("SIZE {depth_mm} mm", values).is_err());
+        assert!(render("SIZE {width_mm mm", values).is_err());
+        assert!(render("SIZE width_mm} mm", values).is_err());
+        assert!(check("{width_mm}", &["width_mm"]).is_ok());
+        assert!(check("{width_mm}", &["height_mm"]).is_err());
+    }
+}
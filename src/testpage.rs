@@ -0,0 +1,148 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Generated test pages, for checking a queue without any input to print.
+//!
+//! Pages are drawn as 8-bit K raster (0 is blank, 255 is full ink), so that
+//! they go through the same path to the printer as a real job. The one
+//! exception is the barcode, which the printer draws itself.
+
+use std::str::FromStr;
+
+/// What to draw on a test page.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    /// A box around the edge of the label and a cross in the middle, for
+    /// checking the label size and reference point.
+    Alignment,
+    /// A gray ramp, showing where the filter's black/white threshold falls.
+    Ramp,
+    /// Groups of bars one to four dots wide, for checking the head and
+    /// darkness, and a Code 128 barcode, for checking that barcodes scan.
+    Bars,
+    /// All of the above.
+    Full,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alignment" => Ok(Self::Alignment),
+            "ramp" => Ok(Self::Ramp),
+            "bars" => Ok(Self::Bars),
+            "full" => Ok(Self::Full),
+            _ => Err(format!(
+                "unknown test page {s:?} (known: alignment, ramp, bars, full)"
+            )),
+        }
+    }
+}
+
+/// Draws a test page `width` by `height` pixels at `dpi`, returning its rows
+/// end to end.
+pub fn render(pattern: Pattern, width: u32, height: u32, dpi: u32) -> Vec<u8> {
+    let mut page = Canvas {
+        pixels: vec![0; width as usize * height as usize],
+        width,
+        height,
+    };
+    // Lines about half a millimeter thick.
+    let line = (dpi / 50).max(2);
+
+    if matches!(pattern, Pattern::Alignment | Pattern::Full) {
+        page.fill(0, 0, width, line, 255);
+        page.fill(0, height.saturating_sub(line), width, line, 255);
+        page.fill(0, 0, line, height, 255);
+        page.fill(width.saturating_sub(line), 0, line, height, 255);
+
+        let arm = width.min(height) / 8;
+        let (cx, cy) = (width / 2, height / 2);
+        page.fill(
+            cx.saturating_sub(arm),
+            cy.saturating_sub(line / 2),
+            2 * arm,
+            line,
+            255,
+        );
+        page.fill(
+            cx.saturating_sub(line / 2),
+            cy.saturating_sub(arm),
+            line,
+            2 * arm,
+            255,
+        );
+    }
+
+    // The ramp and bars sit in bands across the page, clear of the cross.
+    let margin = width / 10;
+    let band = height / 8;
+    if matches!(pattern, Pattern::Ramp | Pattern::Full) {
+        let span = width.saturating_sub(2 * margin).max(1);
+        for x in 0..span {
+            let level = (255 * x / span) as u8;
+            page.fill(margin + x, 5 * band, 1, band, level);
+        }
+    }
+    if has_barcode(pattern) {
+        let mut x = margin;
+        for bar in 1..=4 {
+            for _ in 0..8 {
+                page.fill(x, 2 * band, bar, band, 255);
+                x += 2 * bar;
+            }
+            x += 8;
+        }
+    }
+
+    page.pixels
+}
+
+/// What [`barcode`] encodes.
+const BARCODE_TEXT: &str = "TSPL TEST";
+
+/// Gives the `BARCODE` command that goes on a test page `width` by `height`
+/// pixels at `dpi`, if the pattern has one. It's drawn above the bars, and
+/// sent before the page's bitmap, which is ORed over it.
+pub fn barcode(pattern: Pattern, width: u32, height: u32, dpi: u32) -> Option<String> {
+    if !has_barcode(pattern) {
+        return None;
+    }
+    let band = height / 8;
+    // Narrow bars about a quarter of a millimeter wide, which most scanners
+    // read comfortably, with the text printed underneath.
+    let narrow = (dpi / 100).max(1);
+    Some(format!(
+        "BARCODE {},{},\"128\",{},1,0,{narrow},{narrow},\"{BARCODE_TEXT}\"",
+        width / 10,
+        band / 2,
+        band * 3 / 4
+    ))
+}
+
+fn has_barcode(pattern: Pattern) -> bool {
+    matches!(pattern, Pattern::Bars | Pattern::Full)
+}
+
+struct Canvas {
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl Canvas {
+    /// Fills a rectangle, clipped to the page.
+    fn fill(&mut self, x: u32, y: u32, w: u32, h: u32, level: u8) {
+        let x_end = x.saturating_add(w).min(self.width);
+        let y_end = y.saturating_add(h).min(self.height);
+        if x >= x_end {
+            return;
+        }
+        for row in y..y_end {
+            let start = (row * self.width) as usize;
+            self.pixels[start + x as usize..start + x_end as usize].fill(level);
+        }
+    }
+}
@@ -0,0 +1,402 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Writing TSPL programs.
+//!
+//! A TSPL program is mostly lines of text, each ending in CR LF, but commands
+//! like `BITMAP` and `DOWNLOAD` carry binary data between their arguments and
+//! the end of the line. [`TsplWriter`] keeps track of which of the two it's
+//! sending, so that callers needn't. It also remembers the settings it's sent,
+//! such as `SIZE` and `DENSITY`, so that they needn't be sent again for every
+//! label when they haven't changed.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+};
+
+/// Sends a TSPL command, formatted as by `format!`, to a [`TsplWriter`],
+/// returning from the enclosing function if it can't be sent.
+#[macro_export]
+macro_rules! out {
+    ($out:expr, $fmt:literal $($args:tt)*) => {
+        $out.command(format_args!($fmt $($args)*))?
+    }
+}
+
+/// What ends a command.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    /// CR LF, which TSPL calls for, and most printers want.
+    #[default]
+    CrLf,
+    /// A bare LF, for clone firmwares that choke on the CR.
+    Lf,
+}
+
+impl LineEnding {
+    pub(crate) fn bytes(self) -> &'static [u8] {
+        match self {
+            Self::CrLf => b"\r\n",
+            Self::Lf => b"\n",
+        }
+    }
+}
+
+impl FromStr for LineEnding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crlf" => Ok(Self::CrLf),
+            "lf" => Ok(Self::Lf),
+            _ => Err(format!("unknown line ending {s:?}")),
+        }
+    }
+}
+
+/// The order of the dots in each byte of `BITMAP` data.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BitOrder {
+    /// The leftmost dot in the most significant bit, as TSPL calls for.
+    #[default]
+    MsbFirst,
+    /// The leftmost dot in the least significant bit, as some clone firmwares
+    /// read it.
+    LsbFirst,
+}
+
+impl FromStr for BitOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "msb" => Ok(Self::MsbFirst),
+            "lsb" => Ok(Self::LsbFirst),
+            _ => Err(format!("unknown bit order {s:?}")),
+        }
+    }
+}
+
+/// Which bits of `BITMAP` data are ink.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Polarity {
+    /// Clear bits are ink, as TSPL calls for.
+    #[default]
+    Normal,
+    /// Set bits are ink, for clone firmwares that would otherwise print a
+    /// negative.
+    Inverted,
+}
+
+impl FromStr for Polarity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(Self::Normal),
+            "inverted" => Ok(Self::Inverted),
+            _ => Err(format!("unknown polarity {s:?}")),
+        }
+    }
+}
+
+/// How a firmware reads `BITMAP` data.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BitmapFormat {
+    pub bit_order: BitOrder,
+    pub polarity: Polarity,
+}
+
+impl BitmapFormat {
+    /// Converts packed bitmap data from the way TSPL calls for to this format,
+    /// in place.
+    pub fn encode(self, data: &mut [u8]) {
+        if self == Self::default() {
+            return;
+        }
+        for byte in data {
+            if self.bit_order == BitOrder::LsbFirst {
+                *byte = byte.reverse_bits();
+            }
+            if self.polarity == Polarity::Inverted {
+                *byte = !*byte;
+            }
+        }
+    }
+
+    /// Returns a byte of bitmap data with no ink.
+    pub fn blank(self) -> u8 {
+        match self.polarity {
+            Polarity::Normal => 0xFF,
+            Polarity::Inverted => 0x00,
+        }
+    }
+}
+
+/// Commands that change a setting the printer keeps from label to label, and
+/// what setting that is: `GAP` and `BLINE` both say where labels end.
+const SETTINGS: &[(&str, &str)] = &[
+    ("SIZE", "SIZE"),
+    ("GAP", "GAP"),
+    ("BLINE", "GAP"),
+    ("REFERENCE", "REFERENCE"),
+    ("DIRECTION", "DIRECTION"),
+    ("OFFSET", "OFFSET"),
+    ("DENSITY", "DENSITY"),
+    ("SPEED", "SPEED"),
+];
+
+/// Commands after which the printer's settings may not be what we last sent:
+/// calibrating measures the label size and gap afresh, and initializing puts
+/// everything back to the printer's defaults.
+const RESETS: &[&str] = &["GAPDETECT", "BLINEDETECT", "AUTODETECT", "INITIALPRINTER"];
+
+/// Sends TSPL to a printer, or anywhere else bytes can go.
+pub struct TsplWriter<W: Write> {
+    inner: W,
+    /// Whether to flush after every command and block of data, rather than
+    /// when the caller asks.
+    flush_often: bool,
+    /// Whether we're in the binary data of a command.
+    in_data: bool,
+    line_ending: LineEnding,
+    /// The command that last changed each of the [`SETTINGS`], which the
+    /// printer should still have.
+    settings: BTreeMap<&'static str, String>,
+}
+
+impl<W: Write> TsplWriter<W> {
+    /// Writes to `inner`, flushing after each command and block of data, so
+    /// that a printer on the other end of a pipe gets to work as soon as it
+    /// can. This is what a filter wants.
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            flush_often: true,
+            in_data: false,
+            line_ending: LineEnding::default(),
+            settings: BTreeMap::new(),
+        }
+    }
+
+    /// Ends commands with `line_ending`, rather than CR LF.
+    pub fn with_line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Writes to `inner`, flushing only when asked (or when `inner` decides
+    /// to), for writing to files or other buffers.
+    pub fn buffered(inner: W) -> Self {
+        Self {
+            flush_often: false,
+            ..Self::new(inner)
+        }
+    }
+
+    /// Sends a command with no binary data, such as `SIZE 100 mm,150 mm`.
+    ///
+    /// Panics if a command with binary data hasn't been finished with
+    /// [`end_data`](Self::end_data).
+    pub fn command(&mut self, args: fmt::Arguments<'_>) -> io::Result<()> {
+        assert!(!self.in_data, "command sent in the middle of binary data");
+        let command = args.to_string();
+        let name = command.split(' ').next().unwrap_or_default();
+        if let Some(&(_, setting)) = SETTINGS.iter().find(|(known, _)| *known == name) {
+            self.settings.insert(setting, command.clone());
+        } else if RESETS.contains(&name) {
+            self.settings.clear();
+        }
+        self.inner.write_all(command.as_bytes())?;
+        self.inner.write_all(self.line_ending.bytes())?;
+        self.maybe_flush()
+    }
+
+    /// Sends a command like [`command`](Self::command), unless it's one that
+    /// changes a setting, and the setting was last changed by the very same
+    /// command, so that the printer already has it.
+    pub fn setting(&mut self, args: fmt::Arguments<'_>) -> io::Result<()> {
+        let command = args.to_string();
+        if self.settings.values().any(|sent| *sent == command) {
+            return Ok(());
+        }
+        self.command(format_args!("{command}"))
+    }
+
+    /// Starts a command that carries binary data, sending its leading
+    /// arguments `args` (including the comma before the data), such as
+    /// `BITMAP 0,0,50,1200,1,`.
+    pub fn begin_data(&mut self, args: fmt::Arguments<'_>) -> io::Result<()> {
+        assert!(
+            !self.in_data,
+            "binary command sent in the middle of binary data"
+        );
+        self.inner.write_fmt(args)?;
+        self.in_data = true;
+        Ok(())
+    }
+
+    /// Sends binary data for the command started by
+    /// [`begin_data`](Self::begin_data).
+    pub fn data(&mut self, data: &[u8]) -> io::Result<()> {
+        assert!(self.in_data, "binary data sent outside a command");
+        self.inner.write_all(data)?;
+        self.maybe_flush()
+    }
+
+    /// Finishes a command that carries binary data.
+    pub fn end_data(&mut self) -> io::Result<()> {
+        assert!(self.in_data, "no binary data to end");
+        self.in_data = false;
+        self.inner.write_all(self.line_ending.bytes())?;
+        self.maybe_flush()
+    }
+
+    /// Sends part of a TSPL program that's ready-made, such as a job that's
+    /// already TSPL, as it is. It may change any of the printer's settings, so
+    /// they're all sent again after it.
+    ///
+    /// Panics if a command with binary data hasn't been finished with
+    /// [`end_data`](Self::end_data).
+    pub fn program(&mut self, program: &[u8]) -> io::Result<()> {
+        assert!(!self.in_data, "program sent in the middle of binary data");
+        self.settings.clear();
+        self.inner.write_all(program)?;
+        self.maybe_flush()
+    }
+
+    /// Sends an immediate command, one starting with `<ESC>!` such as
+    /// [`STATUS_QUERY`](crate::backchannel::STATUS_QUERY), which the printer
+    /// acts on at once, and which doesn't change its settings.
+    ///
+    /// Panics if a command with binary data hasn't been finished with
+    /// [`end_data`](Self::end_data).
+    pub fn immediate(&mut self, command: &[u8]) -> io::Result<()> {
+        assert!(
+            !self.in_data,
+            "immediate command sent in the middle of binary data"
+        );
+        self.inner.write_all(command)?;
+        self.maybe_flush()
+    }
+
+    /// Flushes everything sent so far.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Borrows what we're writing to.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Mutably borrows what we're writing to.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Forgets which settings have been sent, so that they're all sent again,
+    /// for when what's sent next won't necessarily follow what's been sent.
+    pub fn forget_settings(&mut self) {
+        self.settings.clear();
+    }
+
+    /// Returns what we're writing to.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn maybe_flush(&mut self) -> io::Result<()> {
+        if self.flush_often {
+            self.inner.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_commands_and_data() {
+        let mut out = TsplWriter::buffered(vec![]);
+        out.command(format_args!("SIZE {} mm,{} mm", 100, 150))
+            .unwrap();
+        out.begin_data(format_args!("BITMAP 0,0,1,2,1,")).unwrap();
+        out.data(&[0xFF]).unwrap();
+        out.data(&[0x00]).unwrap();
+        out.end_data().unwrap();
+        out.command(format_args!("PRINT 1,1")).unwrap();
+        assert_eq!(
+            out.into_inner(),
+            b"SIZE 100 mm,150 mm\r\nBITMAP 0,0,1,2,1,\xFF\x00\r\nPRINT 1,1\r\n"
+        );
+    }
+
+    #[test]
+    fn repeated_settings() {
+        let mut out = TsplWriter::buffered(vec![]);
+        for density in [8, 8, 10] {
+            out.setting(format_args!("SIZE 100 mm,150 mm")).unwrap();
+            out.setting(format_args!("DENSITY {density}")).unwrap();
+            out.setting(format_args!("CLS")).unwrap();
+        }
+        // Calibrating measures the label afresh.
+        out.command(format_args!("GAPDETECT")).unwrap();
+        out.setting(format_args!("SIZE 100 mm,150 mm")).unwrap();
+        out.setting(format_args!("GAP 3 mm,0 mm")).unwrap();
+        out.setting(format_args!("BLINE 3 mm,0 mm")).unwrap();
+        out.setting(format_args!("GAP 3 mm,0 mm")).unwrap();
+        assert_eq!(
+            String::from_utf8(out.into_inner()).unwrap(),
+            "SIZE 100 mm,150 mm\r\nDENSITY 8\r\nCLS\r\nCLS\r\nDENSITY 10\r\nCLS\r\n\
+             GAPDETECT\r\nSIZE 100 mm,150 mm\r\nGAP 3 mm,0 mm\r\nBLINE 3 mm,0 mm\r\n\
+             GAP 3 mm,0 mm\r\n"
+        );
+    }
+
+    #[test]
+    fn bare_line_feeds() {
+        let mut out = TsplWriter::buffered(vec![]).with_line_ending(LineEnding::Lf);
+        out.command(format_args!("CLS")).unwrap();
+        out.begin_data(format_args!("BITMAP 0,0,1,1,1,")).unwrap();
+        out.data(&[0x0D]).unwrap();
+        out.end_data().unwrap();
+        assert_eq!(out.into_inner(), b"CLS\nBITMAP 0,0,1,1,1,\x0D\n");
+    }
+
+    #[test]
+    fn bitmap_formats() {
+        let mut data = [0b0111_1110, 0b1111_0000];
+        BitmapFormat::default().encode(&mut data);
+        assert_eq!(data, [0b0111_1110, 0b1111_0000]);
+
+        let format = BitmapFormat {
+            bit_order: BitOrder::LsbFirst,
+            polarity: Polarity::Normal,
+        };
+        format.encode(&mut data);
+        assert_eq!(data, [0b0111_1110, 0b0000_1111]);
+
+        let format = BitmapFormat {
+            bit_order: BitOrder::MsbFirst,
+            polarity: Polarity::Inverted,
+        };
+        format.encode(&mut data);
+        assert_eq!(data, [0b1000_0001, 0b1111_0000]);
+        assert_eq!(format.blank(), 0x00);
+    }
+
+    #[test]
+    #[should_panic]
+    fn commands_wait_for_data_to_end() {
+        let mut out = TsplWriter::buffered(vec![]);
+        out.begin_data(format_args!("BITMAP 0,0,1,1,1,")).unwrap();
+        let _ = out.command(format_args!("PRINT 1,1"));
+    }
+}
@@ -0,0 +1,244 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Conversions between the units we deal in: points (from the raster header),
+//! millimeters (what TSPL mostly wants), and dots.
+//!
+//! TSPL converts millimeters to dots with a whole number of dots per
+//! millimeter, which doesn't quite match the head's real resolution, so these
+//! take care to round in the direction that keeps the raster on the label.
+
+/// Converts points to the nearest whole millimeter.
+pub fn points_to_mm(pt: u32) -> u32 {
+    (f64::from(pt) * 25.4 / 72.0).round() as u32
+}
+
+/// Converts millimeters to dots, the way the printer will.
+pub fn mm_to_dots(mm: i32, dots_per_mm: u32) -> i32 {
+    mm * dots_per_mm as i32
+}
+
+/// Number of bytes in a packed row `width` pixels wide.
+pub fn bytes_per_row(width: u32) -> u32 {
+    width.div_ceil(8)
+}
+
+/// Works out the label size in millimeters, for `SIZE`, when we'll be sending
+/// `rows` rows of a page `width` pixels wide and `height` tall, whose size in
+/// points (if known) is `page_size_pt`.
+///
+/// CUPS and PWG raster both give the page size in points, which is the better
+/// source when the whole page is going out: converting from pixels goes wrong
+/// at resolutions that aren't a whole number of dots per millimeter. Both
+/// formats carry the full page in the bitmap, so the margins don't move
+/// anything. Either way, the label is never smaller than the raster, so that
+/// the printer doesn't clip it.
+pub fn label_size_mm(
+    page_size_pt: [u32; 2],
+    [width, height]: [u32; 2],
+    rows: u32,
+    dots_per_mm: u32,
+) -> (u32, u32) {
+    let [width_pt, height_pt] = page_size_pt;
+
+    let mut width_mm = width.div_ceil(dots_per_mm);
+    if width_pt != 0 {
+        width_mm = width_mm.max(points_to_mm(width_pt));
+    }
+    let mut height_mm = rows.div_ceil(dots_per_mm);
+    if height_pt != 0 && rows == height {
+        height_mm = height_mm.max(points_to_mm(height_pt));
+    }
+    (width_mm, height_mm)
+}
+
+/// Works out the gap between labels, in millimeters, from the top and bottom
+/// margins of a page `page_height_pt` points long whose imageable area is
+/// `bbox_pt` (left, bottom, right, top, in points). This is for PPDs that
+/// describe the gap this way.
+///
+/// Returns `None` if the raster doesn't say where the imageable area is.
+pub fn margins_gap_mm(page_height_pt: u32, bbox_pt: [u32; 4]) -> Option<u32> {
+    let [_, bottom, _, top] = bbox_pt;
+    if top == 0 || top > page_height_pt {
+        return None;
+    }
+    Some(points_to_mm(bottom + page_height_pt - top))
+}
+
+/// Works out where a raster `size` pixels at `dpi` belongs on a page
+/// `page_size_pt` points, as an offset in dots from the top left, if it covers
+/// only the page's imageable area `bbox_pt` (left, bottom, right, top, in
+/// points) rather than the whole page.
+///
+/// Most rasterizers send the whole page, margins and all, and then the raster
+/// is already where the application put it; this returns `None` for those,
+/// and whenever the header doesn't say enough to tell.
+pub fn imaging_offset_dots(
+    page_size_pt: [u32; 2],
+    bbox_pt: [u32; 4],
+    size: [u32; 2],
+    dpi: [u32; 2],
+) -> Option<(u32, u32)> {
+    let [left, bottom, right, top] = bbox_pt;
+    let [width_pt, height_pt] = page_size_pt;
+    if right <= left || top <= bottom || right > width_pt || top > height_pt {
+        return None;
+    }
+    if left == 0 && top == height_pt {
+        // No margins at the top left to skip.
+        return None;
+    }
+    let dots = |pt: u32, dpi: u32| ((u64::from(pt) * u64::from(dpi) + 36) / 72) as u32;
+    let [x_dpi, y_dpi] = dpi;
+    // Rasterizers round the size their own way, so allow a dot either way.
+    let fits = |pixels: u32, pt: u32, dpi: u32| pixels.abs_diff(dots(pt, dpi)) <= 1;
+    if !(fits(size[0], right - left, x_dpi) && fits(size[1], top - bottom, y_dpi)) {
+        return None;
+    }
+    Some((dots(left, x_dpi), dots(height_pt - top, y_dpi)))
+}
+
+/// Works out the size of a PWG self-describing media name, such as
+/// `na_index-4x6_4x6in` or `om_w100h150_100x150mm`, in points.
+///
+/// This is how driverless clients name sizes, and how a page size gets
+/// chosen without a PPD to list them.
+pub fn media_size_pt(name: &str) -> Option<(f32, f32)> {
+    let (_, size) = name.rsplit_once('_')?;
+    let (size, points_per_unit) = if let Some(size) = size.strip_suffix("mm") {
+        (size, 72.0 / 25.4)
+    } else {
+        (size.strip_suffix("in")?, 72.0)
+    };
+    let (width, length) = size.split_once('x')?;
+    let (width, length) = (width.parse::<f32>().ok()?, length.parse::<f32>().ok()?);
+    (width > 0.0 && length > 0.0).then_some((width * points_per_unit, length * points_per_unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Resolutions we have heads for, with TSPL's idea of their dots per
+    /// millimeter.
+    fn head() -> impl Strategy<Value = (u32, u32)> {
+        prop_oneof![Just((203, 8)), Just((300, 12)), Just((600, 24))]
+    }
+
+    /// A page as a rasterizer would produce it: a size in points, and the
+    /// pixels covering it at `dpi`.
+    fn page(dpi: u32) -> impl Strategy<Value = ([u32; 2], [u32; 2])> {
+        (1..=2000_u32, 1..=4000_u32).prop_map(move |(w, h)| {
+            let px = |pt: u32| (u64::from(pt) * u64::from(dpi)).div_ceil(72) as u32;
+            ([w, h], [px(w), px(h)])
+        })
+    }
+
+    #[test]
+    fn media_names() {
+        assert_eq!(media_size_pt("na_index-4x6_4x6in"), Some((288.0, 432.0)));
+        let (width, length) = media_size_pt("om_w100h150_100x150mm").unwrap();
+        assert_eq!((width.round(), length.round()), (283.0, 425.0));
+        assert_eq!(media_size_pt("w100h150"), None);
+        assert_eq!(media_size_pt("oe_4x6-label_4x6ft"), None);
+        assert_eq!(media_size_pt("oe_empty_0x6in"), None);
+    }
+
+    #[test]
+    fn gap_from_margins() {
+        // 3mm, all at the top.
+        assert_eq!(margins_gap_mm(432, [0, 0, 288, 423]), Some(3));
+        // Split between top and bottom.
+        assert_eq!(margins_gap_mm(432, [0, 4, 288, 427]), Some(3));
+        assert_eq!(margins_gap_mm(432, [0, 0, 288, 432]), Some(0));
+        assert_eq!(margins_gap_mm(432, [0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn imaging_area_offset() {
+        // 1/8" margins all round, at 203dpi.
+        let bbox = [9, 9, 279, 423];
+        assert_eq!(
+            imaging_offset_dots([288, 432], bbox, [761, 1167], [203, 203]),
+            Some((25, 25))
+        );
+        // The whole page, so the margins are already in the raster.
+        assert_eq!(
+            imaging_offset_dots([288, 432], bbox, [812, 1218], [203, 203]),
+            None
+        );
+        // No bounding box.
+        assert_eq!(
+            imaging_offset_dots([288, 432], [0, 0, 0, 0], [761, 1167], [203, 203]),
+            None
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn size_covers_raster(
+            ((_, dots_per_mm), (pt, px)) in head().prop_flat_map(|h| (Just(h), page(h.0)))
+        ) {
+            let (w, h) = label_size_mm(pt, px, px[1], dots_per_mm);
+            prop_assert!(w * dots_per_mm >= px[0]);
+            prop_assert!(h * dots_per_mm >= px[1]);
+        }
+
+        #[test]
+        fn trimmed_size_covers_rows(
+            ((_, dots_per_mm), (pt, px), cut) in head()
+                .prop_flat_map(|h| (Just(h), page(h.0), 0.0..1.0_f64))
+        ) {
+            let rows = (f64::from(px[1]) * cut) as u32;
+            let (_, h) = label_size_mm(pt, px, rows, dots_per_mm);
+            prop_assert!(h * dots_per_mm >= rows);
+            // ...but not by more than a millimeter's worth of feed.
+            prop_assert!(h * dots_per_mm < rows + dots_per_mm);
+        }
+
+        #[test]
+        fn size_without_page_size_is_tight(
+            (_, dots_per_mm) in head(),
+            width in 1..=20000_u32,
+            height in 1..=40000_u32,
+        ) {
+            let (w, h) = label_size_mm([0, 0], [width, height], height, dots_per_mm);
+            prop_assert!(w * dots_per_mm >= width && w * dots_per_mm < width + dots_per_mm);
+            prop_assert!(h * dots_per_mm >= height && h * dots_per_mm < height + dots_per_mm);
+        }
+
+        #[test]
+        fn size_matches_page_size(
+            ((_, dots_per_mm), (pt, px)) in head().prop_flat_map(|h| (Just(h), page(h.0)))
+        ) {
+            // The page size wins unless it would clip the raster, which can
+            // only be by rounding.
+            let (w, h) = label_size_mm(pt, px, px[1], dots_per_mm);
+            prop_assert!(w.abs_diff(points_to_mm(pt[0])) <= 1);
+            prop_assert!(h.abs_diff(points_to_mm(pt[1])) <= 1);
+        }
+
+        #[test]
+        fn reference_scales_linearly(
+            (_, dots_per_mm) in head(),
+            a in -500..=500_i32,
+            b in -500..=500_i32,
+        ) {
+            prop_assert_eq!(
+                mm_to_dots(a + b, dots_per_mm),
+                mm_to_dots(a, dots_per_mm) + mm_to_dots(b, dots_per_mm)
+            );
+            prop_assert_eq!(mm_to_dots(a, dots_per_mm).signum(), a.signum());
+        }
+
+        #[test]
+        fn rows_hold_every_pixel(width in 0..=100_000_u32) {
+            let bytes = bytes_per_row(width);
+            prop_assert!(bytes * 8 >= width);
+            prop_assert!(bytes * 8 < width + 8);
+        }
+    }
+}
@@ -0,0 +1,392 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checking TSPL against the shape of the language, for catching mistakes in
+//! what we generate before a printer does.
+//!
+//! Commands come from templates, profiles, and settings as well as code, and
+//! a slip in any of them (a missing argument, a distance that isn't one, a
+//! `BITMAP` whose data doesn't match the size it gives) has a printer fault
+//! or print garbage, usually without saying why. A [`Validator`] reads the
+//! stream as it's sent and reports the first command that's malformed. The
+//! integration tests run everything the filter sends through one, and the
+//! `tspl-validate` option does the same for real jobs.
+//!
+//! Only form is checked: that each command is one TSPL has, with arguments of
+//! the right number and kind. Whether the commands make sense together is
+//! left to the virtual printer in the tests. Drawing commands, which only
+//! jobs that are already TSPL use, are known by name but their arguments
+//! aren't checked.
+
+use crate::tspl::LineEnding;
+
+/// The kinds of argument a command takes.
+#[derive(Copy, Clone, Debug)]
+enum Arg {
+    /// A whole number, not negative.
+    Count,
+    /// A number, which may have a fraction.
+    Number,
+    /// A distance: a number of inches, or of millimeters or dots with ` mm`
+    /// or ` dot` after it. May be negative.
+    Distance,
+    /// A setting and its value, as `SET` takes them, such as `CUTTER OFF`.
+    Setting,
+    /// `= ` and a whole number, as the clock commands take.
+    Assignment,
+    /// A name in double quotes.
+    Quoted,
+    /// Anything.
+    Any,
+}
+
+use Arg::*;
+
+/// Commands with their arguments: how many must be given, and the kinds of
+/// all of them, in order.
+const COMMANDS: &[(&str, usize, &[Arg])] = &[
+    ("SIZE", 1, &[Distance, Distance]),
+    ("GAP", 2, &[Distance, Distance]),
+    ("BLINE", 2, &[Distance, Distance]),
+    ("OFFSET", 1, &[Distance]),
+    ("LIMITFEED", 1, &[Distance]),
+    ("REFERENCE", 2, &[Count, Count]),
+    ("SHIFT", 1, &[Distance, Distance]),
+    ("DIRECTION", 1, &[Count, Count]),
+    ("DENSITY", 1, &[Count]),
+    ("SPEED", 1, &[Number]),
+    ("CODEPAGE", 1, &[Any]),
+    ("SET", 1, &[Setting]),
+    ("SETC", 1, &[Setting]),
+    ("YEAR", 1, &[Assignment]),
+    ("MONTH", 1, &[Assignment]),
+    ("DATE", 1, &[Assignment]),
+    ("HOUR", 1, &[Assignment]),
+    ("MINUTE", 1, &[Assignment]),
+    ("SECOND", 1, &[Assignment]),
+    ("GAPDETECT", 0, &[Count, Count]),
+    ("BLINEDETECT", 0, &[Count, Count]),
+    ("AUTODETECT", 0, &[Count, Count]),
+    ("CLS", 0, &[]),
+    ("PUTBMP", 3, &[Count, Count, Quoted, Count]),
+    ("BOX", 5, &[Count, Count, Count, Count, Count, Count]),
+    ("BAR", 4, &[Count, Count, Count, Count]),
+    ("PRINT", 1, &[Count, Count]),
+    ("FEED", 1, &[Count]),
+    ("BACKFEED", 1, &[Count]),
+    ("FORMFEED", 0, &[]),
+    ("HOME", 0, &[]),
+    ("CUT", 0, &[]),
+    ("SELFTEST", 0, &[Any]),
+    ("DIAGNOSTIC", 0, &[]),
+    ("INITIALPRINTER", 0, &[]),
+    ("KILL", 1, &[Any]),
+    ("EOP", 0, &[]),
+    ("EOJ", 0, &[]),
+];
+
+/// Commands whose arguments aren't checked.
+const UNCHECKED: &[&str] = &[
+    "TEXT", "BARCODE", "QRCODE", "DMATRIX", "PDF417", "AZTEC", "MAXICODE", "RSS", "TLC39", "BLOCK",
+    "CIRCLE", "ELLIPSE", "DIAGONAL", "ERASE", "REVERSE", "PUTPCX", "PUTPNG", "SOUND", "DISPLAY",
+    "DELAY", "RUN", "MOVE",
+];
+
+/// What the validator expects next.
+enum Expect {
+    /// The text of a command, up to its line ending, or up to the binary data
+    /// of a command that has some.
+    Command,
+    /// This many more bytes of binary data.
+    Data(usize),
+    /// The two bytes that finish an immediate command (`<ESC>!` and a letter).
+    Immediate(usize),
+}
+
+/// Reads a TSPL stream a piece at a time, checking each command as it ends.
+pub struct Validator {
+    line_ending: LineEnding,
+    expect: Expect,
+    /// The command so far.
+    line: Vec<u8>,
+    /// Commands checked so far, for saying where a problem is.
+    commands: u64,
+}
+
+impl Validator {
+    /// Expects commands to end with `line_ending`.
+    pub fn new(line_ending: LineEnding) -> Self {
+        Self {
+            line_ending,
+            expect: Expect::Command,
+            line: vec![],
+            commands: 0,
+        }
+    }
+
+    /// Checks the next part of the stream, which may end partway through a
+    /// command.
+    pub fn feed(&mut self, mut data: &[u8]) -> Result<(), String> {
+        while let Some((&b, rest)) = data.split_first() {
+            match &mut self.expect {
+                Expect::Data(left) => {
+                    let n = (*left).min(data.len());
+                    *left -= n;
+                    if *left == 0 {
+                        self.expect = Expect::Command;
+                    }
+                    data = &data[n..];
+                    continue;
+                }
+                Expect::Immediate(left) => {
+                    if *left == 2 && b != b'!' {
+                        return Err(format!(
+                            "command {}: <ESC> that isn't an immediate command",
+                            self.commands + 1
+                        ));
+                    }
+                    *left -= 1;
+                    if *left == 0 {
+                        self.expect = Expect::Command;
+                        self.commands += 1;
+                    }
+                }
+                Expect::Command if self.line.is_empty() && b == 0x1B => {
+                    self.expect = Expect::Immediate(2);
+                }
+                Expect::Command => {
+                    self.line.push(b);
+                    self.end_of_text()
+                        .map_err(|e| format!("command {}: {e}", self.commands + 1))?;
+                }
+            }
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Checks that the stream didn't stop partway through a command.
+    pub fn finish(self) -> Result<(), String> {
+        match self.expect {
+            Expect::Command if self.line.is_empty() => Ok(()),
+            Expect::Data(left) => Err(format!(
+                "the stream ends {left} bytes short of the data for command {}",
+                self.commands
+            )),
+            _ => Err(format!(
+                "the stream ends partway through command {}",
+                self.commands + 1
+            )),
+        }
+    }
+
+    /// Looks at the command so far, now that another byte's been added to
+    /// it, and checks it if it's ended.
+    fn end_of_text(&mut self) -> Result<(), String> {
+        let ending = self.line_ending.bytes();
+        if let Some(line) = self.line.strip_suffix(ending) {
+            let line = std::str::from_utf8(line).map_err(|_| "binary data in a command")?;
+            check(line)?;
+            self.line.clear();
+            self.commands += 1;
+            return Ok(());
+        }
+        // Anything but text can only be the start of the line ending.
+        if let Some(start) = self
+            .line
+            .iter()
+            .position(|&b| !(b.is_ascii_graphic() || b == b' '))
+            && !ending.starts_with(&self.line[start..])
+        {
+            return Err(format!(
+                "binary data in a command ({:?})",
+                self.line.escape_ascii().to_string()
+            ));
+        }
+        if self.line.last() == Some(&b',')
+            && let Some(len) = data_length(&self.line)?
+        {
+            self.line.clear();
+            self.commands += 1;
+            self.expect = Expect::Data(len);
+        }
+        Ok(())
+    }
+}
+
+/// For a command that carries binary data, once all of the arguments ahead of
+/// the data have come, works out how many bytes of data there are.
+fn data_length(line: &[u8]) -> Result<Option<usize>, String> {
+    let (name, count) = if line.starts_with(b"BITMAP ") {
+        ("BITMAP", 5)
+    } else if line.starts_with(b"DOWNLOAD ") {
+        ("DOWNLOAD", 3)
+    } else {
+        return Ok(None);
+    };
+    let line = std::str::from_utf8(line).map_err(|_| "binary data in a command")?;
+    let args = line[name.len() + 1..]
+        .split(',')
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    // The last is what follows the final comma, which is empty so far.
+    if args.len() <= count {
+        return Ok(None);
+    }
+    let args = &args[..count];
+    let len = match (name, args) {
+        ("BITMAP", [x, y, width, height, mode]) => {
+            for arg in [x, y, mode] {
+                check_arg(Count, arg)?;
+            }
+            count_arg(width)? * count_arg(height)?
+        }
+        ("DOWNLOAD", ["F", file, len]) => {
+            check_arg(Quoted, file)?;
+            count_arg(len)?
+        }
+        _ => {
+            return Err(format!(
+                "{line:?}: only DOWNLOAD F,\"NAME\",LENGTH, is supported"
+            ));
+        }
+    };
+    Ok(Some(len))
+}
+
+/// Checks a command that fits on a line.
+fn check(line: &str) -> Result<(), String> {
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    if name.is_empty() && args.is_empty() {
+        // Blank lines, as after binary data, do nothing.
+        return Ok(());
+    }
+    if name == "REM" || UNCHECKED.contains(&name) {
+        return Ok(());
+    }
+    let Some(&(_, required, kinds)) = COMMANDS.iter().find(|(command, ..)| *command == name) else {
+        return Err(format!("{line:?}: unknown command {name}"));
+    };
+    let args = if args.is_empty() {
+        vec![]
+    } else {
+        args.split(',').map(str::trim).collect()
+    };
+    if args.len() < required || args.len() > kinds.len() {
+        return Err(format!(
+            "{line:?}: {name} takes {} arguments, not {}",
+            if required == kinds.len() {
+                required.to_string()
+            } else {
+                format!("{required} to {}", kinds.len())
+            },
+            args.len()
+        ));
+    }
+    for (kind, arg) in kinds.iter().zip(&args) {
+        check_arg(*kind, arg).map_err(|e| format!("{line:?}: {e}"))?;
+    }
+    if name == "DENSITY" && count_arg(args[0])? > 15 {
+        return Err(format!("{line:?}: density is 0 to 15"));
+    }
+    if name == "PRINT" && args.contains(&"0") {
+        return Err(format!("{line:?}: PRINT of no labels"));
+    }
+    Ok(())
+}
+
+fn check_arg(kind: Arg, arg: &str) -> Result<(), String> {
+    let ok = match kind {
+        Count => arg.parse::<u32>().is_ok(),
+        Number => arg.parse::<f64>().is_ok_and(|n| n >= 0.0),
+        Distance => {
+            let number = arg
+                .strip_suffix(" mm")
+                .or_else(|| arg.strip_suffix(" dot"))
+                .unwrap_or(arg);
+            number.parse::<f64>().is_ok_and(f64::is_finite)
+        }
+        Setting => arg
+            .split_once(' ')
+            .is_some_and(|(key, value)| !key.is_empty() && !value.is_empty()),
+        Assignment => arg
+            .strip_prefix("= ")
+            .is_some_and(|value| value.parse::<u32>().is_ok()),
+        Quoted => arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"'),
+        Any => true,
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("{arg:?} isn't a {}", describe(kind)))
+    }
+}
+
+fn count_arg(arg: &str) -> Result<usize, String> {
+    arg.parse()
+        .map_err(|_| format!("{arg:?} isn't a {}", describe(Count)))
+}
+
+fn describe(kind: Arg) -> &'static str {
+    match kind {
+        Count => "count",
+        Number => "number",
+        Distance => "distance",
+        Setting => "setting and value",
+        Assignment => "clock assignment",
+        Quoted => "quoted name",
+        Any => "value",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(stream: &[u8]) -> Result<(), String> {
+        let mut validator = Validator::new(LineEnding::CrLf);
+        // A byte at a time, as the worst case for splitting.
+        for b in stream {
+            validator.feed(std::slice::from_ref(b))?;
+        }
+        validator.finish()
+    }
+
+    #[test]
+    fn good_streams() {
+        validate(
+            b"SIZE 102 mm,152 mm\r\nGAP 3 mm,0 mm\r\nDIRECTION 0,0\r\nSET TEAR ON\r\n\
+              DENSITY 8\r\nSPEED 4\r\nCLS\r\nBITMAP 0,0,2,2,1,\r\n\x00\xFF\r\n\
+              DOWNLOAD F,\"A.BMP\",3,abc\r\nPUTBMP 0,0,\"A.BMP\"\r\n\x1b!?\
+              YEAR = 2024\r\nPRINT 2,1\r\nREM done\r\n",
+        )
+        .unwrap();
+        let mut lf = Validator::new(LineEnding::Lf);
+        lf.feed(b"SIZE 2,3\nCLS\nPRINT 1\n").unwrap();
+        lf.finish().unwrap();
+    }
+
+    #[test]
+    fn bad_streams() {
+        for (stream, error) in [
+            (&b"SIZE 102 mm,152 mm,3\r\n"[..], "takes 1 to 2 arguments"),
+            (
+                b"SIZE 102 mm\r\nGAP 3 cm,0\r\n",
+                "command 2: \"GAP 3 cm,0\"",
+            ),
+            (b"DENSITY 16\r\n", "density is 0 to 15"),
+            (b"FROB 1\r\n", "unknown command FROB"),
+            (b"SET TEAR\r\n", "isn't a setting and value"),
+            (b"PRINT 0,1\r\n", "PRINT of no labels"),
+            (b"CLS\n", "binary data"),
+            (b"BITMAP 0,0,2,x,1,", "\"x\" isn't a count"),
+            (b"BITMAP 0,0,2,2,1,\x00\x00", "2 bytes short"),
+            (b"BITMAP 0,0,1,1,1,\x00\x00\r\n", "binary data"),
+            (b"CLS\r\nPRINT 1", "partway through command 2"),
+        ] {
+            let e = validate(stream).unwrap_err();
+            assert!(e.contains(error), "{}: {e}", stream.escape_ascii());
+        }
+    }
+}
@@ -0,0 +1,74 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks on the virtual printer itself, so that the filter tests can trust
+//! it.
+
+mod support;
+
+use support::printer::{Media, Printer};
+
+#[test]
+fn prints_a_bitmap() {
+    let mut printer = Printer::new(8);
+    printer
+        .run(b"SIZE 2 mm,1 mm\r\nGAP 3 mm,0 mm\r\nDENSITY 8\r\nCLS\r\nBITMAP 8,2,1,2,1,\x7f\xfe\r\nPRINT 1,2\r\n")
+        .unwrap();
+
+    assert_eq!(printer.media, Some(Media::Gap(3.0, 0.0)));
+    assert_eq!(printer.density, Some(8));
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    assert_eq!((label.width, label.height, label.copies), (16, 8, 2));
+    let inked = (0..8)
+        .flat_map(|y| (0..16).map(move |x| (x, y)))
+        .filter(|&(x, y)| label.black(x, y))
+        .collect::<Vec<_>>();
+    assert_eq!(inked, [(8, 2), (15, 3)]);
+}
+
+#[test]
+fn rejects_binary_data_as_commands() {
+    // One byte more bitmap data than announced.
+    let mut printer = Printer::new(8);
+    let e = printer
+        .run(b"SIZE 1 mm,1 mm\r\nCLS\r\nBITMAP 0,0,1,1,1,\x00\x00\r\nPRINT 1\r\n")
+        .unwrap_err();
+    assert!(e.contains("binary data"), "{e}");
+}
+
+#[test]
+fn rejects_short_bitmaps() {
+    let mut printer = Printer::new(8);
+    let e = printer
+        .run(b"SIZE 1 mm,1 mm\r\nCLS\r\nBITMAP 0,0,1,2,1,\x00")
+        .unwrap_err();
+    assert!(e.contains("only 1 follow"), "{e}");
+}
+
+#[test]
+fn rejects_bitmaps_off_the_label() {
+    let mut printer = Printer::new(8);
+    let e = printer
+        .run(b"SIZE 1 mm,1 mm\r\nCLS\r\nBITMAP 0,0,2,1,1,\x00\x00\r\n")
+        .unwrap_err();
+    assert!(e.contains("doesn't fit"), "{e}");
+}
+
+#[test]
+fn rejects_drawing_before_setup() {
+    let mut printer = Printer::new(8);
+    let e = printer
+        .run(b"CLS\r\nBITMAP 0,0,1,1,1,\x00\r\n")
+        .unwrap_err();
+    assert!(e.contains("before SIZE"), "{e}");
+}
+
+#[test]
+fn rejects_unterminated_commands() {
+    let mut printer = Printer::new(8);
+    let e = printer.run(b"SIZE 1 mm,1 mm\r\nPRINT 1").unwrap_err();
+    assert!(e.contains("CR LF"), "{e}");
+}
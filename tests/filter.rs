@@ -0,0 +1,1665 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs the filter, with the example PPD, and checks what comes out on a
+//! virtual printer.
+
+mod support;
+
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use flate2::{Compression, write::GzEncoder};
+
+use support::{
+    cancel_stalled_filter, check_output, filter_output, filter_output_disconnected,
+    filter_output_with_back_channel, print, print_on,
+    printer::{Media, Printer},
+    raster::{self, K8, Page, W8},
+    run_filter, run_filter_on_file, run_filter_on_files, run_filter_with,
+};
+
+/// 4"x6", the example PPD's default page size, in points.
+const PAGE_4X6: [u32; 2] = [288, 432];
+
+#[test]
+fn test_page() {
+    let printer = print("tspl-test-page=alignment", b"");
+
+    assert_eq!(printer.size_mm, Some((100.0, 150.0)));
+    assert_eq!(printer.media, Some(Media::Gap(3.0, 0.0)));
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    // The box around the edge.
+    assert!(label.black(0, 0));
+    assert!(label.black(label.width / 3, 0));
+    assert!(label.black(0, label.height / 3));
+    // The cross in the middle, and nothing between.
+    assert!(label.black(label.width / 2, label.height / 2));
+    assert!(!label.black(label.width / 4, label.height / 4));
+    assert!(label.barcodes.is_empty());
+
+    // The bars come with a barcode the printer draws, as a real one.
+    let printer = print("tspl-test-page=bars", b"");
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    assert_eq!(label.barcodes, [("128".into(), "TSPL TEST".into())]);
+}
+
+#[test]
+fn pages_become_labels() {
+    let square = |x, y| {
+        if (100..200).contains(&x) && (100..200).contains(&y) {
+            255
+        } else {
+            0
+        }
+    };
+    let pages = [
+        Page::draw(K8, PAGE_4X6, 203, square),
+        Page::draw(K8, PAGE_4X6, 203, |_, _| 0),
+    ];
+    let printer = print("", &raster::stream(&pages));
+
+    assert_eq!(printer.labels.len(), 2);
+    assert!(printer.labels[0].black(150, 150));
+    assert!(!printer.labels[0].black(250, 150));
+    assert!(printer.labels[1].black.iter().all(|ink| !ink));
+}
+
+#[test]
+fn truncated_pages_are_padded() {
+    // The raster ends 100 rows and a bit into the page, as when the
+    // rasterizer dies.
+    let page = Page::draw(K8, PAGE_4X6, 203, |_, _| 255);
+    let width = page.header.cupsBytesPerLine as usize;
+    let height = page.header.cupsHeight;
+    let mut input = raster::stream(&[page]);
+    input.truncate(input.len() - width * (height as usize - 100) + width / 2);
+
+    let output = filter_output(&[], 1, "", &input);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("page 1 ended after 100 of its {height} rows")),
+        "{stderr}"
+    );
+    let mut printer = Printer::new(8);
+    printer.run(&output.stdout).unwrap();
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    assert!(label.black(400, 50));
+    assert!(!label.black(400, 500));
+    assert!(!label.black(400, height - 1));
+
+    // A page that has to be read whole to be turned is padded the same way,
+    // its first rows becoming the label's left edge.
+    let page = Page::draw(K8, [432, 288], 203, |_, _| 255);
+    let width = page.header.cupsBytesPerLine as usize;
+    let height = page.header.cupsHeight;
+    let mut input = raster::stream(&[page]);
+    input.truncate(input.len() - width * (height as usize - 100) + width / 2);
+
+    let output = filter_output(&[], 1, "oversize=rotate", &input);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("page 1 ended after 100 of its {height} rows")),
+        "{stderr}"
+    );
+    let mut printer = Printer::new(8);
+    printer.run(&output.stdout).unwrap();
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    assert!(label.black(50, 600));
+    assert!(!label.black(400, 600));
+    assert!(!label.black(height - 1, 600));
+}
+
+#[test]
+fn continuous_media_limits_feed() {
+    let pages = [Page::draw(
+        K8,
+        PAGE_4X6,
+        203,
+        |_, y| if y < 100 { 255 } else { 0 },
+    )];
+    let printer = print("media-tracking=continuous", &raster::stream(&pages));
+
+    assert_eq!(printer.media, Some(Media::Gap(0.0, 0.0)));
+    assert_eq!(printer.limit_feed_mm, printer.size_mm.map(|(_, h)| h));
+    assert_eq!(printer.labels.len(), 1);
+}
+
+#[test]
+fn media_type_picks_tracking() {
+    let mut page = Page::draw(K8, PAGE_4X6, 203, |_, y| if y < 100 { 255 } else { 0 });
+    for (to, from) in page.header.MediaType.iter_mut().zip(b"Tag-with-mark") {
+        *to = *from as _;
+    }
+    let input = raster::stream(&[page]);
+    let printer = print("", &input);
+    assert!(matches!(printer.media, Some(Media::BLine(..))));
+
+    // Saying so outright wins.
+    let printer = print("media-tracking=gap", &input);
+    assert!(matches!(printer.media, Some(Media::Gap(gap, _)) if gap > 0.0));
+}
+
+#[test]
+fn media_type_changes_between_pages() {
+    let page = |media_type: &[u8]| {
+        let mut page = Page::draw(K8, PAGE_4X6, 203, |_, y| if y < 100 { 255 } else { 0 });
+        for (to, from) in page.header.MediaType.iter_mut().zip(media_type) {
+            *to = *from as _;
+        }
+        page
+    };
+    let input = raster::stream(&[page(b"Labels"), page(b"Continuous")]);
+    let printer = print("", &input);
+    assert_eq!(printer.labels.len(), 2);
+    assert_eq!(printer.media, Some(Media::Gap(0.0, 0.0)));
+    assert!(printer.limit_feed_mm.is_some());
+}
+
+#[test]
+fn validation_stops_malformed_commands() {
+    let options = "tspl-test-page=bars tspl-prolog='DENSITY 20'";
+    let output = filter_output(&[], 1, options, b"");
+    assert!(output.status.success());
+
+    let output = filter_output(&[], 1, &format!("{options} tspl-validate=true"), b"");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("density is 0 to 15"), "{stderr}");
+    // The bad command never ends, so the printer doesn't act on it.
+    assert!(!output.stdout.ends_with(b"\r\n"));
+}
+
+#[test]
+fn copies_are_split_under_the_print_limit() {
+    let mut printer = Printer::new(8);
+    printer.max_print_count = Some(999);
+    let printer = print_on(printer, 2500, "tspl-test-page=bars", b"");
+
+    let counts = printer.labels.iter().map(|l| l.copies).collect::<Vec<_>>();
+    assert_eq!(counts, [999, 999, 502]);
+}
+
+#[test]
+fn sets_the_clock() {
+    let printer = print("SetClock=True tspl-test-page=bars", b"");
+    let keys = printer.clock.keys().map(String::as_str).collect::<Vec<_>>();
+    assert_eq!(keys, ["DATE", "HOUR", "MINUTE", "MONTH", "SECOND", "YEAR"]);
+    assert!(printer.clock["YEAR"] >= 2024);
+
+    let printer = print("tspl-test-page=bars", b"");
+    assert!(printer.clock.is_empty());
+}
+
+#[test]
+fn site_configuration_applies_by_queue() {
+    let config = Path::new(env!("CARGO_TARGET_TMPDIR")).join("tspl-filter.conf");
+    std::fs::write(
+        &config,
+        "[defaults]\n\
+         max-darkness = 4\n\
+         [queue.receipts.options]\n\
+         MediaType = \"Continuous\"\n",
+    )
+    .unwrap();
+    let run = |queue| {
+        let env = [
+            ("TSPL_FILTER_CONF", config.to_str().unwrap()),
+            ("PRINTER", queue),
+        ];
+        let mut printer = Printer::new(8);
+        let output = run_filter_with(&env, 1, "tspl-test-page=bars", b"");
+        printer.run(&output).unwrap();
+        printer
+    };
+
+    let receipts = run("receipts");
+    assert_eq!(receipts.density, Some(4));
+    assert_eq!(receipts.media, Some(Media::Gap(0.0, 0.0)));
+    assert_eq!(receipts.limit_feed_mm, Some(150.0));
+
+    let shipping = run("shipping");
+    assert_eq!(shipping.density, Some(4));
+    assert_eq!(shipping.media, Some(Media::Gap(3.0, 0.0)));
+}
+
+#[test]
+fn threshold_is_a_lightness_in_every_colorspace() {
+    let config = Path::new(env!("CARGO_TARGET_TMPDIR")).join("threshold.conf");
+    std::fs::write(&config, "[defaults]\nthreshold = 100\n").unwrap();
+    let env = [("TSPL_FILTER_CONF", config.to_str().unwrap())];
+    // Ink of 140 on the left and 170 on the right: lightness 115 and 85, so
+    // only the right prints.
+    let inked = |format| {
+        let page = Page::draw(
+            format,
+            PAGE_4X6,
+            203,
+            |x, _| if x < 400 { 140 } else { 170 },
+        );
+        let mut printer = Printer::new(8);
+        printer
+            .run(&run_filter_with(&env, 1, "", &raster::stream(&[page])))
+            .unwrap();
+        let label = &printer.labels[0];
+        let y = label.height / 2;
+        (label.black(200, y), label.black(600, y))
+    };
+    assert_eq!(inked(K8), (false, true));
+    assert_eq!(inked(W8), (false, true));
+}
+
+#[test]
+fn prolog_and_epilog_frame_each_label() {
+    let output = run_filter(
+        2,
+        "tspl-test-page=bars tspl-prolog='SET CUTTER OFF' tspl-epilog='SET PEEL OFF'",
+        b"",
+    );
+    let text = String::from_utf8_lossy(&output);
+    let at = |command| {
+        text.find(command)
+            .unwrap_or_else(|| panic!("no {command:?} in the output"))
+    };
+    assert!(at("SET CUTTER OFF\r\n") < at("CLS\r\n"));
+    assert!(at("PRINT 2,1\r\n") < at("SET PEEL OFF\r\n"));
+
+    let mut printer = Printer::new(8);
+    printer.run(&output).unwrap();
+    assert_eq!(printer.settings["CUTTER"], "OFF");
+    assert_eq!(printer.labels.len(), 1);
+}
+
+#[test]
+fn oversize_pages_follow_the_policy() {
+    // A 4"x6" page, with a mark at the bottom left, for 4"x4" stock.
+    let pages = [Page::draw(K8, PAGE_4X6, 203, |x, y| {
+        if x < 100 && y >= 1100 { 255 } else { 0 }
+    })];
+    let input = raster::stream(&pages);
+
+    // Clipping is the default, and loses the mark.
+    let printer = print("label-stock=4x4", &input);
+    assert_eq!(printer.size_mm, Some((102.0, 102.0)));
+    assert!(!printer.labels[0].black.contains(&true));
+
+    // Scaling keeps it, shrunk by 816/1218.
+    let printer = print("label-stock=4x4 oversize=scale", &input);
+    let label = &printer.labels[0];
+    assert!(label.black(10, 780));
+    assert!(!label.black(10, 700));
+    assert!(!label.black(80, 780));
+
+    let output = filter_output(&[], 1, "label-stock=4x4 oversize=error", &input);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("bigger than the 4x4 label"));
+}
+
+#[test]
+fn unknown_resolutions_use_the_nearest_head() {
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 150, |_, _| 255)]);
+    let output = filter_output(&[], 1, "", &input);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("no 150x150dpi head, printing as 203dpi"),
+        "{stderr}"
+    );
+    assert!(output.stdout.windows(6).any(|w| w == b"BITMAP"));
+}
+
+#[test]
+fn gap_from_margins_where_the_ppd_says_so() {
+    let ppd = Path::new(env!("CARGO_TARGET_TMPDIR")).join("gap-from-margins.ppd");
+    let mut text =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+            .unwrap();
+    text.push_str("*tspl-gap-from-margins: True\n");
+    std::fs::write(&ppd, text).unwrap();
+
+    // A 6mm gap, all of it in the top margin.
+    let mut page = Page::draw(K8, PAGE_4X6, 203, |_, _| 0);
+    page.header.ImagingBoundingBox = [0, 0, 288, 415];
+    let input = raster::stream(&[page]);
+
+    let mut printer = Printer::new(8);
+    printer
+        .run(&run_filter_with(
+            &[("PPD", ppd.to_str().unwrap())],
+            1,
+            "",
+            &input,
+        ))
+        .unwrap();
+    assert_eq!(printer.media, Some(Media::Gap(6.0, 0.0)));
+
+    // Other PPDs don't use the margins that way.
+    let printer = print("", &input);
+    assert_eq!(printer.media, Some(Media::Gap(3.0, 0.0)));
+}
+
+#[test]
+fn gap_or_mark_offset_from_the_ppd() {
+    // Negative offsets are for marks that start ahead of the label.
+    let ppd = Path::new(env!("CARGO_TARGET_TMPDIR")).join("mark-offset.ppd");
+    let text = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+        .unwrap()
+        .replace("*DefaultGapOrMarkOffset: 0", "*DefaultGapOrMarkOffset: -2");
+    std::fs::write(&ppd, text).unwrap();
+
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let mut printer = Printer::new(8);
+    printer
+        .run(&run_filter_with(
+            &[("PPD", ppd.to_str().unwrap())],
+            1,
+            "media-tracking=mark",
+            &input,
+        ))
+        .unwrap();
+    assert!(matches!(printer.media, Some(Media::BLine(_, offset)) if offset == -2.0));
+}
+
+#[test]
+fn reads_gzipped_files() {
+    let pages = [Page::draw(K8, PAGE_4X6, 203, |x, y| {
+        if x < 100 && y < 100 { 255 } else { 0 }
+    })];
+    let input = raster::stream(&pages);
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("input.ras.gz");
+    let mut gz = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+    gz.write_all(&input).unwrap();
+    gz.finish().unwrap();
+
+    let mut printer = Printer::new(8);
+    printer.run(&run_filter_on_file("", &path)).unwrap();
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    assert!(label.black(50, 50));
+    assert!(!label.black(150, 50));
+}
+
+#[test]
+fn reports_labels_used() {
+    let output = filter_output(&[], 3, "tspl-test-page=bars", b"");
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    let summaries = log
+        .lines()
+        .filter(|line| line.starts_with("ATTR: job-media-sheets="))
+        .collect::<Vec<_>>();
+    let [summary] = &summaries[..] else {
+        panic!("expected one summary:\n{log}");
+    };
+    assert!(summary.contains("job-media-sheets=3 "), "{summary}");
+    assert!(
+        summary.contains(" job-impressions-completed=3 "),
+        "{summary}"
+    );
+    let bytes = summary
+        .split_once(" tspl-bytes-sent=")
+        .and_then(|(_, rest)| rest.split(' ').next()?.parse::<usize>().ok());
+    assert_eq!(bytes, Some(output.stdout.len()), "{summary}");
+    assert!(summary.contains(" tspl-elapsed-seconds="), "{summary}");
+}
+
+#[test]
+fn comments_identify_the_job() {
+    let pages = [
+        Page::draw(K8, PAGE_4X6, 203, |_, _| 0),
+        Page::draw(K8, PAGE_4X6, 203, |_, _| 0),
+    ];
+    let printer = print("tspl-comments=true", &raster::stream(&pages));
+    assert_eq!(printer.comments.len(), 3);
+    assert!(printer.comments[0].starts_with("raster-tspl "));
+    assert!(printer.comments[0].ends_with(" job 1 user user"));
+    assert_eq!(printer.comments[1..], ["page 1", "page 2"]);
+
+    let printer = print("", &raster::stream(&pages));
+    assert!(printer.comments.is_empty());
+}
+
+#[test]
+fn multiple_input_files() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let page = |level| Page::draw(K8, PAGE_4X6, 203, move |_, _| level);
+    let paths = [
+        (dir.join("multi-1.ras"), vec![page(255), page(0)]),
+        (dir.join("multi-2.ras"), vec![page(255)]),
+    ]
+    .map(|(path, pages)| {
+        std::fs::write(&path, raster::stream(&pages)).unwrap();
+        path
+    });
+    let list = dir.join("multi.list");
+    std::fs::write(&list, format!("{}\n\n", paths[1].display())).unwrap();
+    let list_arg = format!("@{}", list.display());
+
+    // One session, with one setup, for the pages of all the files in turn.
+    let output = run_filter_on_files(
+        "tspl-comments=true",
+        &[paths[0].as_os_str(), OsStr::new(&list_arg)],
+    );
+    let mut printer = Printer::new(8);
+    printer.run(&output).unwrap();
+    let inked = printer
+        .labels
+        .iter()
+        .map(|label| label.black(10, 10))
+        .collect::<Vec<_>>();
+    assert_eq!(inked, [true, false, true]);
+    assert!(printer.comments[0].starts_with("raster-tspl "));
+    assert_eq!(printer.comments[1..], ["page 1", "page 2", "page 3"]);
+}
+
+#[test]
+fn options_the_ppd_lacks_come_from_the_job() {
+    // The example PPD has no AdjustHorizontal, which tspl-align sets as a
+    // queue default.
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let printer = print("AdjustHorizontal=2 FeedOffset=-1", &input);
+    assert_eq!(printer.reference.0, 16);
+    assert_eq!(printer.offset_mm, -1.0);
+}
+
+#[test]
+fn runs_without_a_ppd() {
+    let pages = [Page::draw(
+        K8,
+        PAGE_4X6,
+        203,
+        |x, _| {
+            if x < 100 { 255 } else { 0 }
+        },
+    )];
+    let input = raster::stream(&pages);
+    let env = [
+        ("PPD", ""),
+        ("TSPL_FILTER_OPTIONS", "Darkness=3 media=na_index-4x6_4x6in"),
+    ];
+    let mut printer = Printer::new(8);
+    printer
+        .run(&run_filter_with(&env, 1, "Darkness=5", &input))
+        .unwrap();
+    assert_eq!(printer.density, Some(5));
+    assert!(printer.labels[0].black(10, 10));
+
+    // Generated pages take their size from the media name.
+    let mut printer = Printer::new(8);
+    printer
+        .run(&run_filter_with(&env, 1, "tspl-test-page=alignment", b""))
+        .unwrap();
+    assert_eq!(printer.size_mm, Some((102.0, 153.0)));
+
+    let env = [("PPD", ""), ("TSPL_FILTER_MODEL", "12345")];
+    let output = filter_output(&env, 1, "", &input);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn runs_from_cups_filter2_lines() {
+    // CUPS reads `*cupsFilter2` lines in place of `*cupsFilter` ones, so each
+    // type the filter takes needs both.
+    let ppd = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+        .unwrap();
+    let sources = |key: &str| {
+        let mut types = ppd
+            .lines()
+            .filter_map(|line| line.strip_prefix(key))
+            .map(|line| line.trim().trim_matches('"').split(' ').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        types.sort();
+        types
+    };
+    let old = sources("*cupsFilter:");
+    let new = sources("*cupsFilter2:");
+    assert_eq!(old.len(), new.len());
+    for (old, new) in old.iter().zip(&new) {
+        assert_eq!(new[..], [old[0], "application/vnd.tspl", old[1], old[2]]);
+    }
+
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let env = [
+        ("CONTENT_TYPE", "image/pwg-raster"),
+        ("FINAL_CONTENT_TYPE", "application/vnd.tspl"),
+    ];
+    let mut printer = Printer::new(8);
+    printer.run(&run_filter_with(&env, 1, "", &input)).unwrap();
+    assert_eq!(printer.labels.len(), 1);
+
+    // Older `*cupsFilter` lines end the chain at the queue itself.
+    let env = [("FINAL_CONTENT_TYPE", "printer/sp420")];
+    assert!(filter_output(&env, 1, "", &input).status.success());
+
+    let env = [("FINAL_CONTENT_TYPE", "application/pdf")];
+    let output = filter_output(&env, 1, "", &input);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn logs_a_checksum_of_each_bitmap() {
+    let page = |size| {
+        Page::draw(K8, PAGE_4X6, 203, move |x, y| {
+            if x < size && y < size { 255 } else { 0 }
+        })
+    };
+    let input = raster::stream(&[page(100), page(100), page(50)]);
+    let output = filter_output(&[], 1, "", &input);
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    let crcs = log
+        .lines()
+        .filter_map(|line| line.strip_prefix("DEBUG: bitmap CRC-32 "))
+        .map(|rest| rest.split(' ').next().unwrap())
+        .collect::<Vec<_>>();
+    let [first, second, third] = crcs[..] else {
+        panic!("expected three checksums:\n{log}");
+    };
+    assert_eq!(first, second);
+    assert_ne!(first, third);
+}
+
+#[test]
+fn check_reports_without_printing() {
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 255)]);
+    let output = check_output("media-tracking=continuous", &input);
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(report.contains("model: Beeprt"), "{report}");
+    assert!(report.contains("812x1218 at 203dpi"), "{report}");
+    assert!(report.contains("  SIZE 102 mm,153 mm"), "{report}");
+    assert!(report.contains("  LIMITFEED"), "{report}");
+    // Nothing for the printer.
+    assert!(!report.contains("BITMAP"), "{report}");
+
+    let output = check_output("", b"not raster");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn deterministic_output() {
+    let options = "SetClock=True tspl-comments=true tspl-deterministic=true tspl-test-page=bars";
+    let first = run_filter(1, options, b"");
+    let second = run_filter(1, options, b"");
+    assert_eq!(first, second);
+
+    let mut printer = Printer::new(8);
+    printer.run(&first).unwrap();
+    assert!(printer.clock.is_empty());
+    assert_eq!(
+        printer.comments,
+        [concat!("raster-tspl ", env!("CARGO_PKG_VERSION"))]
+    );
+}
+
+#[test]
+fn logs_selected_options() {
+    let output = filter_output(&[], 1, "MediaType=Fanfold tspl-test-page=bars", b"");
+    let log = String::from_utf8_lossy(&output.stderr);
+    let selected = log
+        .lines()
+        .find_map(|line| line.strip_prefix("DEBUG: selected options: "))
+        .expect("no selected options in the log");
+    let selected = selected.split(' ').collect::<Vec<_>>();
+    assert!(selected.contains(&"MediaType=Fanfold"));
+    assert!(selected.contains(&"PageSize=w100h150"));
+}
+
+#[test]
+fn conflicts_are_resolved() {
+    let ppd = Path::new(env!("CARGO_TARGET_TMPDIR")).join("conflicts.ppd");
+    let mut text =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+            .unwrap();
+    text.push_str("*UIConstraints: *MediaType Fanfold *SetClock True\n");
+    text.push_str("*UIConstraints: *SetClock True *MediaType Fanfold\n");
+    std::fs::write(&ppd, text).unwrap();
+
+    let output = filter_output(
+        &[("PPD", ppd.to_str().unwrap())],
+        1,
+        "MediaType=Fanfold SetClock=True tspl-test-page=bars",
+        b"",
+    );
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        log.lines()
+            .any(|line| line.starts_with("WARNING: ") && line.ends_with("SetClock=False")),
+        "{log}"
+    );
+
+    let mut printer = Printer::new(8);
+    printer.run(&output.stdout).unwrap();
+    assert!(printer.clock.is_empty());
+    assert_eq!(printer.settings["TEAR"], "OFF");
+}
+
+#[test]
+fn imageable_area_raster_is_placed_within_margins() {
+    // 1/8" margins all round, with only what's inside them in the raster.
+    let mut page = Page::draw(
+        K8,
+        [270, 414],
+        203,
+        |x, y| {
+            if x < 8 && y < 8 { 255 } else { 0 }
+        },
+    );
+    page.header.PageSize = PAGE_4X6;
+    page.header.ImagingBoundingBox = [9, 9, 279, 423];
+    let printer = print("", &raster::stream(&[page]));
+
+    assert_eq!(printer.size_mm, Some((102.0, 152.0)));
+    assert_eq!(printer.reference, (25, 25));
+
+    // A whole page stays put.
+    let mut page = Page::draw(K8, PAGE_4X6, 203, |_, _| 0);
+    page.header.ImagingBoundingBox = [9, 9, 279, 423];
+    let printer = print("", &raster::stream(&[page]));
+    assert_eq!(printer.reference, (0, 0));
+}
+
+#[test]
+fn warns_about_missing_keywords() {
+    // The example PPD, without two of the settings every job looks for.
+    let stripped = Path::new(env!("CARGO_TARGET_TMPDIR")).join("stripped.ppd");
+    let text = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+        .unwrap()
+        .lines()
+        .filter(|line| !line.contains("PrintDarkness") && !line.contains("GapOrMarkHeight"))
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    std::fs::write(&stripped, text).unwrap();
+
+    let warnings = |env: &[(&str, &str)]| {
+        let output = filter_output(env, 1, "tspl-test-page=bars", b"");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .filter(|line| line.starts_with("WARNING: ") && line.contains("has no options"))
+            .map(str::to_owned)
+            .collect::<Vec<_>>()
+    };
+
+    // The example PPD has them all, in one spelling or another.
+    assert_eq!(warnings(&[]), [] as [String; 0]);
+
+    let [line] = &warnings(&[("PPD", stripped.to_str().unwrap())])[..] else {
+        panic!("expected one warning");
+    };
+    assert!(line.contains("Darkness"), "{line}");
+    assert!(line.contains("GapOrMarkHeight"), "{line}");
+
+    // Keywords the site configuration supplies aren't missing.
+    let config = Path::new(env!("CARGO_TARGET_TMPDIR")).join("darkness.conf");
+    std::fs::write(&config, "[defaults.options]\nDarkness = \"8\"\n").unwrap();
+    let env = [
+        ("PPD", stripped.to_str().unwrap()),
+        ("TSPL_FILTER_CONF", config.to_str().unwrap()),
+    ];
+    let [line] = &warnings(&env)[..] else {
+        panic!("expected one warning");
+    };
+    assert!(!line.contains("Darkness"), "{line}");
+}
+
+#[test]
+fn custom_darkness() {
+    let ppd = Path::new(env!("CARGO_TARGET_TMPDIR")).join("custom-darkness.ppd");
+    let mut text =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+            .unwrap();
+    text.push_str(
+        "*OpenUI *Darkness/Darkness: PickOne\n\
+         *DefaultDarkness: Default\n\
+         *Darkness Default/Printer Default: \"\"\n\
+         *Darkness 5/5: \"\"\n\
+         *CloseUI: *Darkness\n\
+         *CustomDarkness True: \"\"\n\
+         *ParamCustomDarkness Value/Darkness: 1 int 0 30\n",
+    );
+    std::fs::write(&ppd, text).unwrap();
+    let density = |options| {
+        let mut printer = Printer::new(8);
+        let env = [("PPD", ppd.to_str().unwrap())];
+        printer
+            .run(&run_filter_with(&env, 1, options, b""))
+            .unwrap();
+        printer.density
+    };
+
+    // A custom value goes through the same curve as a listed one.
+    let listed = density("tspl-test-page=bars Darkness=5");
+    assert_eq!(density("tspl-test-page=bars Darkness=Custom.5"), listed);
+    assert_ne!(density("tspl-test-page=bars Darkness=Custom.12"), listed);
+}
+
+#[test]
+fn vendor_spellings_in_the_ppd() {
+    // The example PPD's own names for Darkness, zePrintRate, FeedOffset, and
+    // zeMediaTracking.
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let printer = print(
+        "PrintDarkness=12 PrintSpeed=3 FowardOffset=-2 PaperType=3",
+        &input,
+    );
+    assert_eq!(printer.density, Some(12));
+    assert_eq!(printer.speed, Some(3.0));
+    assert_eq!(printer.offset_mm, -2.0);
+    assert_eq!(printer.media, Some(Media::Gap(0.0, 0.0)));
+
+    let printer = print("ShiftMove=3 Rotate=1", &input);
+    assert_eq!(printer.reference.1, 24);
+    assert_eq!(printer.direction.0, 1);
+
+    // "Use Currently Printer Setting" is the same as no choice.
+    let printer = print("PrintDarkness=None PrintSpeed=None", &input);
+    assert_eq!(printer.density, Some(8));
+    assert_eq!(printer.speed, Some(4.0));
+}
+
+#[test]
+fn raw_darkness_skips_the_curve() {
+    // The 300dpi head's curve starts at 2.
+    let raw = Path::new(env!("CARGO_TARGET_TMPDIR")).join("raw-darkness.ppd");
+    let text = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+        .unwrap()
+        .replace("*DefaultRawDarkness: False", "*DefaultRawDarkness: True");
+    std::fs::write(&raw, text).unwrap();
+
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 300, |_, _| 0)]);
+    let density = |env: &[(&str, &str)]| {
+        let mut printer = Printer::new(12);
+        printer
+            .run(&run_filter_with(env, 1, "PrintDarkness=0", &input))
+            .unwrap();
+        printer.density
+    };
+    assert_eq!(density(&[]), Some(2));
+    assert_eq!(density(&[("PPD", raw.to_str().unwrap())]), Some(0));
+}
+
+#[test]
+fn numeric_darkness_and_speed() {
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let printer = print("darkness=13 speed=3 print-darkness=-100", &input);
+    assert_eq!(printer.density, Some(13));
+    assert_eq!(printer.speed, Some(3.0));
+
+    for options in [
+        "darkness=16",
+        "darkness=-1",
+        "darkness=dark",
+        "speed=9",
+        "speed=fast",
+    ] {
+        let output = filter_output(&[], 1, options, &input);
+        assert!(!output.status.success(), "{options}");
+    }
+    // Choices of a PPD `Darkness` are left to the PPD.
+    let printer = print("darkness=Custom.12", &input);
+    assert_eq!(printer.density, Some(8));
+}
+
+#[test]
+fn output_can_be_paced() {
+    // A 100x100-dot label: 1250 bytes of bitmap, plus the commands.
+    let input = raster::stream(&[Page::draw(K8, [36, 36], 203, |_, _| 255)]);
+    let started = Instant::now();
+    let output = run_filter(1, "tspl-rate=4000", &input);
+    let elapsed = started.elapsed();
+    let least = Duration::from_secs_f64(output.len() as f64 / 4000.0 - 0.1);
+    assert!(elapsed >= least, "{} bytes in {elapsed:?}", output.len());
+
+    let mut printer = Printer::new(8);
+    printer.run(&output).unwrap();
+    assert!(printer.labels[0].black(50, 50));
+}
+
+#[test]
+fn lost_printer_is_retried() {
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 255)]);
+    let output = filter_output_disconnected("", &input);
+    // CUPS_BACKEND_RETRY, not a panic.
+    assert_eq!(output.status.code(), Some(6));
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("printer connection lost"), "{log}");
+}
+
+#[test]
+fn page_ranges() {
+    // Each page a different width of black.
+    let pages = [50, 100, 150, 200].map(|edge| {
+        Page::draw(
+            K8,
+            PAGE_4X6,
+            203,
+            move |x, _| if x < edge { 255 } else { 0 },
+        )
+    });
+    let input = raster::stream(&pages);
+    let printer = print("page-ranges=2,4-", &input);
+    assert_eq!(printer.labels.len(), 2);
+    assert!(printer.labels[0].black(99, 10) && !printer.labels[0].black(101, 10));
+    assert!(printer.labels[1].black(199, 10));
+
+    // Jobs that weren't raster have had their pages picked out already.
+    let env = [("CONTENT_TYPE", "application/pdf")];
+    let mut printer = Printer::new(8);
+    printer
+        .run(&run_filter_with(&env, 1, "page-ranges=2", &input))
+        .unwrap();
+    assert_eq!(printer.labels.len(), 4);
+
+    assert!(
+        !filter_output(&[], 1, "page-ranges=3-1", &input)
+            .status
+            .success()
+    );
+}
+
+#[test]
+fn reverse_output_order() {
+    // Each page a different width of black, and the last a different size.
+    let mut pages = [50, 100, 150].map(|edge| {
+        Page::draw(
+            K8,
+            PAGE_4X6,
+            203,
+            move |x, _| if x < edge { 255 } else { 0 },
+        )
+    });
+    pages[2] = Page::draw(K8, [144, 72], 203, |x, _| if x < 150 { 255 } else { 0 });
+    let input = raster::stream(&pages);
+    for options in ["outputorder=reverse", "OutputOrder=Reverse"] {
+        let printer = print(options, &input);
+        let edges = printer
+            .labels
+            .iter()
+            .map(|label| (0..300).find(|&x| !label.black(x, 10)))
+            .collect::<Vec<_>>();
+        assert_eq!(edges, [Some(150), Some(100), Some(50)], "{options}");
+        // Each label still gets its own size.
+        assert!(printer.labels[0].height < printer.labels[2].height);
+    }
+
+    // Jobs that weren't raster have been put in order already.
+    let env = [("CONTENT_TYPE", "application/pdf")];
+    let mut printer = Printer::new(8);
+    printer
+        .run(&run_filter_with(&env, 1, "outputorder=reverse", &input))
+        .unwrap();
+    assert!(printer.labels[0].black(40, 10) && !printer.labels[0].black(60, 10));
+}
+
+#[test]
+fn wide_pages_are_turned_to_fit() {
+    // 6"x4", landscape, with a mark in the top right corner.
+    let pages = [Page::draw(K8, [432, 288], 203, |x, y| {
+        if x > 1100 && y < 100 { 255 } else { 0 }
+    })];
+    let input = raster::stream(&pages);
+    let printer = print("oversize=rotate", &input);
+    assert_eq!(printer.size_mm, Some((102.0, 153.0)));
+    let label = &printer.labels[0];
+    assert!(label.black(50, 50));
+    assert!(!label.black(50, 1150) && !label.black(750, 50));
+
+    // A page that fits is left alone.
+    let pages = [Page::draw(
+        K8,
+        PAGE_4X6,
+        203,
+        |_, y| if y < 100 { 255 } else { 0 },
+    )];
+    let printer = print("oversize=rotate", &raster::stream(&pages));
+    assert_eq!(printer.size_mm, Some((102.0, 153.0)));
+    assert!(printer.labels[0].black(750, 50));
+}
+
+#[test]
+fn unsupported_options_are_warned_about() {
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let output = filter_output(&[], 2, "sides=two-sided-long-edge Collate=True", &input);
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("ignoring sides=two-sided-long-edge"), "{log}");
+    assert!(log.contains("ignoring Collate=True"), "{log}");
+
+    // Nothing to say when they ask for what happens anyway.
+    let output = filter_output(&[], 1, "sides=one-sided Collate=True", &input);
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(!log.contains("ignoring"), "{log}");
+}
+
+#[test]
+fn unchanged_settings_are_sent_once() {
+    let pages = [50, 100, 150].map(|edge| {
+        Page::draw(
+            K8,
+            PAGE_4X6,
+            203,
+            move |x, _| if x < edge { 255 } else { 0 },
+        )
+    });
+    let input = raster::stream(&pages);
+    let output = run_filter(1, "", &input);
+    let text = String::from_utf8_lossy(&output);
+    for setting in ["SIZE ", "GAP ", "DENSITY ", "SPEED ", "REFERENCE "] {
+        assert_eq!(text.matches(setting).count(), 1, "{setting}");
+    }
+    let mut printer = Printer::new(8);
+    printer.run(&output).unwrap();
+    assert_eq!(printer.labels.len(), 3);
+    assert!(printer.labels[2].black(120, 10));
+}
+
+#[test]
+fn cancelled_while_the_printer_is_stalled() {
+    // Enough labels, each with plenty of bitmap, to more than fill the pipe.
+    let pages = (0..8)
+        .map(|n| {
+            Page::draw(K8, PAGE_4X6, 203, move |x, y| {
+                if (x * 7 + y * 13 + n) % 5 < 2 { 255 } else { 0 }
+            })
+        })
+        .collect::<Vec<_>>();
+    let input = raster::stream(&pages);
+    let (status, elapsed) = cancel_stalled_filter("", &input, Duration::from_millis(500));
+    assert!(status.success(), "{status}");
+    assert!(elapsed < Duration::from_millis(500), "{elapsed:?}");
+}
+
+#[test]
+fn bare_line_feeds_for_clone_firmware() {
+    let pages = [Page::draw(K8, PAGE_4X6, 203, |x, y| {
+        if x < 100 && y < 100 { 255 } else { 0 }
+    })];
+    let input = raster::stream(&pages);
+    // A printer that wants CR LF wouldn't make sense of it...
+    let output = run_filter(1, "tspl-line-ending=lf", &input);
+    assert!(Printer::new(8).run(&output).is_err());
+
+    // ...but one that wants LF alone does, and would choke on a CR.
+    let mut printer = Printer::new(8);
+    printer.bare_lf = true;
+    let printer = print_on(printer, 1, "tspl-line-ending=lf", &input);
+    assert!(printer.labels[0].black(10, 10));
+}
+
+#[test]
+fn border_outlines_the_label() {
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let printer = print("tspl-border=true", &input);
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    let (right, bottom) = (label.width - 1, label.height - 1);
+    for (x, y) in [
+        (0, 0),
+        (right, 0),
+        (0, bottom),
+        (right, bottom),
+        (right / 2, 0),
+    ] {
+        assert!(label.black(x, y), "no border at {x},{y}");
+    }
+    assert!(!label.black(1, 1));
+    assert!(!label.black(right / 2, bottom / 2));
+
+    let printer = print("", &input);
+    assert!(!printer.labels[0].black(0, 0));
+}
+
+#[test]
+fn display_needs_a_display() {
+    // The SP420 has none, so the option is ignored, not sent.
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let output = filter_output(&[], 1, "tspl-display=progress", &input);
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        log.lines()
+            .any(|line| line.starts_with("WARNING: ") && line.contains("tspl-display")),
+        "{log}"
+    );
+    Printer::new(8).run(&output.stdout).unwrap();
+}
+
+#[test]
+fn strict_mode_leaves_out_setc() {
+    let input = raster::stream(&[Page::draw(
+        K8,
+        PAGE_4X6,
+        203,
+        |x, _| {
+            if x < 100 { 255 } else { 0 }
+        },
+    )]);
+    let printer = print("", &input);
+    assert!(printer.settings.contains_key("AUTODOTTED"));
+
+    let output = run_filter(1, "tspl-strict=true", &input);
+    assert!(!String::from_utf8_lossy(&output).contains("SETC"));
+    let mut printer = Printer::new(8);
+    printer.run(&output).unwrap();
+    assert!(printer.settings.is_empty());
+    assert!(printer.labels[0].black(10, 10));
+}
+
+#[test]
+fn self_test_after_the_job() {
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let printer = print("tspl-self-test=diagnostic", &input);
+    assert_eq!(printer.labels.len(), 1);
+    assert_eq!(printer.reports, ["DIAGNOSTIC"]);
+
+    // On its own, the input isn't even read.
+    let printer = print("tspl-self-test=selftest tspl-self-test-only=true", b"");
+    assert!(printer.labels.is_empty());
+    assert_eq!(printer.reports, ["SELFTEST"]);
+
+    let output = filter_output(&[], 1, "tspl-self-test-only=true", &input);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn keywords_in_other_spellings() {
+    let ppd = Path::new(env!("CARGO_TARGET_TMPDIR")).join("print-rate.ppd");
+    let mut text =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+            .unwrap();
+    text.push_str(
+        "*OpenUI *PrintRate/Print Rate: PickOne\n\
+         *DefaultPrintRate: 2\n\
+         *PrintRate 2/2 in/sec: \"\"\n\
+         *PrintRate 5/5 in/sec: \"\"\n\
+         *CloseUI: *PrintRate\n",
+    );
+    std::fs::write(&ppd, text).unwrap();
+
+    let output = run_filter_with(
+        &[("PPD", ppd.to_str().unwrap())],
+        1,
+        "tspl-test-page=bars",
+        b"",
+    );
+    let mut printer = Printer::new(8);
+    printer.run(&output).unwrap();
+    assert_eq!(printer.speed, Some(2.0));
+
+    // The example PPD has neither spelling.
+    assert_eq!(print("tspl-test-page=bars", b"").speed, Some(4.0));
+}
+
+#[test]
+fn tall_labels_trimmed_to_content() {
+    // A page five feet long, which holds more bitmap than the filter keeps in
+    // memory, inked down the left edge for most of its length.
+    let config = Path::new(env!("CARGO_TARGET_TMPDIR")).join("content-length.conf");
+    std::fs::write(
+        &config,
+        "[defaults.options]\n\
+         ContinuousLength = \"Content\"\n",
+    )
+    .unwrap();
+    let input = raster::stream(&[Page::draw(K8, [288, 4320], 203, |x, y| {
+        if x < 8 && y < 10_000 { 255 } else { 0 }
+    })]);
+    let output = run_filter_with(
+        &[("TSPL_FILTER_CONF", config.to_str().unwrap())],
+        1,
+        "media-tracking=continuous",
+        &input,
+    );
+    let mut printer = Printer::new(8);
+    printer.run(&output).unwrap();
+
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    assert_eq!(label.height, 10_000);
+    assert!(label.black(0, 0) && label.black(7, 9_999));
+    assert!(!label.black(8, 5_000));
+}
+
+#[test]
+fn continuous_length_from_the_ppd() {
+    let ppd = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+        .unwrap();
+    assert!(ppd.contains("*OpenUI *ContinuousLength/"));
+    assert!(ppd.contains("*ContinuousLength Content/"));
+
+    let input = raster::stream(&[Page::draw(
+        K8,
+        PAGE_4X6,
+        203,
+        |_, y| {
+            if y < 400 { 255 } else { 0 }
+        },
+    )]);
+    let printer = print("media-tracking=continuous ContinuousLength=Content", &input);
+    assert_eq!(printer.labels[0].height, 400);
+}
+
+#[test]
+fn bitmap_format_for_clone_firmware() {
+    // A ramp of short bars, so that mirrored bytes or a negative would show.
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |x, y| {
+        if x % 16 < y % 16 { 255 } else { 0 }
+    })]);
+    let normal = print("", &input);
+
+    let mut clone = Printer::new(8);
+    clone.lsb_first = true;
+    clone.inverted = true;
+    let clone = print_on(
+        clone,
+        1,
+        "tspl-bit-order=lsb tspl-polarity=inverted",
+        &input,
+    );
+    assert_eq!(clone.labels[0].black, normal.labels[0].black);
+
+    // The same output looks wrong on a printer that reads it as TSPL says.
+    let mismatched = print("tspl-bit-order=lsb tspl-polarity=inverted", &input);
+    assert_ne!(mismatched.labels[0].black, normal.labels[0].black);
+}
+
+#[test]
+fn printer_profiles_add_models() {
+    // A clone with its own model number, described by a profile rather than
+    // the built-in registry.
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("profiles");
+    std::fs::create_dir_all(&dir).unwrap();
+    let ppd = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+        .unwrap()
+        .replace("*cupsModelNumber: 37155", "*cupsModelNumber: 40000");
+    std::fs::write(dir.join("clone.ppd"), ppd).unwrap();
+    std::fs::write(
+        dir.join("clone.toml"),
+        r#"
+            name = "Clone"
+            model-numbers = [40000, 40000]
+            family = "beeprt"
+            ppd = "clone.ppd"
+            line-ending = "lf"
+            bit-order = "lsb"
+            # This one wants the darkness and speed before anything else.
+            setup-template = """
+                DENSITY {density}
+                SPEED {speed}
+                SIZE {width_mm} mm,{height_mm} mm
+                {media}
+            """
+
+            [[heads]]
+            dpi = 203
+            default-speed = 4
+            default-darkness = 8
+        "#,
+    )
+    .unwrap();
+    let ppd_path = dir.join("clone.ppd");
+    let env = [
+        ("PPD", ppd_path.to_str().unwrap()),
+        ("TSPL_FILTER_MODELS", dir.to_str().unwrap()),
+    ];
+
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |x, y| {
+        if x % 16 < y % 16 { 255 } else { 0 }
+    })]);
+    let normal = print("", &input);
+    let mut clone = Printer::new(8);
+    clone.bare_lf = true;
+    clone.lsb_first = true;
+    let output = run_filter_with(&env, 1, "", &input);
+    assert!(
+        output.windows(20).any(|w| w == b"DENSITY 8\nSPEED 4\nSI"),
+        "{}",
+        output.escape_ascii()
+    );
+    clone.run(&output).unwrap();
+    assert_eq!(clone.labels[0].black, normal.labels[0].black);
+
+    // Without the profile, the model is unknown.
+    let output = filter_output(&env[..1], 1, "", &input);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn peeling_prints_copies_one_at_a_time() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("peeler");
+    std::fs::create_dir_all(&dir).unwrap();
+    let ppd = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"))
+        .unwrap()
+        .replace(
+            "*CloseUI: *HeadOpenSensor\n",
+            "*CloseUI: *HeadOpenSensor\n\
+             *OpenUI *Peel/Peel Labels: PickOne\n\
+             *OrderDependency: 205 AnySetup *Peel\n\
+             *DefaultPeel: PrinterDefault\n\
+             *Peel PrinterDefault/Printer Default: \"\"\n\
+             *Peel On/On: \"\"\n\
+             *Peel Off/Off: \"\"\n\
+             *CloseUI: *Peel\n",
+        );
+    std::fs::write(dir.join("sp420.ppd"), &ppd).unwrap();
+    std::fs::write(
+        dir.join("dispenser.ppd"),
+        ppd.replace("*cupsModelNumber: 37155", "*cupsModelNumber: 40001"),
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("dispenser.toml"),
+        r#"
+            name = "Dispenser"
+            model-numbers = [40001, 40001]
+            family = "beeprt"
+            ppd = "dispenser.ppd"
+            peeler = true
+
+            [[heads]]
+            dpi = 203
+            default-speed = 4
+            default-darkness = 8
+        "#,
+    )
+    .unwrap();
+    let input = raster::stream(&[Page::draw(
+        K8,
+        PAGE_4X6,
+        203,
+        |x, _| {
+            if x < 100 { 0 } else { 255 }
+        },
+    )]);
+
+    let ppd_path = dir.join("dispenser.ppd");
+    let env = [
+        ("PPD", ppd_path.to_str().unwrap()),
+        ("TSPL_FILTER_MODELS", dir.to_str().unwrap()),
+    ];
+    let output = run_filter_with(&env, 3, "Peel=On", &input);
+    let text = String::from_utf8_lossy(&output);
+    assert_eq!(text.matches("PRINT 1,1\r\n").count(), 3, "{text}");
+    let mut printer = Printer::new(8);
+    printer.run(&output).unwrap();
+    assert_eq!(printer.settings["PEEL"], "ON");
+    assert_eq!(printer.labels.len(), 3);
+
+    // The SP420 has no peeler, so the option is ignored.
+    let ppd_path = dir.join("sp420.ppd");
+    let output = filter_output(&[("PPD", ppd_path.to_str().unwrap())], 3, "Peel=On", &input);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no peeler"), "{stderr}");
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(!text.contains("PEEL"), "{text}");
+    assert!(text.contains("PRINT 3,1\r\n"), "{text}");
+}
+
+#[test]
+fn cutting_with_the_cutter_ppd() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cutter");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("cutter.toml"),
+        r#"
+            name = "Cutter"
+            model-numbers = [39990, 39990]
+            family = "beeprt"
+            ppd = "beeprt-cutter-peeler.tspl.ppd"
+            cutter = "full"
+
+            [[heads]]
+            dpi = 203
+            default-speed = 4
+            default-darkness = 8
+        "#,
+    )
+    .unwrap();
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let env = [
+        (
+            "PPD",
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/ppd/beeprt-cutter-peeler.tspl.ppd"
+            ),
+        ),
+        ("TSPL_FILTER_MODELS", dir.to_str().unwrap()),
+    ];
+    let mut printer = Printer::new(8);
+    printer
+        .run(&run_filter_with(&env, 1, "Cutter=Full", &input))
+        .unwrap();
+    assert_eq!(printer.settings["CUTTER"], "1");
+
+    // The SP420 has no cutter to turn off, and no peeler either.
+    let output = filter_output(&[], 1, "Cutter=Off Peel=Off", &input);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("WARNING"), "{stderr}");
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(!text.contains("CUTTER") && !text.contains("PEEL"), "{text}");
+}
+
+#[test]
+fn tspl_jobs_pass_through() {
+    let job = b"SIZE 50 mm,30 mm\r\nGAP 2 mm,0 mm\r\nCLS\r\nBOX 0,0,99,99,2\r\nPRINT 1,1\r\n";
+    let output = run_filter(2, "", job);
+    assert!(output.ends_with(job), "{}", output.escape_ascii());
+    let mut printer = Printer::new(8);
+    printer.run(&output).unwrap();
+    assert_eq!(printer.size_mm, Some((50.0, 30.0)));
+    assert_eq!(printer.media, Some(Media::Gap(2.0, 0.0)));
+    // The queue's darkness, since the job doesn't say.
+    assert_eq!(printer.density, Some(8));
+    assert_eq!(printer.labels.len(), 2);
+    assert!(printer.labels[0].black(0, 0) && !printer.labels[0].black(50, 50));
+
+    // The queue's label size fills in for a job that leaves it out, with
+    // CUPS saying what the job is.
+    let output = filter_output(
+        &[("CONTENT_TYPE", "application/vnd.tspl")],
+        1,
+        "",
+        b"CLS\r\nPRINT 1\r\n",
+    );
+    assert!(output.status.success());
+    let mut printer = Printer::new(8);
+    printer.run(&output.stdout).unwrap();
+    assert_eq!(printer.size_mm, Some((100.0, 150.0)));
+
+    let output = filter_output(&[], 1, "", b"SIZE 50 mm,30 mm\r\nCLS\r\n");
+    assert!(!output.status.success());
+    let output = filter_output(
+        &[("CONTENT_TYPE", "application/vnd.tspl")],
+        1,
+        "",
+        b"hello\n",
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn images_print_directly() {
+    // A 2x3 PNG, the proportions of the default 100x150 mm label: black on
+    // the left, transparent on the right.
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, 2, 3);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer
+        .write_image_data(&[0, 0, 0, 255, 0, 0, 0, 0].repeat(3))
+        .unwrap();
+    writer.finish().unwrap();
+
+    let printer = print("", &png);
+    assert_eq!(printer.size_mm, Some((100.0, 150.0)));
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    let (w, h) = (label.width, label.height);
+    // The page is a little smaller than the label, so stay clear of the edges.
+    assert!(label.black(w / 4, h / 2) && label.black(8, 8) && label.black(8, h - 8));
+    assert!(!label.black(w * 3 / 4, h / 2) && !label.black(w - 8, 8));
+
+    // Mid gray, in a JPEG, comes out as about half ink.
+    let mut jpeg = vec![];
+    jpeg_encoder::Encoder::new(&mut jpeg, 90)
+        .encode(&[128; 32 * 48], 32, 48, jpeg_encoder::ColorType::Luma)
+        .unwrap();
+    let printer = print("", &jpeg);
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    let inked = label.black.iter().filter(|&&black| black).count();
+    let half = label.black.len() / 2;
+    assert!(
+        inked.abs_diff(half) < half / 20,
+        "{inked} of {}",
+        label.black.len()
+    );
+
+    let output = filter_output(&[("CONTENT_TYPE", "image/png")], 1, "", b"not a PNG");
+    assert!(!output.status.success());
+    let output = filter_output(&[], 1, "", b"\x89PNG\r\n\x1A\ntruncated");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn png_overlays() {
+    // A 100-dot square, black on the left half and transparent on the right.
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, 100, 100);
+    encoder.set_color(png::ColorType::GrayscaleAlpha);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    let row = [[0, 255]; 50].concat().into_iter().chain([0; 100]);
+    writer
+        .write_image_data(&row.collect::<Vec<_>>().repeat(100))
+        .unwrap();
+    writer.finish().unwrap();
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("overlay.png");
+    std::fs::write(&path, png).unwrap();
+
+    let blank = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let options = format!(
+        "overlay-image={} overlay-position=25.4,25.4",
+        path.display()
+    );
+    let printer = print(&options, &blank);
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    // The page sits a little inside the label, so stay clear of the edges of
+    // the overlay.
+    assert!(label.black(203 + 25, 203 + 50));
+    assert!(!label.black(203 + 75, 203 + 50));
+    assert!(!label.black(150, 150));
+}
+
+#[test]
+fn blank_pages_can_be_skipped() {
+    let inked = || Page::draw(K8, PAGE_4X6, 203, |x, _| if x < 8 { 255 } else { 0 });
+    let blank = Page::draw(K8, PAGE_4X6, 203, |_, _| 0);
+    let input = raster::stream(&[inked(), blank, inked()]);
+
+    let all = print("", &input);
+    assert_eq!(all.labels.len(), 3);
+    let printer = print("SkipBlankPages=True", &input);
+    assert_eq!(printer.labels.len(), 2);
+    // The labels left are as they'd have been, not trimmed to their ink.
+    for label in &printer.labels {
+        assert_eq!(label.height, all.labels[0].height);
+        assert_eq!(label.black, all.labels[0].black);
+    }
+}
+
+#[test]
+fn previews_are_saved() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("previews");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("previews.conf");
+    std::fs::write(
+        &config,
+        format!("[defaults]\npreview-dir = {:?}\n", dir.to_str().unwrap()),
+    )
+    .unwrap();
+    // Ink down the left quarter of two pages.
+    let page = || Page::draw(K8, PAGE_4X6, 203, |x, _| if x < 203 { 255 } else { 0 });
+    run_filter_with(
+        &[
+            ("TSPL_FILTER_CONF", config.to_str().unwrap()),
+            ("PRINTER", "labels"),
+        ],
+        1,
+        "",
+        &raster::stream(&[page(), page()]),
+    );
+
+    for n in 1..=2 {
+        let file = File::open(dir.join(format!("labels-1-{n}.png"))).unwrap();
+        let mut reader = png::Decoder::new(std::io::BufReader::new(file))
+            .read_info()
+            .unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        // A quarter of the size of the 816x1218 dot bitmap.
+        assert_eq!((info.width, info.height), (204, 305));
+        let row = &pixels[100 * info.line_size..];
+        assert_eq!(row[..3], [0, 0, 0]);
+        assert_eq!(row[100 * 3..103 * 3], [255; 9]);
+    }
+    assert!(!dir.join("labels-1-3.png").exists());
+}
+
+#[test]
+fn settings_are_resolved_once_per_job() {
+    let page = || Page::draw(K8, PAGE_4X6, 203, |x, _| if x < 8 { 255 } else { 0 });
+    let output = filter_output(&[], 1, "", &raster::stream(&[page(), page(), page()]));
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    let resolved = log
+        .lines()
+        .filter(|line| line.starts_with("DEBUG: settings at 203dpi: "))
+        .collect::<Vec<_>>();
+    let [line] = &resolved[..] else {
+        panic!("expected settings to be logged once, got {resolved:?}");
+    };
+    assert!(line.contains("Gap tracking"), "{line}");
+}
+
+#[test]
+fn waits_for_the_printer_to_be_ready() {
+    // Out of paper when first asked, then ready, then not answering.
+    let replies = Path::new(env!("CARGO_TARGET_TMPDIR")).join("status-replies");
+    std::fs::write(&replies, [0x04, 0x00]).unwrap();
+    let page = || Page::draw(K8, PAGE_4X6, 203, |x, _| if x < 8 { 255 } else { 0 });
+    let input = raster::stream(&[page(), page(), page()]);
+
+    let output = filter_output_with_back_channel("tspl-wait-ready=true", &input, &replies, None);
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        log.contains("INFO: waiting for the printer: out of paper"),
+        "{log}"
+    );
+    assert!(log.contains("INFO: the printer is ready again"), "{log}");
+    assert!(log.contains("DEBUG: no status from the printer"), "{log}");
+
+    let mut printer = Printer::new(8);
+    printer.run(&output.stdout).unwrap();
+    assert_eq!(printer.labels.len(), 3);
+    // Twice for the first page, and once more before giving up.
+    assert_eq!(printer.immediate, ['?'; 3]);
+
+    // Not unless asked.
+    let output = filter_output_with_back_channel("", &input, &replies, None);
+    let mut printer = Printer::new(8);
+    printer.run(&output.stdout).unwrap();
+    assert!(printer.immediate.is_empty());
+
+    // Nor when the backend says the printer can't answer, though the backend
+    // can still say what it knows.
+    let (filter_end, mut backend_end) = UnixStream::pair().unwrap();
+    let backend = std::thread::spawn(move || {
+        // Out of paper when first asked, then fine.
+        let mut states = [0x21, 0x01, 0x01, 0x01].into_iter();
+        let mut commands = vec![];
+        let mut request = [0; 4];
+        while backend_end.read_exact(&mut request).is_ok() {
+            let command = request[0];
+            commands.push(command);
+            // The command, CUPS_SC_STATUS_OK, and the data's length and data.
+            let reply = match command {
+                // CUPS_SC_CMD_GET_BIDI: CUPS_SC_BIDI_NOT_SUPPORTED.
+                3 => vec![command, 1, 0, 1, 0],
+                // CUPS_SC_CMD_GET_STATE.
+                5 => vec![command, 1, 0, 1, states.next().unwrap()],
+                _ => vec![command, 1, 0, 0],
+            };
+            backend_end.write_all(&reply).unwrap();
+        }
+        commands
+    });
+    let output =
+        filter_output_with_back_channel("tspl-wait-ready=true", &input, &replies, Some(filter_end));
+    // Whether the printer can answer, its state twice for the first page and
+    // once each for the others, and to drain the output at the end.
+    assert_eq!(backend.join().unwrap(), [3, 5, 5, 5, 5, 2]);
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        log.contains("INFO: waiting for the printer: out of paper"),
+        "{log}"
+    );
+    let mut printer = Printer::new(8);
+    printer.run(&output.stdout).unwrap();
+    assert!(printer.immediate.is_empty());
+    assert_eq!(printer.labels.len(), 3);
+}
+
+#[test]
+fn calibrates_when_the_stock_changes() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("calibration");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let env = [
+        ("CUPS_CACHEDIR", dir.to_str().unwrap()),
+        ("PRINTER", "labels"),
+    ];
+    let input = raster::stream(&[Page::draw(K8, PAGE_4X6, 203, |_, _| 0)]);
+    let calibrations = |options: &str| {
+        let mut printer = Printer::new(8);
+        printer
+            .run(&run_filter_with(&env, 1, options, &input))
+            .unwrap();
+        printer.calibrations
+    };
+
+    assert_eq!(calibrations("tspl-calibrate=auto"), ["GAPDETECT"]);
+    assert!(dir.join("tspl-labels.calibration").exists());
+    // The same stock again needn't be.
+    assert!(calibrations("tspl-calibrate=auto").is_empty());
+    assert_eq!(
+        calibrations("tspl-calibrate=auto media-tracking=mark"),
+        ["BLINEDETECT"]
+    );
+    assert_eq!(
+        calibrations("tspl-calibrate=always media-tracking=mark"),
+        ["BLINEDETECT"]
+    );
+    // An interval of 0 hours has always passed.
+    assert_eq!(
+        calibrations("tspl-calibrate=auto media-tracking=mark tspl-calibrate-interval=0"),
+        ["BLINEDETECT"]
+    );
+    assert!(calibrations("").is_empty());
+}
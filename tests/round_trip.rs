@@ -0,0 +1,234 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Round trips: a page of raster goes through the filter and the virtual
+//! printer, and the printed label has to match it dot for dot.
+//!
+//! The filter thresholds rather than dithers, so there's no tolerance: the test
+//! pattern keeps its grays well away from the threshold, and every dot has to
+//! come out as expected. On a mismatch, the expected and printed labels are
+//! saved as PNGs next to the test binaries.
+
+mod support;
+
+use support::{
+    print_on,
+    printer::Printer,
+    raster::{self, Format, K1, K8, Page, RGB8, SRGB8, SW8, W8},
+    save_png,
+};
+
+/// 4"x6", the example PPD's default page size, in points.
+const PAGE_4X6: [u32; 2] = [288, 432];
+
+/// Ink levels for a pattern that can't be mistaken for itself shifted,
+/// mirrored, or turned around, on a page `width` by `height` pixels.
+fn pattern(width: u32, height: u32) -> impl Fn(u32, u32) -> u8 {
+    move |x, y| {
+        let checkers = (16..32).contains(&x) && (16..24).contains(&y);
+        let light = (width * 3 / 4..width - 8).contains(&x) && (40..80).contains(&y);
+        let dark = (8..width / 4).contains(&x) && (height - 80..height - 40).contains(&y);
+        if x == 0 || y == 0 || x == width - 1 || x == y / 2 {
+            // Top, left, and right edges, but not the bottom, and a shallow
+            // diagonal.
+            255
+        } else if checkers {
+            if (x + y) % 2 == 0 { 255 } else { 0 }
+        } else if light {
+            64
+        } else if dark {
+            192
+        } else {
+            0
+        }
+    }
+}
+
+/// Prints a page of the test pattern and checks the label against it.
+fn round_trip(name: &str, format: Format, size_pt: [u32; 2], dpi: u32) {
+    let dots_per_mm = if dpi == 300 { 12 } else { 8 };
+    round_trip_with(
+        name,
+        format,
+        size_pt,
+        dpi,
+        "",
+        Printer::new(dots_per_mm),
+        false,
+    );
+}
+
+/// Prints a page of the test pattern with `options` on `printer`, and checks
+/// the label against it, turned half a turn if it should come out
+/// `upside_down`.
+fn round_trip_with(
+    name: &str,
+    format: Format,
+    size_pt: [u32; 2],
+    dpi: u32,
+    options: &str,
+    printer: Printer,
+    upside_down: bool,
+) {
+    let (width, height) = (size_pt[0] * dpi / 72, size_pt[1] * dpi / 72);
+    let level = pattern(width, height);
+    let page = Page::draw(format, size_pt, dpi, &level);
+    assert_eq!(
+        (page.header.cupsWidth, page.header.cupsHeight),
+        (width, height)
+    );
+
+    let printer = print_on(printer, 1, options, &raster::stream(&[page]));
+    let [label] = &printer.labels[..] else {
+        panic!("expected one label, got {}", printer.labels.len());
+    };
+    assert!(label.width >= width && label.height >= height);
+    assert!(label.red.iter().all(|red| !red));
+
+    let upright = |x, y| x < width && y < height && level(x, y) >= 128;
+    let expected = |x, y| {
+        if upside_down {
+            upright(label.width - 1 - x, label.height - 1 - y)
+        } else {
+            upright(x, y)
+        }
+    };
+    let wrong = (0..label.height)
+        .flat_map(|y| (0..label.width).map(move |x| (x, y)))
+        .filter(|&(x, y)| label.black(x, y) != expected(x, y))
+        .collect::<Vec<_>>();
+    if !wrong.is_empty() {
+        let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"));
+        let shade = |ink: bool| if ink { [0; 3] } else { [255; 3] };
+        let expected_png = dir.join(format!("{name}-expected.png"));
+        let printed_png = dir.join(format!("{name}-printed.png"));
+        save_png(&expected_png, label.width, label.height, |x, y| {
+            shade(expected(x, y))
+        });
+        save_png(&printed_png, label.width, label.height, |x, y| {
+            shade(label.black(x, y))
+        });
+        panic!(
+            "{} dots wrong, first at {:?}; see {} and {}",
+            wrong.len(),
+            wrong[0],
+            expected_png.display(),
+            printed_png.display()
+        );
+    }
+}
+
+#[test]
+fn black_8bit() {
+    round_trip("black_8bit", K8, PAGE_4X6, 203);
+}
+
+#[test]
+fn black_1bit() {
+    round_trip("black_1bit", K1, PAGE_4X6, 203);
+}
+
+#[test]
+fn white_8bit() {
+    round_trip("white_8bit", W8, PAGE_4X6, 203);
+}
+
+#[test]
+fn srgb_white_8bit() {
+    round_trip("srgb_white_8bit", SW8, PAGE_4X6, 203);
+}
+
+#[test]
+fn rgb_8bit() {
+    round_trip("rgb_8bit", RGB8, PAGE_4X6, 203);
+}
+
+#[test]
+fn srgb_8bit() {
+    round_trip("srgb_8bit", SRGB8, PAGE_4X6, 203);
+}
+
+#[test]
+fn head_300dpi() {
+    round_trip("head_300dpi", K8, PAGE_4X6, 300);
+}
+
+#[test]
+fn landscape() {
+    round_trip("landscape", K8, [432, 288], 203);
+}
+
+#[test]
+fn narrow_odd_width() {
+    // 57mm x 32mm, which is 453 dots across: not a whole number of bytes.
+    round_trip("narrow_odd_width", K1, [161, 90], 203);
+}
+
+#[test]
+fn narrow_odd_width_8bit() {
+    // The right edge is inked, so dark padding at the end of each row would
+    // show as a fringe.
+    round_trip("narrow_odd_width_8bit", K8, [161, 90], 203);
+}
+
+#[test]
+fn narrow_odd_width_rgb() {
+    round_trip("narrow_odd_width_rgb", RGB8, [161, 90], 203);
+}
+
+#[test]
+fn black_1bit_msb_first() {
+    round_trip_with(
+        "black_1bit_msb_first",
+        K1,
+        PAGE_4X6,
+        203,
+        "tspl-bit-order=msb",
+        Printer::new(8),
+        false,
+    );
+}
+
+#[test]
+fn black_1bit_lsb_first() {
+    let mut printer = Printer::new(8);
+    printer.lsb_first = true;
+    round_trip_with(
+        "black_1bit_lsb_first",
+        K1,
+        PAGE_4X6,
+        203,
+        "tspl-bit-order=lsb",
+        printer,
+        false,
+    );
+}
+
+#[test]
+fn upside_down() {
+    round_trip_with(
+        "upside_down",
+        K8,
+        PAGE_4X6,
+        203,
+        "Rotate=1",
+        Printer::new(8),
+        true,
+    );
+}
+
+#[test]
+fn upside_down_narrow_odd_width() {
+    // Turned, the end of each row is the left edge, so padding there would
+    // show as a fringe.
+    round_trip_with(
+        "upside_down_narrow_odd_width",
+        K1,
+        [161, 90],
+        203,
+        "Rotate=1",
+        Printer::new(8),
+        true,
+    );
+}
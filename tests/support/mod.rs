@@ -0,0 +1,271 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Shared pieces of the integration tests: a way to make raster, a way to run
+//! the filter over it, and a virtual printer to run the result.
+
+// Each test binary uses a different subset of this.
+#![allow(dead_code)]
+
+pub mod printer;
+pub mod raster;
+
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{BufWriter, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    path::Path,
+    process::{Command, ExitStatus, Output, Stdio},
+    time::{Duration, Instant},
+};
+
+use printer::Printer;
+use raster_tspl::{tspl::LineEnding, validate::Validator};
+
+/// Runs the filter with the example PPD over `input`, making `copies` copies
+/// with job options `options`, and returns its output.
+///
+/// Panics if the filter fails.
+pub fn run_filter(copies: u32, options: &str, input: &[u8]) -> Vec<u8> {
+    run_filter_with(&[], copies, options, input)
+}
+
+/// Runs the filter like [`run_filter`], with extra environment variables.
+///
+/// Unless `env` says otherwise, the filter runs without a site configuration,
+/// printer profiles, or a queue name, so that whatever's installed doesn't leak
+/// into the tests.
+pub fn run_filter_with(env: &[(&str, &str)], copies: u32, options: &str, input: &[u8]) -> Vec<u8> {
+    let output = filter_output(env, copies, options, input);
+    assert!(
+        output.status.success(),
+        "filter failed ({}):\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+/// Runs the filter like [`run_filter`], reading its input from the file at
+/// `path` rather than stdin.
+pub fn run_filter_on_file(options: &str, path: &Path) -> Vec<u8> {
+    run_filter_on_files(options, &[path.as_os_str()])
+}
+
+/// Runs the filter like [`run_filter`], with `args` (files, or `@` and a
+/// file listing files) for its input rather than stdin.
+pub fn run_filter_on_files(options: &str, args: &[&OsStr]) -> Vec<u8> {
+    let output = filter_command(&[], 1, options)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .expect("can't run the filter");
+    assert!(
+        output.status.success(),
+        "filter failed ({}):\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+/// Runs the filter like [`run_filter_with`], whether or not it succeeds.
+pub fn filter_output(env: &[(&str, &str)], copies: u32, options: &str, input: &[u8]) -> Output {
+    output_for_input(filter_command(env, copies, options), input)
+}
+
+/// Runs the filter with `--check` over `input`, whether or not it succeeds.
+pub fn check_output(options: &str, input: &[u8]) -> Output {
+    output_for_input(filter_command_with(&["--check"], &[], 1, options), input)
+}
+
+/// Runs the filter like [`filter_output`], with `replies` as what the printer
+/// says on the back channel, and `side_channel`, if given, as the filter's end
+/// of the side channel to the backend.
+pub fn filter_output_with_back_channel(
+    options: &str,
+    input: &[u8],
+    replies: &Path,
+    side_channel: Option<UnixStream>,
+) -> Output {
+    let replies = File::open(replies).unwrap();
+    let mut command = filter_command(&[], 1, options);
+    unsafe {
+        // Each is moved out of the way first, in case one is where the other
+        // goes, and then kept open across exec by hand, since dup2 does
+        // nothing for a file that's already in place.
+        command.pre_exec(move || {
+            let mut channels = vec![(replies.as_raw_fd(), 3)];
+            channels.extend(side_channel.as_ref().map(|side| (side.as_raw_fd(), 4)));
+            for (fd, _) in &mut channels {
+                *fd = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 10);
+            }
+            for (fd, to) in channels {
+                if fd == -1 || libc::dup2(fd, to) == -1 || libc::fcntl(to, libc::F_SETFD, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    output_for_input(command, input)
+}
+
+/// Runs `command`, feeding it `input`, and collects what it says.
+fn output_for_input(mut command: Command, input: &[u8]) -> Output {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("can't run the filter");
+
+    // Feed the input from another thread, so that we're reading output while
+    // the filter is still reading input.
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+
+    let output = child.wait_with_output().unwrap();
+    // The filter may fail without reading all of its input.
+    let _ = writer.join().unwrap();
+    output
+}
+
+/// Runs the filter like [`filter_output`], with the printer gone: nothing is
+/// reading its output.
+pub fn filter_output_disconnected(options: &str, input: &[u8]) -> Output {
+    let mut child = filter_command(&[], 1, options)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("can't run the filter");
+    drop(child.stdout.take());
+
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output().unwrap();
+    let _ = writer.join().unwrap();
+    output
+}
+
+/// Runs the filter like [`filter_output`], with a printer that's stopped
+/// taking data: its output is never read. After `stall`, the filter is sent
+/// `SIGTERM`, as CUPS does to cancel a job. Returns how it exited, and how long
+/// it took to after the signal (killing it if it takes more than a few
+/// seconds).
+pub fn cancel_stalled_filter(
+    options: &str,
+    input: &[u8],
+    stall: Duration,
+) -> (ExitStatus, Duration) {
+    let mut child = filter_command(&[], 1, options)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("can't run the filter");
+    let stdout = child.stdout.take();
+
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    std::thread::sleep(stall);
+    assert!(
+        child.try_wait().unwrap().is_none(),
+        "the filter finished without stalling"
+    );
+
+    let signalled = Instant::now();
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    // A filter that doesn't notice gets killed, rather than hanging the test.
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if signalled.elapsed() > Duration::from_secs(5) {
+            child.kill().unwrap();
+            break child.wait().unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    };
+    let elapsed = signalled.elapsed();
+    drop(stdout);
+    let _ = writer.join().unwrap();
+    (status, elapsed)
+}
+
+/// Sets up a command to run the filter with the example PPD.
+fn filter_command(env: &[(&str, &str)], copies: u32, options: &str) -> Command {
+    filter_command_with(&[], env, copies, options)
+}
+
+/// Sets up a command like [`filter_command`], with `flags` ahead of the usual
+/// arguments.
+fn filter_command_with(
+    flags: &[&str],
+    env: &[(&str, &str)],
+    copies: u32,
+    options: &str,
+) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_raster-tspl"));
+    command
+        .args(flags)
+        .args(["1", "user", "title", &copies.to_string(), options])
+        .env(
+            "PPD",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/ppd/sp420.tspl.ppd"),
+        )
+        .env("TSPL_FILTER_CONF", "/dev/null")
+        .env("TSPL_FILTER_MODELS", "/nonexistent")
+        .env_remove("PRINTER")
+        .envs(env.iter().copied());
+    command
+}
+
+/// Runs the filter over `input` and the result through a 203dpi printer.
+///
+/// Panics if the filter fails or sends the printer something it rejects.
+pub fn print(options: &str, input: &[u8]) -> Printer {
+    print_on(Printer::new(8), 1, options, input)
+}
+
+/// Runs the filter over `input`, making `copies` copies, and the result
+/// through `printer`, checking it against TSPL's grammar on the way.
+pub fn print_on(mut printer: Printer, copies: u32, options: &str, input: &[u8]) -> Printer {
+    let output = run_filter(copies, options, input);
+    let line_ending = if printer.bare_lf {
+        LineEnding::Lf
+    } else {
+        LineEnding::CrLf
+    };
+    let mut validator = Validator::new(line_ending);
+    if let Err(e) = validator.feed(&output).and_then(|()| validator.finish()) {
+        panic!("the filter output isn't valid TSPL: {e}");
+    }
+    if let Err(e) = printer.run(&output) {
+        panic!("printer rejected the filter output: {e}");
+    }
+    printer
+}
+
+/// Saves an image as an RGB PNG, for looking at when a test fails.
+pub fn save_png(path: &Path, width: u32, height: u32, color: impl Fn(u32, u32) -> [u8; 3]) {
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let color = &color;
+    let data = (0..height)
+        .flat_map(|y| (0..width).flat_map(move |x| color(x, y)))
+        .collect::<Vec<_>>();
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&data).unwrap();
+}
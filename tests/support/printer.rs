@@ -0,0 +1,414 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A virtual TSPL printer, for checking what the filter sends.
+//!
+//! This interprets the subset of TSPL described in `tspl.mkdn`, plus the
+//! commands the filter sends during setup. It's stricter than any real printer
+//! we know of: anything a real printer might get wrong, such as a stray byte
+//! of bitmap data landing where a command should be, is reported as an error
+//! rather than quietly ignored.
+
+use std::collections::BTreeMap;
+
+/// How labels are told apart on the roll.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Media {
+    /// Gapped labels, with the gap and offset in millimeters. A zero gap means
+    /// continuous stock.
+    Gap(f64, f64),
+    /// Black marks, with the mark height and offset in millimeters.
+    BLine(f64, f64),
+}
+
+/// A printed label, the way up it comes out of the printer: turned half a
+/// turn from the image buffer under `DIRECTION 1`, and mirrored under
+/// `DIRECTION n,1`.
+#[derive(Clone, Debug)]
+pub struct Label {
+    /// Size in dots.
+    pub width: u32,
+    pub height: u32,
+    /// One entry per dot, row by row; `true` for ink.
+    pub black: Vec<bool>,
+    pub red: Vec<bool>,
+    /// Stored forms drawn onto the label with `PUTBMP`, by name.
+    pub forms: Vec<String>,
+    /// Barcodes drawn onto the label with `BARCODE`, by type and contents.
+    pub barcodes: Vec<(String, String)>,
+    pub copies: u32,
+}
+
+impl Label {
+    /// Checks whether the dot at `x`, `y` is black. Dots off the label aren't.
+    pub fn black(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height && self.black[(y * self.width + x) as usize]
+    }
+
+    /// Checks whether the dot at `x`, `y` is red.
+    pub fn red(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height && self.red[(y * self.width + x) as usize]
+    }
+}
+
+/// Printer state, as set up by the commands it's been sent.
+#[derive(Default, Debug)]
+pub struct Printer {
+    /// Dots per millimeter, as the firmware reckons it: 8 at 203dpi, 12 at
+    /// 300dpi.
+    pub dots_per_mm: u32,
+    /// `BITMAP` mode that draws in red, for two-color printers.
+    pub red_mode: Option<u32>,
+    /// Most labels one `PRINT` may ask for.
+    pub max_print_count: Option<u32>,
+    /// Whether commands end with a bare LF, as on some clone firmwares,
+    /// rather than CR LF.
+    pub bare_lf: bool,
+    /// Whether `BITMAP` data has the leftmost dot of each byte in the least
+    /// significant bit, and whether set bits are ink, as on some clone
+    /// firmwares.
+    pub lsb_first: bool,
+    pub inverted: bool,
+
+    pub size_mm: Option<(f64, f64)>,
+    pub media: Option<Media>,
+    pub limit_feed_mm: Option<f64>,
+    pub offset_mm: f64,
+    pub reference: (i32, i32),
+    pub direction: (u32, u32),
+    pub density: Option<u32>,
+    pub speed: Option<f64>,
+    /// Settings from `SET` and `SETC`, e.g. `"TEAR"` → `"ON"`.
+    pub settings: BTreeMap<String, String>,
+    /// Real-time clock settings, e.g. `"YEAR"` → 2026.
+    pub clock: BTreeMap<String, u32>,
+    /// Files downloaded to flash, by name.
+    pub files: BTreeMap<String, Vec<u8>>,
+    /// Reports asked for with `SELFTEST` and `DIAGNOSTIC`, in order.
+    pub reports: Vec<String>,
+    /// Text of `REM` comments, in order.
+    pub comments: Vec<String>,
+    /// Sensor calibrations asked for with `GAPDETECT` and `BLINEDETECT`.
+    pub calibrations: Vec<String>,
+    /// Immediate commands (`<ESC>!` and a character), in order.
+    pub immediate: Vec<char>,
+    pub labels: Vec<Label>,
+
+    /// The image buffer, once it's been cleared for the current label size.
+    buffer: Option<Label>,
+}
+
+impl Printer {
+    pub fn new(dots_per_mm: u32) -> Self {
+        Self {
+            dots_per_mm,
+            ..Self::default()
+        }
+    }
+
+    /// Runs a TSPL program, stopping at the first error.
+    pub fn run(&mut self, program: &[u8]) -> Result<(), String> {
+        let mut pos = 0;
+        while pos < program.len() {
+            pos = self
+                .command(program, pos)
+                .map_err(|e| format!("at byte {pos}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Interprets the command starting at `pos`, returning where the next one
+    /// starts.
+    fn command(&mut self, program: &[u8], pos: usize) -> Result<usize, String> {
+        let rest = &program[pos..];
+        // Immediate commands take effect as soon as they arrive, with no
+        // terminator.
+        if let [0x1B, b'!', command, ..] = rest {
+            self.immediate.push(*command as char);
+            return Ok(pos + 3);
+        }
+        // Commands that carry binary data give its length in their leading
+        // arguments, so they can't be split at the end of the line.
+        if let Some(args) = rest.strip_prefix(b"BITMAP ") {
+            let (args, data) = split_args(args, 5)?;
+            let consumed = self.bitmap(&args, data)?;
+            return Ok(pos + rest.len() - data.len() + consumed);
+        }
+        if let Some(args) = rest.strip_prefix(b"DOWNLOAD ") {
+            let (args, data) = split_args(args, 3)?;
+            let consumed = self.download(&args, data)?;
+            return Ok(pos + rest.len() - data.len() + consumed);
+        }
+
+        let (terminator, name): (&[u8], _) = if self.bare_lf {
+            (b"\n", "LF")
+        } else {
+            (b"\r\n", "CR LF")
+        };
+        let end = rest
+            .windows(terminator.len())
+            .position(|w| w == terminator)
+            .ok_or_else(|| format!("command isn't terminated with {name}"))?;
+        let line = &rest[..end];
+        if let Some(&b) = line.iter().find(|b| !(b.is_ascii_graphic() || **b == b' ')) {
+            return Err(format!(
+                "binary data in command context ({:?} in {:?})",
+                b as char,
+                line.escape_ascii().to_string()
+            ));
+        }
+        let line = std::str::from_utf8(line).unwrap();
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        if name == "REM" {
+            self.comments.push(args.into());
+            return Ok(pos + end + terminator.len());
+        }
+        let args = if args.is_empty() {
+            vec![]
+        } else {
+            args.split(',').map(str::trim).collect()
+        };
+        self.simple(name, &args)
+            .map_err(|e| format!("{line:?}: {e}"))?;
+        Ok(pos + end + terminator.len())
+    }
+
+    /// Interprets a command that fits on one line.
+    fn simple(&mut self, name: &str, args: &[&str]) -> Result<(), String> {
+        match (name, args) {
+            // Blank lines separate bitmap data from the next command.
+            ("", []) => {}
+            ("SIZE", [w, h]) => self.size_mm = Some((distance_mm(w)?, distance_mm(h)?)),
+            ("GAP", [gap, offset]) => {
+                self.media = Some(Media::Gap(distance_mm(gap)?, distance_mm(offset)?))
+            }
+            ("BLINE", [height, offset]) => {
+                self.media = Some(Media::BLine(distance_mm(height)?, distance_mm(offset)?))
+            }
+            ("LIMITFEED", [length]) => self.limit_feed_mm = Some(distance_mm(length)?),
+            ("OFFSET", [distance]) => self.offset_mm = distance_mm(distance)?,
+            ("REFERENCE", [x, y]) => self.reference = (number(x)?, number(y)?),
+            ("DIRECTION", [d]) => self.direction = (number(d)?, 0),
+            ("DIRECTION", [d, m]) => self.direction = (number(d)?, number(m)?),
+            ("DENSITY", [d]) => {
+                let d = number(d)?;
+                if d > 15 {
+                    return Err(format!("density {d} is out of range"));
+                }
+                self.density = Some(d);
+            }
+            ("SPEED", [s]) => self.speed = Some(number(s)?),
+            ("SET" | "SETC", [setting]) => {
+                let (key, value) = setting
+                    .split_once(' ')
+                    .ok_or("expected a setting and a value")?;
+                self.settings.insert(key.into(), value.into());
+            }
+            ("YEAR" | "MONTH" | "DATE" | "HOUR" | "MINUTE" | "SECOND", [value]) => {
+                let value = value.strip_prefix("= ").ok_or("expected an assignment")?;
+                self.clock.insert(name.into(), number(value)?);
+            }
+            ("GAPDETECT" | "BLINEDETECT", []) => self.calibrations.push(name.into()),
+            ("CLS", []) => {
+                self.buffer = self.size_mm.map(|(w, h)| {
+                    let width = (w * f64::from(self.dots_per_mm)).round() as u32;
+                    let height = (h * f64::from(self.dots_per_mm)).round() as u32;
+                    let dots = width as usize * height as usize;
+                    Label {
+                        width,
+                        height,
+                        black: vec![false; dots],
+                        red: vec![false; dots],
+                        forms: vec![],
+                        barcodes: vec![],
+                        copies: 0,
+                    }
+                });
+            }
+            ("PUTBMP", [x, y, name]) => {
+                number::<u32>(x)?;
+                number::<u32>(y)?;
+                let buffer = self.buffer.as_mut().ok_or("PUTBMP before SIZE and CLS")?;
+                buffer.forms.push(quoted(name)?.into());
+            }
+            ("BOX", [x, y, x_end, y_end, thickness]) => {
+                let (x, y, x_end, y_end, thickness): (u32, u32, u32, u32, u32) = (
+                    number(x)?,
+                    number(y)?,
+                    number(x_end)?,
+                    number(y_end)?,
+                    number(thickness)?,
+                );
+                let buffer = self.buffer.as_mut().ok_or("BOX before SIZE and CLS")?;
+                if x > x_end || y > y_end || x_end >= buffer.width || y_end >= buffer.height {
+                    return Err(format!(
+                        "box from {x},{y} to {x_end},{y_end} doesn't fit on a {}x{} label",
+                        buffer.width, buffer.height
+                    ));
+                }
+                for dot_y in y..=y_end {
+                    for dot_x in x..=x_end {
+                        let edge = dot_x < x + thickness
+                            || dot_x + thickness > x_end
+                            || dot_y < y + thickness
+                            || dot_y + thickness > y_end;
+                        if edge {
+                            buffer.black[(dot_y * buffer.width + dot_x) as usize] = true;
+                        }
+                    }
+                }
+            }
+            ("BARCODE", [x, y, kind, height, readable, rotation, narrow, wide, text]) => {
+                for n in [x, y, height, readable, rotation, narrow, wide] {
+                    number::<u32>(n)?;
+                }
+                let buffer = self.buffer.as_mut().ok_or("BARCODE before SIZE and CLS")?;
+                buffer
+                    .barcodes
+                    .push((quoted(kind)?.into(), quoted(text)?.into()));
+            }
+            ("SELFTEST" | "DIAGNOSTIC", []) => self.reports.push(name.into()),
+            ("PRINT", [sets]) => self.print(number(sets)?, 1)?,
+            ("PRINT", [sets, copies]) => self.print(number(sets)?, number(copies)?)?,
+            _ => return Err("unknown command or wrong arguments".into()),
+        }
+        Ok(())
+    }
+
+    fn print(&mut self, sets: u32, copies: u32) -> Result<(), String> {
+        let buffer = self.buffer.as_ref().ok_or("PRINT before SIZE and CLS")?;
+        if sets == 0 || copies == 0 {
+            return Err("PRINT of no labels".into());
+        }
+        if self.max_print_count.is_some_and(|max| sets * copies > max) {
+            return Err(format!(
+                "PRINT of {} labels is over the limit",
+                sets * copies
+            ));
+        }
+        let mut label = Label {
+            copies: sets * copies,
+            ..buffer.clone()
+        };
+        let (direction, mirror) = self.direction;
+        for plane in [&mut label.black, &mut label.red] {
+            if direction == 1 {
+                plane.reverse();
+            }
+            if mirror == 1 {
+                for row in plane.chunks_mut(label.width as usize) {
+                    row.reverse();
+                }
+            }
+        }
+        self.labels.push(label);
+        Ok(())
+    }
+
+    /// Draws a bitmap, returning the number of data bytes it used.
+    fn bitmap(&mut self, args: &[&str], data: &[u8]) -> Result<usize, String> {
+        let [x, y, width_bytes, height, mode] = args else {
+            unreachable!()
+        };
+        let (x, y, width_bytes, height, mode): (u32, u32, u32, u32, u32) = (
+            number(x)?,
+            number(y)?,
+            number(width_bytes)?,
+            number(height)?,
+            number(mode)?,
+        );
+        let len = width_bytes as usize * height as usize;
+        if data.len() < len {
+            return Err(format!(
+                "BITMAP needs {len} bytes of data, but only {} follow",
+                data.len()
+            ));
+        }
+        let (red_mode, lsb_first, inverted) = (self.red_mode, self.lsb_first, self.inverted);
+        let buffer = self.buffer.as_mut().ok_or("BITMAP before SIZE and CLS")?;
+        if x + width_bytes * 8 > buffer.width || y + height > buffer.height {
+            return Err(format!(
+                "{}x{} BITMAP at {x},{y} doesn't fit on a {}x{} label",
+                width_bytes * 8,
+                height,
+                buffer.width,
+                buffer.height
+            ));
+        }
+        let plane = match mode {
+            0..=2 => &mut buffer.black,
+            m if Some(m) == red_mode => &mut buffer.red,
+            m => return Err(format!("unknown BITMAP mode {m}")),
+        };
+        for (row, bytes) in data[..len].chunks(width_bytes as usize).enumerate() {
+            for bit in 0..width_bytes * 8 {
+                // A clear bit is ink, unless the firmware's inverted.
+                let mask = if lsb_first {
+                    0x01 << (bit % 8)
+                } else {
+                    0x80 >> (bit % 8)
+                };
+                let ink = (bytes[bit as usize / 8] & mask == 0) != inverted;
+                let dot = &mut plane[((y + row as u32) * buffer.width + x + bit) as usize];
+                match mode {
+                    0 => *dot = ink,
+                    2 => *dot ^= ink,
+                    _ => *dot |= ink,
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    /// Stores a file, returning the number of data bytes it used.
+    fn download(&mut self, args: &[&str], data: &[u8]) -> Result<usize, String> {
+        let ["F", name, len] = args else {
+            return Err("only DOWNLOAD F,\"NAME\",LENGTH,DATA is supported".into());
+        };
+        let len = number(len)?;
+        let contents = data
+            .get(..len)
+            .ok_or_else(|| format!("DOWNLOAD needs {len} bytes of data"))?;
+        self.files.insert(quoted(name)?.into(), contents.to_vec());
+        Ok(len)
+    }
+}
+
+/// Splits `count` comma-terminated arguments off the front of a command,
+/// returning them and what follows.
+fn split_args(args: &[u8], count: usize) -> Result<(Vec<&str>, &[u8]), String> {
+    let mut rest = args;
+    let mut split = vec![];
+    for _ in 0..count {
+        let comma = rest
+            .iter()
+            .position(|&b| b == b',')
+            .ok_or("missing arguments")?;
+        let arg = std::str::from_utf8(&rest[..comma])
+            .ok()
+            .filter(|a| a.bytes().all(|b| b.is_ascii_graphic() || b == b' '))
+            .ok_or("binary data in arguments")?;
+        split.push(arg.trim());
+        rest = &rest[comma + 1..];
+    }
+    Ok((split, rest))
+}
+
+fn number<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("bad number {s:?}"))
+}
+
+/// Parses a distance, which is in inches unless it says `mm`.
+fn distance_mm(s: &str) -> Result<f64, String> {
+    match s.strip_suffix(" mm") {
+        Some(mm) => number(mm),
+        None => Ok(number::<f64>(s)? * 25.4),
+    }
+}
+
+fn quoted(s: &str) -> Result<&str, String> {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted name, found {s:?}"))
+}
@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Generated CUPS raster, to feed the filter.
+//!
+//! Streams are written uncompressed (`RaS3`), which libcups reads the same as
+//! what a real rasterizer sends.
+
+use std::mem::size_of;
+
+use cups_raster_sys::{
+    cups_cspace_e_CUPS_CSPACE_K, cups_cspace_e_CUPS_CSPACE_RGB, cups_cspace_e_CUPS_CSPACE_SRGB,
+    cups_cspace_e_CUPS_CSPACE_SW, cups_cspace_e_CUPS_CSPACE_W, cups_page_header2_t,
+};
+use raster_tspl::api;
+
+/// A pixel format the filter accepts.
+#[derive(Copy, Clone, Debug)]
+pub struct Format {
+    pub colorspace: u32,
+    pub bits: u32,
+}
+
+pub const K1: Format = Format {
+    colorspace: cups_cspace_e_CUPS_CSPACE_K,
+    bits: 1,
+};
+pub const K8: Format = Format {
+    colorspace: cups_cspace_e_CUPS_CSPACE_K,
+    bits: 8,
+};
+pub const W8: Format = Format {
+    colorspace: cups_cspace_e_CUPS_CSPACE_W,
+    bits: 8,
+};
+pub const SW8: Format = Format {
+    colorspace: cups_cspace_e_CUPS_CSPACE_SW,
+    bits: 8,
+};
+pub const RGB8: Format = Format {
+    colorspace: cups_cspace_e_CUPS_CSPACE_RGB,
+    bits: 8,
+};
+pub const SRGB8: Format = Format {
+    colorspace: cups_cspace_e_CUPS_CSPACE_SRGB,
+    bits: 8,
+};
+
+/// A page of raster.
+pub struct Page {
+    pub header: cups_page_header2_t,
+    pub pixels: Vec<u8>,
+}
+
+impl Page {
+    /// Draws a page `width` by `height` points at `dpi`, asking `level` how
+    /// much ink each pixel gets, from 0 (none) to 255 (full).
+    ///
+    /// 1-bit pages can't do gray, so pixels are inked if they'd get at least
+    /// half.
+    pub fn draw(
+        format: Format,
+        [width_pt, height_pt]: [u32; 2],
+        dpi: u32,
+        level: impl Fn(u32, u32) -> u8,
+    ) -> Self {
+        let mut header = api::blank_page_header();
+        header.HWResolution = [dpi, dpi];
+        header.PageSize = [width_pt, height_pt];
+        header.cupsWidth = width_pt * dpi / 72;
+        header.cupsHeight = height_pt * dpi / 72;
+        header.cupsBitsPerColor = format.bits;
+        header.cupsColorSpace = format.colorspace;
+
+        let cs = format.colorspace;
+        let rgb = cs == cups_cspace_e_CUPS_CSPACE_RGB || cs == cups_cspace_e_CUPS_CSPACE_SRGB;
+        let channels = if rgb { 3 } else { 1 };
+        header.cupsBitsPerPixel = format.bits * channels;
+        header.cupsBytesPerLine = (header.cupsWidth * header.cupsBitsPerPixel).div_ceil(8);
+
+        let mut pixels = vec![];
+        for y in 0..header.cupsHeight {
+            if format.bits == 1 {
+                let mut row = vec![0; header.cupsBytesPerLine as usize];
+                for x in 0..header.cupsWidth {
+                    if level(x, y) >= 128 {
+                        row[x as usize / 8] |= 0x80 >> (x % 8);
+                    }
+                }
+                pixels.extend(row);
+                continue;
+            }
+            for x in 0..header.cupsWidth {
+                let ink = level(x, y);
+                if cs == cups_cspace_e_CUPS_CSPACE_K {
+                    pixels.push(ink);
+                } else {
+                    // Everything else counts light.
+                    pixels.extend(std::iter::repeat_n(255 - ink, channels as usize));
+                }
+            }
+        }
+        Self { header, pixels }
+    }
+}
+
+/// Writes a raster stream holding `pages`.
+pub fn stream(pages: &[Page]) -> Vec<u8> {
+    // libcups works out the byte order from the sync word.
+    let mut data = 0x5261_5333_u32.to_ne_bytes().to_vec();
+    for page in pages {
+        let header = unsafe {
+            std::slice::from_raw_parts(
+                (&raw const page.header).cast::<u8>(),
+                size_of::<cups_page_header2_t>(),
+            )
+        };
+        data.extend_from_slice(header);
+        data.extend_from_slice(&page.pixels);
+    }
+    data
+}